@@ -0,0 +1,131 @@
+extern crate riquid;
+
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+
+use riquid::codegen;
+use riquid::diagnostic::Diagnostic;
+use riquid::environment::Environment;
+
+// Where `compile` writes the module, under `OUT_DIR`.
+pub const MODULE: &str = "templates.rs";
+
+#[derive(Debug)]
+pub enum Error {
+    // Not running in a build script.
+    NoOutDir,
+    Io(io::Error),
+    // One diagnostic per template that couldn't be loaded or parsed, with an excerpt of its source.
+    Templates(Vec<String>)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::NoOutDir              => write!(f, "OUT_DIR isn't set; templates can only be compiled from a build script"),
+            Error::Io(ref error)         => write!(f, "could not write the templates module: {}", error),
+            Error::Templates(ref errors) => write!(f, "{}", errors.join("\n\n"))
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+// For `build.rs`: compiles every template under `root` with the given extension into a module
+// with a `register` function adding them all to an Environment, so the binary has no template
+// files to ship. The crate includes it from `OUT_DIR`:
+//
+//     mod templates {
+//         include!(concat!(env!("OUT_DIR"), "/templates.rs"));
+//     }
+//
+//     let mut environment = Environment::new();
+//     templates::register(&mut environment);
+//
+// Templates are named like `Environment::compile_dir` names them, and the build script is rerun
+// whenever anything under `root` changes.
+pub fn compile<P: AsRef<Path>>(root: P, extension: &str) -> Result<(), Error> {
+    let root = root.as_ref();
+    let out  = env::var_os("OUT_DIR").map(PathBuf::from).ok_or(Error::NoOutDir)?;
+    println!("cargo:rerun-if-changed={}", root.display());
+
+    fs::write(out.join(MODULE), generate(root, extension)?).map_err(Error::Io)
+}
+
+// The module `compile` writes. Templates come in order of their names, so the output only changes
+// when they do.
+pub fn generate<P: AsRef<Path>>(root: P, extension: &str) -> Result<String, Error> {
+    let root            = root.as_ref();
+    let mut environment = Environment::new();
+    let errors          = environment.compile_dir(root, extension);
+
+    if !errors.is_empty() {
+        return Err(Error::Templates(errors.iter().map(|error| diagnose(root, extension, error)).collect()));
+    }
+
+    let mut names = environment.template_names().collect::<Vec<_>>();
+    names.sort();
+
+    let mut module = String::from("// Generated by riquid-build. Don't edit.\n\npub fn register(environment: &mut ::riquid::environment::Environment) {\n");
+    for name in names {
+        let Some(template) = environment.template(name) else { continue };
+        module.push_str(&format!("    environment.insert_template({:?}, {});\n", name, codegen::template(template)));
+    }
+    module.push_str("}\n");
+
+    Ok(module)
+}
+
+// Points at the template's file, when there is one to show.
+fn diagnose(root: &Path, extension: &str, error: &riquid::Error) -> String {
+    let mut diagnostic = Diagnostic::from(error);
+    let path           = diagnostic.template.as_ref().map(|name| root.join(format!("{}.{}", name, extension)));
+    let source         = path.as_ref().and_then(|path| fs::read_to_string(path).ok()).unwrap_or_default();
+
+    if let Some(path) = path { diagnostic.template = Some(path.display().to_string()); }
+    diagnostic.display(&source).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn generate_registers_every_template_in_order() {
+        let root = env::temp_dir().join(format!("riquid-build-{}", process::id()));
+        let _    = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("partials")).unwrap();
+        fs::write(root.join("page.liquid"), "{% include 'partials/footer' %}").unwrap();
+        fs::write(root.join("partials/footer.liquid"), "Bye").unwrap();
+        fs::write(root.join("notes.txt"), "{{").unwrap();
+
+        let module = generate(&root, "liquid").unwrap();
+        let lines  = module.lines().collect::<Vec<_>>();
+
+        assert_eq!("pub fn register(environment: &mut ::riquid::environment::Environment) {", lines[2]);
+        assert!(lines[3].starts_with("    environment.insert_template(\"page\", ::riquid::template::Template { "));
+        assert!(lines[4].starts_with("    environment.insert_template(\"partials/footer\", ::riquid::template::Template { "));
+        assert_eq!("}", lines[5]);
+    }
+
+    #[test]
+    fn generate_reports_every_broken_template() {
+        let root = env::temp_dir().join(format!("riquid-build-broken-{}", process::id()));
+        let _    = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("page.liquid"), "{{ # }}").unwrap();
+
+        let error = generate(&root, "liquid").unwrap_err().to_string();
+        assert_eq!(
+            format!("error: Unexpected character '#' at line 1, column 4\n --> {}:1:4\n  |\n1 | {{{{ # }}}}\n  |    ^", root.join("page.liquid").display()),
+            error
+        );
+
+        assert!(matches!(generate(root.join("missing"), "liquid"), Err(Error::Templates(ref errors)) if errors.len() == 1));
+    }
+}
@@ -0,0 +1,53 @@
+extern crate proc_macro;
+extern crate riquid;
+extern crate syn;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use proc_macro::{Span,TokenStream};
+use syn::{parse_macro_input,LitStr};
+
+use riquid::codegen;
+use riquid::diagnostic::Diagnostic;
+use riquid::template::Template;
+
+// `include_liquid!("templates/page.liquid")` is the template at that path, relative to the
+// crate's manifest, parsed while compiling. It's built without parsing at runtime, and a template
+// that doesn't parse fails the build with the error.
+//
+// Templates are parsed with the default options and tags, so tags registered with an Environment
+// are kept unparsed, like any other unknown tag.
+#[proc_macro]
+pub fn include_liquid(input: TokenStream) -> TokenStream {
+    let path = parse_macro_input!(input as LitStr);
+
+    match expand(&path) {
+        Ok(expression) => expression,
+        Err(message)   => compile_error(&message, path.span().unwrap())
+    }
+}
+
+fn expand(path: &LitStr) -> Result<TokenStream, String> {
+    let root     = env::var("CARGO_MANIFEST_DIR").map_err(|error| format!("CARGO_MANIFEST_DIR isn't set: {}", error))?;
+    let path     = PathBuf::from(root).join(path.value());
+    let source   = fs::read_to_string(&path).map_err(|error| format!("could not read {}: {}", path.display(), error))?;
+    let template = Template::parse(&source).map_err(|error| {
+        let mut diagnostic = Diagnostic::from(&riquid::Error::from(error));
+        diagnostic.template = Some(path.display().to_string());
+
+        // rustc says it's an error already.
+        diagnostic.display(&source).to_string().trim_start_matches("error: ").to_string()
+    })?;
+
+    // Including the file as well has it rebuilt whenever the template changes.
+    let expression = format!("{{ const _: &str = include_str!({:?}); {} }}", path.display().to_string(), codegen::template(&template));
+    expression.parse().map_err(|error| format!("could not compile {}: {:?}", path.display(), error))
+}
+
+// Unlike syn's errors this doesn't name `::core`, which 2015 edition crates can't.
+fn compile_error(message: &str, span: Span) -> TokenStream {
+    let error = format!("compile_error!({:?})", message).parse::<TokenStream>().unwrap_or_default();
+    error.into_iter().map(|mut token| { token.set_span(span); token }).collect()
+}
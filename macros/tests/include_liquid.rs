@@ -0,0 +1,23 @@
+#[macro_use]
+extern crate riquid_macros;
+extern crate riquid;
+
+use riquid::context::Context;
+use riquid::environment::Environment;
+use riquid::template::Template;
+use riquid::variable::Variable;
+
+#[test]
+fn include_liquid_builds_the_parsed_template() {
+    let template = include_liquid!("tests/templates/page.liquid");
+    assert_eq!(Template::parse(include_str!("templates/page.liquid")).unwrap(), template);
+
+    let mut environment = Environment::new();
+    environment.add_template("footer", "(c) {{ year }}").unwrap();
+
+    let mut context = Context::new();
+    context.add("title", Variable::from("<Home>")).unwrap();
+    context.add("items", Variable::Int(3)).unwrap();
+
+    assert_eq!("<h1>&lt;Home&gt;</h1>\n  (c) 2024\n2.5\n", environment.render_template(&template, &mut context).unwrap());
+}
@@ -0,0 +1,97 @@
+use typed_arena::Arena;
+
+use ast::Node;
+use parser::{ParseResult,ParserOptions};
+use template::{Template,Warning};
+
+// Holds the nodes of any number of templates in a few large blocks instead of a vector per
+// template, so a site's partials sit together in memory and are all freed at once. Each template's
+// nodes stay contiguous, and the templates borrow them from the arena.
+pub struct TemplateArena {
+    nodes: Arena<Node>
+}
+
+// A template whose nodes live in a TemplateArena. Renders the same as a Template, since the
+// renderer only needs the nodes.
+#[derive(Debug, PartialEq)]
+pub struct ArenaTemplate<'a> {
+    pub nodes: &'a [Node],
+    pub front_matter: Option<String>,
+    pub warnings: Vec<Warning>
+}
+
+impl Default for TemplateArena {
+    fn default() -> TemplateArena {
+        TemplateArena::new()
+    }
+}
+
+impl TemplateArena {
+    pub fn new() -> TemplateArena {
+        TemplateArena { nodes: Arena::new() }
+    }
+
+    // Room for `nodes` nodes before the arena needs another block.
+    pub fn with_capacity(nodes: usize) -> TemplateArena {
+        TemplateArena { nodes: Arena::with_capacity(nodes) }
+    }
+
+    pub fn parse(&self, source: &str) -> ParseResult<ArenaTemplate<'_>> {
+        self.parse_with(source, &ParserOptions::default())
+    }
+
+    pub fn parse_with(&self, source: &str, options: &ParserOptions) -> ParseResult<ArenaTemplate<'_>> {
+        Template::parse_with(source, options).map(|template| self.alloc(template))
+    }
+
+    // Moves an already parsed template's nodes into the arena.
+    pub fn alloc(&self, template: Template) -> ArenaTemplate<'_> {
+        ArenaTemplate {
+            nodes: self.nodes.alloc_extend(template.nodes),
+            front_matter: template.front_matter,
+            warnings: template.warnings
+        }
+    }
+
+    // How many nodes the arena holds, across every template.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use context::Context;
+    use environment::Environment;
+    use render::Renderer;
+
+    #[test]
+    fn templates_share_the_arena() {
+        let arena  = TemplateArena::with_capacity(8);
+        let header = arena.parse("<h1>{{ title }}</h1>").unwrap();
+        let footer = arena.parse("<footer>{% if year %}{{ year }}{% endif %}</footer>").unwrap();
+
+        assert_eq!(Template::parse("<h1>{{ title }}</h1>").unwrap().nodes, header.nodes);
+        assert_eq!(8, arena.len());
+        assert_eq!(5, footer.nodes.len());
+        assert!(arena.parse("{{ a | }}").is_err());
+    }
+
+    #[test]
+    fn arena_templates_render() {
+        let arena       = TemplateArena::new();
+        let template    = arena.parse("Hello {{ name }}").unwrap();
+        let environment = Environment::new();
+        let mut context = Context::new();
+        let mut output  = String::new();
+        context.add("name", "Ada".into()).unwrap();
+
+        Renderer::new(&environment).render(template.nodes, &mut context, &mut output).unwrap();
+        assert_eq!("Hello Ada", output);
+    }
+}
@@ -0,0 +1,416 @@
+use std::fmt;
+use std::ops::{Deref,Range};
+use std::sync::Arc;
+use std::vec;
+
+use lexer::{CompareOp,Span};
+use variable::Variable;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize,Deserializer,Serialize,Serializer};
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Expression {
+    Literal(Literal),
+    // The variable's name and then each lookup into it, like `user`, `address` and `[key]` for
+    // `user.address[key]`. Never empty.
+    Variable(Vec<Lookup>, Span),
+    Range(Box<Expression>, Box<Expression>, Span)
+}
+
+// Bracketed lookups are only evaluated at render time, so `items[i]` reads whatever `i` holds.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Lookup {
+    Key(String),
+    Index(Expression)
+}
+
+impl Expression {
+    // A variable read through `.` lookups only, like `user.name`.
+    pub fn variable(path: &str, span: Span) -> Expression {
+        Expression::Variable(path.split('.').map(|key| Lookup::Key(key.to_string())).collect(), span)
+    }
+
+    pub fn span(&self) -> Span {
+        match *self {
+            Expression::Literal(ref literal) => literal.span,
+            Expression::Variable(_, span)    => span,
+            Expression::Range(_, _, span)    => span
+        }
+    }
+}
+
+// Writes the expression back out as markup.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expression::Literal(ref literal)         => write!(f, "{}", literal),
+            Expression::Variable(ref lookups, _)     => {
+                for (index, lookup) in lookups.iter().enumerate() {
+                    match *lookup {
+                        Lookup::Key(ref key) if index == 0 => write!(f, "{}", key)?,
+                        Lookup::Key(ref key)               => write!(f, ".{}", key)?,
+                        Lookup::Index(ref index)           => write!(f, "[{}]", index)?
+                    }
+                }
+
+                Ok(())
+            },
+            Expression::Range(ref start, ref end, _) => write!(f, "({}..{})", start, end)
+        }
+    }
+}
+
+// A string, number or keyword literal. Strings are stored without their quotes and with escapes
+// resolved; `span` still covers the literal as written.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Literal {
+    #[cfg_attr(feature = "serde", serde(with = "literal_value"))]
+    pub value: Variable,
+    pub span: Span
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Variable::Str(ref value) => write!(f, "'{}'", value.replace('\\', "\\\\").replace('\'', "\\'")),
+            Variable::Nil            => write!(f, "nil"),
+            Variable::Empty          => write!(f, "empty"),
+            Variable::Blank          => write!(f, "blank"),
+            ref value                => write!(f, "{}", value)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FilterChain {
+    pub expression: Expression,
+    pub filters: Vec<Filter>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Filter {
+    pub name: String,
+    pub arguments: Vec<Expression>,
+    pub keyword_arguments: KeywordArguments
+}
+
+// `name: expression` pairs in the order they were written. Repeating a name replaces the earlier
+// value but keeps its position, like a Ruby hash.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct KeywordArguments {
+    arguments: Vec<(String, Expression)>
+}
+
+impl KeywordArguments {
+    pub fn new() -> KeywordArguments {
+        KeywordArguments::default()
+    }
+
+    pub fn insert(&mut self, name: String, value: Expression) {
+        match self.arguments.iter_mut().find(|(key, _)| *key == name) {
+            Some(argument) => argument.1 = value,
+            None           => self.arguments.push((name, value))
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Expression> {
+        self.arguments.iter().find(|(key, _)| key == name).map(|(_, value)| value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Expression)> {
+        self.arguments.iter().map(|(key, value)| (key.as_str(), value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.arguments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.arguments.is_empty()
+    }
+}
+
+impl IntoIterator for KeywordArguments {
+    type Item     = (String, Expression);
+    type IntoIter = vec::IntoIter<(String, Expression)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.arguments.into_iter()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Condition {
+    Test(Expression),
+    Comparison(Expression, CompareOp, Expression),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>)
+}
+
+// Each node's span covers it as written, delimiters included.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Node {
+    Text(Text, Span),
+    Output(FilterChain, Span),
+    Tag(Tag, Span)
+}
+
+impl Node {
+    pub fn span(&self) -> Span {
+        match *self {
+            Node::Text(_, span) | Node::Output(_, span) | Node::Tag(_, span) => span
+        }
+    }
+}
+
+// A node's text, as a range of the template's source rather than a copy of it. Every text node
+// of a parse shares the one source, which lives as long as any of them. Text that isn't in the
+// source, like an inline error, gets a source of its own.
+#[derive(Clone)]
+pub struct Text {
+    source: Arc<str>,
+    range: Range<usize>
+}
+
+impl Text {
+    // Panics unless `range` falls on character boundaries of `source`.
+    pub fn new(source: Arc<str>, range: Range<usize>) -> Text {
+        assert!(source.get(range.clone()).is_some(), "{:?} is not a slice of the source", range);
+        Text { source, range }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.source[self.range.clone()]
+    }
+
+    // Shortens the text the way `String::truncate` would, without copying it.
+    pub fn truncate(&mut self, length: usize) {
+        if length < self.len() {
+            assert!(self.is_char_boundary(length), "{} is not a character boundary", length);
+            self.range.end = self.range.start + length;
+        }
+    }
+}
+
+impl Deref for Text {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<'a> From<&'a str> for Text {
+    fn from(text: &'a str) -> Text {
+        Text { source: Arc::from(text), range: 0..text.len() }
+    }
+}
+
+impl From<String> for Text {
+    fn from(text: String) -> Text {
+        let length = text.len();
+        Text { source: Arc::from(text), range: 0..length }
+    }
+}
+
+impl PartialEq for Text {
+    fn eq(&self, other: &Text) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl fmt::Debug for Text {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for Text {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Written out as a plain string, so a deserialized template owns each text separately.
+#[cfg(feature = "serde")]
+impl Serialize for Text {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Text {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Text, D::Error> {
+        String::deserialize(deserializer).map(Text::from)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tag {
+    pub name: String,
+    pub markup: String,
+    pub arguments: TagArguments
+}
+
+// Whatever the tag's registered parser made of its markup.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TagArguments {
+    #[default]
+    Unparsed,
+    Condition(Condition),
+    Assign(String, FilterChain),
+    Output(FilterChain),
+    Include(Include),
+    For(Box<ForLoop>)
+}
+
+// `include 'name' with value as alias, key: value, ...`, where the name can be any expression
+// that renders to one. Without an alias the value is named after the partial.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Include {
+    pub template: Expression,
+    pub value: Option<IncludeValue>,
+    pub alias: Option<String>,
+    pub arguments: KeywordArguments
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum IncludeValue {
+    With(Expression),
+    // Renders the partial once per item.
+    For(Expression)
+}
+
+// `for item in collection reversed limit: n offset: n`. The collection can be any expression, and
+// a range is counted through without being built.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ForLoop {
+    pub variable: String,
+    pub collection: Expression,
+    // Applied after the offset and limit, as in Liquid.
+    pub reversed: bool,
+    pub limit: Option<Expression>,
+    pub offset: Option<Expression>
+}
+
+// Literals only ever hold scalars, so they're stored as a closed enum of their own rather than
+// depending on however `Variable` chooses to serialize.
+#[cfg(feature = "serde")]
+mod literal_value {
+    use serde::{Deserialize,Deserializer,Serialize,Serializer};
+    use serde::ser::Error;
+
+    use small_string::SmallString;
+    use variable::Variable;
+
+    #[derive(Serialize, Deserialize)]
+    enum Scalar {
+        Nil,
+        Empty,
+        Blank,
+        Bool(bool),
+        Int(i64),
+        Float(f64),
+        Str(SmallString)
+    }
+
+    pub fn serialize<S: Serializer>(value: &Variable, serializer: S) -> Result<S::Ok, S::Error> {
+        let scalar = match *value {
+            Variable::Nil            => Scalar::Nil,
+            Variable::Empty          => Scalar::Empty,
+            Variable::Blank          => Scalar::Blank,
+            Variable::Bool(value)    => Scalar::Bool(value),
+            Variable::Int(value)     => Scalar::Int(value),
+            Variable::Float(value)   => Scalar::Float(value),
+            Variable::Str(ref value) => Scalar::Str(value.clone()),
+            ref value                => return Err(S::Error::custom(format!("a literal can't hold {}", value.type_name())))
+        };
+
+        scalar.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Variable, D::Error> {
+        let value = match Scalar::deserialize(deserializer)? {
+            Scalar::Nil          => Variable::Nil,
+            Scalar::Empty        => Variable::Empty,
+            Scalar::Blank        => Variable::Blank,
+            Scalar::Bool(value)  => Variable::Bool(value),
+            Scalar::Int(value)   => Variable::Int(value),
+            Scalar::Float(value) => Variable::Float(value),
+            Scalar::Str(value)   => Variable::Str(value)
+        };
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literal<V: Into<Variable>>(value: V) -> Expression {
+        Expression::Literal(Literal { value: value.into(), span: Span::default() })
+    }
+
+    #[test]
+    fn expression_display_writes_markup() {
+        let range  = Expression::Range(Box::new(literal(1)), Box::new(Expression::variable("items.size", Span::default())), Span::default());
+        let lookup = Expression::Variable(vec![Lookup::Key("a".to_string()), Lookup::Index(literal("b")), Lookup::Index(Expression::variable("c.d", Span::default())), Lookup::Key("e".to_string())], Span::default());
+
+        assert_eq!("(1..items.size)", range.to_string());
+        assert_eq!("a['b'][c.d].e", lookup.to_string());
+        assert_eq!("'it\\'s \\\\'", literal("it's \\").to_string());
+        assert_eq!("2.5", literal(2.5).to_string());
+        assert_eq!("nil", literal(Variable::Nil).to_string());
+        assert_eq!("blank", literal(Variable::Blank).to_string());
+    }
+
+    #[test]
+    fn keyword_arguments_keep_insertion_order() {
+        let mut arguments = KeywordArguments::new();
+        arguments.insert("b".to_string(), literal(1));
+        arguments.insert("a".to_string(), literal(2));
+        arguments.insert("b".to_string(), literal(3));
+
+        assert_eq!(vec![("b", &literal(3)), ("a", &literal(2))], arguments.iter().collect::<Vec<_>>());
+        assert_eq!(Some(&literal(2)), arguments.get("a"));
+        assert_eq!(None, arguments.get("c"));
+        assert_eq!(2, arguments.len());
+    }
+
+    #[test]
+    fn text_slices_a_shared_source() {
+        let source   = Arc::<str>::from("héllo world");
+        let mut text = Text::new(source.clone(), 0..6);
+        assert_eq!("héllo", text.as_str());
+        assert_eq!(Text::from("héllo"), text);
+        assert_eq!(2, Arc::strong_count(&source));
+
+        text.truncate(3);
+        assert_eq!("hé", text.to_string());
+        text.truncate(10);
+        assert_eq!("\"hé\"", format!("{:?}", text));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn literals_only_serialize_scalars() {
+        let error = bincode::serialize(&literal(Variable::Array(vec![]))).unwrap_err();
+        assert_eq!("a literal can't hold array", error.to_string());
+    }
+}
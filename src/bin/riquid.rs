@@ -0,0 +1,157 @@
+extern crate clap;
+extern crate riquid;
+extern crate serde_json;
+extern crate serde_yaml;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self,Read};
+use std::path::{Path,PathBuf};
+use std::process;
+
+use clap::{Parser,Subcommand,ValueEnum};
+use serde_json::Value;
+
+use riquid::context::{Context,ErrorMode};
+use riquid::diagnostic::Diagnostic;
+use riquid::environment::Environment;
+use riquid::loader::{FsLoader,Loader};
+use riquid::template::Template;
+use riquid::variable::Variable;
+
+#[derive(Parser)]
+#[command(name = "riquid", version, about = "Renders and checks Liquid templates")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Renders a template to stdout")]
+    Render {
+        template: PathBuf,
+        #[arg(long, short, help = "A JSON or YAML file of variables, by its extension, or - for stdin")]
+        data: Option<PathBuf>,
+        #[arg(long, value_enum, default_value = "json", help = "The format of variables read from stdin")]
+        format: Format,
+        #[arg(long, help = "Where included templates are looked up [default: next to the template]")]
+        partials: Option<PathBuf>,
+        #[arg(long, help = "Fail on undefined variables and unknown filters")]
+        strict: bool
+    },
+    #[command(about = "Reports every syntax error in the templates, exiting with 1 if there were any")]
+    Check {
+        #[arg(required = true)]
+        templates: Vec<PathBuf>
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Json,
+    Yaml
+}
+
+fn main() {
+    let result = match Cli::parse().command {
+        Command::Render { template, data, format, partials, strict } => render(&template, data.as_deref(), format, partials, strict),
+        Command::Check { templates }                                 => check(&templates)
+    };
+
+    if let Err(message) = result {
+        eprintln!("{}", message);
+        process::exit(1);
+    }
+}
+
+fn render(path: &Path, data: Option<&Path>, format: Format, partials: Option<PathBuf>, strict: bool) -> Result<(), String> {
+    let source      = read(path)?;
+    let root        = partials.unwrap_or_else(|| directory(path));
+    let loader      = FsLoader::new(root, "liquid");
+    let mut context = Context::new();
+
+    if strict { context.set_error_mode(ErrorMode::Strict); }
+    if let Some(data) = data { context.extend(variables(data, format)?).map_err(|error| error.to_string())?; }
+
+    let mut env = Environment::new();
+    env.set_loader(loader.clone());
+
+    let output = env.parse(&source)
+        .and_then(|template| env.render_template(&template, &mut context))
+        .map_err(|error| {
+            let mut diagnostic = Diagnostic::from(&error);
+
+            // Errors in an include carry the include's name, and point into its source.
+            let source = match diagnostic.template {
+                Some(ref name) => loader.load(name).unwrap_or_default(),
+                None           => source.clone()
+            };
+            diagnostic.template.get_or_insert_with(|| path.display().to_string());
+
+            diagnostic.display(&source).to_string()
+        })?;
+
+    print!("{}", output);
+    Ok(())
+}
+
+fn check(paths: &[PathBuf]) -> Result<(), String> {
+    let mut failed = 0;
+
+    for path in paths {
+        let source = read(path)?;
+
+        for error in Template::parse_recovering(&source).1 {
+            let mut diagnostic = Diagnostic::from(&riquid::Error::from(error));
+            diagnostic.template = Some(path.display().to_string());
+
+            eprintln!("{}\n", diagnostic.display(&source));
+            failed += 1;
+        }
+    }
+
+    match failed {
+        0 => Ok(()),
+        1 => Err("1 error found".to_string()),
+        n => Err(format!("{} errors found", n))
+    }
+}
+
+// The directory `path` is in, as something that can be canonicalized.
+fn directory(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _                                              => PathBuf::from(".")
+    }
+}
+
+fn read(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|error| format!("error: could not read {}: {}", path.display(), error))
+}
+
+fn variables(path: &Path, format: Format) -> Result<HashMap<String, Variable>, String> {
+    let (source, format) = if path == Path::new("-") {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source).map_err(|error| format!("error: could not read stdin: {}", error))?;
+
+        (source, format)
+    } else {
+        let format = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yml") | Some("yaml") => Format::Yaml,
+            _                          => Format::Json
+        };
+
+        (read(path)?, format)
+    };
+
+    let value = match format {
+        Format::Json => serde_json::from_str(&source).map_err(|error| error.to_string()),
+        Format::Yaml => serde_yaml::from_str(&source).map_err(|error| error.to_string())
+    };
+
+    match value.map_err(|error| format!("error: could not parse {}: {}", path.display(), error))? {
+        Value::Object(map) => Ok(map.into_iter().map(|(key, value)| (key, Variable::from(value))).collect()),
+        _                  => Err(format!("error: {} has to hold an object of variables", path.display()))
+    }
+}
@@ -0,0 +1,164 @@
+use ast::{Condition,Expression,Filter,FilterChain,ForLoop,Include,IncludeValue,KeywordArguments,Literal,Lookup,Node,Tag,TagArguments};
+use lexer::{CompareOp,Span};
+use template::{Template,Warning};
+use variable::Variable;
+
+// Rust source for an expression that builds `template` again without parsing it, for templates
+// compiled into a binary. Paths in it start at `::riquid`.
+pub fn template(template: &Template) -> String {
+    format!(
+        "::riquid::template::Template {{ nodes: {}, front_matter: {}, warnings: {} }}",
+        list(&template.nodes, node), option(template.front_matter.as_ref(), |front_matter| string(front_matter)), list(&template.warnings, warning)
+    )
+}
+
+fn node(node: &Node) -> String {
+    match *node {
+        Node::Text(ref text, span)    => format!("::riquid::ast::Node::Text(::riquid::ast::Text::from({:?}), {})", text.as_str(), self::span(span)),
+        Node::Output(ref chain, span) => format!("::riquid::ast::Node::Output({}, {})", filter_chain(chain), self::span(span)),
+        Node::Tag(ref tag, span)      => format!("::riquid::ast::Node::Tag({}, {})", self::tag(tag), self::span(span))
+    }
+}
+
+fn tag(tag: &Tag) -> String {
+    format!("::riquid::ast::Tag {{ name: {}, markup: {}, arguments: {} }}", string(&tag.name), string(&tag.markup), tag_arguments(&tag.arguments))
+}
+
+fn tag_arguments(arguments: &TagArguments) -> String {
+    match *arguments {
+        TagArguments::Unparsed                    => "::riquid::ast::TagArguments::Unparsed".to_string(),
+        TagArguments::Condition(ref test)         => format!("::riquid::ast::TagArguments::Condition({})", condition(test)),
+        TagArguments::Assign(ref name, ref chain) => format!("::riquid::ast::TagArguments::Assign({}, {})", string(name), filter_chain(chain)),
+        TagArguments::Output(ref chain)           => format!("::riquid::ast::TagArguments::Output({})", filter_chain(chain)),
+        TagArguments::Include(ref include)        => format!("::riquid::ast::TagArguments::Include({})", self::include(include)),
+        TagArguments::For(ref each)               => format!("::riquid::ast::TagArguments::For(Box::new({}))", for_loop(each))
+    }
+}
+
+fn for_loop(each: &ForLoop) -> String {
+    format!(
+        "::riquid::ast::ForLoop {{ variable: {}, collection: {}, reversed: {}, limit: {}, offset: {} }}",
+        string(&each.variable), expression(&each.collection), each.reversed, option(each.limit.as_ref(), expression), option(each.offset.as_ref(), expression)
+    )
+}
+
+fn include(include: &Include) -> String {
+    let value = option(include.value.as_ref(), |value| match *value {
+        IncludeValue::With(ref value) => format!("::riquid::ast::IncludeValue::With({})", expression(value)),
+        IncludeValue::For(ref values) => format!("::riquid::ast::IncludeValue::For({})", expression(values))
+    });
+
+    format!(
+        "::riquid::ast::Include {{ template: {}, value: {}, alias: {}, arguments: {} }}",
+        expression(&include.template), value, option(include.alias.as_ref(), |alias| string(alias)), keyword_arguments(&include.arguments)
+    )
+}
+
+fn condition(condition: &Condition) -> String {
+    match *condition {
+        Condition::Test(ref expression)                => format!("::riquid::ast::Condition::Test({})", self::expression(expression)),
+        Condition::Comparison(ref left, op, ref right) => format!("::riquid::ast::Condition::Comparison({}, {}, {})", expression(left), compare_op(op), expression(right)),
+        Condition::And(ref left, ref right)            => format!("::riquid::ast::Condition::And(Box::new({}), Box::new({}))", self::condition(left), self::condition(right)),
+        Condition::Or(ref left, ref right)             => format!("::riquid::ast::Condition::Or(Box::new({}), Box::new({}))", self::condition(left), self::condition(right))
+    }
+}
+
+fn filter_chain(chain: &FilterChain) -> String {
+    format!("::riquid::ast::FilterChain {{ expression: {}, filters: {} }}", expression(&chain.expression), list(&chain.filters, filter))
+}
+
+fn filter(filter: &Filter) -> String {
+    format!(
+        "::riquid::ast::Filter {{ name: {}, arguments: {}, keyword_arguments: {} }}",
+        string(&filter.name), list(&filter.arguments, expression), keyword_arguments(&filter.keyword_arguments)
+    )
+}
+
+fn keyword_arguments(arguments: &KeywordArguments) -> String {
+    if arguments.is_empty() { return "::riquid::ast::KeywordArguments::new()".to_string(); }
+
+    let inserts = arguments.iter().map(|(name, value)| format!("arguments.insert({}, {}); ", string(name), expression(value))).collect::<String>();
+    format!("{{ let mut arguments = ::riquid::ast::KeywordArguments::new(); {}arguments }}", inserts)
+}
+
+fn expression(expression: &Expression) -> String {
+    match *expression {
+        Expression::Literal(ref literal)        => format!("::riquid::ast::Expression::Literal({})", self::literal(literal)),
+        Expression::Variable(ref lookups, span) => format!("::riquid::ast::Expression::Variable({}, {})", list(lookups, lookup), self::span(span)),
+        Expression::Range(ref start, ref end, span) => {
+            format!("::riquid::ast::Expression::Range(Box::new({}), Box::new({}), {})", self::expression(start), self::expression(end), self::span(span))
+        }
+    }
+}
+
+fn lookup(lookup: &Lookup) -> String {
+    match *lookup {
+        Lookup::Key(ref key)     => format!("::riquid::ast::Lookup::Key({})", string(key)),
+        Lookup::Index(ref index) => format!("::riquid::ast::Lookup::Index({})", expression(index))
+    }
+}
+
+// Panics on a literal holding anything but a scalar, which parsing never makes.
+fn literal(literal: &Literal) -> String {
+    let value = match literal.value {
+        Variable::Nil            => "::riquid::variable::Variable::Nil".to_string(),
+        Variable::Empty          => "::riquid::variable::Variable::Empty".to_string(),
+        Variable::Blank          => "::riquid::variable::Variable::Blank".to_string(),
+        Variable::Bool(value)    => format!("::riquid::variable::Variable::Bool({})", value),
+        Variable::Int(value)     => format!("::riquid::variable::Variable::Int({}i64)", value),
+        Variable::Float(value)   => format!("::riquid::variable::Variable::Float({:?}f64)", value),
+        Variable::Str(ref value) => format!("::riquid::variable::Variable::Str(::riquid::small_string::SmallString::from({:?}))", value.as_str()),
+        ref value                => panic!("a literal can't hold {}", value.type_name())
+    };
+
+    format!("::riquid::ast::Literal {{ value: {}, span: {} }}", value, span(literal.span))
+}
+
+fn warning(warning: &Warning) -> String {
+    match *warning {
+        Warning::UnknownTag(ref name, span) => format!("::riquid::template::Warning::UnknownTag({}, {})", string(name), self::span(span))
+    }
+}
+
+fn compare_op(op: CompareOp) -> String {
+    format!("::riquid::lexer::CompareOp::{:?}", op)
+}
+
+fn span(span: Span) -> String {
+    format!("::riquid::lexer::Span {{ start: {}, end: {}, line: {}, col: {} }}", span.start, span.end, span.line, span.col)
+}
+
+// Debug-formatted strings are valid Rust literals.
+fn string(value: &str) -> String {
+    format!("String::from({:?})", value)
+}
+
+fn list<T, F>(items: &[T], item: F) -> String where F: Fn(&T) -> String {
+    format!("vec![{}]", items.iter().map(item).collect::<Vec<_>>().join(", "))
+}
+
+fn option<T, F>(value: Option<&T>, some: F) -> String where T: ?Sized, F: FnOnce(&T) -> String {
+    match value {
+        Some(value) => format!("Some({})", some(value)),
+        None        => "None".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_writes_an_expression_building_the_template() {
+        let parsed = Template::parse("Hi {{ name | append: '!', by: 2 }}{% if a > 1.5 %}\"{% endif %}{{ items[i] }}").unwrap();
+        let code   = template(&parsed);
+
+        assert!(code.starts_with("::riquid::template::Template { nodes: vec![::riquid::ast::Node::Text(::riquid::ast::Text::from(\"Hi \"), ::riquid::lexer::Span { start: 0, end: 3, line: 1, col: 1 }), "));
+        assert!(code.contains("{ let mut arguments = ::riquid::ast::KeywordArguments::new(); arguments.insert(String::from(\"by\"), ::riquid::ast::Expression::Literal(::riquid::ast::Literal { value: ::riquid::variable::Variable::Int(2i64), "));
+        assert!(code.contains("::riquid::ast::Condition::Comparison(::riquid::ast::Expression::Variable(vec![::riquid::ast::Lookup::Key(String::from(\"a\"))], "));
+        assert!(code.contains("vec![::riquid::ast::Lookup::Key(String::from(\"items\")), ::riquid::ast::Lookup::Index(::riquid::ast::Expression::Variable(vec![::riquid::ast::Lookup::Key(String::from(\"i\"))], "));
+        assert!(code.contains("::riquid::lexer::CompareOp::Gt, ::riquid::ast::Expression::Literal(::riquid::ast::Literal { value: ::riquid::variable::Variable::Float(1.5f64)"));
+        assert!(code.contains("::riquid::ast::Text::from(\"\\\"\")"));
+        assert!(code.ends_with("front_matter: None, warnings: vec![] }"));
+    }
+}
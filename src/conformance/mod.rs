@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde_json::Value;
+
+use context::{Context,ErrorMode};
+use environment::Environment;
+use error::Error;
+use variable::Variable;
+
+// Golden-file cases checking templates render the way they do in Ruby's Liquid. Cases are kept in
+// plain text, one after another:
+//
+//     === plus adds numbers
+//     --- data
+//     {"price": 10}
+//     --- template
+//     {{ price | plus: 2 }}
+//     --- expected
+//     12
+//
+// A case has a `template` and either the `expected` output or the `error` it fails with. It can
+// also have `data`, a JSON object of its variables, `partial <name>` sections for the templates it
+// includes and a `strict` section, which is left empty, to render in strict mode. Trailing empty
+// lines aren't part of a section, so cases can be spaced out, and lines before a case's first
+// section can be `#` comments.
+//
+// The cases here are ported from Ruby Liquid's own tests. More are welcome: anything that renders
+// differently from Ruby is a bug.
+pub const SUITES: &[(&str, &str)] = &[
+    ("variables", include_str!("cases/variables.txt")),
+    ("assign", include_str!("cases/assign.txt")),
+    ("conditions", include_str!("cases/conditions.txt")),
+    ("filters", include_str!("cases/filters.txt")),
+    ("whitespace", include_str!("cases/whitespace.txt")),
+    ("include", include_str!("cases/include.txt")),
+    ("for", include_str!("cases/for.txt"))
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Case {
+    pub name: String,
+    pub template: String,
+    pub variables: HashMap<String, Variable>,
+    pub partials: Vec<(String, String)>,
+    pub strict: bool,
+    pub expected: Outcome
+}
+
+// What rendering a case gives: its output, or the error's message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Output(String),
+    Error(String)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatError {
+    pub line: usize,
+    pub message: String
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Failure {
+    pub case: String,
+    pub expected: Outcome,
+    pub actual: Outcome
+}
+
+impl Case {
+    // Renders the case with its partials added to a copy of `environment`, so the same one can run
+    // every case.
+    pub fn run(&self, environment: &Environment) -> Result<(), Failure> {
+        let mut environment = environment.clone();
+        let mut context     = Context::new();
+        if self.strict { context.set_error_mode(ErrorMode::Strict); }
+
+        let rendered = self.partials.iter()
+            .try_for_each(|(name, source)| environment.add_template(name, source))
+            .and_then(|_| context.extend(self.variables.clone()).map_err(Error::from))
+            .and_then(|_| environment.parse(&self.template))
+            .and_then(|template| environment.render_template(&template, &mut context));
+
+        let actual = match rendered {
+            Ok(output) => Outcome::Output(output),
+            Err(error) => Outcome::Error(error.to_string())
+        };
+
+        match actual == self.expected {
+            true  => Ok(()),
+            false => Err(Failure { case: self.name.clone(), expected: self.expected.clone(), actual })
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Outcome::Output(ref output) => write!(f, "output {:?}", output),
+            Outcome::Error(ref message) => write!(f, "error {:?}", message)
+        }
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at line {}", self.message, self.line)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: expected {}, got {}", self.case, self.expected, self.actual)
+    }
+}
+
+struct Section<'s> {
+    line: usize,
+    header: &'s str,
+    lines: Vec<&'s str>
+}
+
+pub fn parse(source: &str) -> Result<Vec<Case>, FormatError> {
+    let mut cases: Vec<(usize, &str, Vec<Section>)> = vec![];
+
+    for (line, text) in source.lines().enumerate().map(|(index, text)| (index + 1, text)) {
+        if let Some(name) = text.strip_prefix("=== ") {
+            cases.push((line, name.trim(), vec![]));
+        } else if let Some(header) = text.strip_prefix("--- ") {
+            let Some((_, _, sections)) = cases.last_mut() else { return Err(error(line, "Expected a case's `===` line first")) };
+            sections.push(Section { line, header: header.trim(), lines: vec![] });
+        } else if let Some(section) = cases.last_mut().and_then(|(_, _, sections)| sections.last_mut()) {
+            section.lines.push(text);
+        } else if !text.trim().is_empty() && !text.starts_with('#') {
+            return Err(error(line, "Expected a `===` or `---` line"));
+        }
+    }
+
+    cases.into_iter().map(|(line, name, sections)| case(line, name, sections)).collect()
+}
+
+// Every case in `SUITES`.
+pub fn bundled() -> Vec<Case> {
+    SUITES.iter()
+        .flat_map(|&(name, source)| parse(source).unwrap_or_else(|error| panic!("{}: {}", name, error)))
+        .collect()
+}
+
+pub fn run(environment: &Environment, cases: &[Case]) -> Vec<Failure> {
+    cases.iter().filter_map(|case| case.run(environment).err()).collect()
+}
+
+fn case(line: usize, name: &str, sections: Vec<Section>) -> Result<Case, FormatError> {
+    let mut template = None;
+    let mut expected = None;
+    let mut case     = Case { name: name.to_string(), template: String::new(), variables: HashMap::new(), partials: vec![], strict: false, expected: Outcome::Output(String::new()) };
+
+    for section in sections {
+        let mut lines = section.lines;
+        while lines.last().is_some_and(|line| line.is_empty()) { lines.pop(); }
+        let text = lines.join("\n");
+
+        match section.header.split_once(' ').unwrap_or((section.header, "")) {
+            ("template", "")                      => template = Some(text),
+            ("expected", "")                      => expected = Some(Outcome::Output(text)),
+            ("error", "")                         => expected = Some(Outcome::Error(text)),
+            ("data", "")                          => case.variables = variables(section.line, &text)?,
+            ("strict", "")                        => case.strict = true,
+            ("partial", name) if !name.is_empty() => case.partials.push((name.trim().to_string(), text)),
+            _                                     => return Err(error(section.line, &format!("Unknown section '{}'", section.header)))
+        }
+    }
+
+    let Some(template) = template else { return Err(error(line, &format!("Case '{}' has no template", name))) };
+    let Some(expected) = expected else { return Err(error(line, &format!("Case '{}' has no expected output or error", name))) };
+
+    Ok(Case { template, expected, ..case })
+}
+
+fn variables(line: usize, data: &str) -> Result<HashMap<String, Variable>, FormatError> {
+    match serde_json::from_str::<Value>(data).map(Variable::from) {
+        Ok(Variable::Object(variables)) => Ok(variables.into_iter().collect()),
+        Ok(_)                           => Err(error(line, "The data has to be a JSON object")),
+        Err(json)                       => Err(error(line, &json.to_string()))
+    }
+}
+
+fn error(line: usize, message: &str) -> FormatError {
+    FormatError { line, message: message.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_reads_every_section() {
+        let cases = parse("# Comments come first.\n=== one\n--- data\n{\"a\": 1}\n--- partial p\n{{ a }}\n--- template\n{% include 'p' %}\n\n--- expected\n1\n\n=== two\n--- strict\n--- template\n{{ b }}\n--- error\nUndefined variable 'b'\n").unwrap();
+
+        assert_eq!(2, cases.len());
+        assert_eq!(("one", "{% include 'p' %}", false), (cases[0].name.as_str(), cases[0].template.as_str(), cases[0].strict));
+        assert_eq!(vec![("p".to_string(), "{{ a }}".to_string())], cases[0].partials);
+        assert_eq!(Some(&Variable::Int(1)), cases[0].variables.get("a"));
+        assert_eq!(Outcome::Output("1".to_string()), cases[0].expected);
+        assert!(cases[1].strict);
+        assert_eq!(Ok(()), cases[0].run(&Environment::new()));
+    }
+
+    #[test]
+    fn parse_reports_where_the_format_is_broken() {
+        assert_eq!(Err(error(1, "Expected a case's `===` line first")), parse("--- template"));
+        assert_eq!(Err(error(2, "Unknown section 'output'")), parse("=== a\n--- output\n"));
+        assert_eq!(Err(error(1, "Case 'a' has no expected output or error")), parse("=== a\n--- template\nx"));
+        assert_eq!(Err(error(2, "The data has to be a JSON object")), parse("=== a\n--- data\n[]\n--- template\n--- expected"));
+    }
+
+    #[test]
+    fn run_reports_what_was_rendered_instead() {
+        let cases    = parse("=== sum\n--- template\n{{ 1 | plus: 1 }}\n--- expected\n3").unwrap();
+        let failures = run(&Environment::new(), &cases);
+
+        assert_eq!(vec!["sum: expected output \"3\", got output \"2\"".to_string()], failures.iter().map(ToString::to_string).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bundled_cases_render_like_ruby_liquid() {
+        let failures = run(&Environment::new(), &bundled());
+        assert!(failures.is_empty(), "{}", failures.iter().map(ToString::to_string).collect::<Vec<_>>().join("\n"));
+    }
+}
@@ -0,0 +1,1023 @@
+use std::borrow::Cow;
+use std::collections::{HashMap,HashSet};
+use std::error;
+use std::fmt;
+use std::sync::{Arc,Mutex,PoisonError};
+
+use variable::{Lambda,Variable};
+
+const MAX_DEPTH: usize = 100;
+
+pub type Scope = HashMap<Arc<str>, Variable>;
+
+pub type ContextResult<T> = Result<T, ContextError>;
+
+pub trait ValueProvider: Send + Sync {
+    fn get(&self, key: &str) -> Option<Variable>;
+}
+
+impl<F> ValueProvider for F where F: Fn(&str) -> Option<Variable> + Send + Sync {
+    fn get(&self, key: &str) -> Option<Variable> {
+        self(key)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ContextError {
+    StackTooDeep,
+    StackUnderflow,
+    InvalidKey(String),
+    InvalidPath(String),
+    UndefinedVariable(String),
+    LimitExceeded(&'static str, usize)
+}
+
+impl fmt::Display for ContextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ContextError::StackTooDeep               => write!(f, "Stack level too deep (max {})", MAX_DEPTH),
+            ContextError::StackUnderflow             => write!(f, "Cannot pop the global scope"),
+            ContextError::InvalidKey(ref key)        => write!(f, "Invalid variable name: '{}'", key),
+            ContextError::InvalidPath(ref key)       => write!(f, "Invalid variable lookup: '{}'", key),
+            ContextError::UndefinedVariable(ref key) => write!(f, "Undefined variable '{}'", key),
+            ContextError::LimitExceeded(name, limit) => write!(f, "Exceeded the {} limit of {}", name, limit)
+        }
+    }
+}
+
+impl error::Error for ContextError {}
+
+// Caps on what a render may store through the Context (assign, capture and friends). Data added
+// to the Globals by the host is not counted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Limits {
+    pub max_variables: Option<usize>,
+    pub max_depth: Option<usize>,
+    pub max_string_bytes: Option<usize>
+}
+
+// How a render treats problems it can get past, like an undefined variable or a filter it can't
+// apply: `Strict` fails, `Warn` records them (see `Context::missing_variables` and
+// `Context::warnings`) and carries on, and `Lax` carries on quietly but for unknown filters, which
+// are still recorded as warnings. The same as setting `strict_variables` and `record_missing` by
+// hand.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ErrorMode {
+    Strict,
+    Warn,
+    #[default]
+    Lax
+}
+
+// Data and settings shared by every render. Globals are immutable once wrapped in an Arc, so a
+// single instance can back any number of concurrent renders, each with its own Context. A Context
+// that changes a setting or adds a provider gets its own copy, sharing the variables, so the others
+// are left be.
+#[derive(Clone, Default)]
+pub struct Globals {
+    variables: Arc<Scope>,
+    providers: Vec<Arc<dyn ValueProvider>>,
+    strict_variables: bool,
+    record_missing: bool,
+    limits: Limits
+}
+
+impl Globals {
+    pub fn new() -> Globals {
+        Globals::default()
+    }
+
+    pub fn strict_variables(&self) -> bool {
+        self.strict_variables
+    }
+
+    pub fn set_strict_variables(&mut self, strict: bool) {
+        self.strict_variables = strict;
+    }
+
+    pub fn record_missing(&self) -> bool {
+        self.record_missing
+    }
+
+    pub fn set_record_missing(&mut self, record: bool) {
+        self.record_missing = record;
+    }
+
+    pub fn error_mode(&self) -> ErrorMode {
+        match (self.strict_variables, self.record_missing) {
+            (true, _)     => ErrorMode::Strict,
+            (false, true) => ErrorMode::Warn,
+            _             => ErrorMode::Lax
+        }
+    }
+
+    pub fn set_error_mode(&mut self, mode: ErrorMode) {
+        self.strict_variables = mode == ErrorMode::Strict;
+        self.record_missing   = mode == ErrorMode::Warn;
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn add(&mut self, key: &str, value: Variable) -> ContextResult<()> {
+        if !is_valid_key(key) { return Err(ContextError::InvalidKey(key.into())); }
+
+        Arc::make_mut(&mut self.variables).insert(key.into(), value);
+        Ok(())
+    }
+
+    // Asked before the providers added ahead of it.
+    pub fn add_provider<P>(&mut self, provider: P) where P: ValueProvider + 'static {
+        self.providers.push(Arc::new(provider));
+    }
+
+    fn find(&self, key: &str) -> Option<&Variable> {
+        self.variables.get(key)
+    }
+
+    fn provided(&self, key: &str) -> Option<Variable> {
+        self.providers.iter().rev().filter_map(|provider| provider.get(key)).next()
+    }
+}
+
+pub struct Context {
+    globals: Arc<Globals>,
+    scopes: Vec<Arc<Scope>>,
+    keys: HashSet<Arc<str>>,
+    string_bytes: usize,
+    missing: Mutex<Vec<String>>,
+    warnings: Mutex<Vec<String>>
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context::new()
+    }
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context::with_globals(Arc::new(Globals::new()))
+    }
+
+    pub fn with_globals(globals: Arc<Globals>) -> Context {
+        Context {
+            globals,
+            scopes: vec![Arc::new(Scope::new())],
+            keys: HashSet::new(),
+            string_bytes: 0,
+            missing: Mutex::new(Vec::new()),
+            warnings: Mutex::new(Vec::new())
+        }
+    }
+
+    // With this render's settings and providers, which are only copied out of the shared Globals
+    // once one is changed.
+    pub fn globals(&self) -> &Arc<Globals> {
+        &self.globals
+    }
+
+    pub fn strict_variables(&self) -> bool {
+        self.globals.strict_variables()
+    }
+
+    pub fn set_strict_variables(&mut self, strict: bool) {
+        Arc::make_mut(&mut self.globals).set_strict_variables(strict);
+    }
+
+    pub fn set_record_missing(&mut self, record: bool) {
+        Arc::make_mut(&mut self.globals).set_record_missing(record);
+    }
+
+    pub fn error_mode(&self) -> ErrorMode {
+        self.globals.error_mode()
+    }
+
+    pub fn set_error_mode(&mut self, mode: ErrorMode) {
+        Arc::make_mut(&mut self.globals).set_error_mode(mode);
+    }
+
+    pub fn missing_variables(&self) -> Vec<String> {
+        self.missing.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn take_missing_variables(&mut self) -> Vec<String> {
+        self.missing.get_mut().unwrap_or_else(PoisonError::into_inner).drain(..).collect()
+    }
+
+    // What the render got past in warn mode other than missing variables, like bad filter
+    // arguments, and the unknown filters it skipped in any mode but strict.
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        self.warnings.get_mut().unwrap_or_else(PoisonError::into_inner).drain(..).collect()
+    }
+
+    pub fn warn(&self, warning: String) {
+        self.warnings.lock().unwrap_or_else(PoisonError::into_inner).push(warning);
+    }
+
+    pub fn limits(&self) -> &Limits {
+        self.globals.limits()
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) {
+        Arc::make_mut(&mut self.globals).set_limits(limits);
+    }
+
+    pub fn depth(&self) -> usize {
+        self.scopes.len()
+    }
+
+    pub fn push(&mut self) -> ContextResult<()> {
+        if self.depth() >= MAX_DEPTH { return Err(ContextError::StackTooDeep); }
+
+        self.scopes.push(Arc::new(Scope::new()));
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> ContextResult<Scope> {
+        if self.depth() == 1 { return Err(ContextError::StackUnderflow); }
+
+        let scope = self.scopes.pop().map(Arc::unwrap_or_clone).ok_or(ContextError::StackUnderflow)?;
+        self.string_bytes = self.string_bytes.saturating_sub(scope.values().map(string_bytes).sum());
+        Ok(scope)
+    }
+
+    pub fn add(&mut self, key: &str, value: Variable) -> ContextResult<()> {
+        self.extend(Some((key, value)))
+    }
+
+    pub fn extend<I, K>(&mut self, variables: I) -> ContextResult<()>
+        where I: IntoIterator<Item = (K, Variable)>, K: AsRef<str> {
+        let variables: Vec<(K, Variable)> = variables.into_iter().collect();
+
+        if let Some((key, _)) = variables.iter().find(|&(key, _)| !is_valid_key(key.as_ref())) {
+            return Err(ContextError::InvalidKey(key.as_ref().into()));
+        }
+
+        self.string_bytes = self.check_limits(&variables)?;
+
+        let variables: Vec<(Arc<str>, Variable)> = variables.into_iter()
+            .map(|(key, value)| (self.intern(key.as_ref()), value))
+            .collect();
+
+        let scope = self.scopes.last_mut().ok_or(ContextError::StackUnderflow)?;
+        Arc::make_mut(scope).extend(variables);
+        Ok(())
+    }
+
+    // The other context's variables are checked like any others added here, so on an error nothing
+    // is merged. Its providers are asked before these ones, and its settings are left behind.
+    pub fn merge(&mut self, other: Context) -> ContextResult<()> {
+        let other_globals = Arc::unwrap_or_clone(other.globals);
+
+        let variables = Arc::unwrap_or_clone(other_globals.variables).into_iter()
+            .chain(other.scopes.into_iter().flat_map(Arc::unwrap_or_clone));
+        self.extend(variables)?;
+
+        if !other_globals.providers.is_empty() { Arc::make_mut(&mut self.globals).providers.extend(other_globals.providers); }
+        Ok(())
+    }
+
+    // Scopes are shared with the fork and only copied when either side writes to one, so forking
+    // is cheap enough to do per partial before handing the fork to another thread.
+    pub fn fork(&self) -> Context {
+        Context {
+            globals: self.globals.clone(),
+            scopes: self.scopes.clone(),
+            keys: self.keys.clone(),
+            string_bytes: self.string_bytes,
+            missing: Mutex::new(self.missing_variables()),
+            warnings: Mutex::new(self.warnings())
+        }
+    }
+
+    pub fn has_key(&self, key: &str) -> bool {
+        self.find(key).is_some()
+    }
+
+    pub fn get(&self, path: &str) -> ContextResult<Variable> {
+        self.fetch(path).map(Cow::into_owned)
+    }
+
+    // Like get, but borrows the value when it's stored in a scope or the globals. Only lambda and
+    // provider results need to be handed back as owned values.
+    pub fn fetch(&self, path: &str) -> ContextResult<Cow<'_, Variable>> {
+        self.fetch_keys(&split_path(path)?, &path)
+    }
+
+    // Like fetch, for a path that's already split into its keys, like a template's once its
+    // bracketed lookups are evaluated. `path` is only written out for errors and missing variables.
+    pub fn fetch_keys<K: AsRef<str>>(&self, keys: &[K], path: &dyn fmt::Display) -> ContextResult<Cow<'_, Variable>> {
+        let (first, rest) = keys.split_first().ok_or_else(|| ContextError::InvalidPath(path.to_string()))?;
+        let first         = first.as_ref();
+
+        if let Some(value) = self.find(first) {
+            return self.resolve(value, rest, path);
+        }
+
+        match self.provided(first) {
+            Some(value) => self.resolve(&value, rest, path).map(|value| Cow::Owned(value.into_owned())),
+            None => self.undefined(path)
+        }
+    }
+
+    // Asked before the providers added ahead of it, the Globals' included.
+    pub fn add_provider<P>(&mut self, provider: P) where P: ValueProvider + 'static {
+        Arc::make_mut(&mut self.globals).add_provider(provider);
+    }
+
+    pub fn add_lambda<F>(&mut self, key: &str, function: F) -> ContextResult<()>
+        where F: Fn(&Context) -> Variable + Send + Sync + 'static {
+        self.add(key, Variable::Lambda(Lambda::new(function)))
+    }
+
+    fn check_limits<K: AsRef<str>>(&self, variables: &[(K, Variable)]) -> ContextResult<usize> {
+        let limits = self.limits();
+        let scope  = self.scopes.last().ok_or(ContextError::StackUnderflow)?;
+
+        if let Some(max) = limits.max_variables {
+            let mut new_keys: Vec<&str> = variables.iter()
+                .map(|(key, _)| key.as_ref())
+                .filter(|key| !scope.contains_key(*key))
+                .collect();
+            new_keys.sort();
+            new_keys.dedup();
+
+            let current: usize = self.scopes.iter().map(|scope| scope.len()).sum();
+            if current + new_keys.len() > max { return Err(ContextError::LimitExceeded("variables", max)); }
+        }
+
+        if let Some(max) = limits.max_depth {
+            if variables.iter().any(|(_, value)| nesting_depth(value) > max) {
+                return Err(ContextError::LimitExceeded("nesting depth", max));
+            }
+        }
+
+        // A replaced value's bytes are freed, and only the last of a repeated key is kept.
+        let latest: HashMap<&str, &Variable> = variables.iter().map(|(key, value)| (key.as_ref(), value)).collect();
+        let bytes = latest.iter().fold(self.string_bytes, |total, (key, value)| {
+            total.saturating_sub(scope.get(*key).map_or(0, string_bytes)) + string_bytes(value)
+        });
+        if let Some(max) = limits.max_string_bytes {
+            if bytes > max { return Err(ContextError::LimitExceeded("string bytes", max)); }
+        }
+
+        Ok(bytes)
+    }
+
+    fn intern(&mut self, key: &str) -> Arc<str> {
+        if let Some(interned) = self.keys.get(key) { return interned.clone(); }
+
+        let interned: Arc<str> = Arc::from(key);
+        self.keys.insert(interned.clone());
+        interned
+    }
+
+    fn resolve<'a, K: AsRef<str>>(&self, value: &'a Variable, segments: &[K], path: &dyn fmt::Display) -> ContextResult<Cow<'a, Variable>> {
+        if let Variable::Lambda(ref lambda) = *value {
+            let computed = lambda.call(self);
+            return self.resolve(&computed, segments, path).map(|value| Cow::Owned(value.into_owned()));
+        }
+
+        match segments.split_first() {
+            None => Ok(Cow::Borrowed(value)),
+            Some((segment, rest)) => match value.child(segment.as_ref()) {
+                Some(child) => self.resolve(child, rest, path),
+                None => self.undefined(path)
+            }
+        }
+    }
+
+    fn undefined<'a>(&self, path: &dyn fmt::Display) -> ContextResult<Cow<'a, Variable>> {
+        if self.globals.record_missing() {
+            let path        = path.to_string();
+            let mut missing = self.missing.lock().unwrap_or_else(PoisonError::into_inner);
+            if !missing.contains(&path) { missing.push(path); }
+        }
+
+        if self.strict_variables() { return Err(ContextError::UndefinedVariable(path.to_string())); }
+
+        Ok(Cow::Owned(Variable::Nil))
+    }
+
+    fn provided(&self, key: &str) -> Option<Variable> {
+        self.globals.provided(key)
+    }
+
+    fn find(&self, key: &str) -> Option<&Variable> {
+        self.scopes.iter().rev().filter_map(|scope| scope.get(key)).next()
+            .or_else(|| self.globals.find(key))
+    }
+}
+
+fn nesting_depth(value: &Variable) -> usize {
+    match *value {
+        Variable::Array(ref items) => 1 + items.iter().map(nesting_depth).max().unwrap_or(0),
+        Variable::Object(ref map)  => 1 + map.values().map(nesting_depth).max().unwrap_or(0),
+        _                          => 0
+    }
+}
+
+fn string_bytes(value: &Variable) -> usize {
+    match *value {
+        Variable::Str(ref value)   => value.len(),
+        Variable::Safe(ref value)  => value.len(),
+        Variable::Array(ref items) => items.iter().map(string_bytes).sum(),
+        Variable::Object(ref map)  => map.iter().map(|(key, value)| key.len() + string_bytes(value)).sum(),
+        _                          => 0
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {},
+        _ => return false
+    }
+
+    let rest = chars.as_str();
+    let body = rest.strip_suffix('?').unwrap_or(rest);
+    body.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+fn split_path(path: &str) -> ContextResult<Vec<&str>> {
+    let invalid      = || ContextError::InvalidPath(path.into());
+    let bytes        = path.as_bytes();
+    let mut segments = Vec::new();
+    let mut start    = 0;
+    let mut index    = 0;
+    let mut bracket  = false;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'.' | b'[' => {
+                if index > start { segments.push(&path[start..index]); }
+                else if segments.is_empty() || (bytes[index] == b'.' && !bracket) { return Err(invalid()); }
+                bracket = false;
+
+                if bytes[index] == b'[' {
+                    let close = path[index..].find(']').map(|offset| index + offset).ok_or_else(invalid)?;
+                    let inner = path[index + 1..close].trim().trim_matches(|c| c == '\'' || c == '"');
+                    if inner.is_empty() { return Err(invalid()); }
+
+                    segments.push(inner);
+                    bracket = true;
+                    index   = close;
+                }
+
+                start = index + 1;
+            },
+            b']' => return Err(invalid()),
+            _ if bracket => return Err(invalid()),
+            _ => {}
+        }
+
+        index += 1;
+    }
+
+    if start < bytes.len() { segments.push(&path[start..]); }
+    else if !bracket { return Err(invalid()); }
+
+    if !is_valid_key(segments[0]) { return Err(invalid()); }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use variable::{Lambda,Variable};
+
+    fn string(value: &str) -> Variable {
+        Variable::Str(value.into())
+    }
+
+    #[test]
+    fn new_creates_a_context_with_a_global_scope() {
+        let context = Context::new();
+        assert_eq!(1, context.depth());
+    }
+
+    #[test]
+    fn push_and_pop_manage_scopes() {
+        let mut context = Context::new();
+        context.push().unwrap();
+        assert_eq!(2, context.depth());
+
+        context.pop().unwrap();
+        assert_eq!(1, context.depth());
+    }
+
+    #[test]
+    fn push_returns_an_error_when_the_stack_is_too_deep() {
+        let mut context = Context::new();
+        for _ in 1..MAX_DEPTH { context.push().unwrap(); }
+
+        assert_eq!(Err(ContextError::StackTooDeep), context.push());
+        assert_eq!(MAX_DEPTH, context.depth());
+    }
+
+    #[test]
+    fn pop_returns_an_error_for_the_global_scope() {
+        let mut context = Context::new();
+        assert_eq!(Err(ContextError::StackUnderflow), context.pop());
+        assert_eq!(1, context.depth());
+    }
+
+    #[test]
+    fn pop_discards_variables_in_the_scope() {
+        let mut context = Context::new();
+        context.push().unwrap();
+        context.add("name", string("Peter")).unwrap();
+
+        let scope = context.pop().unwrap();
+        assert_eq!(Some(&string("Peter")), scope.get("name"));
+        assert!(!context.has_key("name"));
+    }
+
+    #[test]
+    fn add_inserts_into_the_innermost_scope() {
+        let mut context = Context::new();
+        context.add("name", string("Peter")).unwrap();
+        context.push().unwrap();
+        context.add("name", string("Hegemon")).unwrap();
+
+        assert_eq!(Ok(string("Hegemon")), context.get("name"));
+        context.pop().unwrap();
+        assert_eq!(Ok(string("Peter")), context.get("name"));
+    }
+
+    #[test]
+    fn add_returns_an_error_for_invalid_keys() {
+        let mut context = Context::new();
+
+        for key in &["", "5five", "user.name", "a b", "items[0]"] {
+            assert_eq!(Err(ContextError::InvalidKey(key.to_string())), context.add(key, Variable::Nil));
+        }
+
+        assert!(context.add("valid_key-name?", Variable::Nil).is_ok());
+    }
+
+    #[test]
+    fn get_returns_nil_for_missing_variables() {
+        let context = Context::new();
+        assert_eq!(Ok(Variable::Nil), context.get("missing"));
+        assert_eq!(Ok(Variable::Nil), context.get("missing.child"));
+    }
+
+    #[test]
+    fn get_resolves_nested_paths() {
+        let mut user = HashMap::new();
+        user.insert("name".to_string(), string("Peter"));
+        user.insert("tags".to_string(), Variable::Array(vec![string("a"), string("b"), string("c")]));
+
+        let mut context = Context::new();
+        context.add("user", Variable::Object(user)).unwrap();
+
+        assert_eq!(Ok(string("Peter")), context.get("user.name"));
+        assert_eq!(Ok(string("Peter")), context.get("user['name']"));
+        assert_eq!(Ok(string("b")), context.get("user.tags[1]"));
+        assert_eq!(Ok(string("c")), context.get("user.tags[-1]"));
+        assert_eq!(Ok(string("a")), context.get("user.tags.first"));
+        assert_eq!(Ok(string("c")), context.get("user.tags.last"));
+        assert_eq!(Ok(Variable::Nil), context.get("user.tags[5]"));
+        assert_eq!(Ok(Variable::Nil), context.get("user.name.first"));
+    }
+
+    #[test]
+    fn get_returns_an_error_for_malformed_paths() {
+        let context = Context::new();
+
+        for path in &["", ".name", "user.", "user..name", "[0]", "user[]", "user]"] {
+            assert_eq!(Err(ContextError::InvalidPath(path.to_string())), context.get(path));
+        }
+    }
+
+    #[test]
+    fn strict_variables_is_off_by_default() {
+        let context = Context::new();
+        assert!(!context.strict_variables());
+    }
+
+    #[test]
+    fn get_returns_an_error_for_undefined_variables_when_strict() {
+        let mut user = HashMap::new();
+        user.insert("name".to_string(), string("Peter"));
+        user.insert("email".to_string(), Variable::Nil);
+
+        let mut context = Context::new();
+        context.set_strict_variables(true);
+        context.add("user", Variable::Object(user)).unwrap();
+
+        assert_eq!(Ok(string("Peter")), context.get("user.name"));
+        assert_eq!(Ok(Variable::Nil), context.get("user.email"));
+        assert_eq!(Err(ContextError::UndefinedVariable("usr.name".into())), context.get("usr.name"));
+        assert_eq!(Err(ContextError::UndefinedVariable("user.nme".into())), context.get("user.nme"));
+    }
+
+    #[test]
+    fn get_evaluates_lambdas_at_access_time() {
+        let mut context = Context::new();
+        context.add("count", Variable::Int(1)).unwrap();
+        context.add_lambda("double", |ctx| ctx.get("count").unwrap().times(&Variable::Int(2)).unwrap()).unwrap();
+
+        assert_eq!(Ok(Variable::Int(2)), context.get("double"));
+
+        context.add("count", Variable::Int(5)).unwrap();
+        assert_eq!(Ok(Variable::Int(10)), context.get("double"));
+    }
+
+    #[test]
+    fn get_evaluates_lambdas_inside_nested_paths() {
+        let mut cart = HashMap::new();
+        cart.insert("total".to_string(), Variable::Lambda(Lambda::new(|_| Variable::Float(9.5))));
+
+        let mut context = Context::new();
+        context.add("cart", Variable::Object(cart)).unwrap();
+        context.add_lambda("user", |_| {
+            let mut user = HashMap::new();
+            user.insert("name".to_string(), Variable::Str("Peter".into()));
+            Variable::Object(user)
+        }).unwrap();
+
+        assert_eq!(Ok(Variable::Float(9.5)), context.get("cart.total"));
+        assert_eq!(Ok(Variable::Str("Peter".into())), context.get("user.name"));
+        assert_eq!(Ok(Variable::Nil), context.get("user.email"));
+    }
+
+    #[test]
+    fn get_consults_providers_when_scopes_miss() {
+        let mut context = Context::new();
+        context.add("path", Variable::Str("/from/scope".into())).unwrap();
+        context.add_provider(|key: &str| match key {
+            "path" | "request" => {
+                let mut request = HashMap::new();
+                request.insert("path".to_string(), Variable::Str("/from/provider".into()));
+                Some(Variable::Object(request))
+            },
+            _ => None
+        });
+        context.add_provider(|key: &str| if key == "fallback" { Some(Variable::Int(1)) } else { None });
+
+        assert_eq!(Ok(Variable::Str("/from/scope".into())), context.get("path"));
+        assert_eq!(Ok(Variable::Str("/from/provider".into())), context.get("request.path"));
+        assert_eq!(Ok(Variable::Int(1)), context.get("fallback"));
+        assert_eq!(Ok(Variable::Nil), context.get("missing"));
+    }
+
+    #[test]
+    fn get_still_fails_in_strict_mode_when_providers_miss() {
+        let mut context = Context::new();
+        context.set_strict_variables(true);
+        context.add_provider(|_: &str| None);
+
+        assert_eq!(Err(ContextError::UndefinedVariable("missing".into())), context.get("missing"));
+    }
+
+    #[test]
+    fn extend_adds_all_variables_to_the_innermost_scope() {
+        let mut context = Context::new();
+        context.push().unwrap();
+        context.extend(vec![("title", string("Hi")), ("count", Variable::Int(2))]).unwrap();
+
+        assert_eq!(Ok(string("Hi")), context.get("title"));
+        assert_eq!(Ok(Variable::Int(2)), context.get("count"));
+
+        context.pop().unwrap();
+        assert!(!context.has_key("title"));
+    }
+
+    #[test]
+    fn extend_adds_nothing_when_a_key_is_invalid() {
+        let mut context = Context::new();
+        let result      = context.extend(vec![("title".to_string(), string("Hi")), ("bad key".to_string(), Variable::Nil)]);
+
+        assert_eq!(Err(ContextError::InvalidKey("bad key".into())), result);
+        assert!(!context.has_key("title"));
+    }
+
+    #[test]
+    fn merge_folds_the_other_context_into_the_innermost_scope() {
+        let mut site = Context::new();
+        site.extend(vec![("title", string("Site")), ("url", string("/"))]).unwrap();
+
+        let mut page = Context::new();
+        page.add("title", string("Global")).unwrap();
+        page.push().unwrap();
+        page.add("title", string("Page")).unwrap();
+        page.add_provider(|key: &str| if key == "request" { Some(Variable::Int(1)) } else { None });
+
+        site.merge(page).unwrap();
+
+        assert_eq!(1, site.depth());
+        assert_eq!(Ok(string("Page")), site.get("title"));
+        assert_eq!(Ok(string("/")), site.get("url"));
+        assert_eq!(Ok(Variable::Int(1)), site.get("request"));
+    }
+
+    #[test]
+    fn scopes_shadow_globals() {
+        let mut globals = Globals::new();
+        globals.add("title", string("Global")).unwrap();
+        globals.add("site", string("riquid")).unwrap();
+
+        let mut context = Context::with_globals(Arc::new(globals));
+        context.add("title", string("Local")).unwrap();
+
+        assert_eq!(Ok(string("Local")), context.get("title"));
+        assert_eq!(Ok(string("riquid")), context.get("site"));
+    }
+
+    #[test]
+    fn globals_add_returns_an_error_for_invalid_keys() {
+        let mut globals = Globals::new();
+        assert_eq!(Err(ContextError::InvalidKey("a b".into())), globals.add("a b", Variable::Nil));
+    }
+
+    #[test]
+    fn changing_settings_does_not_affect_shared_globals() {
+        let mut globals = Globals::new();
+        globals.set_limits(Limits { max_variables: Some(1), ..Limits::default() });
+
+        let globals     = Arc::new(globals);
+        let mut context = Context::with_globals(globals.clone());
+        assert_eq!(Some(1), context.limits().max_variables);
+
+        context.set_error_mode(ErrorMode::Warn);
+        context.set_limits(Limits::default());
+        context.add_provider(|key: &str| if key == "now" { Some(Variable::Int(1)) } else { None });
+
+        assert_eq!((ErrorMode::Warn, Ok(Variable::Int(1))), (context.error_mode(), context.get("now")));
+        assert_eq!((ErrorMode::Lax, Some(1)), (globals.error_mode(), globals.limits().max_variables));
+        assert_eq!((1, true), (Arc::strong_count(&globals), Arc::ptr_eq(&globals.variables, &context.globals().variables)));
+        assert!(globals.provided("now").is_none());
+        assert_eq!((ErrorMode::Warn, None), (context.globals().error_mode(), context.globals().limits().max_variables));
+    }
+
+    #[test]
+    fn providers_added_later_are_asked_first() {
+        let mut globals = Globals::new();
+        globals.add_provider(|key: &str| if key == "user" { Some(string("host")) } else { None });
+        globals.add_provider(|key: &str| if key == "site" { Some(string("site")) } else { None });
+
+        let mut context = Context::with_globals(Arc::new(globals));
+        context.add_provider(|key: &str| if key == "user" { Some(string("render")) } else { None });
+
+        assert_eq!((Ok(string("render")), Ok(string("site"))), (context.get("user"), context.get("site")));
+    }
+
+    #[test]
+    fn globals_can_be_shared_across_threads() {
+        use std::thread;
+
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Globals>();
+        assert_send_sync::<Context>();
+
+        let mut globals = Globals::new();
+        globals.add("base", Variable::Int(10)).unwrap();
+        globals.add_provider(|key: &str| if key == "offset" { Some(Variable::Int(1)) } else { None });
+
+        let globals = Arc::new(globals);
+        let handles: Vec<_> = (0..4).map(|n| {
+            let globals = globals.clone();
+
+            thread::spawn(move || {
+                let mut context = Context::with_globals(globals);
+                context.add("n", Variable::Int(n)).unwrap();
+
+                let base = context.get("base").unwrap().plus(&context.get("offset").unwrap()).unwrap();
+                base.plus(&context.get("n").unwrap()).unwrap()
+            })
+        }).collect();
+
+        let results: Vec<Variable> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(vec![Variable::Int(11), Variable::Int(12), Variable::Int(13), Variable::Int(14)], results);
+    }
+
+    #[test]
+    fn limits_cap_the_number_of_variables() {
+        let mut context = Context::new();
+        context.set_limits(Limits { max_variables: Some(2), ..Limits::default() });
+        context.add("a", Variable::Int(1)).unwrap();
+        context.push().unwrap();
+        context.add("b", Variable::Int(2)).unwrap();
+        context.add("b", Variable::Int(3)).unwrap();
+
+        assert_eq!(Err(ContextError::LimitExceeded("variables", 2)), context.add("c", Variable::Int(4)));
+        assert_eq!(Err(ContextError::LimitExceeded("variables", 2)), context.extend(vec![("b", Variable::Nil), ("c", Variable::Nil)]));
+
+        context.pop().unwrap();
+        assert!(context.add("c", Variable::Int(4)).is_ok());
+    }
+
+    #[test]
+    fn limits_cap_the_nesting_depth_of_values() {
+        let mut context = Context::new();
+        context.set_limits(Limits { max_depth: Some(2), ..Limits::default() });
+
+        let nested = Variable::Array(vec![Variable::Array(vec![Variable::Int(1)])]);
+        assert!(context.add("ok", nested.clone()).is_ok());
+        assert_eq!(
+            Err(ContextError::LimitExceeded("nesting depth", 2)),
+            context.add("deep", Variable::Array(vec![nested]))
+        );
+    }
+
+    #[test]
+    fn limits_cap_the_total_string_bytes_stored() {
+        let mut context = Context::new();
+        context.set_limits(Limits { max_string_bytes: Some(10), ..Limits::default() });
+        context.add("a", string("12345")).unwrap();
+        context.add("a", string("1234")).unwrap();
+        context.add("b", string("12")).unwrap();
+
+        assert_eq!(Err(ContextError::LimitExceeded("string bytes", 10)), context.add("c", string("12345")));
+        assert_eq!(Ok(string("1234")), context.get("a"));
+    }
+
+    #[test]
+    fn limits_free_the_bytes_of_replaced_and_popped_values() {
+        let mut context = Context::new();
+        context.set_limits(Limits { max_string_bytes: Some(10), ..Limits::default() });
+
+        for n in 0..100 {
+            context.add("item", string(&format!("item {}", n))).unwrap();
+            context.push().unwrap();
+            context.extend(vec![("a", string("12")), ("a", string("123"))]).unwrap();
+            context.pop().unwrap();
+        }
+
+        assert_eq!(Ok(string("item 99")), context.get("item"));
+    }
+
+    #[test]
+    fn merge_checks_keys_and_limits() {
+        let mut site = Context::new();
+        site.set_limits(Limits { max_variables: Some(2), ..Limits::default() });
+        site.add("title", string("Site")).unwrap();
+
+        let mut page = Context::new();
+        page.extend(vec![("title", string("Page")), ("url", string("/")), ("body", string(""))]).unwrap();
+        assert_eq!(Err(ContextError::LimitExceeded("variables", 2)), site.merge(page));
+        assert_eq!(Ok(string("Site")), site.get("title"));
+
+        let mut globals = Globals::new();
+        Arc::make_mut(&mut globals.variables).insert("not valid".into(), string("x"));
+        assert_eq!(Err(ContextError::InvalidKey("not valid".into())), site.merge(Context::with_globals(Arc::new(globals))));
+    }
+
+    #[test]
+    fn limits_ignore_host_provided_globals() {
+        let mut globals = Globals::new();
+        globals.set_limits(Limits { max_variables: Some(1), max_string_bytes: Some(1), ..Limits::default() });
+        globals.add("site", string("a long site title")).unwrap();
+        globals.add("page", string("a long page title")).unwrap();
+
+        let mut context = Context::with_globals(Arc::new(globals));
+        assert!(context.add("x", Variable::Int(1)).is_ok());
+    }
+
+    #[test]
+    fn get_resolves_lookups_after_brackets() {
+        let context = context! { items: [{ name: "first" }, { name: "second" }], grid: [[1, 2], [3, 4]] };
+
+        assert_eq!(Ok(string("second")), context.get("items[1].name"));
+        assert_eq!(Ok(Variable::Int(3)), context.get("grid[1][0]"));
+        assert_eq!(Err(ContextError::InvalidPath("items[1]name".into())), context.get("items[1]name"));
+        assert_eq!(Err(ContextError::InvalidPath("items[1".into())), context.get("items[1"));
+    }
+
+    #[test]
+    fn fetch_borrows_stored_values() {
+        let mut context = context! { user: { name: "Peter" } };
+        context.add_lambda("computed", |_| Variable::Int(1)).unwrap();
+
+        assert!(matches!(context.fetch("user.name"), Ok(Cow::Borrowed(&Variable::Str(_)))));
+        assert!(matches!(context.fetch("computed"), Ok(Cow::Owned(Variable::Int(1)))));
+        assert!(matches!(context.fetch("missing"), Ok(Cow::Owned(Variable::Nil))));
+    }
+
+    #[test]
+    fn keys_are_interned_across_scopes() {
+        let mut context = Context::new();
+        context.push().unwrap();
+        context.add("name", Variable::Nil).unwrap();
+        let first = context.pop().unwrap();
+
+        context.push().unwrap();
+        context.add("name", Variable::Nil).unwrap();
+        let second = context.pop().unwrap();
+
+        let (first_key, _)  = first.iter().next().unwrap();
+        let (second_key, _) = second.iter().next().unwrap();
+        assert!(Arc::ptr_eq(first_key, second_key));
+    }
+
+    #[test]
+    fn fork_shares_variables_with_the_parent() {
+        let mut parent = context! { title: "Hi" };
+        parent.push().unwrap();
+        parent.add("page", string("home")).unwrap();
+
+        let fork = parent.fork();
+        assert_eq!(2, fork.depth());
+        assert_eq!(Ok(string("Hi")), fork.get("title"));
+        assert_eq!(Ok(string("home")), fork.get("page"));
+        assert!(Arc::ptr_eq(&parent.scopes[0], &fork.scopes[0]));
+    }
+
+    #[test]
+    fn fork_copies_scopes_on_write() {
+        let mut parent = context! { title: "Hi" };
+        let mut fork   = parent.fork();
+
+        fork.add("title", string("Fork")).unwrap();
+        parent.add("extra", Variable::Int(1)).unwrap();
+
+        assert_eq!(Ok(string("Hi")), parent.get("title"));
+        assert_eq!(Ok(string("Fork")), fork.get("title"));
+        assert!(!fork.has_key("extra"));
+    }
+
+    #[test]
+    fn forks_can_be_used_on_other_threads() {
+        use std::thread;
+
+        let parent  = context! { greeting: "Hello" };
+        let handles: Vec<_> = vec!["a", "b"].into_iter().map(|name| {
+            let mut fork = parent.fork();
+
+            thread::spawn(move || {
+                fork.add("name", string(name)).unwrap();
+                format!("{} {}", fork.get("greeting").unwrap(), fork.get("name").unwrap())
+            })
+        }).collect();
+
+        let results: Vec<String> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(vec!["Hello a".to_string(), "Hello b".to_string()], results);
+        assert!(!parent.has_key("name"));
+    }
+
+    #[test]
+    fn missing_variables_are_not_recorded_by_default() {
+        let context = Context::new();
+        context.get("missing").unwrap();
+
+        assert!(context.missing_variables().is_empty());
+    }
+
+    #[test]
+    fn missing_variables_records_each_undefined_path_once() {
+        let mut context = context! { product: { price: 10 } };
+        context.set_record_missing(true);
+
+        context.get("product.price").unwrap();
+        context.get("product.pric").unwrap();
+        context.get("usr").unwrap();
+        context.get("product.pric").unwrap();
+
+        assert_eq!(vec!["product.pric".to_string(), "usr".to_string()], context.missing_variables());
+        assert_eq!(vec!["product.pric".to_string(), "usr".to_string()], context.take_missing_variables());
+        assert!(context.missing_variables().is_empty());
+    }
+
+    #[test]
+    fn missing_variables_are_recorded_in_strict_mode() {
+        let mut context = Context::new();
+        context.set_strict_variables(true);
+        context.set_record_missing(true);
+
+        assert!(context.get("missing").is_err());
+        assert_eq!(vec!["missing".to_string()], context.missing_variables());
+    }
+
+    #[test]
+    fn error_mode_decides_what_undefined_variables_do() {
+        let mut context = Context::new();
+        assert_eq!(ErrorMode::Lax, context.error_mode());
+        assert_eq!(Ok(Variable::Nil), context.get("missing"));
+        assert!(context.missing_variables().is_empty());
+
+        context.set_error_mode(ErrorMode::Warn);
+        assert_eq!(Ok(Variable::Nil), context.get("missing"));
+        assert_eq!(vec!["missing".to_string()], context.take_missing_variables());
+
+        context.set_error_mode(ErrorMode::Strict);
+        assert_eq!(Err(ContextError::UndefinedVariable("missing".into())), context.get("missing"));
+        assert!(context.missing_variables().is_empty());
+
+        context.set_record_missing(true);
+        assert_eq!(ErrorMode::Strict, context.error_mode());
+    }
+}
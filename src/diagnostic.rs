@@ -0,0 +1,105 @@
+use std::fmt;
+
+use error::Error;
+use lexer::Span;
+
+// An error ready to be shown to whoever wrote the template, rustc style:
+//
+//     error: Unexpected character '#' at line 2, column 6
+//      --> product.liquid:2:6
+//       |
+//     2 | {{ a # }}
+//       |      ^
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Option<Span>,
+    pub template: Option<String>
+}
+
+impl Diagnostic {
+    pub fn new<S: Into<String>>(message: S, span: Option<Span>) -> Diagnostic {
+        Diagnostic { message: message.into(), span, template: None }
+    }
+
+    // `source` has to be the template the span points into.
+    pub fn display<'a>(&'a self, source: &'a str) -> Excerpt<'a> {
+        Excerpt { diagnostic: self, source }
+    }
+}
+
+impl<'a> From<&'a Error> for Diagnostic {
+    fn from(error: &'a Error) -> Diagnostic {
+        match *error {
+            Error::Template(ref name, ref error) => Diagnostic { template: Some(name.clone()), ..Diagnostic::from(&**error) },
+            ref error                            => Diagnostic::new(error.to_string(), error.span())
+        }
+    }
+}
+
+pub struct Excerpt<'a> {
+    diagnostic: &'a Diagnostic,
+    source: &'a str
+}
+
+impl<'a> fmt::Display for Excerpt<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let diagnostic = self.diagnostic;
+        write!(f, "error: {}", diagnostic.message)?;
+
+        let Some(span) = diagnostic.span else {
+            return match diagnostic.template {
+                Some(ref name) => write!(f, "\n --> {}", name),
+                None           => Ok(())
+            };
+        };
+
+        let Some(line) = self.source.lines().nth(span.line - 1) else { return Ok(()) };
+        let gutter     = " ".repeat(span.line.to_string().len());
+        let name       = diagnostic.template.as_deref().unwrap_or("<template>");
+
+        // Tabs stay tabs so the caret lines up however wide the terminal draws them. A span that
+        // runs past the end of the line is underlined to the end of it.
+        let before = line.chars().take(span.col - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect::<String>();
+        let width  = self.source[span.start..span.end].chars().take_while(|&c| c != '\n').count().max(1);
+
+        write!(f, "\n{}--> {}:{}:{}", gutter, name, span.line, span.col)?;
+        write!(f, "\n{} |", gutter)?;
+        write!(f, "\n{} | {}", span.line, line.trim_end_matches('\r'))?;
+        write!(f, "\n{} | {}{}", gutter, before, "^".repeat(width))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use template::Template;
+
+    fn diagnose(source: &str, name: &str) -> String {
+        let error = Error::from(Template::parse(source).unwrap_err()).in_template(name);
+        Diagnostic::from(&error).display(source).to_string()
+    }
+
+    #[test]
+    fn display_points_at_the_span() {
+        assert_eq!(
+            "error: Unexpected character '#' at line 2, column 6\n --> product.liquid:2:6\n  |\n2 | {{ a # }}\n  |      ^",
+            diagnose("<p>\n{{ a # }}\n</p>", "product.liquid")
+        );
+    }
+
+    #[test]
+    fn display_underlines_the_whole_span() {
+        let source = "\t{% if x %}{{ a";
+        assert_eq!(
+            "error: '{{' opened at line 1, column 12 was never closed\n --> page:1:12\n  |\n1 | \t{% if x %}{{ a\n  | \t          ^^",
+            diagnose(source, "page")
+        );
+    }
+
+    #[test]
+    fn display_without_a_span_is_just_the_message() {
+        let diagnostic = Diagnostic::new("Divided by 0", None);
+        assert_eq!("error: Divided by 0", diagnostic.display("").to_string());
+    }
+}
@@ -0,0 +1,577 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path,PathBuf};
+use std::sync::{Arc,Mutex,PoisonError};
+
+use context::Context;
+use error::{Error,Result};
+use filters::{ContextFilter,Filter,FilterRegistry};
+use loader::{LoadError,Loader};
+use parser::ParserOptions;
+use profile::Profiler;
+#[cfg(feature = "rayon")]
+use rayon::iter::{IntoParallelIterator,ParallelIterator};
+use render::{RenderError,RenderLimits,Renderer};
+use sandbox::Sandbox;
+use tags::TagParser;
+use template::Template;
+
+// Everything templates share: how they're parsed (registered tags included), the filters they can
+// use and the compiled templates themselves, by name. Templates that aren't registered come from
+// the loader, when there is one, and are parsed once and cached until they're invalidated.
+#[derive(Clone, Debug)]
+pub struct Environment {
+    options: ParserOptions,
+    filters: FilterRegistry,
+    templates: HashMap<String, Arc<Template>>,
+    loader: Option<Arc<dyn Loader>>,
+    cache: Cache,
+    max_include_depth: usize,
+    render_limits: RenderLimits,
+    auto_escape: bool,
+    size_hint: fn(&Template) -> usize
+}
+
+// Well short of the Context's own stack limit, which every include also counts against.
+const MAX_INCLUDE_DEPTH: usize = 50;
+
+// Behind a lock so renders, which only borrow the environment, can fill it in.
+#[derive(Debug, Default)]
+struct Cache {
+    templates: Mutex<HashMap<String, Arc<Template>>>
+}
+
+impl Clone for Cache {
+    fn clone(&self) -> Cache {
+        Cache { templates: Mutex::new(self.templates.lock().unwrap_or_else(PoisonError::into_inner).clone()) }
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment {
+            options: ParserOptions::default(),
+            filters: FilterRegistry::default(),
+            templates: HashMap::new(),
+            loader: None,
+            cache: Cache::default(),
+            max_include_depth: MAX_INCLUDE_DEPTH,
+            render_limits: RenderLimits::default(),
+            auto_escape: false,
+            size_hint: Template::size_hint
+        }
+    }
+}
+
+impl Environment {
+    pub fn new() -> Environment {
+        Environment::default()
+    }
+
+    pub fn with_options(options: ParserOptions) -> Environment {
+        Environment { options, ..Environment::default() }
+    }
+
+    pub fn options(&self) -> &ParserOptions {
+        &self.options
+    }
+
+    // Only affects templates added afterwards. Cached ones are dropped so they get parsed again.
+    pub fn set_options(&mut self, options: ParserOptions) {
+        self.options = options;
+        self.clear();
+    }
+
+    // Shorthand for setting `ParserOptions::sandbox`. Like any change to the options, it only
+    // applies to templates added from here on.
+    pub fn set_sandbox(&mut self, sandbox: Sandbox) {
+        self.options.sandbox = Some(sandbox);
+        self.clear();
+    }
+
+    pub fn filters(&self) -> &FilterRegistry {
+        &self.filters
+    }
+
+    pub fn max_include_depth(&self) -> usize {
+        self.max_include_depth
+    }
+
+    // How deeply includes may nest, counting from the template being rendered.
+    pub fn set_max_include_depth(&mut self, depth: usize) {
+        self.max_include_depth = depth;
+    }
+
+    pub fn render_limits(&self) -> RenderLimits {
+        self.render_limits
+    }
+
+    // Applies to every render from here on, each one counted on its own.
+    pub fn set_render_limits(&mut self, limits: RenderLimits) {
+        self.render_limits = limits;
+    }
+
+    pub fn auto_escape(&self) -> bool {
+        self.auto_escape
+    }
+
+    // HTML-escapes everything outputs and `echo` write, apart from Safe strings such as those from
+    // the `escape` and `raw` filters.
+    pub fn set_auto_escape(&mut self, auto_escape: bool) {
+        self.auto_escape = auto_escape;
+    }
+
+    pub fn set_loader<L: Loader + 'static>(&mut self, loader: L) {
+        self.loader = Some(Arc::new(loader));
+        self.clear();
+    }
+
+    // Like changing the options, cached templates are dropped so the tag gets parsed in them too.
+    pub fn register_tag(&mut self, name: &str, parser: TagParser) {
+        self.options.tags.register(name, parser);
+        self.clear();
+    }
+
+    // With `ParserOptions::fold` set, cached templates may have applied the filter this replaces,
+    // so they're dropped too.
+    pub fn register_filter(&mut self, name: &str, filter: Filter) {
+        self.filters.register(name, filter);
+        self.clear();
+    }
+
+    // See `FilterRegistry::register_volatile`.
+    pub fn register_volatile_filter(&mut self, name: &str, filter: Filter) {
+        self.filters.register_volatile(name, filter);
+        self.clear();
+    }
+
+    pub fn register_contextual_filter(&mut self, name: &str, filter: ContextFilter) {
+        self.filters.register_contextual(name, filter);
+        self.clear();
+    }
+
+    // Templates parsed here also get their filters folded, as they're the environment's to apply.
+    pub fn parse(&self, source: &str) -> Result<Template> {
+        let mut template = Template::parse_with(source, &self.options)?;
+        if self.options.fold { template.fold(&self.filters); }
+
+        Ok(template)
+    }
+
+    // Compiles `source` and keeps it under `name`, replacing any template already there. Errors
+    // carry the name.
+    pub fn add_template(&mut self, name: &str, source: &str) -> Result<()> {
+        let template = self.parse(source).map_err(|error| error.in_template(name))?;
+        self.templates.insert(name.to_string(), Arc::new(template));
+
+        Ok(())
+    }
+
+    // Keeps a template parsed ahead of time, like one built by `include_liquid!`, under `name`.
+    pub fn insert_template(&mut self, name: &str, template: Template) {
+        self.templates.insert(name.to_string(), Arc::new(template));
+    }
+
+    // Registers every template under `root` whose file has the given extension, named like
+    // FsLoader would name it (`partials/header` for `partials/header.liquid`). Carries on past
+    // failures and returns all of them; the templates that did parse are registered either way.
+    pub fn compile_dir<P: AsRef<Path>>(&mut self, root: P, extension: &str) -> Vec<Error> {
+        let root       = root.as_ref();
+        let mut errors = vec![];
+        let mut files  = vec![];
+        find_files(root, extension, &mut files, &mut errors);
+
+        for path in files {
+            let relative = path.strip_prefix(root).unwrap_or(&path).with_extension("");
+            let name     = relative.iter().map(|part| part.to_string_lossy()).collect::<Vec<_>>().join("/");
+
+            let added = fs::read_to_string(&path)
+                .map_err(|error| Error::from(LoadError::Io(name.clone(), error.to_string())).in_template(name.as_str()))
+                .and_then(|source| self.add_template(&name, &source));
+
+            errors.extend(added.err());
+        }
+
+        errors
+    }
+
+    pub fn template(&self, name: &str) -> Option<&Template> {
+        self.templates.get(name).map(|template| &**template)
+    }
+
+    // The registered template, or else whatever the loader finds under `name`. Loaded templates
+    // are only parsed again once the loader reports a change; failures aren't cached.
+    pub fn load(&self, name: &str) -> Result<Arc<Template>> {
+        if let Some(template) = self.templates.get(name) { return Ok(template.clone()); }
+
+        let loader = self.loader.as_ref().ok_or_else(|| RenderError::UnknownTemplate(name.to_string()))?;
+        for changed in loader.changes() { self.invalidate(&changed); }
+        if let Some(template) = self.cache.get(name) { return Ok(template); }
+
+        let source   = loader.load(name).map_err(|error| Error::from(error).in_template(name))?;
+        let template = Arc::new(self.parse(&source).map_err(|error| error.in_template(name))?);
+
+        self.cache.insert(name, template.clone());
+        Ok(template)
+    }
+
+    // Forgets the cached parse of `name` so the next render loads it again. Returns whether
+    // there was one.
+    pub fn invalidate(&self, name: &str) -> bool {
+        self.cache.remove(name)
+    }
+
+    // Forgets every cached parse. Registered templates are kept.
+    pub fn clear(&self) {
+        self.cache.clear();
+    }
+
+    pub fn remove_template(&mut self, name: &str) -> Option<Arc<Template>> {
+        self.templates.remove(name)
+    }
+
+    pub fn template_names(&self) -> impl Iterator<Item = &str> {
+        self.templates.keys().map(String::as_str)
+    }
+
+    pub fn render(&self, name: &str, context: &mut Context) -> Result<String> {
+        let template   = self.load(name)?;
+        let mut output = String::with_capacity((self.size_hint)(&template));
+        self.render_named(name, &template, context, &mut output)?;
+
+        Ok(output)
+    }
+
+    // Renders into a buffer of the caller's, say one kept per worker and cleared between requests.
+    // Whatever was rendered before an error is left in `output`.
+    pub fn render_into(&self, name: &str, context: &mut Context, output: &mut dyn fmt::Write) -> Result<()> {
+        let template = self.load(name)?;
+        self.render_named(name, &template, context, output)
+    }
+
+    // Renders like `render`, telling `profiler` how long each node took and how much it wrote. Like
+    // a render timeout, this needs a clock, so it isn't for wasm32-unknown-unknown.
+    pub fn render_profiled(&self, name: &str, context: &mut Context, profiler: &dyn Profiler) -> Result<String> {
+        let template   = self.load(name)?;
+        let mut output = String::with_capacity((self.size_hint)(&template));
+        Renderer::with_name(self, name).with_profiler(profiler).render(&template.nodes, context, &mut output).map_err(|error| error.in_template(name))?;
+
+        Ok(output)
+    }
+
+    // Renders a template that isn't registered, with this environment's filters and partials.
+    pub fn render_template(&self, template: &Template, context: &mut Context) -> Result<String> {
+        let mut output = String::with_capacity((self.size_hint)(template));
+        self.render_template_into(template, context, &mut output)?;
+
+        Ok(output)
+    }
+
+    pub fn render_template_into(&self, template: &Template, context: &mut Context, output: &mut dyn fmt::Write) -> Result<()> {
+        Renderer::new(self).render(&template.nodes, context, output)
+    }
+
+    // Renders each `(name, context)` job, in parallel with the `rayon` feature. The results come
+    // back in the same order as the jobs.
+    pub fn render_many<I, S>(&self, jobs: I) -> Vec<Result<String>>
+        where I: IntoIterator<Item = (S, Context)>, S: AsRef<str> + Send {
+        map_jobs(jobs.into_iter().collect(), |(name, mut context)| self.render(name.as_ref(), &mut context))
+    }
+
+    // How many bytes a template is expected to render to, used to size the output up front.
+    // `Template::size_hint` by default.
+    pub fn size_hint(&self, template: &Template) -> usize {
+        (self.size_hint)(template)
+    }
+
+    pub fn set_size_hint(&mut self, hint: fn(&Template) -> usize) {
+        self.size_hint = hint;
+    }
+
+    fn render_named(&self, name: &str, template: &Template, context: &mut Context, output: &mut dyn fmt::Write) -> Result<()> {
+        Renderer::with_name(self, name).render(&template.nodes, context, output).map_err(|error| error.in_template(name))
+    }
+}
+
+#[cfg(feature = "rayon")]
+fn map_jobs<T: Send, R: Send, F: Fn(T) -> R + Send + Sync>(jobs: Vec<T>, render: F) -> Vec<R> {
+    jobs.into_par_iter().map(render).collect()
+}
+
+#[cfg(not(feature = "rayon"))]
+fn map_jobs<T, R, F: Fn(T) -> R>(jobs: Vec<T>, render: F) -> Vec<R> {
+    jobs.into_iter().map(render).collect()
+}
+
+// Every file under `directory` with the extension, in a stable order. Symlinked directories are
+// skipped so a link back up the tree can't loop forever.
+fn find_files(directory: &Path, extension: &str, files: &mut Vec<PathBuf>, errors: &mut Vec<Error>) {
+    let mut entries = match fs::read_dir(directory).and_then(|entries| entries.collect::<io::Result<Vec<_>>>()) {
+        Ok(entries) => entries,
+        Err(error)  => {
+            errors.push(LoadError::Io(directory.display().to_string(), error.to_string()).into());
+            return;
+        }
+    };
+
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            find_files(&path, extension, files, errors);
+        } else if path.is_file() && path.extension().is_some_and(|found| found == extension) {
+            files.push(path);
+        }
+    }
+}
+
+impl Cache {
+    fn get(&self, name: &str) -> Option<Arc<Template>> {
+        self.templates.lock().unwrap_or_else(PoisonError::into_inner).get(name).cloned()
+    }
+
+    fn insert(&self, name: &str, template: Arc<Template>) {
+        self.templates.lock().unwrap_or_else(PoisonError::into_inner).insert(name.to_string(), template);
+    }
+
+    fn remove(&self, name: &str) -> bool {
+        self.templates.lock().unwrap_or_else(PoisonError::into_inner).remove(name).is_some()
+    }
+
+    fn clear(&self) {
+        self.templates.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::thread;
+    use ast::{Node,TagArguments};
+    use context::ErrorMode;
+    use loader::{FsLoader,LoadError};
+    use parser::{ParseResult,Parser};
+    use variable::Variable;
+
+    #[test]
+    fn renders_named_templates() {
+        let mut env     = Environment::new();
+        let mut context = Context::new();
+        context.add("name", "Ada".into()).unwrap();
+        env.add_template("emails/welcome", "Welcome, {{ name }}!").unwrap();
+
+        assert_eq!("Welcome, Ada!", env.render("emails/welcome", &mut context).unwrap());
+        assert_eq!(vec!["emails/welcome"], env.template_names().collect::<Vec<_>>());
+
+        env.insert_template("emails/welcome", Template::parse("Hi, {{ name }}!").unwrap());
+        assert_eq!("Hi, Ada!", env.render("emails/welcome", &mut context).unwrap());
+    }
+
+    #[test]
+    fn folding_uses_the_registered_filters_and_keeps_escaping() {
+        let mut env = Environment::with_options(ParserOptions { fold: true, ..ParserOptions::default() });
+        env.register_filter("shout", |input, _| Ok(format!("{}!", input).into()));
+        env.set_auto_escape(true);
+
+        let template = env.parse("{% if false %}x{% endif %}{{ 'a' | upcase }}{{ '<b>' }}{{ 'b' | whisper }}").unwrap();
+        assert_eq!(3, template.nodes.len());
+        assert!(matches!(template.nodes[2], Node::Output(..)));
+        assert_eq!("A&lt;b&gt;b", env.render_template(&template, &mut Context::new()).unwrap());
+
+        let template = env.parse("{{ 'a' | shout | upcase }}").unwrap();
+        assert_eq!(vec![Node::Text("A!".into(), template.nodes[0].span())], template.nodes);
+    }
+
+    // Only needs the serde feature: it brings serde_json along for the filter.
+    #[cfg(feature = "serde")]
+    #[test]
+    fn renders_json() {
+        let mut env     = Environment::new();
+        let mut context = Context::new();
+        context.add("tags", vec!["a", "b"].into()).unwrap();
+        env.add_template("data", "var tags = {{ tags | json }};").unwrap();
+
+        assert_eq!("var tags = [\"a\",\"b\"];", env.render("data", &mut context).unwrap());
+    }
+
+    #[test]
+    fn errors_name_the_template() {
+        let mut env     = Environment::new();
+        let mut context = Context::new();
+
+        let error = env.add_template("broken", "{{ a | }}").unwrap_err();
+        assert_eq!(Some("broken"), error.template_name());
+
+        let error = env.render("missing", &mut context).unwrap_err();
+        assert_eq!(Error::Render(RenderError::UnknownTemplate("missing".to_string())), error);
+
+        env.add_template("divide", "{{ 1 | divided_by: 0 }}").unwrap();
+        context.set_error_mode(ErrorMode::Strict);
+        assert_eq!("divide: Divided by 0", env.render("divide", &mut context).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn uses_registered_filters_and_tags() {
+        fn shout(parser: &mut Parser) -> ParseResult<TagArguments> {
+            parser.filter_chain().map(TagArguments::Output)
+        }
+
+        let mut env = Environment::new();
+        env.register_filter("exclaim", |input, _| Ok(Variable::Str(format!("{}!", input).into())));
+        env.register_tag("shout", shout);
+        env.add_template("page", "{{ 'hi' | exclaim }}{% shout 'x' %}").unwrap();
+
+        assert_eq!("hi!", env.render("page", &mut Context::new()).unwrap());
+        assert!(env.template("page").unwrap().warnings.is_empty());
+    }
+
+    #[test]
+    fn registering_a_tag_reparses_cached_templates() {
+        fn shout(parser: &mut Parser) -> ParseResult<TagArguments> {
+            parser.filter_chain().map(TagArguments::Output)
+        }
+
+        let root = env::temp_dir().join(format!("riquid-register-tag-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("page.liquid"), "{% shout 'x' %}").unwrap();
+
+        let mut env = Environment::new();
+        env.set_loader(FsLoader::new(&root, "liquid"));
+        assert!(!env.load("page").unwrap().warnings.is_empty());
+
+        env.register_tag("shout", shout);
+        assert!(env.load("page").unwrap().warnings.is_empty());
+    }
+
+    #[test]
+    fn includes_partials_from_the_loader() {
+        let root = env::temp_dir().join(format!("riquid-environment-{}", process::id()));
+        fs::create_dir_all(root.join("partials")).unwrap();
+        fs::write(root.join("partials/header.liquid"), "<h1>{{ title }}</h1>").unwrap();
+
+        let mut env     = Environment::new();
+        let mut context = Context::new();
+        context.add("title", "Home".into()).unwrap();
+        env.set_loader(FsLoader::new(&root, "liquid"));
+        env.add_template("page", "{% include 'partials/header' %}<p>Hi</p>").unwrap();
+        env.add_template("escape", "{% include '../secrets' %}").unwrap();
+
+        assert_eq!("<h1>Home</h1><p>Hi</p>", env.render("page", &mut context).unwrap());
+        assert_eq!("<h1>Home</h1>", env.render("partials/header", &mut context).unwrap());
+
+        let error = Error::from(LoadError::InvalidName("../secrets".to_string())).in_template("../secrets").in_template("escape");
+        assert_eq!(error, env.render("escape", &mut context).unwrap_err());
+    }
+
+    #[test]
+    fn caches_loaded_templates_until_invalidated() {
+        let root = env::temp_dir().join(format!("riquid-cache-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("footer.liquid"), "v1").unwrap();
+
+        let mut env     = Environment::new();
+        let mut context = Context::new();
+        env.set_loader(FsLoader::new(&root, "liquid"));
+        env.add_template("page", "{% include 'footer' %}").unwrap();
+        assert_eq!("v1", env.render("page", &mut context).unwrap());
+
+        fs::write(root.join("footer.liquid"), "v2").unwrap();
+        assert!(Arc::ptr_eq(&env.load("footer").unwrap(), &env.load("footer").unwrap()));
+        assert_eq!("v1", env.render("page", &mut context).unwrap());
+
+        assert!(env.invalidate("footer"));
+        assert!(!env.invalidate("footer"));
+        assert_eq!("v2", env.render("page", &mut context).unwrap());
+
+        fs::write(root.join("footer.liquid"), "v3").unwrap();
+        env.clear();
+        assert_eq!("v3", env.render("page", &mut context).unwrap());
+        assert!(env.template("page").is_some());
+    }
+
+    #[test]
+    fn compile_dir_registers_what_parses_and_reports_the_rest() {
+        let root = env::temp_dir().join(format!("riquid-compile-{}", process::id()));
+        let _    = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("emails/partials")).unwrap();
+        fs::write(root.join("emails/welcome.liquid"), "Welcome, {{ name }}!").unwrap();
+        fs::write(root.join("emails/partials/footer.liquid"), "Bye").unwrap();
+        fs::write(root.join("emails/broken.liquid"), "{{ name | }}").unwrap();
+        fs::write(root.join("emails/notes.txt"), "{{").unwrap();
+
+        let mut env   = Environment::new();
+        let errors    = env.compile_dir(&root, "liquid");
+        let mut names = env.template_names().collect::<Vec<_>>();
+        names.sort();
+
+        assert_eq!(vec!["emails/partials/footer", "emails/welcome"], names);
+        assert_eq!(1, errors.len());
+        assert_eq!(Some("emails/broken"), errors[0].template_name());
+
+        let errors = env.compile_dir(root.join("missing"), "liquid");
+        assert!(matches!(errors[..], [Error::Load(LoadError::Io(..))]));
+    }
+
+    #[test]
+    fn one_template_serves_concurrent_renders() {
+        let mut env = Environment::new();
+        env.add_template("greeting", "Hello {{ name }}").unwrap();
+
+        let env      = Arc::new(env);
+        let template = env.load("greeting").unwrap();
+        let renders  = (0..4).map(|n| {
+            let (env, template) = (env.clone(), template.clone());
+
+            thread::spawn(move || {
+                let mut context = Context::new();
+                context.add("name", Variable::Int(n)).unwrap();
+                env.render_template(&template, &mut context).unwrap()
+            })
+        }).collect::<Vec<_>>();
+
+        let output = renders.into_iter().map(|render| render.join().unwrap()).collect::<Vec<_>>();
+        assert_eq!(vec!["Hello 0", "Hello 1", "Hello 2", "Hello 3"], output);
+    }
+
+    #[test]
+    fn renders_into_a_reused_buffer() {
+        let mut env     = Environment::new();
+        let mut context = Context::new();
+        let mut buffer  = String::new();
+        env.add_template("a", "a{{ 1 | plus: 1 }}").unwrap();
+        env.add_template("b", "b").unwrap();
+
+        env.render_into("a", &mut context, &mut buffer).unwrap();
+        env.render_into("b", &mut context, &mut buffer).unwrap();
+        assert_eq!("a2b", buffer);
+
+        env.set_size_hint(|_| 1024);
+        assert_eq!(1024, env.size_hint(env.template("b").unwrap()));
+        assert!(env.render("b", &mut context).unwrap().capacity() >= 1024);
+    }
+
+    #[test]
+    fn render_many_keeps_the_order_of_the_jobs() {
+        let mut env = Environment::new();
+        env.add_template("page", "{{ n | times: 2 }}").unwrap();
+
+        let jobs = (0..50).map(|n| {
+            let mut context = Context::new();
+            context.add("n", Variable::Int(n)).unwrap();
+            (if n == 7 { "missing" } else { "page" }, context)
+        });
+
+        let results = env.render_many(jobs);
+        assert_eq!(50, results.len());
+        assert_eq!(Ok("12".to_string()), results[6]);
+        assert_eq!(Ok("98".to_string()), results[49]);
+        assert!(results[7].is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use std::error;
+use std::fmt;
+use std::result;
+
+use context::ContextError;
+use lexer::{LexError,Span};
+use loader::LoadError;
+use parser::ParseError;
+use render::RenderError;
+use variable::VariableError;
+
+pub type Result<T> = result::Result<T, Error>;
+
+// Everything that can go wrong between reading a template and rendering it, so callers can use `?`
+// across all of it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    Lex(LexError),
+    Parse(ParseError),
+    Render(RenderError),
+    Context(ContextError),
+    Load(LoadError),
+    // Any of the above, raised by the named template.
+    Template(String, Box<Error>)
+}
+
+impl Error {
+    pub fn in_template<S: Into<String>>(self, name: S) -> Error {
+        Error::Template(name.into(), Box::new(self))
+    }
+
+    pub fn template_name(&self) -> Option<&str> {
+        match *self {
+            Error::Template(ref name, _) => Some(name),
+            _                            => None
+        }
+    }
+
+    // What Shopify writes into the output in place of a node that failed.
+    pub fn inline_message(&self) -> String {
+        let (name, error) = match *self {
+            Error::Template(ref name, ref error) => (Some(name), &**error),
+            ref error                            => (None, error)
+        };
+
+        let kind = match *error {
+            Error::Lex(_) | Error::Parse(_) => "Liquid syntax error",
+            _                               => "Liquid error"
+        };
+
+        match name {
+            Some(name) => format!("{} ({}): {}", kind, name, error),
+            None       => format!("{}: {}", kind, error)
+        }
+    }
+
+    // Where in the template the problem is, when that's known.
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            Error::Lex(ref error)         => Some(error.span()),
+            Error::Parse(ref error)       => error.span(),
+            Error::Render(_)              => None,
+            Error::Context(_)             => None,
+            Error::Load(_)                => None,
+            Error::Template(_, ref error) => error.span()
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Lex(ref error)                => write!(f, "{}", error),
+            Error::Parse(ref error)              => write!(f, "{}", error),
+            Error::Render(ref error)             => write!(f, "{}", error),
+            Error::Context(ref error)            => write!(f, "{}", error),
+            Error::Load(ref error)               => write!(f, "{}", error),
+            Error::Template(ref name, ref error) => write!(f, "{}: {}", name, error)
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<LexError> for Error {
+    fn from(error: LexError) -> Error {
+        Error::Lex(error)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Error {
+        match error {
+            ParseError::Lex(error) => Error::Lex(error),
+            error                  => Error::Parse(error)
+        }
+    }
+}
+
+impl From<RenderError> for Error {
+    fn from(error: RenderError) -> Error {
+        Error::Render(error)
+    }
+}
+
+impl From<VariableError> for Error {
+    fn from(error: VariableError) -> Error {
+        Error::Render(RenderError::Variable(error))
+    }
+}
+
+impl From<ContextError> for Error {
+    fn from(error: ContextError) -> Error {
+        Error::Context(error)
+    }
+}
+
+impl From<LoadError> for Error {
+    fn from(error: LoadError) -> Error {
+        Error::Load(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use template::Template;
+
+    fn parse(source: &str) -> Result<Template> {
+        Ok(Template::parse(source)?)
+    }
+
+    #[test]
+    fn errors_convert_with_question_marks() {
+        let error = parse("{{ a | }}").unwrap_err();
+        assert!(matches!(error, Error::Parse(ParseError::UnexpectedEnd(_))));
+
+        let error = parse("{{ a # }}").unwrap_err();
+        assert_eq!(Some(Span { start: 5, end: 6, line: 1, col: 6 }), error.span());
+        assert!(matches!(error, Error::Lex(_)));
+    }
+
+    #[test]
+    fn errors_can_name_their_template() {
+        let error = parse("\n{{ a # }}").unwrap_err().in_template("product.liquid");
+
+        assert_eq!(Some("product.liquid"), error.template_name());
+        assert_eq!(Some(2), error.span().map(|span| span.line));
+        assert_eq!("product.liquid: Unexpected character '#' at line 2, column 6", error.to_string());
+        assert_eq!(None, Error::from(VariableError::DivisionByZero).span());
+    }
+
+    #[test]
+    fn inline_messages_say_what_kind_of_error_it_was() {
+        assert_eq!("Liquid error: Divided by 0", Error::from(VariableError::DivisionByZero).inline_message());
+        assert_eq!(
+            "Liquid syntax error (product): Invalid tag: '{% 1 %}'",
+            Error::from(ParseError::InvalidTag("1".to_string())).in_template("product").inline_message()
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::{HashMap,HashSet};
+
+use context::Context;
+use error::Result;
+use render::Renderer;
+use variable::{Variable,VariableResult};
+
+pub type Filter = fn(&Variable, &[Variable]) -> VariableResult<Variable>;
+
+// For the few filters that need more than their input and arguments: the variables of the render,
+// like `site.baseurl`, or a renderer to evaluate expressions passed in as strings.
+pub type ContextFilter = fn(&Variable, &[Variable], &Renderer, &Context) -> Result<Variable>;
+
+// A missing argument is nil, like in Liquid.
+static NIL: Variable = Variable::Nil;
+
+// Maps filter names to the functions that apply them. Each gets the input value and its
+// positional arguments, already evaluated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterRegistry {
+    filters: HashMap<String, Filter>,
+    contextual: HashMap<String, ContextFilter>,
+    // Filters that can give a different value for the same input, so folding leaves them be.
+    volatile: HashSet<String>
+}
+
+impl Default for FilterRegistry {
+    fn default() -> FilterRegistry {
+        let mut registry = FilterRegistry::empty();
+        registry.register("plus", |input, arguments| input.plus(argument(arguments, 0)));
+        registry.register("minus", |input, arguments| input.minus(argument(arguments, 0)));
+        registry.register("times", |input, arguments| input.times(argument(arguments, 0)));
+        registry.register("divided_by", |input, arguments| input.divided_by(argument(arguments, 0)));
+        registry.register("modulo", |input, arguments| input.modulo(argument(arguments, 0)));
+        registry.register("escape", |input, _| Ok(Variable::Safe(escape_html(&input.to_string()).into())));
+        registry.register("raw", |input, _| Ok(Variable::Safe(input.to_string().into())));
+        registry.register("upcase", |input, _| Ok(input.to_string().to_uppercase().into()));
+        registry.register("downcase", |input, _| Ok(input.to_string().to_lowercase().into()));
+        registry.register("capitalize", |input, _| Ok(capitalize(&input.to_string()).into()));
+        registry.register("strip", |input, _| Ok(input.to_string().trim().into()));
+        registry.register("lstrip", |input, _| Ok(input.to_string().trim_start().into()));
+        registry.register("rstrip", |input, _| Ok(input.to_string().trim_end().into()));
+        registry.register("append", |input, arguments| Ok(format!("{}{}", input, argument(arguments, 0)).into()));
+        registry.register("prepend", |input, arguments| Ok(format!("{}{}", argument(arguments, 0), input).into()));
+        // Writing a Variable out as JSON can't fail.
+        #[cfg(feature = "serde")]
+        registry.register("json", |input, _| Ok(Variable::Str(serde_json::to_string(input).unwrap_or_default().into())));
+
+        registry
+    }
+}
+
+impl FilterRegistry {
+    pub fn new() -> FilterRegistry {
+        FilterRegistry::default()
+    }
+
+    // Without any of the built-in filters.
+    pub fn empty() -> FilterRegistry {
+        FilterRegistry { filters: HashMap::new(), contextual: HashMap::new(), volatile: HashSet::new() }
+    }
+
+    // Replaces any filter already registered under `name`, built-in or not.
+    pub fn register(&mut self, name: &str, filter: Filter) {
+        self.contextual.remove(name);
+        self.volatile.remove(name);
+        self.filters.insert(name.to_string(), filter);
+    }
+
+    // For a filter whose value can change from one render to the next, like one reading the clock.
+    pub fn register_volatile(&mut self, name: &str, filter: Filter) {
+        self.register(name, filter);
+        self.volatile.insert(name.to_string());
+    }
+
+    pub fn register_contextual(&mut self, name: &str, filter: ContextFilter) {
+        self.filters.remove(name);
+        self.volatile.remove(name);
+        self.contextual.insert(name.to_string(), filter);
+    }
+
+    pub fn get(&self, name: &str) -> Option<Filter> {
+        self.filters.get(name).copied()
+    }
+
+    pub fn get_contextual(&self, name: &str) -> Option<ContextFilter> {
+        self.contextual.get(name).copied()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.filters.contains_key(name) || self.contextual.contains_key(name)
+    }
+
+    // The filter, if its value only ever depends on its input and arguments, so it can be applied
+    // ahead of the render.
+    pub fn get_pure(&self, name: &str) -> Option<Filter> {
+        if self.volatile.contains(name) { return None; }
+        self.get(name)
+    }
+}
+
+// Like Ruby's CGI.escapeHTML, which Liquid's `escape` uses.
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for character in value.chars() {
+        match character {
+            '&'  => escaped.push_str("&amp;"),
+            '<'  => escaped.push_str("&lt;"),
+            '>'  => escaped.push_str("&gt;"),
+            '"'  => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _    => escaped.push(character)
+        }
+    }
+
+    escaped
+}
+
+// Like Ruby's String#capitalize: the first character upper case and the rest lower case.
+fn capitalize(value: &str) -> String {
+    let mut characters = value.chars();
+
+    match characters.next() {
+        Some(first) => first.to_uppercase().chain(characters.as_str().to_lowercase().chars()).collect(),
+        None        => String::new()
+    }
+}
+
+fn argument(arguments: &[Variable], index: usize) -> &Variable {
+    arguments.get(index).unwrap_or(&NIL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_filters_are_registered() {
+        let registry = FilterRegistry::new();
+        let plus     = registry.get("plus").unwrap();
+
+        assert_eq!(Variable::Int(3), plus(&Variable::Int(1), &[Variable::Int(2)]).unwrap());
+        assert_eq!(Variable::Int(1), plus(&Variable::Int(1), &[]).unwrap());
+        assert!(!FilterRegistry::empty().contains("plus"));
+    }
+
+    #[test]
+    fn escape_and_raw_mark_strings_safe() {
+        let registry = FilterRegistry::new();
+        let escape   = registry.get("escape").unwrap();
+        let raw      = registry.get("raw").unwrap();
+
+        assert_eq!(Variable::Safe("&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;".into()), escape(&"<a href=\"x\">Tom & Jerry's</a>".into(), &[]).unwrap());
+        assert_eq!(Variable::Safe("<b>".into()), raw(&"<b>".into(), &[]).unwrap());
+        assert_eq!(Variable::Str("<b>".into()), raw(&"<b>".into(), &[]).unwrap());
+    }
+
+    #[test]
+    fn string_filters_change_case_strip_and_join() {
+        let registry = FilterRegistry::new();
+        let apply    = |name: &str, input: Variable, arguments: &[Variable]| registry.get(name).unwrap()(&input, arguments).unwrap().to_string();
+
+        assert_eq!(("HELLO", "hello", "Hello world"), (apply("upcase", "hello".into(), &[]).as_str(), apply("downcase", "HeLLo".into(), &[]).as_str(), apply("capitalize", "hELLO World".into(), &[]).as_str()));
+        assert_eq!(("a b", "a b  ", "  a b"), (apply("strip", "  a b  ".into(), &[]).as_str(), apply("lstrip", "  a b  ".into(), &[]).as_str(), apply("rstrip", "  a b  ".into(), &[]).as_str()));
+        assert_eq!(("file.txt", "/file", "1"), (apply("append", "file".into(), &[".txt".into()]).as_str(), apply("prepend", "file".into(), &["/".into()]).as_str(), apply("append", 1.into(), &[]).as_str()));
+        assert_eq!("", apply("capitalize", Variable::Nil, &[]));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_writes_values_as_json() {
+        let json = FilterRegistry::new().get("json").unwrap();
+        let item = Variable::Object(vec![("name".to_string(), "a \"b\"".into()), ("tags".to_string(), vec![1, 2].into())].into_iter().collect());
+
+        assert_eq!(Variable::Str(r#"{"name":"a \"b\"","tags":[1,2]}"#.into()), json(&item, &[]).unwrap());
+        assert_eq!(Variable::Str("null".into()), json(&Variable::Nil, &[]).unwrap());
+    }
+
+    #[test]
+    fn registering_replaces_filters() {
+        let mut registry = FilterRegistry::new();
+        registry.register("plus", |input, _| Ok(input.clone()));
+
+        let plus = registry.get("plus").unwrap();
+        assert_eq!(Variable::Int(1), plus(&Variable::Int(1), &[Variable::Int(2)]).unwrap());
+
+        registry.register_contextual("plus", |_, _, _, context| Ok(context.get("x")?));
+        assert_eq!((None, true), (registry.get("plus"), registry.get_contextual("plus").is_some()));
+        assert!(registry.contains("plus"));
+    }
+
+    #[test]
+    fn only_plain_filters_that_arent_volatile_are_pure() {
+        let mut registry = FilterRegistry::new();
+        registry.register_volatile("now", |_, _| Ok(Variable::Nil));
+        registry.register_contextual("minus", |_, _, _, context| Ok(context.get("x")?));
+
+        assert!(registry.get_pure("plus").is_some());
+        assert_eq!((None, true), (registry.get_pure("now"), registry.get("now").is_some()));
+        assert!(registry.get_pure("minus").is_none());
+
+        registry.register("now", |input, _| Ok(input.clone()));
+        assert!(registry.get_pure("now").is_some());
+    }
+}
@@ -0,0 +1,199 @@
+use ast::{Condition,Expression,FilterChain,Node,TagArguments,Text};
+use filters::{escape_html,FilterRegistry};
+use lexer::Span;
+use render;
+use variable::Variable;
+
+// Does at parse time what would come out the same on every render. `if` and `unless` blocks whose
+// conditions are all literals are replaced by the branch they take, and outputs of a literal put
+// through pure filters (see `FilterRegistry::get_pure`) are rendered and joined with the text
+// around them. An output is only folded when HTML-escaping would leave it as it is, so templates
+// render the same with `Environment::auto_escape` either way. Anything that would fail is left for
+// the render to report.
+pub fn fold(nodes: Vec<Node>, filters: &FilterRegistry) -> Vec<Node> {
+    let mut folded: Vec<Node> = Vec::with_capacity(nodes.len());
+    let mut run               = vec![];
+    let kept                  = branches(&nodes);
+
+    for (node, _) in nodes.into_iter().zip(kept).filter(|&(_, kept)| kept) {
+        match node {
+            Node::Text(text, span) => run.push((text, span)),
+            Node::Output(output, span) => match constant(&output, filters) {
+                Some(text) => run.push((Text::from(text), span)),
+                None       => {
+                    join(&mut run, &mut folded);
+                    folded.push(Node::Output(output, span));
+                }
+            },
+            node => {
+                join(&mut run, &mut folded);
+                folded.push(node);
+            }
+        }
+    }
+
+    join(&mut run, &mut folded);
+    folded
+}
+
+// An `if` or `unless` block being walked: where each of its branches starts and whether it holds,
+// when that's known.
+struct Block {
+    branches: Vec<(usize, Option<bool>)>
+}
+
+// Which nodes are left once blocks with literal conditions are down to the branch they take.
+// Branches are followed the way the renderer follows them, so a block is only folded once it's
+// closed and every branch up to the one taken can be decided.
+fn branches(nodes: &[Node]) -> Vec<bool> {
+    let mut kept   = vec![true; nodes.len()];
+    let mut blocks = vec![];
+
+    for (index, node) in nodes.iter().enumerate() {
+        let Node::Tag(ref tag, _) = *node else { continue };
+
+        match (tag.name.as_str(), &tag.arguments) {
+            (name @ "if", TagArguments::Condition(condition)) | (name @ "unless", TagArguments::Condition(condition)) => {
+                blocks.push(Block { branches: vec![(index, holds(condition).map(|holds| holds == (name == "if")))] });
+            },
+            ("elsif", TagArguments::Condition(condition)) => {
+                if let Some(block) = blocks.last_mut() { block.branches.push((index, holds(condition))); }
+            },
+            ("else", _) => {
+                if let Some(block) = blocks.last_mut() { block.branches.push((index, Some(true))); }
+            },
+            ("endif", _) | ("endunless", _) => {
+                if let Some(block) = blocks.pop() { prune(&block, index, &mut kept); }
+            },
+            _ => {}
+        }
+    }
+
+    kept
+}
+
+fn prune(block: &Block, end: usize, kept: &mut [bool]) {
+    let mut taken = None;
+    for (branch, &(_, holds)) in block.branches.iter().enumerate() {
+        match holds {
+            Some(true)  => { taken = Some(branch); break; },
+            Some(false) => continue,
+            None        => return
+        }
+    }
+
+    let starts = block.branches.iter().map(|&(start, _)| start);
+    let ends   = block.branches.iter().skip(1).map(|&(start, _)| start).chain(Some(end));
+
+    for (branch, (start, end)) in starts.zip(ends).enumerate() {
+        kept[start] = false;
+        if taken != Some(branch) { kept[start..end].iter_mut().for_each(|kept| *kept = false); }
+    }
+    kept[end] = false;
+}
+
+fn holds(condition: &Condition) -> Option<bool> {
+    match *condition {
+        Condition::Test(ref expression)                => literal(expression).map(Variable::is_truthy),
+        Condition::Comparison(ref left, op, ref right) => render::compare(literal(left)?, op, literal(right)?).ok(),
+        Condition::And(ref left, ref right)            => Some(holds(left)? && holds(right)?),
+        Condition::Or(ref left, ref right)             => Some(holds(left)? || holds(right)?)
+    }
+}
+
+// Replaces a run of adjacent texts with a single node. A text on its own is kept as it is, so it
+// still points into the template's source.
+fn join(run: &mut Vec<(Text, Span)>, folded: &mut Vec<Node>) {
+    let (Some(&(_, first)), Some(&(_, last))) = (run.first(), run.last()) else { return };
+
+    let text = match run.len() {
+        1 => run.remove(0).0,
+        _ => Text::from(run.drain(..).map(|(text, _)| text).fold(String::new(), |joined, text| joined + &text))
+    };
+
+    if !text.is_empty() { folded.push(Node::Text(text, first.to(last))); }
+}
+
+fn constant(output: &FilterChain, filters: &FilterRegistry) -> Option<String> {
+    let mut value = literal(&output.expression)?.clone();
+
+    for filter in &output.filters {
+        let apply     = filters.get_pure(&filter.name)?;
+        let arguments = filter.arguments.iter().map(|argument| literal(argument).cloned()).collect::<Option<Vec<_>>>()?;
+        value = apply(&value, &arguments).ok()?;
+    }
+
+    let text = value.render_to_string();
+    match value {
+        Variable::Safe(_)               => Some(text),
+        _ if escape_html(&text) == text => Some(text),
+        _                               => None
+    }
+}
+
+fn literal(expression: &Expression) -> Option<&Variable> {
+    match *expression {
+        Expression::Literal(ref literal) => Some(&literal.value),
+        _                                => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use template::Template;
+
+    fn folded(source: &str) -> Vec<Node> {
+        fold(Template::parse(source).unwrap().nodes, &FilterRegistry::new())
+    }
+
+    fn text(value: &str, start: usize, end: usize) -> Node {
+        Node::Text(value.into(), Span { start, end, line: 1, col: start + 1 })
+    }
+
+    #[test]
+    fn fold_renders_literal_outputs_into_the_surrounding_text() {
+        assert_eq!(vec![text("a 1 b 2.5 c true d", 0, 36)], folded("a {{ 1 }} b {{ 2.5 }} c {{ true }} d"));
+        assert_eq!(vec![text("here and there", 0, 30)], folded("{{ 'here' }} and {{ 'there' }}"));
+    }
+
+    #[test]
+    fn fold_drops_outputs_that_render_nothing() {
+        assert_eq!(vec![text("ab", 0, 22)], folded("a{{ nil }}{{ empty }}b"));
+        assert_eq!(Vec::<Node>::new(), folded("{{ nil }}"));
+    }
+
+    #[test]
+    fn fold_applies_pure_filters_to_literals() {
+        assert_eq!(vec![text("A 6 &lt;b&gt;", 0, 68)], folded("{{ 'a' | upcase }} {{ 1 | plus: 2 | times: 2 }} {{ '<b>' | escape }}"));
+
+        let nodes = fold(Template::parse("{{ 'a' | upcase }}{{ 1 | plus: 2 }}").unwrap().nodes, &FilterRegistry::empty());
+        assert!(nodes.iter().all(|node| matches!(*node, Node::Output(..))));
+        assert_eq!(2, nodes.len());
+    }
+
+    #[test]
+    fn fold_prunes_branches_with_literal_conditions() {
+        assert_eq!(vec![text("ac", 0, 28)], folded("a{% if false %}b{% endif %}c"));
+        assert_eq!(vec![text("b", 31, 32)], folded("{% if 1 > 2 %}a{% elsif true %}b{% else %}c{% endif %}"));
+        assert_eq!(vec![text("ab", 0, 34)], folded("a{% unless 'x' == 'y' and true %}b{% else %}c{% endunless %}"));
+        assert_eq!(Vec::<Node>::new(), folded("{% if false %}a{% if x %}b{% endif %}{% endif %}"));
+    }
+
+    #[test]
+    fn fold_leaves_what_it_cant_decide_to_the_render() {
+        let nodes = folded("{{ name }}{{ 'a' | shout }}{{ 1 | divided_by: 0 }}{{ 1 | plus: n }}{% if x %}a{% elsif true %}b{% endif %}{% if true %}c");
+
+        assert!(nodes[..4].iter().all(|node| matches!(*node, Node::Output(..))));
+        assert!(matches!(nodes[4], Node::Tag(ref tag, _) if tag.name == "if"));
+        assert_eq!(11, nodes.len());
+    }
+
+    #[test]
+    fn fold_leaves_outputs_html_escaping_would_change() {
+        let nodes = folded("{{ '<b>' }}{{ 'a & b' | raw }}");
+
+        assert!(matches!(nodes[0], Node::Output(..)));
+        assert_eq!(Node::Text("a & b".into(), Span { start: 11, end: 30, line: 1, col: 12 }), nodes[1]);
+    }
+}
@@ -0,0 +1,237 @@
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use context::Context;
+use environment::Environment;
+use error::Result;
+use filters::escape_html;
+use parser::{ParseResult,Parser};
+use render::Renderer;
+use variable::{Variable,VariableResult};
+
+static NIL: Variable = Variable::Nil;
+
+// Jekyll's own filters, for sites moving over from it. `relative_url` and `absolute_url` read
+// `site.baseurl` and `site.url` from the render's variables, the way Jekyll does.
+pub fn register(environment: &mut Environment) {
+    environment.register_contextual_filter("where_exp", where_exp);
+    environment.register_contextual_filter("group_by_exp", group_by_exp);
+    environment.register_contextual_filter("relative_url", |input, _, _, context| Ok(relative_url(input, context)));
+    environment.register_contextual_filter("absolute_url", |input, _, _, context| Ok(absolute_url(input, context)));
+    environment.register_volatile_filter("date_to_xmlschema", date_to_xmlschema);
+    environment.register_filter("xml_escape", |input, _| Ok(xml_escape(input)));
+    environment.register_filter("cgi_escape", |input, _| Ok(Variable::Str(cgi_escape(&input.to_string()).into())));
+    environment.register_filter("number_of_words", |input, arguments| Ok(number_of_words(&input.to_string(), argument(arguments, 0))));
+}
+
+// `items | where_exp: "item", "item.price > 10"` keeps the items the condition holds for, with
+// each one in turn named by the first argument.
+fn where_exp(input: &Variable, arguments: &[Variable], renderer: &Renderer, context: &Context) -> Result<Variable> {
+    let Some(items) = items(input) else { return Ok(input.clone()) };
+    let name        = argument(arguments, 0).to_string();
+    let condition   = parse(argument(arguments, 1), |parser| parser.condition())?;
+
+    let mut kept = vec![];
+    for item in items {
+        if renderer.test(&condition, &with(context, &name, item.clone())?)? { kept.push(item.clone()); }
+    }
+
+    Ok(Variable::Array(kept))
+}
+
+// `items | group_by_exp: "item", "item.date | date: '%Y'"` groups the items by the value of the
+// expression, in the order each value first comes up. Each group is a hash of its `name`, its
+// `items` and their `size`.
+fn group_by_exp(input: &Variable, arguments: &[Variable], renderer: &Renderer, context: &Context) -> Result<Variable> {
+    let Some(items) = items(input) else { return Ok(input.clone()) };
+    let name        = argument(arguments, 0).to_string();
+    let chain       = parse(argument(arguments, 1), |parser| parser.filter_chain())?;
+
+    let mut groups: Vec<(Variable, Vec<Variable>)> = vec![];
+    for item in items {
+        let key = renderer.evaluate(&chain, &with(context, &name, item.clone())?)?.into_owned();
+
+        match groups.iter_mut().find(|(name, _)| *name == key) {
+            Some((_, items)) => items.push(item.clone()),
+            None             => groups.push((key, vec![item.clone()]))
+        }
+    }
+
+    Ok(Variable::Array(groups.into_iter().map(|(name, items)| {
+        let size = Variable::Int(items.len() as i64);
+        Variable::Object(vec![("name".to_string(), name), ("items".to_string(), Variable::Array(items)), ("size".to_string(), size)].into_iter().collect())
+    }).collect()))
+}
+
+// The path with `site.baseurl` in front. Absolute URLs are left alone.
+fn relative_url(input: &Variable, context: &Context) -> Variable {
+    if *input == Variable::Nil { return Variable::Nil; }
+
+    let input = input.to_string();
+    if is_absolute(&input) { return Variable::Str(input.into()); }
+
+    let base = context.get("site.baseurl").map(|base| base.to_string()).unwrap_or_default();
+    let base = base.trim_end_matches('/');
+
+    Variable::Str(format!("{}{}", leading_slash(base), leading_slash(&input)).into())
+}
+
+// The relative URL with `site.url` in front.
+fn absolute_url(input: &Variable, context: &Context) -> Variable {
+    let relative = relative_url(input, context);
+    let url      = context.get("site.url").map(|url| url.to_string()).unwrap_or_default();
+
+    match relative {
+        Variable::Str(ref path) if !is_absolute(path) && !url.is_empty() => Variable::Str(format!("{}{}", url.trim_end_matches('/'), path).into()),
+        relative                                                         => relative
+    }
+}
+
+#[cfg(feature = "chrono")]
+fn date_to_xmlschema(input: &Variable, _arguments: &[Variable]) -> VariableResult<Variable> {
+    match input.to_date() {
+        Some(date) => Ok(Variable::Str(date.format("%Y-%m-%dT%H:%M:%S%:z").to_string().into())),
+        None       => Ok(input.clone())
+    }
+}
+
+// Without chrono there's nothing to read dates with, so they pass through.
+#[cfg(not(feature = "chrono"))]
+fn date_to_xmlschema(input: &Variable, _arguments: &[Variable]) -> VariableResult<Variable> {
+    Ok(input.clone())
+}
+
+fn xml_escape(input: &Variable) -> Variable {
+    match *input {
+        Variable::Nil => Variable::Str("".into()),
+        ref input     => Variable::Safe(escape_html(&input.to_string()).into())
+    }
+}
+
+// Like Ruby's CGI.escape: spaces become `+` and anything but letters, digits and `_.-~` is
+// percent-encoded.
+fn cgi_escape(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+
+    for byte in input.bytes() {
+        match byte {
+            b' '                                                                => escaped.push('+'),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' | b'-' | b'~' => escaped.push(byte as char),
+            _                                                                   => escaped.push_str(&format!("%{:02X}", byte))
+        }
+    }
+
+    escaped
+}
+
+// Words are separated by whitespace. With "cjk", every Chinese, Japanese or Korean character
+// counts as a word of its own; "auto" does that only when there are any.
+fn number_of_words(input: &str, mode: &Variable) -> Variable {
+    static CJK: OnceLock<(Regex, Regex)> = OnceLock::new();
+    let (cjk, word) = CJK.get_or_init(|| {
+        let charset = r"\p{Han}\p{Katakana}\p{Hiragana}\p{Hangul}";
+        (Regex::new(&format!("[{}]", charset)).unwrap(), Regex::new(&format!(r"[^{}\s]+", charset)).unwrap())
+    });
+
+    let characters = || cjk.find_iter(input).count();
+    let count      = match mode.as_str() {
+        Some("cjk")                      => characters() + word.find_iter(input).count(),
+        Some("auto") if characters() > 0 => characters() + word.find_iter(input).count(),
+        _                                => input.split_whitespace().count()
+    };
+
+    Variable::Int(count as i64)
+}
+
+// What `where_exp` and `group_by_exp` go through: an array's items or a hash's values.
+fn items(input: &Variable) -> Option<Vec<&Variable>> {
+    match *input {
+        Variable::Array(ref items)  => Some(items.iter().collect()),
+        Variable::Object(ref items) => Some(items.values().collect()),
+        _                           => None
+    }
+}
+
+// All of an expression passed in as a string.
+fn parse<T, F>(markup: &Variable, parse: F) -> Result<T> where F: FnOnce(&mut Parser) -> ParseResult<T> {
+    let markup     = markup.to_string();
+    let mut parser = Parser::new(&markup)?;
+    let parsed     = parse(&mut parser)?;
+    parser.expect_end()?;
+
+    Ok(parsed)
+}
+
+fn with(context: &Context, name: &str, value: Variable) -> Result<Context> {
+    let mut context = context.fork();
+    context.push()?;
+    context.add(name, value)?;
+
+    Ok(context)
+}
+
+fn is_absolute(url: &str) -> bool {
+    url.split_once("://").is_some_and(|(scheme, _)| !scheme.is_empty() && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)))
+}
+
+fn leading_slash(path: &str) -> String {
+    match path.is_empty() || path.starts_with('/') {
+        true  => path.to_string(),
+        false => format!("/{}", path)
+    }
+}
+
+fn argument(arguments: &[Variable], index: usize) -> &Variable {
+    arguments.get(index).unwrap_or(&NIL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str, site: &[(&str, &str)]) -> String {
+        let mut environment = Environment::new();
+        register(&mut environment);
+
+        let mut context = Context::new();
+        context.add("site", Variable::Object(site.iter().map(|&(key, value)| (key.to_string(), value.into())).collect())).unwrap();
+        context.add("posts", Variable::Array((1..=4).map(|n| Variable::Object(vec![("n".to_string(), Variable::Int(n))].into_iter().collect())).collect())).unwrap();
+
+        environment.render_template(&environment.parse(source).unwrap(), &mut context).unwrap()
+    }
+
+    #[test]
+    fn where_exp_and_group_by_exp_evaluate_expressions_per_item() {
+        assert_eq!("34", render("{% assign big = posts | where_exp: 'post', 'post.n > 2' %}{{ big[0].n }}{{ big[1].n }}{{ big[2].n }}", &[]));
+        assert_eq!("23", render("{% assign some = posts | where_exp: 'post', 'post.n > 1 and post.n < 4' %}{{ some[0].n }}{{ some[1].n }}", &[]));
+        assert_eq!("1:2:3 0", render("{% assign groups = posts | group_by_exp: 'post', 'post.n | modulo: 2' %}{{ groups[0].name }}:{{ groups[0].size }}:{{ groups[0].items[1].n }} {{ groups[1].name }}", &[]));
+        assert_eq!("5", render("{{ 5 | where_exp: 'post', 'post' }}", &[]));
+    }
+
+    #[test]
+    fn urls_use_the_site_settings() {
+        let site = [("url", "https://example.com/"), ("baseurl", "/blog/")];
+
+        assert_eq!("/blog/about", render("{{ 'about' | relative_url }}", &site));
+        assert_eq!("https://example.com/blog/about", render("{{ '/about' | absolute_url }}", &site));
+        assert_eq!("http://x.org/a", render("{{ 'http://x.org/a' | absolute_url }}", &site));
+        assert_eq!("/about", render("{{ 'about' | relative_url }}", &[]));
+        assert_eq!("/about", render("{{ 'about' | absolute_url }}", &[]));
+    }
+
+    #[test]
+    fn text_filters_match_jekyll() {
+        assert_eq!("a+b%26c%2Fd%C3%A9~", cgi_escape("a b&c/dé~"));
+        assert_eq!("&lt;a&gt; &amp; &quot;b&quot;", render("{{ '<a> & \"b\"' | xml_escape }}", &[]));
+        assert_eq!("3", render("{{ 'one  two\nthree' | number_of_words }}", &[]));
+        assert_eq!("4 1 4", render("{{ '你好 world 世' | number_of_words: 'cjk' }} {{ '你好世' | number_of_words }} {{ '你好 world 世' | number_of_words: 'auto' }}", &[]));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn date_to_xmlschema_writes_iso_8601() {
+        assert_eq!("2024-03-01T00:00:00+00:00", render("{{ '2024-03-01' | date_to_xmlschema }}", &[]));
+        assert_eq!("1970-01-01T00:01:40+00:00", render("{{ 100 | date_to_xmlschema }}", &[]));
+    }
+}
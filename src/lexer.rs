@@ -1,18 +1,15 @@
-use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
 
 use scanner::Scanner;
-use regex::Regex;
 
-const COMPARISON           : &'static str = r"^(==|!=|<>|<=?|>=?|contains)";
-const SINGLE_STRING_LITERAL: &'static str = r"^'[^']*'";
-const DOUBLE_STRING_LITERAL: &'static str = r#"^"[^"]*""#;
-const NUMBER_LITERAL:        &'static str = r"^-?\d+(\.\d+)?";
-const IDENTIFIER:            &'static str = r"^[a-zA-Z_][\w-]*\??";
-const RANGE_OP:              &'static str = r"^\.\.";
+#[cfg(feature = "serde")]
+use serde::{Deserialize,Serialize};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
-    Comparison,
+    Comparison(CompareOp),
     Identifier,
     Number,
     String,
@@ -26,12 +23,234 @@ pub enum Token {
     OpenRound,
     CloseRound,
     Question,
-    Dash
+    Dash,
+    Assign,
+    Empty,
+    Blank,
+    Bool,
+    Nil,
+    And,
+    Or,
+    Not
 }
 
-pub type LexedToken = (Token, String);
+impl Token {
+    pub fn is_literal(&self) -> bool {
+        matches!(*self, Token::Empty | Token::Blank | Token::Bool | Token::Nil)
+    }
+
+    pub fn is_keyword(&self) -> bool {
+        self.is_literal() || matches!(*self, Token::And | Token::Or | Token::Not)
+    }
+
+    // Whether a `-` right after this token reads as subtraction rather than a negative sign.
+    pub fn ends_operand(&self) -> bool {
+        self.is_literal() || matches!(*self, Token::Identifier | Token::Number | Token::String | Token::CloseSquare | Token::CloseRound)
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Comparison(op) => write!(f, "'{}'", op.as_str()),
+            Token::Identifier     => write!(f, "identifier"),
+            Token::Number         => write!(f, "number literal"),
+            Token::String         => write!(f, "string literal"),
+            Token::Range          => write!(f, "'..'"),
+            Token::Pipe           => write!(f, "'|'"),
+            Token::Dot            => write!(f, "'.'"),
+            Token::Colon          => write!(f, "':'"),
+            Token::Comma          => write!(f, "','"),
+            Token::OpenSquare     => write!(f, "'['"),
+            Token::CloseSquare    => write!(f, "']'"),
+            Token::OpenRound      => write!(f, "'('"),
+            Token::CloseRound     => write!(f, "')'"),
+            Token::Question       => write!(f, "'?'"),
+            Token::Dash           => write!(f, "'-'"),
+            Token::Assign         => write!(f, "'='"),
+            Token::Empty          => write!(f, "'empty'"),
+            Token::Blank          => write!(f, "'blank'"),
+            Token::Bool           => write!(f, "boolean literal"),
+            Token::Nil            => write!(f, "nil literal"),
+            Token::And            => write!(f, "'and'"),
+            Token::Or             => write!(f, "'or'"),
+            Token::Not            => write!(f, "'not'")
+        }
+    }
+}
+
+// Words with a meaning inside particular tags (`for x in y`, `include 'a' with b as c`, `include 'a' for b`) but that
+// remain valid variable names everywhere else, so they're lexed as identifiers and matched by the
+// parser only where a tag expects them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Keyword {
+    In,
+    With,
+    For,
+    As,
+    Offset,
+    Limit,
+    Reversed
+}
+
+impl Keyword {
+    pub fn parse(value: &str) -> Option<Keyword> {
+        match value {
+            "in"       => Some(Keyword::In),
+            "with"     => Some(Keyword::With),
+            "for"      => Some(Keyword::For),
+            "as"       => Some(Keyword::As),
+            "offset"   => Some(Keyword::Offset),
+            "limit"    => Some(Keyword::Limit),
+            "reversed" => Some(Keyword::Reversed),
+            _          => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Keyword::In       => "in",
+            Keyword::With     => "with",
+            Keyword::For      => "for",
+            Keyword::As       => "as",
+            Keyword::Offset   => "offset",
+            Keyword::Limit    => "limit",
+            Keyword::Reversed => "reversed"
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains
+}
+
+impl CompareOp {
+    pub fn parse(value: &str) -> Option<CompareOp> {
+        match value {
+            "=="        => Some(CompareOp::Eq),
+            "!=" | "<>" => Some(CompareOp::Ne),
+            "<"         => Some(CompareOp::Lt),
+            "<="        => Some(CompareOp::Le),
+            ">"         => Some(CompareOp::Gt),
+            ">="        => Some(CompareOp::Ge),
+            "contains"  => Some(CompareOp::Contains),
+            _           => None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CompareOp::Eq       => "==",
+            CompareOp::Ne       => "!=",
+            CompareOp::Lt       => "<",
+            CompareOp::Le       => "<=",
+            CompareOp::Gt       => ">",
+            CompareOp::Ge       => ">=",
+            CompareOp::Contains => "contains"
+        }
+    }
+}
+
+// Byte offsets into the lexed source, along with the 1-based line and column (in characters) of
+// the first byte.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize
+}
+
+impl Default for Span {
+    fn default() -> Span {
+        Span { start: 0, end: 0, line: 1, col: 1 }
+    }
+}
+
+impl Span {
+    // The empty span just past `text`, which begins where this span does.
+    pub fn after(self, text: &str) -> Span {
+        let mut span = Span { start: self.start + text.len(), end: self.start + text.len(), ..self };
+
+        for character in text.chars() {
+            if character == '\n' {
+                span.line += 1;
+                span.col   = 1;
+            } else {
+                span.col += 1;
+            }
+        }
+
+        span
+    }
+
+    // From the start of this span to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span { end: other.end, ..self }
+    }
+
+    // Moves a span measured from the start of some markup to where that markup sits in the
+    // template, given the markup's own position as `origin`.
+    pub fn offset(self, origin: Span) -> Span {
+        Span {
+            start: origin.start + self.start,
+            end: origin.start + self.end,
+            line: origin.line + self.line - 1,
+            col: if self.line == 1 { origin.col + self.col - 1 } else { self.col }
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.col)
+    }
+}
 
+pub type LexedToken = (Token, String, Span);
+pub type LexResult<T> = Result<T, LexError>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexError {
+    UnexpectedCharacter(char, Span)
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LexError::UnexpectedCharacter(character, span) => write!(f, "Unexpected character '{}' at {}", character, span)
+        }
+    }
+}
+
+impl error::Error for LexError {}
+
+impl LexError {
+    pub fn span(&self) -> Span {
+        match *self {
+            LexError::UnexpectedCharacter(_, span) => span
+        }
+    }
+
+    pub fn offset(self, origin: Span) -> LexError {
+        match self {
+            LexError::UnexpectedCharacter(character, span) => LexError::UnexpectedCharacter(character, span.offset(origin))
+        }
+    }
+}
+
+#[cfg(test)]
 macro_rules! token {
+    (Comparison, $value:expr)       => ((Token::Comparison(CompareOp::parse($value).unwrap()), String::from($value)));
     (Range)                         => (token!(Range, ".."));
     (Pipe)                          => (token!(Pipe, "|"));
     (Dot)                           => (token!(Dot, "."));
@@ -43,93 +262,259 @@ macro_rules! token {
     (CloseRound)                    => (token!(CloseRound, ")"));
     (Question)                      => (token!(Question, "?"));
     (Dash)                          => (token!(Dash, "-"));
+    (Assign)                        => (token!(Assign, "="));
+    (Empty)                         => (token!(Empty, "empty"));
+    (Blank)                         => (token!(Blank, "blank"));
+    (And)                           => (token!(And, "and"));
+    (Or)                            => (token!(Or, "or"));
+    (Not)                           => (token!(Not, "not"));
     ($tokenType:ident, $value:expr) => ((Token::$tokenType, String::from($value)));
 }
 
 pub struct Tokens<'t> {
-    scanner: &'t Scanner<'t>,
-    specials: HashMap<&'t str, Token>,
-    matchers: Vec<Regex>
+    scanner: Scanner<'t>,
+    previous: Option<Token>,
+    lookahead: VecDeque<LexResult<LexedToken>>,
+    unicode_identifiers: bool,
+    failed: bool
 }
 
 impl<'t> Tokens<'t> {
-    fn new<'a>(scanner: &'a Scanner<'a>) -> Tokens<'a> {
-        let mut specials = HashMap::new();
-        specials.insert("|", Token::Pipe);
-        specials.insert(".", Token::Dot);
-        specials.insert(":", Token::Colon);
-        specials.insert(",", Token::Comma);
-        specials.insert("[", Token::OpenSquare);
-        specials.insert("]", Token::CloseSquare);
-        specials.insert("(", Token::OpenRound);
-        specials.insert(")", Token::CloseRound);
-        specials.insert("?", Token::Question);
-        specials.insert("-", Token::Dash);
-
-        let matchers = vec![
-            Regex::new(COMPARISON).unwrap(),
-            Regex::new(SINGLE_STRING_LITERAL).unwrap(),
-            Regex::new(DOUBLE_STRING_LITERAL).unwrap(),
-            Regex::new(NUMBER_LITERAL).unwrap(),
-            Regex::new(IDENTIFIER).unwrap(),
-            Regex::new(RANGE_OP).unwrap()
-        ];
+    fn new<'a>(source: &'a str, unicode_identifiers: bool) -> Tokens<'a> {
+        Tokens {
+            scanner: Scanner::new(source),
+            previous: None,
+            lookahead: VecDeque::new(),
+            unicode_identifiers,
+            failed: false
+        }
+    }
 
-        Tokens { scanner: scanner, specials: specials, matchers: matchers }
+    pub fn peek(&mut self) -> Option<&LexResult<LexedToken>> {
+        self.peek_n(0)
     }
 
-    fn token_for(&self, pattern: &Regex, value: &str) -> LexedToken {
-        match pattern.as_str() {
-            COMPARISON            => token!(Comparison, value),
-            SINGLE_STRING_LITERAL => token!(String, value),
-            DOUBLE_STRING_LITERAL => token!(String, value),
-            NUMBER_LITERAL        => token!(Number, value),
-            IDENTIFIER            => token!(Identifier, value),
-            RANGE_OP              => token!(Range),
-            _                     => unreachable!() // already been checked for existence
+    // Looks `n` tokens past the next one without consuming anything, so `peek_n(0)` is `peek()`.
+    pub fn peek_n(&mut self, n: usize) -> Option<&LexResult<LexedToken>> {
+        while self.lookahead.len() <= n {
+            match self.lex() {
+                Some(token) => self.lookahead.push_back(token),
+                None        => break
+            }
         }
+
+        self.lookahead.get(n)
     }
 
-    fn next_match(&self) -> Option<LexedToken> {
-        self.matchers.iter().find(|&m| self.scanner.check(m))
-            .and_then(|regex| self.matched_token(&regex))
-            .or_else(|| self.matched_special())
+    // Tokens lexed earlier, handed back in order.
+    pub fn lexed<'a>(tokens: Vec<LexedToken>) -> Tokens<'a> {
+        Tokens { lookahead: tokens.into_iter().map(Ok).collect(), ..Tokens::new("", true) }
     }
 
-    fn matched_token(&self, pattern: &Regex) -> Option<LexedToken> {
-        let value = self.scanner.scan(pattern).unwrap();
-        Some(self.token_for(pattern, value))
+    // Lexes up to the first token boundary where `closed` matches the rest of the source, for
+    // markup whose end isn't known yet. Returns the tokens and the position they stop at, or None
+    // if the source runs out or fails to lex first. Anything already peeked is skipped over.
+    pub fn until<F: Fn(&str) -> bool>(&mut self, closed: F) -> Option<(Vec<LexedToken>, usize)> {
+        let mut tokens = vec![];
+
+        loop {
+            self.scanner.skip_whitespace();
+            if closed(self.scanner.rest()?) { return Some((tokens, self.scanner.position())); }
+
+            tokens.push(self.lex()?.ok()?);
+        }
     }
 
-    fn matched_special(&self) -> Option<LexedToken> {
-        self.scanner.get_char()
-            .and_then(|character| {
-                self.specials.get(character)
-                    .and_then(|token| Some(((*token).clone(), character.into())))
-                    .or_else(|| unreachable!("Syntax Error"))
-            })
+    fn lex(&mut self) -> Option<LexResult<LexedToken>> {
+        if self.failed { return None; }
+
+        self.scanner.skip_whitespace();
+        let start = Span { start: self.scanner.position(), end: self.scanner.position(), line: self.scanner.line(), col: self.scanner.col() };
+
+        self.next_match().map(|matched| match matched {
+            Ok((token, value)) => {
+                let span      = span_for(start, value.len());
+                self.previous = Some(token.clone());
+                Ok((token, value, span))
+            },
+            Err(character) => {
+                self.failed = true;
+                Err(LexError::UnexpectedCharacter(character, span_for(start, character.len_utf8())))
+            }
+        })
+    }
+
+    // Dispatches on the first character so every token is lexed in a single pass over its input.
+    // Unknown characters are left unconsumed and handed back as the error.
+    fn next_match(&self) -> Option<Result<(Token, String), char>> {
+        let rest      = self.scanner.rest()?;
+        let character = rest.chars().next()?;
+
+        let matched = match character {
+            _ if self.continues_operand(rest)  => None,
+            '=' | '!' | '<' | '>'              => comparison(rest),
+            '\'' | '"'                         => string(rest, character),
+            '.' if rest.starts_with("..")      => Some((Token::Range, 2)),
+            '0'..='9' | '-' | '.'              => number(rest),
+            _ if self.starts_identifier(character) => Some(identifier(rest, self.unicode_identifiers)),
+            _                                  => None
+        };
+
+        match matched.or_else(|| special(character).map(|token| (token, 1))) {
+            Some((token, length)) => {
+                self.scanner.skip(length);
+                Some(Ok((token, rest[..length].to_string())))
+            },
+            None => Some(Err(character))
+        }
+    }
+
+    fn starts_identifier(&self, character: char) -> bool {
+        character == '_' || character.is_ascii_alphabetic() || (self.unicode_identifiers && character.is_alphabetic())
+    }
+
+    // `-3` and `.5` are numbers at the start of an expression or after an operator, but right after
+    // an operand `5-3` subtracts and `a.5` is a lookup. Hyphens inside identifiers never get this
+    // far, and `..` is always a range.
+    fn continues_operand(&self, rest: &str) -> bool {
+        self.previous.as_ref().is_some_and(Token::ends_operand) &&
+            (rest.starts_with('-') || (rest.starts_with('.') && !rest.starts_with("..")))
     }
 }
 
 impl<'t> Iterator for Tokens<'t> {
-    type Item = (Token, String);
+    type Item = LexResult<LexedToken>;
 
-    fn next(&mut self) -> Option<LexedToken> {
-        self.next_match()
+    fn next(&mut self) -> Option<LexResult<LexedToken>> {
+        self.lookahead.pop_front().or_else(|| self.lex())
+    }
+}
+
+// `start` is the empty span where the token begins.
+fn span_for(start: Span, length: usize) -> Span {
+    Span { end: start.start + length, ..start }
+}
+
+fn comparison(rest: &str) -> Option<(Token, usize)> {
+    [2, 1].iter()
+        .filter_map(|&length| rest.get(..length))
+        .find_map(|op| CompareOp::parse(op).map(|compare| (Token::Comparison(compare), op.len())))
+}
+
+// A backslash keeps the character after it from closing the string.
+fn string(rest: &str, quote: char) -> Option<(Token, usize)> {
+    let mut escaped = false;
+
+    rest.char_indices().skip(1)
+        .find(|&(_, character)| {
+            let closes = !escaped && character == quote;
+            escaped    = !escaped && character == '\\';
+            closes
+        })
+        .map(|(end, _)| (Token::String, end + 1))
+}
+
+fn number(rest: &str) -> Option<(Token, usize)> {
+    let bytes  = rest.as_bytes();
+    let digits = |start: usize| bytes.get(start..).map_or(0, |tail| tail.iter().take_while(|b| b.is_ascii_digit()).count());
+
+    let mut length = if bytes[0] == b'-' { 1 } else { 0 };
+    let integer    = digits(length);
+    length        += integer;
+
+    let fraction = if bytes.get(length) == Some(&b'.') { digits(length + 1) } else { 0 };
+    if fraction > 0 {
+        length += fraction + 1;
+    } else if integer == 0 {
+        return None;
+    }
+
+    if matches!(bytes.get(length), Some(b'e') | Some(b'E')) {
+        let sign     = if matches!(bytes.get(length + 1), Some(b'+') | Some(b'-')) { 1 } else { 0 };
+        let exponent = digits(length + 1 + sign);
+        if exponent > 0 { length += 1 + sign + exponent; }
+    }
+
+    Some((Token::Number, length))
+}
+
+fn identifier(rest: &str, unicode: bool) -> (Token, usize) {
+    let continues  = |c: char| c == '_' || c == '-' || c.is_ascii_alphanumeric() || (unicode && c.is_alphanumeric());
+    let mut length = rest.char_indices()
+        .find(|&(_, c)| !continues(c))
+        .map_or(rest.len(), |(index, _)| index);
+
+    if rest[length..].starts_with('?') { length += 1; }
+
+    (keyword_or_identifier(&rest[..length]), length)
+}
+
+fn keyword_or_identifier(value: &str) -> Token {
+    match value {
+        "contains"       => Token::Comparison(CompareOp::Contains),
+        "empty"          => Token::Empty,
+        "blank"          => Token::Blank,
+        "true" | "false" => Token::Bool,
+        "nil" | "null"   => Token::Nil,
+        "and"            => Token::And,
+        "or"             => Token::Or,
+        "not"            => Token::Not,
+        _                => Token::Identifier
+    }
+}
+
+fn special(character: char) -> Option<Token> {
+    match character {
+        '|' => Some(Token::Pipe),
+        '.' => Some(Token::Dot),
+        ':' => Some(Token::Colon),
+        ',' => Some(Token::Comma),
+        '[' => Some(Token::OpenSquare),
+        ']' => Some(Token::CloseSquare),
+        '(' => Some(Token::OpenRound),
+        ')' => Some(Token::CloseRound),
+        '?' => Some(Token::Question),
+        '-' => Some(Token::Dash),
+        '=' => Some(Token::Assign),
+        _   => None
     }
 }
 
 pub struct Lexer<'t> {
-    scanner: Scanner<'t>
+    scanner: Scanner<'t>,
+    unicode_identifiers: bool
 }
 
 impl<'t> Lexer<'t> {
     pub fn new<'a>(source: &'a str) -> Lexer<'a> {
-        Lexer { scanner: Scanner::new(source) }
+        Lexer { scanner: Scanner::new(source), unicode_identifiers: true }
+    }
+
+    // Every call starts again from the beginning of the source, and the tokens only borrow the
+    // source rather than the lexer.
+    pub fn tokens(&self) -> Tokens<'t> {
+        Tokens::new(self.scanner.source(), self.unicode_identifiers)
+    }
+
+    // Identifiers may use any Unicode letters and digits unless this is turned off, in which case
+    // they're restricted to ASCII like Ruby's Liquid.
+    pub fn unicode_identifiers(&self) -> bool {
+        self.unicode_identifiers
+    }
+
+    pub fn set_unicode_identifiers(&mut self, unicode_identifiers: bool) {
+        self.unicode_identifiers = unicode_identifiers;
     }
 
-    pub fn tokens(&self) -> Tokens {
-        Tokens::new(&self.scanner)
+    // Lexes the whole source, one token per line with its position, ending with the error if there
+    // is one.
+    pub fn debug_dump(&self) -> String {
+        self.tokens()
+            .map(|token| match token {
+                Ok((token, value, span)) => format!("{:>4}:{:<4} {:<18} {}\n", span.line, span.col, token.to_string(), value),
+                Err(error)               => format!("error: {}\n", error)
+            })
+            .collect()
     }
 }
 
@@ -137,12 +522,9 @@ impl<'t> Lexer<'t> {
 mod tests {
     use super::*;
 
-    fn compare_tokens(lexer: &Lexer, expected_tokens: Vec<LexedToken>) {
-        let zipped = lexer.tokens().zip(expected_tokens);
-
-        for (actual, expected) in zipped {
-            assert_eq!(expected, actual);
-        }
+    fn compare_tokens(lexer: &Lexer, expected_tokens: Vec<(Token, String)>) {
+        let actual: Vec<_> = lexer.tokens().map(|token| token.unwrap()).map(|(token, value, _)| (token, value)).collect();
+        assert_eq!(expected_tokens, actual);
     }
 
     #[test]
@@ -154,7 +536,7 @@ mod tests {
     #[test]
     fn tokens_when_given_a_blank_string() {
         let lexer                   = Lexer::new("");
-        let tokens: Vec<LexedToken> = lexer.tokens().collect();
+        let tokens: Vec<_> = lexer.tokens().collect();
 
         assert_eq!(0, tokens.len());
     }
@@ -162,7 +544,7 @@ mod tests {
     #[test]
     fn tokens_when_given_a_whitespace_only_string() {
         let lexer                   = Lexer::new("  \t \n\r ");
-        let tokens: Vec<LexedToken> = lexer.tokens().collect();
+        let tokens: Vec<_> = lexer.tokens().collect();
 
         assert_eq!(0, tokens.len());
     }
@@ -178,6 +560,34 @@ mod tests {
         compare_tokens(&lexer, expected);
     }
 
+    #[test]
+    fn tokens_parses_unicode_identifiers() {
+        let lexer    = Lexer::new("côte | 名前.値 ünïcödé-kéy? _ß２");
+        let expected = vec![
+            token!(Identifier, "côte"),
+            token!(Pipe),
+            token!(Identifier, "名前"),
+            token!(Dot),
+            token!(Identifier, "値"),
+            token!(Identifier, "ünïcödé-kéy?"),
+            token!(Identifier, "_ß２")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
+    #[test]
+    fn tokens_can_restrict_identifiers_to_ascii() {
+        let mut lexer = Lexer::new("cote côte");
+        lexer.set_unicode_identifiers(false);
+
+        let tokens: Vec<_> = lexer.tokens().collect();
+        assert_eq!(3, tokens.len());
+        assert_eq!("cote", tokens[0].as_ref().unwrap().1);
+        assert_eq!("c", tokens[1].as_ref().unwrap().1);
+        assert_eq!(Err(LexError::UnexpectedCharacter('ô', Span { start: 6, end: 8, line: 1, col: 7 })), tokens[2]);
+    }
+
     #[test]
     fn tokens_knows_that_identifiers_dont_start_with_numbers() {
         let lexer    = Lexer::new("2foo 5.0bar");
@@ -202,6 +612,19 @@ mod tests {
         compare_tokens(&lexer, expected);
     }
 
+    #[test]
+    fn tokens_parses_escaped_quotes_in_strings() {
+        let lexer    = Lexer::new(r#"'it\'s' "a \"b\"" 'c\\' 'd'"#);
+        let expected = vec![
+            token!(String, r#"'it\'s'"#),
+            token!(String, r#""a \"b\"""#),
+            token!(String, r#"'c\\'"#),
+            token!(String, "'d'")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
     #[test]
     fn tokens_parses_integers() {
         let lexer    = Lexer::new("hi 50");
@@ -224,6 +647,99 @@ mod tests {
         compare_tokens(&lexer, expected);
     }
 
+    #[test]
+    fn tokens_parses_scientific_notation() {
+        let lexer    = Lexer::new("1e6 2.5E-3 (-4e+2) 2em 1.5e");
+        let expected = vec![
+            token!(Number, "1e6"),
+            token!(Number, "2.5E-3"),
+            token!(OpenRound),
+            token!(Number, "-4e+2"),
+            token!(CloseRound),
+            token!(Number, "2"),
+            token!(Identifier, "em"),
+            token!(Number, "1.5"),
+            token!(Identifier, "e")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
+    #[test]
+    fn tokens_parses_leading_dot_numbers_only_where_a_value_is_expected() {
+        let lexer    = Lexer::new(".5 x == -.25 (.5..2) a.b c.5");
+        let expected = vec![
+            token!(Number, ".5"),
+            token!(Identifier, "x"),
+            token!(Comparison, "=="),
+            token!(Number, "-.25"),
+            token!(OpenRound),
+            token!(Number, ".5"),
+            token!(Range),
+            token!(Number, "2"),
+            token!(CloseRound),
+            token!(Identifier, "a"),
+            token!(Dot),
+            token!(Identifier, "b"),
+            token!(Identifier, "c"),
+            token!(Dot),
+            token!(Number, "5")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
+    #[test]
+    fn tokens_parses_negative_numbers() {
+        let lexer    = Lexer::new("-3 slice: -2, -1.5 (1..-2) x == -1");
+        let expected = vec![
+            token!(Number, "-3"),
+            token!(Identifier, "slice"),
+            token!(Colon),
+            token!(Number, "-2"),
+            token!(Comma),
+            token!(Number, "-1.5"),
+            token!(OpenRound),
+            token!(Number, "1"),
+            token!(Range),
+            token!(Number, "-2"),
+            token!(CloseRound),
+            token!(Identifier, "x"),
+            token!(Comparison, "=="),
+            token!(Number, "-1")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
+    #[test]
+    fn tokens_parses_dashes_after_operands_as_subtraction() {
+        let lexer    = Lexer::new("5 - 3 5-3 5 -3 a[0]-1 'a'-1 foo-bar");
+        let expected = vec![
+            token!(Number, "5"),
+            token!(Dash),
+            token!(Number, "3"),
+            token!(Number, "5"),
+            token!(Dash),
+            token!(Number, "3"),
+            token!(Number, "5"),
+            token!(Dash),
+            token!(Number, "3"),
+            token!(Identifier, "a"),
+            token!(OpenSquare),
+            token!(Number, "0"),
+            token!(CloseSquare),
+            token!(Dash),
+            token!(Number, "1"),
+            token!(String, "'a'"),
+            token!(Dash),
+            token!(Number, "1"),
+            token!(Identifier, "foo-bar")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
     #[test]
     fn tokens_parses_comparisons() {
         let lexer    = Lexer::new("== <> contains");
@@ -236,6 +752,49 @@ mod tests {
         compare_tokens(&lexer, expected);
     }
 
+    #[test]
+    fn tokens_parses_every_comparison_operator() {
+        let lexer           = Lexer::new("== != <> < <= > >= contains");
+        let ops: Vec<Token> = lexer.tokens().map(|token| token.unwrap().0).collect();
+
+        assert_eq!(vec![
+            Token::Comparison(CompareOp::Eq),
+            Token::Comparison(CompareOp::Ne),
+            Token::Comparison(CompareOp::Ne),
+            Token::Comparison(CompareOp::Lt),
+            Token::Comparison(CompareOp::Le),
+            Token::Comparison(CompareOp::Gt),
+            Token::Comparison(CompareOp::Ge),
+            Token::Comparison(CompareOp::Contains)
+        ], ops);
+    }
+
+    #[test]
+    fn compare_op_round_trips_through_its_string_form() {
+        for op in &[CompareOp::Eq, CompareOp::Ne, CompareOp::Lt, CompareOp::Le, CompareOp::Gt, CompareOp::Ge, CompareOp::Contains] {
+            assert_eq!(Some(*op), CompareOp::parse(op.as_str()));
+        }
+
+        assert_eq!(None, CompareOp::parse("="));
+    }
+
+    #[test]
+    fn tokens_parses_assignment() {
+        let lexer    = Lexer::new("x = 1 y=a==b");
+        let expected = vec![
+            token!(Identifier, "x"),
+            token!(Assign),
+            token!(Number, "1"),
+            token!(Identifier, "y"),
+            token!(Assign),
+            token!(Identifier, "a"),
+            token!(Comparison, "=="),
+            token!(Identifier, "b")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
     #[test]
     fn tokens_parses_range_operator() {
         let lexer    = Lexer::new("1..10");
@@ -269,6 +828,77 @@ mod tests {
         compare_tokens(&lexer, expected);
     }
 
+    #[test]
+    fn tokens_parses_empty_and_blank_keywords() {
+        let lexer    = Lexer::new("empty blank empty? blanks");
+        let expected = vec![
+            token!(Empty),
+            token!(Blank),
+            token!(Identifier, "empty?"),
+            token!(Identifier, "blanks")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
+    #[test]
+    fn tokens_parses_boolean_and_nil_literals() {
+        let lexer    = Lexer::new("true false nil null truthy nil?");
+        let expected = vec![
+            token!(Bool, "true"),
+            token!(Bool, "false"),
+            token!(Nil, "nil"),
+            token!(Nil, "null"),
+            token!(Identifier, "truthy"),
+            token!(Identifier, "nil?")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
+    #[test]
+    fn tokens_parses_logical_operators() {
+        let lexer    = Lexer::new("a and b or not c android order");
+        let expected = vec![
+            token!(Identifier, "a"),
+            token!(And),
+            token!(Identifier, "b"),
+            token!(Or),
+            token!(Not),
+            token!(Identifier, "c"),
+            token!(Identifier, "android"),
+            token!(Identifier, "order")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
+    #[test]
+    fn tokens_leaves_tag_keywords_as_identifiers() {
+        let lexer    = Lexer::new("item in items limit: 2 reversed");
+        let expected = vec![
+            token!(Identifier, "item"),
+            token!(Identifier, "in"),
+            token!(Identifier, "items"),
+            token!(Identifier, "limit"),
+            token!(Colon),
+            token!(Number, "2"),
+            token!(Identifier, "reversed")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
+    #[test]
+    fn keyword_parse_recognizes_tag_keywords() {
+        for keyword in &[Keyword::In, Keyword::With, Keyword::For, Keyword::As, Keyword::Offset, Keyword::Limit, Keyword::Reversed] {
+            assert_eq!(Some(*keyword), Keyword::parse(keyword.as_str()));
+        }
+
+        assert_eq!(None, Keyword::parse("inside"));
+        assert_eq!(None, Keyword::parse("IN"));
+    }
+
     #[test]
     fn tokens_skips_internal_whitespace() {
         let lexer    = Lexer::new("five|\n\t ==");
@@ -282,9 +912,288 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Syntax Error")]
-    fn tokens_freaks_out_with_syntax_error() {
-        let lexer                   = Lexer::new("%");
-        let tokens: Vec<LexedToken> = lexer.tokens().collect();
+    fn tokens_include_their_span() {
+        let lexer = Lexer::new("  a.b\n\t[ 'ü' ]\n\nx");
+        let spans: Vec<Span> = lexer.tokens().map(|token| token.unwrap().2).collect();
+
+        assert_eq!(vec![
+            Span { start: 2, end: 3, line: 1, col: 3 },
+            Span { start: 3, end: 4, line: 1, col: 4 },
+            Span { start: 4, end: 5, line: 1, col: 5 },
+            Span { start: 7, end: 8, line: 2, col: 2 },
+            Span { start: 9, end: 13, line: 2, col: 4 },
+            Span { start: 14, end: 15, line: 2, col: 8 },
+            Span { start: 17, end: 18, line: 4, col: 1 }
+        ], spans);
+    }
+
+    #[test]
+    fn tokens_until_stop_at_a_closer() {
+        let closed = |rest: &str| rest.starts_with("%}");
+
+        let (tokens, end) = Lexer::new("a == '%}' %} b").tokens().until(closed).unwrap();
+        assert_eq!(vec![token!(Identifier, "a"), token!(Comparison, "=="), token!(String, "'%}'")], tokens.into_iter().map(|(token, value, _)| (token, value)).collect::<Vec<_>>());
+        assert_eq!(10, end);
+
+        assert_eq!(None, Lexer::new("a b").tokens().until(closed));
+        assert_eq!(None, Lexer::new("a # %}").tokens().until(closed));
+        assert_eq!(Some((vec![], 1)), Lexer::new(" %}").tokens().until(closed));
+    }
+
+    #[test]
+    fn span_displays_its_line_and_column() {
+        assert_eq!("line 14, column 7", Span { start: 0, end: 1, line: 14, col: 7 }.to_string());
+    }
+
+    #[test]
+    fn spans_can_be_moved_into_a_larger_source() {
+        let origin = Span::default().after("ab\n{{ ");
+
+        assert_eq!(Span { start: 6, end: 6, line: 2, col: 4 }, origin);
+        assert_eq!(Span { start: 7, end: 8, line: 2, col: 5 }, Span { start: 1, end: 2, line: 1, col: 2 }.offset(origin));
+        assert_eq!(Span { start: 9, end: 10, line: 3, col: 2 }, Span { start: 3, end: 4, line: 2, col: 2 }.offset(origin));
+        assert_eq!(Span { start: 1, end: 9, line: 1, col: 2 }, Span { start: 1, end: 2, line: 1, col: 2 }.to(Span { start: 8, end: 9, line: 3, col: 1 }));
+    }
+
+    #[test]
+    fn tokens_only_treats_the_whole_word_contains_as_a_comparison() {
+        let lexer    = Lexer::new("tags contains 'a' containsx contains?");
+        let expected = vec![
+            token!(Identifier, "tags"),
+            token!(Comparison, "contains"),
+            token!(String, "'a'"),
+            token!(Identifier, "containsx"),
+            token!(Identifier, "contains?")
+        ];
+
+        compare_tokens(&lexer, expected);
+    }
+
+    #[test]
+    fn tokens_matches_the_regex_reference_lexer() {
+        let sources = [
+            "user.name | upcase | append: '!' | truncate: 20, '...'",
+            "products[0].variants[-1]['price'] | money_with_currency",
+            "(1..product.count) (-5..-1.5) (.5..2)",
+            "a == b and c != d or e <> f and g <= -2 or h >= 3.25 and i < 1e6 and j > 2.5E-3",
+            "tags contains \"sale\" or not empty? and blank",
+            "x | plus: -1 | minus: 5-3 | times: a[0] -1 | divided_by: .5",
+            "item in items limit: 2 offset: 3 reversed",
+            "first-name last_name? 'it''s' \"quoted 'single'\" 5.0bar 2foo",
+            "  \t\n  padded \n\r\n  tokens  ",
+            "trailing junk % after"
+        ];
+
+        for source in &sources {
+            let lexer            = Lexer::new(source);
+            let actual: Vec<_>   = lexer.tokens().map_while(Result::ok).map(|(token, value, _)| (token, value)).collect();
+            let expected: Vec<_> = reference::tokens(source);
+
+            assert_eq!(expected, actual, "lexing {:?}", source);
+        }
+    }
+
+    // Lexing arbitrary input has to end in tokens or an error, never a panic. Every string of up to
+    // three characters from an alphabet of awkward characters is tried, followed by longer strings
+    // from a fixed-seed generator, checking that each token's span slices its value out of the
+    // source and that an error always ends the stream.
+    mod fuzz {
+        use super::super::*;
+
+        const ALPHABET: [char; 25] = [
+            'a', 'Z', '_', '?', '-', '.', '0', '9', 'e', '\'', '"', '\\', '=', '!', '<', '>',
+            '|', '[', ')', ' ', '\n', '%', 'é', '名', '😀'
+        ];
+
+        fn assert_lexes(source: &str) {
+            let lexer      = Lexer::new(source);
+            let mut end    = 0;
+            let mut failed = false;
+
+            for token in lexer.tokens().take(source.len() + 1) {
+                assert!(!failed, "token after an error in {:?}", source);
+
+                match token {
+                    Ok((_, value, span)) => {
+                        assert!(span.start >= end, "overlapping spans in {:?}", source);
+                        assert_eq!(Some(value.as_str()), source.get(span.start..span.end), "bad span in {:?}", source);
+                        end = span.end;
+                    },
+                    Err(LexError::UnexpectedCharacter(character, span)) => {
+                        assert_eq!(source[span.start..].chars().next(), Some(character), "bad error in {:?}", source);
+                        failed = true;
+                    }
+                }
+            }
+
+            if !failed {
+                assert_eq!(source.trim_end().len(), end, "unlexed input in {:?}", source);
+            }
+        }
+
+        #[test]
+        fn lexing_short_strings_never_panics() {
+            let mut sources = vec![String::new()];
+
+            for _ in 0..3 {
+                sources = sources.iter()
+                    .flat_map(|source| ALPHABET.iter().map(move |c| format!("{}{}", source, c)))
+                    .collect();
+
+                for source in &sources {
+                    assert_lexes(source);
+                }
+            }
+        }
+
+        #[test]
+        fn lexing_long_strings_never_panics() {
+            let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+
+            for _ in 0..2000 {
+                let mut source = String::new();
+
+                for _ in 0..(seed % 64) {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    source.push(ALPHABET[(seed % ALPHABET.len() as u64) as usize]);
+                }
+
+                assert_lexes(&source);
+            }
+        }
+    }
+
+    // The original regex-driven lexer, kept as a reference for the hand-written one.
+    mod reference {
+        use regex::Regex;
+        use scanner::Scanner;
+        use super::super::{keyword_or_identifier,special,CompareOp,Token};
+
+        const PATTERNS: [&str; 6] = [
+            r"^(==|!=|<>|<=?|>=?|contains)",
+            r"^'[^']*'",
+            r#"^"[^"]*""#,
+            r"^-?(\d+(\.\d+)?|\.\d+)([eE][+-]?\d+)?",
+            r"^[a-zA-Z_][\w-]*\??",
+            r"^\.\."
+        ];
+
+        pub fn tokens(source: &str) -> Vec<(Token, String)> {
+            let scanner                          = Scanner::new(source);
+            let matchers: Vec<Regex>             = PATTERNS.iter().map(|pattern| Regex::new(pattern).unwrap()).collect();
+            let mut tokens: Vec<(Token, String)> = vec![];
+
+            loop {
+                scanner.skip_whitespace();
+
+                let rest      = match scanner.rest() { Some(rest) => rest, None => return tokens };
+                let continues = tokens.last().is_some_and(|(token, _)| token.ends_operand()) &&
+                    (rest.starts_with('-') || (rest.starts_with('.') && !rest.starts_with("..")));
+
+                let matched = if continues { None } else { matchers.iter().position(|m| scanner.check(m)) };
+                match matched {
+                    Some(index) => {
+                        let value = scanner.scan(&matchers[index]).unwrap();
+                        tokens.push((token_for(index, value), value.to_string()));
+                    },
+                    None => {
+                        let character = rest.chars().next().unwrap();
+                        match special(character) {
+                            Some(token) => tokens.push((token, scanner.get_char().unwrap().to_string())),
+                            None        => return tokens
+                        }
+                    }
+                }
+            }
+        }
+
+        fn token_for(index: usize, value: &str) -> Token {
+            match index {
+                0     => Token::Comparison(CompareOp::parse(value).unwrap()),
+                1 | 2 => Token::String,
+                3     => Token::Number,
+                4     => keyword_or_identifier(value),
+                _     => Token::Range
+            }
+        }
+    }
+
+    #[test]
+    fn token_display_names_the_token() {
+        assert_eq!("identifier", Token::Identifier.to_string());
+        assert_eq!("string literal", Token::String.to_string());
+        assert_eq!("'<='", Token::Comparison(CompareOp::Le).to_string());
+        assert_eq!("']'", Token::CloseSquare.to_string());
+        assert_eq!("'and'", Token::And.to_string());
+    }
+
+    #[test]
+    fn debug_dump_lists_every_token_with_its_position() {
+        let lexer = Lexer::new("user.name |\n  upcase: 'x' %");
+        lexer.tokens().next();
+
+        let expected = concat!(
+            "   1:1    identifier         user\n",
+            "   1:5    '.'                .\n",
+            "   1:6    identifier         name\n",
+            "   1:11   '|'                |\n",
+            "   2:3    identifier         upcase\n",
+            "   2:9    ':'                :\n",
+            "   2:11   string literal     'x'\n",
+            "error: Unexpected character '%' at line 2, column 15\n"
+        );
+
+        assert_eq!(expected, lexer.debug_dump());
+    }
+
+    #[test]
+    fn peek_returns_the_next_token_without_consuming_it() {
+        let lexer      = Lexer::new("a | b");
+        let mut tokens = lexer.tokens();
+
+        assert_eq!(Token::Identifier, tokens.peek().unwrap().as_ref().unwrap().0);
+        assert_eq!(Token::Identifier, tokens.peek().unwrap().as_ref().unwrap().0);
+        assert_eq!("a", tokens.next().unwrap().unwrap().1);
+        assert_eq!(Token::Pipe, tokens.peek().unwrap().as_ref().unwrap().0);
+    }
+
+    #[test]
+    fn peek_n_looks_further_ahead() {
+        let lexer      = Lexer::new("x - 1 %");
+        let mut tokens = lexer.tokens();
+
+        assert_eq!(Token::Number, tokens.peek_n(2).unwrap().as_ref().unwrap().0);
+        assert_eq!(Token::Dash, tokens.peek_n(1).unwrap().as_ref().unwrap().0);
+        assert!(tokens.peek_n(3).unwrap().is_err());
+        assert_eq!(None, tokens.peek_n(4));
+
+        let kinds: Vec<_> = tokens.map(|token| token.map(|(kind, _, _)| kind)).collect();
+        assert_eq!(vec![Ok(Token::Identifier), Ok(Token::Dash), Ok(Token::Number)], kinds[..3].to_vec());
+        assert_eq!(4, kinds.len());
+    }
+
+    #[test]
+    fn tokens_returns_an_error_for_unexpected_characters() {
+        let lexer                     = Lexer::new("%");
+        let tokens: LexResult<Vec<_>> = lexer.tokens().collect();
+
+        let error = LexError::UnexpectedCharacter('%', Span { start: 0, end: 1, line: 1, col: 1 });
+        assert_eq!(Err(error), tokens);
+    }
+
+    #[test]
+    fn tokens_stops_after_an_error() {
+        let lexer      = Lexer::new("a |\n  § b");
+        let mut tokens = lexer.tokens();
+
+        assert!(tokens.next().unwrap().is_ok());
+        assert!(tokens.next().unwrap().is_ok());
+
+        let error = tokens.next().unwrap().unwrap_err();
+        assert_eq!(LexError::UnexpectedCharacter('§', Span { start: 6, end: 8, line: 2, col: 3 }), error);
+        assert_eq!("Unexpected character '§' at line 2, column 3", error.to_string());
+        assert_eq!(None, tokens.next());
     }
 }
@@ -1,6 +1,63 @@
+extern crate memchr;
 extern crate regex;
+#[cfg(feature = "actix")]
+extern crate actix_web;
+#[cfg(feature = "axum")]
+extern crate axum;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "notify")]
+extern crate notify;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde_json")]
+extern crate serde_json;
+#[cfg(feature = "typed-arena")]
+extern crate typed_arena;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+#[cfg(all(test, feature = "serde"))]
+extern crate bincode;
 
-mod scanner;
-mod lexer;
-mod parser;
-mod tokenizer;
+#[macro_use]
+mod macros;
+
+#[cfg(feature = "typed-arena")]
+pub mod arena;
+pub mod ast;
+pub mod codegen;
+#[cfg(feature = "conformance")]
+pub mod conformance;
+pub mod context;
+pub mod diagnostic;
+pub mod environment;
+pub mod error;
+pub mod filters;
+pub mod fold;
+#[cfg(feature = "jekyll")]
+pub mod jekyll;
+pub mod scanner;
+pub mod tags;
+pub mod lexer;
+pub mod loader;
+pub mod parser;
+pub mod policy;
+pub mod profile;
+#[cfg(feature = "notify")]
+pub mod reload;
+pub mod render;
+pub mod sandbox;
+#[cfg(feature = "shopify")]
+pub mod shopify;
+pub mod small_string;
+pub mod template;
+pub mod tokenizer;
+pub mod variable;
+pub mod visit;
+pub mod web;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use error::{Error,Result};
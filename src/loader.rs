@@ -0,0 +1,135 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component,Path,PathBuf};
+
+pub type LoadResult<T> = Result<T, LoadError>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadError {
+    InvalidName(String),
+    NotFound(String),
+    // The template's name and why it couldn't be read.
+    Io(String, String)
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::InvalidName(ref name)     => write!(f, "Illegal template name '{}'", name),
+            LoadError::NotFound(ref name)        => write!(f, "Template '{}' not found", name),
+            LoadError::Io(ref name, ref message) => write!(f, "Could not read template '{}': {}", name, message)
+        }
+    }
+}
+
+impl error::Error for LoadError {}
+
+impl LoadError {
+    fn io(name: &str, error: io::Error) -> LoadError {
+        match error.kind() {
+            io::ErrorKind::NotFound => LoadError::NotFound(name.to_string()),
+            _                       => LoadError::Io(name.to_string(), error.to_string())
+        }
+    }
+}
+
+// Finds the source of templates that weren't registered by name, such as the partials named by
+// `include`.
+pub trait Loader: fmt::Debug + Send + Sync {
+    fn load(&self, name: &str) -> LoadResult<String>;
+
+    // The names whose source changed since the last call, so their cached parses can be dropped.
+    // Loaders that can't tell report nothing.
+    fn changes(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+// Loads `name` from `root/name.extension`. The extension is only added when the name doesn't
+// have one, and names can't reach outside `root`, whether with `..`, an absolute path or a symlink.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FsLoader {
+    pub root: PathBuf,
+    pub extension: String
+}
+
+impl FsLoader {
+    pub fn new<P: Into<PathBuf>>(root: P, extension: &str) -> FsLoader {
+        FsLoader { root: root.into(), extension: extension.to_string() }
+    }
+
+    // Where `name` lives, once it's checked to be under the root.
+    pub fn path(&self, name: &str) -> LoadResult<PathBuf> {
+        let relative = Path::new(name);
+        if name.is_empty() || !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(LoadError::InvalidName(name.to_string()));
+        }
+
+        let mut path = self.root.join(relative);
+        if !self.extension.is_empty() && relative.extension().is_none() { path.set_extension(&self.extension); }
+
+        let root = self.root.canonicalize().map_err(|error| LoadError::io(name, error))?;
+        let path = path.canonicalize().map_err(|error| LoadError::io(name, error))?;
+        if !path.starts_with(root) { return Err(LoadError::InvalidName(name.to_string())); }
+
+        Ok(path)
+    }
+}
+
+impl Loader for FsLoader {
+    fn load(&self, name: &str) -> LoadResult<String> {
+        fs::read_to_string(self.path(name)?).map_err(|error| LoadError::io(name, error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    // A fresh directory per test, so tests can run in parallel.
+    fn root(test: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("riquid-{}-{}", test, process::id()));
+        let _    = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("partials")).unwrap();
+        fs::write(root.join("partials/header.liquid"), "<h1>{{ title }}</h1>").unwrap();
+        fs::write(root.join("robots.txt"), "User-agent: *").unwrap();
+
+        root
+    }
+
+    #[test]
+    fn loads_templates_under_the_root() {
+        let loader = FsLoader::new(root("loads"), "liquid");
+
+        assert_eq!(Ok("<h1>{{ title }}</h1>".to_string()), loader.load("partials/header"));
+        assert_eq!(Ok("User-agent: *".to_string()), loader.load("robots.txt"));
+        assert_eq!(Err(LoadError::NotFound("footer".to_string())), loader.load("footer"));
+    }
+
+    #[test]
+    fn rejects_names_outside_the_root() {
+        let root   = root("rejects");
+        let loader = FsLoader::new(root.join("partials"), "liquid");
+        let header = root.join("partials/header.liquid");
+
+        for name in &["", "../robots.txt", "partials/../../robots", "./header", header.to_str().unwrap()] {
+            assert_eq!(Err(LoadError::InvalidName(name.to_string())), loader.load(name));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlinks_out_of_the_root() {
+        let root = root("symlinks");
+        std::os::unix::fs::symlink(root.join("robots.txt"), root.join("partials/robots.liquid")).unwrap();
+        std::os::unix::fs::symlink(root.join("partials/header.liquid"), root.join("partials/title.liquid")).unwrap();
+
+        let loader = FsLoader::new(root.join("partials"), "liquid");
+        assert_eq!(Err(LoadError::InvalidName("robots".to_string())), loader.load("robots"));
+        assert!(loader.load("title").is_ok());
+    }
+}
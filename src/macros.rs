@@ -0,0 +1,125 @@
+#[macro_export]
+macro_rules! variable {
+    (nil) => ($crate::variable::Variable::Nil);
+    ([ $($tt:tt)* ]) => ($crate::variable::Variable::Array($crate::variable!(@array [] $($tt)*)));
+    ({}) => ($crate::variable::Variable::Object(::std::collections::HashMap::new()));
+    ({ $($tt:tt)+ }) => {{
+        let mut object = ::std::collections::HashMap::new();
+        $crate::variable!(@object object $($tt)*);
+        $crate::variable::Variable::Object(object)
+    }};
+
+    (@array [$($items:expr,)*]) => (vec![$($items,)*]);
+    (@array [$($items:expr,)*] nil $(, $($rest:tt)*)?) => (
+        $crate::variable!(@array [$($items,)* $crate::variable!(nil),] $($($rest)*)?)
+    );
+    (@array [$($items:expr,)*] [$($array:tt)*] $(, $($rest:tt)*)?) => (
+        $crate::variable!(@array [$($items,)* $crate::variable!([$($array)*]),] $($($rest)*)?)
+    );
+    (@array [$($items:expr,)*] {$($object:tt)*} $(, $($rest:tt)*)?) => (
+        $crate::variable!(@array [$($items,)* $crate::variable!({$($object)*}),] $($($rest)*)?)
+    );
+    (@array [$($items:expr,)*] $value:expr $(, $($rest:tt)*)?) => (
+        $crate::variable!(@array [$($items,)* $crate::variable!($value),] $($($rest)*)?)
+    );
+
+    (@object $object:ident) => ();
+    (@object $object:ident $key:tt : nil $(, $($rest:tt)*)?) => {
+        $object.insert($crate::variable!(@key $key), $crate::variable!(nil));
+        $crate::variable!(@object $object $($($rest)*)?);
+    };
+    (@object $object:ident $key:tt : [$($array:tt)*] $(, $($rest:tt)*)?) => {
+        $object.insert($crate::variable!(@key $key), $crate::variable!([$($array)*]));
+        $crate::variable!(@object $object $($($rest)*)?);
+    };
+    (@object $object:ident $key:tt : {$($nested:tt)*} $(, $($rest:tt)*)?) => {
+        $object.insert($crate::variable!(@key $key), $crate::variable!({$($nested)*}));
+        $crate::variable!(@object $object $($($rest)*)?);
+    };
+    (@object $object:ident $key:tt : $value:expr $(, $($rest:tt)*)?) => {
+        $object.insert($crate::variable!(@key $key), $crate::variable!($value));
+        $crate::variable!(@object $object $($($rest)*)?);
+    };
+
+    (@key $key:ident) => (stringify!($key).to_string());
+    (@key $key:expr) => (String::from($key));
+
+    ($value:expr) => ($crate::variable::Variable::from($value));
+}
+
+#[macro_export]
+macro_rules! context {
+    ($($tt:tt)*) => {{
+        let mut context = $crate::context::Context::new();
+
+        if let $crate::variable::Variable::Object(variables) = $crate::variable!({ $($tt)* }) {
+            context.extend(variables).expect("context! keys must be valid variable names");
+        }
+
+        context
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use variable::Variable;
+
+    #[test]
+    fn variable_builds_scalars() {
+        assert_eq!(Variable::Nil, variable!(nil));
+        assert_eq!(Variable::Bool(true), variable!(true));
+        assert_eq!(Variable::Int(-5), variable!(-5));
+        assert_eq!(Variable::Float(1.5), variable!(1.5));
+        assert_eq!(Variable::Str("hi".into()), variable!("hi"));
+        assert_eq!(Variable::Int(3), variable!(1 + 2));
+    }
+
+    #[test]
+    fn variable_builds_arrays() {
+        let expected = Variable::Array(vec![
+            Variable::Int(1),
+            Variable::Nil,
+            Variable::Array(vec![Variable::Str("a".into())]),
+            Variable::Int(-2)
+        ]);
+
+        assert_eq!(expected, variable!([1, nil, ["a"], -2,]));
+        assert_eq!(Variable::Array(vec![]), variable!([]));
+    }
+
+    #[test]
+    fn variable_builds_nested_objects() {
+        let mut user = HashMap::new();
+        user.insert("name".to_string(), Variable::Str("x".into()));
+        user.insert("first-name".to_string(), Variable::Nil);
+
+        let mut expected = HashMap::new();
+        expected.insert("user".to_string(), Variable::Object(user));
+        expected.insert("tags".to_string(), Variable::Array(vec![Variable::Str("a".into())]));
+        expected.insert("count".to_string(), Variable::Int(2));
+
+        let count = 2;
+        assert_eq!(Variable::Object(expected), variable!({
+            user: { name: "x", "first-name": nil },
+            tags: ["a"],
+            count: count
+        }));
+    }
+
+    #[test]
+    fn context_builds_a_context_from_the_given_variables() {
+        let context = context! { title: "Hi", tags: ["a", "b"], user: { name: "x" } };
+
+        assert_eq!(Ok(Variable::Str("Hi".into())), context.get("title"));
+        assert_eq!(Ok(Variable::Str("b".into())), context.get("tags[1]"));
+        assert_eq!(Ok(Variable::Str("x".into())), context.get("user.name"));
+        assert_eq!(1, context! {}.depth());
+    }
+
+    #[test]
+    #[should_panic(expected = "context! keys must be valid variable names")]
+    fn context_panics_with_invalid_keys() {
+        context! { "not valid": 1 };
+    }
+}
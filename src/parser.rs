@@ -1,60 +1,341 @@
-use lexer::{LexedToken,Lexer,Token};
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+
+use ast::{Condition,Expression,Filter,FilterChain,KeywordArguments,Literal,Lookup};
+use lexer::{Keyword,LexError,LexedToken,Lexer,Span,Token,Tokens};
+use sandbox::Sandbox;
+use tags::TagRegistry;
+use tokenizer::Delimiters;
+use variable::Variable;
+
+pub type ParseResult<T> = Result<T, ParseError>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    Lex(LexError),
+    UnexpectedToken(String, Token, Span),
+    UnexpectedEnd(String),
+    InvalidJump(isize),
+    InvalidTag(String),
+    Unclosed(String, Span),
+    // Not allowed by the `ParserOptions::sandbox`.
+    ForbiddenTag(String, Span),
+    ForbiddenFilter(String, Span),
+    // Past one of the `ParseLimits`, given along with the limit.
+    TooLarge(usize),
+    TooDeep(usize, Span),
+    TooComplex(usize, Span),
+    // Read by a template but not in the `Policy::variables` it was validated against.
+    UnknownVariable(String, Span)
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Lex(ref error)                                 => write!(f, "{}", error),
+            ParseError::UnexpectedToken(ref expected, ref found, span) => write!(f, "Expected {} but found {} at {}", expected, found, span),
+            ParseError::UnexpectedEnd(ref expected)                    => write!(f, "Expected {} but reached the end of the markup", expected),
+            ParseError::InvalidJump(offset)                            => write!(f, "Cannot jump {} tokens from the current position", offset),
+            ParseError::InvalidTag(ref markup)                         => write!(f, "Invalid tag: '{{% {} %}}'", markup),
+            ParseError::Unclosed(ref delimiter, span)                  => write!(f, "'{}' opened at {} was never closed", delimiter, span),
+            ParseError::ForbiddenTag(ref name, span)                   => write!(f, "Tag '{}' is not allowed at {}", name, span),
+            ParseError::ForbiddenFilter(ref name, span)                => write!(f, "Filter '{}' is not allowed at {}", name, span),
+            ParseError::TooLarge(max)                                  => write!(f, "Template is larger than the limit of {} bytes", max),
+            ParseError::TooDeep(max, span)                             => write!(f, "Blocks nest deeper than the limit of {} at {}", max, span),
+            ParseError::TooComplex(max, span)                          => write!(f, "Expression nests deeper than the limit of {} at {}", max, span),
+            ParseError::UnknownVariable(ref name, span)                => write!(f, "Variable '{}' is not allowed at {}", name, span)
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
+impl ParseError {
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            ParseError::Lex(ref error)              => Some(error.span()),
+            ParseError::UnexpectedToken(_, _, span) => Some(span),
+            ParseError::Unclosed(_, span)           => Some(span),
+            ParseError::ForbiddenTag(_, span)       => Some(span),
+            ParseError::ForbiddenFilter(_, span)    => Some(span),
+            ParseError::TooDeep(_, span)            => Some(span),
+            ParseError::TooComplex(_, span)         => Some(span),
+            ParseError::UnknownVariable(_, span)    => Some(span),
+            ParseError::TooLarge(_)                 => None,
+            ParseError::UnexpectedEnd(_)            => None,
+            ParseError::InvalidJump(_)              => None,
+            ParseError::InvalidTag(_)               => None
+        }
+    }
+}
+
+impl From<LexError> for ParseError {
+    fn from(error: LexError) -> ParseError {
+        ParseError::Lex(error)
+    }
+}
 
-pub struct Parser {
-    tokens: Vec<LexedToken>,
-    current_index: usize
+// Ruby's Liquid gives `and` and `or` the same precedence and groups them from the right, so
+// `a or b and c` is `a or (b and c)` but `a and b or c` is `a and (b or c)`. `Standard` binds `and`
+// tighter than `or` and groups from the left instead.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Precedence {
+    #[default]
+    RightToLeft,
+    Standard
 }
 
-impl Parser {
-    pub fn new(source: &str) -> Parser {
-        let lexer = Lexer::new(source);
-        Parser { tokens: lexer.tokens().collect(), current_index: 0 }
+// Caps that keep hostile templates from taking unbounded time or stack to parse, and render. Only
+// expressions are capped by default, well past anything a real template needs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ParseLimits {
+    pub max_source_bytes: Option<usize>,
+    // How deeply block tags like `if` and `for` may nest.
+    pub max_nesting: Option<usize>,
+    // How deeply an expression may nest, counting bracketed lookups, range bounds and each `and`
+    // or `or` in a condition.
+    pub max_expression_depth: Option<usize>
+}
+
+const MAX_EXPRESSION_DEPTH: usize = 100;
+
+impl Default for ParseLimits {
+    fn default() -> ParseLimits {
+        ParseLimits { max_source_bytes: None, max_nesting: None, max_expression_depth: Some(MAX_EXPRESSION_DEPTH) }
+    }
+}
+
+// Everything that changes how markup is parsed, so new settings don't mean new constructors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParserOptions {
+    pub precedence: Precedence,
+    pub unicode_identifiers: bool,
+    pub fold: bool,
+    pub trim_blank_text: bool,
+    pub inline_errors: bool,
+    pub front_matter: bool,
+    pub strip_bom: bool,
+    pub normalize_newlines: bool,
+    // Lexes each output and registered tag while looking for where it ends, instead of cutting the
+    // template up first and lexing the pieces again. Templates parse the same either way. Only the
+    // default delimiters are fused.
+    pub fused: bool,
+    // Restricts templates to the tags and filters it allows.
+    pub sandbox: Option<Sandbox>,
+    pub limits: ParseLimits,
+    pub tags: TagRegistry,
+    pub delimiters: Delimiters
+}
+
+impl Default for ParserOptions {
+    fn default() -> ParserOptions {
+        ParserOptions {
+            precedence: Precedence::default(),
+            unicode_identifiers: true,
+            fold: false,
+            trim_blank_text: false,
+            inline_errors: false,
+            front_matter: false,
+            strip_bom: true,
+            normalize_newlines: false,
+            fused: false,
+            sandbox: None,
+            limits: ParseLimits::default(),
+            tags: TagRegistry::default(),
+            delimiters: Delimiters::default()
+        }
+    }
+}
+
+// Tokens are pulled from the lexer as the parser reaches them. The parser can look `LOOKAHEAD`
+// tokens ahead of the current one and `jump` back at most `HISTORY` tokens.
+const LOOKAHEAD: usize = 3;
+const HISTORY: usize   = 8;
+
+pub struct Parser<'t> {
+    source: &'t str,
+    tokens: Tokens<'t>,
+    origin: Span,
+    buffer: VecDeque<LexedToken>,
+    buffer_start: usize,
+    error: Option<LexError>,
+    current_index: usize,
+    precedence: Precedence,
+    // How deeply the expression being parsed nests so far.
+    depth: usize,
+    max_depth: Option<usize>
+}
+
+impl<'t> Parser<'t> {
+    // Only a lexing error in the very first token fails here. Later ones are returned by whichever
+    // call reaches them.
+    pub fn new(source: &str) -> ParseResult<Parser<'_>> {
+        Parser::at(source, Span::default())
+    }
+
+    // For markup cut out of a larger template, where `origin` is the position the markup starts at.
+    // Spans in the tokens, the AST and any errors then point into the template.
+    pub fn at(source: &str, origin: Span) -> ParseResult<Parser<'_>> {
+        Parser::with_options(source, origin, &ParserOptions::default())
+    }
+
+    pub fn with_options<'a>(source: &'a str, origin: Span, options: &ParserOptions) -> ParseResult<Parser<'a>> {
+        let mut lexer = Lexer::new(source);
+        lexer.set_unicode_identifiers(options.unicode_identifiers);
+
+        Parser::from_tokens(source, lexer.tokens(), origin, options)
     }
 
-    pub fn jump(&mut self, n: isize) {
+    // For markup that was lexed while the template was being cut up, as with `ParserOptions::fused`.
+    // The tokens' spans are measured from the start of `source`, as the lexer gives them.
+    pub fn lexed<'a>(source: &'a str, tokens: Vec<LexedToken>, origin: Span, options: &ParserOptions) -> ParseResult<Parser<'a>> {
+        Parser::from_tokens(source, Tokens::lexed(tokens), origin, options)
+    }
+
+    fn from_tokens<'a>(source: &'a str, tokens: Tokens<'a>, origin: Span, options: &ParserOptions) -> ParseResult<Parser<'a>> {
+        let mut parser = Parser {
+            source,
+            tokens,
+            origin,
+            buffer: VecDeque::with_capacity(LOOKAHEAD + HISTORY),
+            buffer_start: 0,
+            error: None,
+            current_index: 0,
+            precedence: options.precedence,
+            depth: 0,
+            max_depth: options.limits.max_expression_depth
+        };
+
+        parser.fill();
+
+        match parser.error {
+            Some(ref error) if parser.buffer.is_empty() => Err(ParseError::Lex(error.clone())),
+            _                                           => Ok(parser)
+        }
+    }
+
+    pub fn precedence(&self) -> Precedence {
+        self.precedence
+    }
+
+    pub fn set_precedence(&mut self, precedence: Precedence) {
+        self.precedence = precedence;
+    }
+
+    pub fn jump(&mut self, n: isize) -> ParseResult<()> {
         match self.index_with_offset(n) {
-            Some(index) => self.current_index = index,
-            None => panic!("Attempted to jump too far back")
+            Some(index) if index >= self.buffer_start => self.current_index = index,
+            _                                         => return Err(ParseError::InvalidJump(n))
         }
+
+        self.fill();
+        Ok(())
     }
 
-    pub fn consume(&mut self, token: Token) -> Option<String> {
+    // The consumed token as written, borrowed from the markup rather than copied out of it.
+    pub fn consume(&mut self, token: Token) -> Option<&'t str> {
         self.token_at(self.current_index)
-            .and_then(|&(ref token_type, ref value)| {
-                if *token_type != token { return None; }
-                Some(value.clone().into())
-            })
-            .and_then(|string| {
-                self.current_index += 1;
-                Some(string)
-            })
+            .filter(|(token_type, _, _)| *token_type == token)
+            .map(|(_, _, span)| self.text(*span))
+            .inspect(|_| self.advance())
     }
 
-    pub fn expression(&mut self) -> Option<String> {
-        self.type_at(self.current_index)
-            .and_then(|token_type| {
-                match token_type {
-                    Token::Identifier => self.variable(),
-                    Token::OpenRound => self.range(),
-                    Token::String | Token::Number => self.consume(token_type),
-                    _ => panic!("Syntax Error")
-                }
-            })
+    pub fn expect(&mut self, token: Token) -> ParseResult<&'t str> {
+        let expected = token.to_string();
+        self.consume(token).ok_or_else(|| self.unexpected(&expected))
+    }
+
+    // Errors unless every token has been consumed.
+    pub fn expect_end(&self) -> ParseResult<()> {
+        match self.token_at(self.current_index) {
+            Some(_) => Err(self.unexpected("end of markup")),
+            None    => self.error.clone().map_or(Ok(()), |error| Err(ParseError::Lex(error)))
+        }
+    }
+
+    pub fn expression(&mut self) -> ParseResult<Expression> {
+        let depth = self.enter()?;
+
+        let expression = match self.type_at(self.current_index) {
+            Some(Token::Identifier)                                         => self.variable(),
+            Some(Token::OpenRound)                                          => self.range(),
+            Some(token) if token == Token::String || token == Token::Number => self.literal(),
+            Some(token) if token.is_literal()                               => self.literal(),
+            _                                                               => Err(self.unexpected("expression"))
+        };
+
+        self.depth = depth;
+        expression
+    }
+
+    // `expression | name: argument, ... | name ...`, where every filter's arguments are optional.
+    pub fn filter_chain(&mut self) -> ParseResult<FilterChain> {
+        let expression  = self.expression()?;
+        let mut filters = vec![];
+
+        while self.consume(Token::Pipe).is_some() {
+            filters.push(self.filter()?);
+        }
+
+        Ok(FilterChain { expression, filters })
+    }
+
+    pub fn condition(&mut self) -> ParseResult<Condition> {
+        match self.precedence {
+            Precedence::RightToLeft => self.right_to_left_condition(),
+            Precedence::Standard    => self.or_condition()
+        }
+    }
+
+    // `name: expression, ...` as used by filters, `include` and `render`. Commas between pairs are
+    // optional, and parsing stops at the first token that doesn't start another pair.
+    pub fn keyword_arguments(&mut self) -> ParseResult<KeywordArguments> {
+        let mut arguments = KeywordArguments::new();
+
+        while self.is_keyword_argument() {
+            let name = self.expect(Token::Identifier)?.to_string();
+            self.expect(Token::Colon)?;
+            arguments.insert(name, self.expression()?);
+
+            if self.is_current(Token::Comma) && self.is_keyword_argument_at(1) {
+                self.advance();
+            }
+        }
+
+        Ok(arguments)
     }
 
-    pub fn argument(&mut self) -> Option<String> {
+    pub fn argument(&mut self) -> ParseResult<String> {
         if self.is_current(Token::Identifier) && self.is_current_offset(Token::Colon, 1) {
-            let mut result = String::new();
-            result.push_str(&self.consume(Token::Identifier).unwrap());
-            result.push_str(&self.consume(Token::Colon).unwrap());
+            let mut result = self.expect(Token::Identifier)?.to_string();
+            result.push_str(self.expect(Token::Colon)?);
+            result.push_str(&self.expression()?.to_string());
 
-            return self.expression().and_then(|value| {
-                result.push_str(&value);
-                Some(result)
-            });
+            return Ok(result);
         }
 
-        self.expression()
+        self.expression().map(|expression| expression.to_string())
+    }
+
+    pub fn is_keyword(&self, keyword: Keyword) -> bool {
+        self.token_at(self.current_index)
+            .is_some_and(|(token_type, value, _)| *token_type == Token::Identifier && value == keyword.as_str())
+    }
+
+    pub fn consume_keyword(&mut self, keyword: Keyword) -> Option<&'t str> {
+        if !self.is_keyword(keyword) { return None; }
+
+        self.consume(Token::Identifier)
+    }
+
+    pub fn expect_keyword(&mut self, keyword: Keyword) -> ParseResult<&'t str> {
+        let expected = format!("'{}'", keyword.as_str());
+        self.consume_keyword(keyword).ok_or_else(|| self.unexpected(&expected))
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.token_at(self.current_index).map(|(_, _, span)| *span)
     }
 
     pub fn is_current(&self, token: Token) -> bool {
@@ -64,23 +345,71 @@ impl Parser {
     pub fn is_current_offset(&self, token: Token, offset: isize) -> bool {
         self.index_with_offset(offset)
             .and_then(|index| self.is_token(index, token))
-            .or_else(|| Some(false))
-            .unwrap()
+            .unwrap_or(false)
+    }
+
+    fn is_keyword_argument(&self) -> bool {
+        self.is_keyword_argument_at(0)
+    }
+
+    fn is_keyword_argument_at(&self, offset: isize) -> bool {
+        self.is_current_offset(Token::Identifier, offset) && self.is_current_offset(Token::Colon, offset + 1)
+    }
+
+    // A lexing error takes the place of the end of the markup, since nothing after it was lexed.
+    fn unexpected(&self, expected: &str) -> ParseError {
+        match (self.token_at(self.current_index), &self.error) {
+            (Some((token, _, span)), _) => ParseError::UnexpectedToken(expected.to_string(), token.clone(), *span),
+            (None, Some(error))         => ParseError::Lex(error.clone()),
+            (None, None)                => ParseError::UnexpectedEnd(expected.to_string())
+        }
+    }
+
+    fn advance(&mut self) {
+        self.current_index += 1;
+        self.fill();
+    }
+
+    // Lexes far enough ahead of the current token to cover any lookahead, and drops whatever is too
+    // far behind it to jump back to.
+    fn fill(&mut self) {
+        while self.error.is_none() && self.buffer_start + self.buffer.len() < self.current_index + LOOKAHEAD {
+            match self.tokens.next() {
+                Some(Ok((token, value, span))) => self.buffer.push_back((token, value, span.offset(self.origin))),
+                Some(Err(error))               => self.error = Some(error.offset(self.origin)),
+                None                           => break
+            }
+        }
+
+        while self.buffer_start + HISTORY < self.current_index && self.buffer.pop_front().is_some() {
+            self.buffer_start += 1;
+        }
     }
 
     fn token_at(&self, index: usize) -> Option<&LexedToken> {
-        self.tokens.get(index)
+        index.checked_sub(self.buffer_start).and_then(|offset| self.buffer.get(offset))
     }
 
     fn type_at(&self, index: usize) -> Option<Token> {
         self.token_at(index)
-            .and_then(|&(ref token, _)| Some(token.clone()))
+            .map(|(token, _, _)| token.clone())
     }
 
     fn is_token(&self, index: usize, token: Token) -> Option<bool> {
         self.token_at(index)
-            .and_then(|&(ref token_type, _)| Some(*token_type == token))
-            .or_else(|| Some(false))
+            .map(|(token_type, _, _)| *token_type == token)
+            .or(Some(false))
+    }
+
+    // From `start` to the end of the last token consumed.
+    fn span_since(&self, start: Span) -> Span {
+        let previous = self.current_index.checked_sub(1).and_then(|index| self.token_at(index));
+        previous.map_or(start, |(_, _, end)| start.to(*end))
+    }
+
+    // A token's text, found from its span since the span has been moved by `origin`.
+    fn text(&self, span: Span) -> &'t str {
+        &self.source[span.start - self.origin.start..span.end - self.origin.start]
     }
 
     fn index_with_offset(&self, offset: isize) -> Option<usize> {
@@ -90,76 +419,268 @@ impl Parser {
         Some(index as usize)
     }
 
-    fn variable(&mut self) -> Option<String> {
-        self.consume(Token::Identifier)
-            .and_then(|mut value| {
-                while self.is_current(Token::OpenSquare) {
-                    value.push_str(&self.consume(Token::OpenSquare).unwrap());
-                    value.push_str(&self.expression().unwrap());
-                    value.push_str(&self.consume(Token::CloseSquare).unwrap());
-                }
+    fn literal(&mut self) -> ParseResult<Expression> {
+        let (token, raw, span) = self.token_at(self.current_index).cloned().ok_or_else(|| self.unexpected("expression"))?;
+        self.advance();
+
+        let value = match token {
+            Token::String => Variable::Str(unescape(&raw[1..raw.len() - 1]).into()),
+            Token::Number => number(&raw),
+            Token::Bool   => Variable::Bool(raw == "true"),
+            Token::Empty  => Variable::Empty,
+            Token::Blank  => Variable::Blank,
+            _             => Variable::Nil
+        };
+
+        Ok(Expression::Literal(Literal { value, span }))
+    }
+
+    fn variable(&mut self) -> ParseResult<Expression> {
+        let start       = self.span().unwrap_or_default();
+        let mut lookups = vec![Lookup::Key(self.expect(Token::Identifier)?.to_string())];
+        self.lookups(&mut lookups)?;
+
+        Ok(Expression::Variable(lookups, self.span_since(start)))
+    }
+
+    // Appends any `[key]` and `.name` lookups.
+    fn lookups(&mut self, lookups: &mut Vec<Lookup>) -> ParseResult<()> {
+        while self.is_current(Token::OpenSquare) {
+            self.expect(Token::OpenSquare)?;
+            lookups.push(Lookup::Index(self.expression()?));
+            self.expect(Token::CloseSquare)?;
+        }
+
+        if self.is_current(Token::Dot) {
+            self.expect(Token::Dot)?;
+            lookups.push(Lookup::Key(self.property()?.to_string()));
+            self.lookups(lookups)?;
+        }
+
+        Ok(())
+    }
+
+    fn property(&mut self) -> ParseResult<&'t str> {
+        self.type_at(self.current_index)
+            .filter(|token_type| *token_type == Token::Identifier || token_type.is_keyword())
+            .and_then(|token_type| self.consume(token_type))
+            .ok_or_else(|| self.unexpected("property name"))
+    }
+
+    // One level deeper, or an error past the limit. Returns the depth to go back to afterwards.
+    fn enter(&mut self) -> ParseResult<usize> {
+        let depth = self.depth;
+
+        match self.max_depth {
+            Some(max) if depth >= max => Err(ParseError::TooComplex(max, self.span().unwrap_or(self.origin))),
+            _ => {
+                self.depth += 1;
+                Ok(depth)
+            }
+        }
+    }
+
+    fn right_to_left_condition(&mut self) -> ParseResult<Condition> {
+        let depth = self.enter()?;
+        let left  = self.comparison()?;
+
+        let condition = if self.consume(Token::And).is_some() {
+            Condition::And(Box::new(left), Box::new(self.right_to_left_condition()?))
+        } else if self.consume(Token::Or).is_some() {
+            Condition::Or(Box::new(left), Box::new(self.right_to_left_condition()?))
+        } else {
+            left
+        };
+
+        self.depth = depth;
+        Ok(condition)
+    }
+
+    // Each `or` nests the conditions before it one level deeper.
+    fn or_condition(&mut self) -> ParseResult<Condition> {
+        let depth         = self.depth;
+        let mut condition = self.and_condition()?;
+
+        while self.consume(Token::Or).is_some() {
+            self.enter()?;
+            condition = Condition::Or(Box::new(condition), Box::new(self.and_condition()?));
+        }
+
+        self.depth = depth;
+        Ok(condition)
+    }
+
+    fn and_condition(&mut self) -> ParseResult<Condition> {
+        let depth         = self.depth;
+        let mut condition = self.comparison()?;
 
-                if self.is_current(Token::Dot) {
-                    value.push_str(&self.consume(Token::Dot).unwrap());
-                    value.push_str(&self.variable().unwrap());
+        while self.consume(Token::And).is_some() {
+            self.enter()?;
+            condition = Condition::And(Box::new(condition), Box::new(self.comparison()?));
+        }
+
+        self.depth = depth;
+        Ok(condition)
+    }
+
+    fn comparison(&mut self) -> ParseResult<Condition> {
+        let left = self.expression()?;
+
+        match self.type_at(self.current_index) {
+            Some(Token::Comparison(op)) => {
+                self.advance();
+                Ok(Condition::Comparison(left, op, self.expression()?))
+            },
+            _ => Ok(Condition::Test(left))
+        }
+    }
+
+    // Positional and keyword arguments can be mixed, but each keyword argument is pulled out into
+    // `keyword_arguments` rather than kept in line.
+    fn filter(&mut self) -> ParseResult<Filter> {
+        let name                  = self.consume(Token::Identifier).map(str::to_string).ok_or_else(|| self.unexpected("filter name"))?;
+        let mut arguments         = vec![];
+        let mut keyword_arguments = KeywordArguments::new();
+
+        if self.consume(Token::Colon).is_some() {
+            loop {
+                if self.is_keyword_argument() {
+                    for (name, value) in self.keyword_arguments()? {
+                        keyword_arguments.insert(name, value);
+                    }
+                } else {
+                    arguments.push(self.expression()?);
                 }
 
-                Some(value)
-            })
+                if self.consume(Token::Comma).is_none() { break; }
+            }
+        }
+
+        Ok(Filter { name, arguments, keyword_arguments })
+    }
+
+    // `(start..end)` where either bound can be any expression, including another lookup. The bounds
+    // are kept as expressions and read when the range is rendered. A `for` loop counts through the
+    // range; anywhere else it's built into an array.
+    fn range(&mut self) -> ParseResult<Expression> {
+        let open = self.span().unwrap_or_default();
+        self.expect(Token::OpenRound)?;
+        let start = self.expression()?;
+        self.expect(Token::Range)?;
+        let end = self.expression()?;
+        self.expect(Token::CloseRound)?;
+
+        Ok(Expression::Range(Box::new(start), Box::new(end), self.span_since(open)))
     }
+}
 
-    fn range(&mut self) -> Option<String> {
-        self.consume(Token::OpenRound)
-            .and_then(|mut value| {
-                value.push_str(&self.expression().unwrap());
-                value.push_str(&self.consume(Token::Range).unwrap());
-                value.push_str(&self.expression().unwrap());
-                value.push_str(&self.consume(Token::CloseRound).unwrap());
-                Some(value)
-            })
+// Integers too big for an i64 fall back to floats rather than failing.
+fn number(raw: &str) -> Variable {
+    if !raw.contains(['.', 'e', 'E']) {
+        if let Ok(value) = raw.parse() { return Variable::Int(value); }
     }
+
+    Variable::Float(raw.parse().unwrap_or(0.0))
+}
+
+// `\n`, `\t` and `\r` become control characters and a backslash before a quote or another
+// backslash is dropped. Any other backslash is kept as written, so `'C:\dir'` survives.
+fn unescape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut chars  = value.chars();
+
+    while let Some(character) = chars.next() {
+        if character != '\\' {
+            result.push(character);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n')                           => result.push('\n'),
+            Some('t')                           => result.push('\t'),
+            Some('r')                           => result.push('\r'),
+            Some(escaped @ ('\\' | '\'' | '"')) => result.push(escaped),
+            Some(other)                         => { result.push('\\'); result.push(other); },
+            None                                => result.push('\\')
+        }
+    }
+
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use lexer::Token;
+    use lexer::{CompareOp,LexError,Token};
 
     #[test]
     fn jump_moves_the_current_index() {
-        let mut parser = Parser::new("wat: 7");
-        parser.jump(2);
+        let mut parser = Parser::new("wat: 7").unwrap();
+        parser.jump(2).unwrap();
 
         assert!(parser.is_current(Token::Number));
     }
 
     #[test]
     fn jump_can_move_backwards() {
-        let mut parser = Parser::new("wat: 7");
-        parser.jump(2);
-        parser.jump(-1);
+        let mut parser = Parser::new("wat: 7").unwrap();
+        parser.jump(2).unwrap();
+        parser.jump(-1).unwrap();
 
         assert!(parser.is_current(Token::Colon));
     }
 
     #[test]
-    #[should_panic(expected="Attempted to jump too far back")]
-    fn jump_panics_when_index_goes_below_zero() {
-        let mut parser = Parser::new("wat: 7");
-        parser.jump(-1)
+    fn jump_fails_when_index_goes_below_zero() {
+        let mut parser = Parser::new("wat: 7").unwrap();
+        assert_eq!(Err(ParseError::InvalidJump(-1)), parser.jump(-1));
     }
 
     #[test]
     fn consume_things() {
-        let mut parser = Parser::new("wat: 7");
+        let mut parser = Parser::new("wat: 7").unwrap();
         assert_eq!("wat", parser.consume(Token::Identifier).unwrap());
         assert_eq!(":", parser.consume(Token::Colon).unwrap());
         assert_eq!("7", parser.consume(Token::Number).unwrap());
     }
 
+    #[test]
+    fn expressions_nest_only_so_deep() {
+        let lookups = format!("{}x{}", "a[".repeat(1000), "]".repeat(1000));
+        let error   = Parser::new(&lookups).unwrap().expression().unwrap_err();
+        assert_eq!(ParseError::TooComplex(100, Span { start: 200, end: 201, line: 1, col: 201 }), error);
+
+        let conditions = vec!["a"; 150].join(" and ");
+        for &precedence in &[Precedence::RightToLeft, Precedence::Standard] {
+            let mut parser = Parser::new(&conditions).unwrap();
+            parser.set_precedence(precedence);
+            assert!(matches!(parser.condition(), Err(ParseError::TooComplex(100, _))));
+        }
+
+        let options = ParserOptions { limits: ParseLimits { max_expression_depth: Some(3), ..ParseLimits::default() }, ..ParserOptions::default() };
+        assert!(Parser::with_options("a[b[c]]", Span::default(), &options).unwrap().expression().is_ok());
+        assert!(Parser::with_options("a[b[c[d]]]", Span::default(), &options).unwrap().expression().is_err());
+    }
+
+    #[test]
+    fn consume_borrows_from_the_markup() {
+        let markup     = "{{ product.title | append: '!' }}";
+        let origin     = Span { start: 3, end: 3, line: 1, col: 4 };
+        let mut parser = Parser::at(&markup[3..markup.len() - 3], origin).unwrap();
+
+        let name = parser.consume(Token::Identifier).unwrap();
+        assert_eq!("product", name);
+        assert_eq!(markup[3..].as_ptr(), name.as_ptr());
+
+        parser.jump(3).unwrap();
+        assert_eq!("append", parser.consume(Token::Identifier).unwrap());
+        parser.jump(1).unwrap();
+        assert_eq!("'!'", parser.consume(Token::String).unwrap());
+    }
+
     #[test]
     fn consume_returns_none_when_token_doesnt_match() {
-        let mut parser = Parser::new("wat: 7");
+        let mut parser = Parser::new("wat: 7").unwrap();
         assert_eq!(None, parser.consume(Token::Number));
         assert_eq!(None, parser.consume(Token::Colon));
         assert!(parser.consume(Token::Identifier).is_some());
@@ -167,53 +688,73 @@ mod tests {
 
     #[test]
     fn is_current_checks_token_type() {
-        let mut parser = Parser::new("wat 6 Peter Hegemon");
+        let mut parser = Parser::new("wat 6 Peter Hegemon").unwrap();
 
         assert!(parser.is_current(Token::Identifier));
         parser.consume(Token::Identifier);
 
-        assert_eq!(false, parser.is_current(Token::Comparison));
+        assert!(!parser.is_current(Token::Comparison(CompareOp::Eq)));
         assert!(parser.is_current(Token::Number));
         assert!(parser.is_current_offset(Token::Identifier, 1));
-        assert_eq!(false, parser.is_current_offset(Token::Number, 1));
+        assert!(!parser.is_current_offset(Token::Number, 1));
     }
 
     #[test]
     fn is_current_offset_returns_false_when_offset_is_not_valid() {
-        let mut parser = Parser::new("wat 6 Peter Hegemon");
-        parser.jump(1);
+        let mut parser = Parser::new("wat 6 Peter Hegemon").unwrap();
+        parser.jump(1).unwrap();
 
         assert!(parser.is_current_offset(Token::Number, 0));
         assert!(parser.is_current_offset(Token::Identifier, -1));
-        assert_eq!(false, parser.is_current_offset(Token::Identifier, -2));
+        assert!(!parser.is_current_offset(Token::Identifier, -2));
     }
 
     #[test]
     fn expression_parsing_identifiers_strings_and_numbers() {
-        let mut parser = Parser::new("hi.there hi?[5].there? hi.there.bob");
-        assert_eq!("hi.there", parser.expression().unwrap());
-        assert_eq!("hi?[5].there?", parser.expression().unwrap());
-        assert_eq!("hi.there.bob", parser.expression().unwrap());
+        let mut parser = Parser::new("hi.there hi?[5].there? hi.there.bob").unwrap();
+        assert_eq!("hi.there", parser.expression().unwrap().to_string());
+        assert_eq!("hi?[5].there?", parser.expression().unwrap().to_string());
+        assert_eq!("hi.there.bob", parser.expression().unwrap().to_string());
+
+        let mut parser = Parser::new("567 6.0 'lol' \"wut\"").unwrap();
+        assert_eq!(literal(567, 0, 3), parser.expression().unwrap());
+        assert_eq!(literal(6.0, 4, 7), parser.expression().unwrap());
+        assert_eq!(literal("lol", 8, 13), parser.expression().unwrap());
+        assert_eq!(literal("wut", 14, 19), parser.expression().unwrap());
+    }
 
-        let mut parser = Parser::new("567 6.0 'lol' \"wut\"");
-        assert_eq!("567", parser.expression().unwrap());
-        assert_eq!("6.0", parser.expression().unwrap());
-        assert_eq!("'lol'", parser.expression().unwrap());
-        assert_eq!("\"wut\"", parser.expression().unwrap());
+    #[test]
+    fn expression_parsing_empty_and_blank() {
+        let mut parser = Parser::new("empty blank items.empty? page.blank").unwrap();
+        assert_eq!("empty", parser.expression().unwrap().to_string());
+        assert_eq!("blank", parser.expression().unwrap().to_string());
+        assert_eq!("items.empty?", parser.expression().unwrap().to_string());
+        assert_eq!("page.blank", parser.expression().unwrap().to_string());
+    }
+
+    #[test]
+    fn expression_parsing_boolean_and_nil_literals() {
+        let mut parser = Parser::new("true false nil null settings.nil rules.not").unwrap();
+        assert_eq!("true", parser.expression().unwrap().to_string());
+        assert_eq!("false", parser.expression().unwrap().to_string());
+        assert_eq!("nil", parser.expression().unwrap().to_string());
+        assert_eq!("nil", parser.expression().unwrap().to_string());
+        assert_eq!("settings.nil", parser.expression().unwrap().to_string());
+        assert_eq!("rules.not", parser.expression().unwrap().to_string());
     }
 
     #[test]
     fn expression_parsing_ranges() {
-        let mut parser = Parser::new("(5..7) (1.5..9.6) (young..old) (hi[5].wat..old)");
-        assert_eq!("(5..7)", parser.expression().unwrap());
-        assert_eq!("(1.5..9.6)", parser.expression().unwrap());
-        assert_eq!("(young..old)", parser.expression().unwrap());
-        assert_eq!("(hi[5].wat..old)", parser.expression().unwrap());
+        let mut parser = Parser::new("(5..7) (1.5..9.6) (young..old) (hi[5].wat..old)").unwrap();
+        assert_eq!("(5..7)", parser.expression().unwrap().to_string());
+        assert_eq!("(1.5..9.6)", parser.expression().unwrap().to_string());
+        assert_eq!("(young..old)", parser.expression().unwrap().to_string());
+        assert_eq!("(hi[5].wat..old)", parser.expression().unwrap().to_string());
     }
 
     #[test]
     fn argument_parses_simple_arguments() {
-        let mut parser = Parser::new("filter: hi?.there[5]");
+        let mut parser = Parser::new("filter: hi?.there[5]").unwrap();
         parser.consume(Token::Identifier);
         parser.consume(Token::Colon);
 
@@ -222,7 +763,7 @@ mod tests {
 
     #[test]
     fn argument_can_handle_keyword_arguments() {
-        let mut parser = Parser::new("filter: hi?.there[5], type: 7");
+        let mut parser = Parser::new("filter: hi?.there[5], type: 7").unwrap();
 
         parser.consume(Token::Identifier);
         parser.consume(Token::Colon);
@@ -231,4 +772,246 @@ mod tests {
         parser.consume(Token::Comma);
         assert_eq!("type:7", parser.argument().unwrap());
     }
+
+    #[test]
+    fn consume_keyword_matches_identifiers_by_name() {
+        let mut parser = Parser::new("item in (1..5) reversed limit: 2").unwrap();
+        assert_eq!(None, parser.consume_keyword(Keyword::In));
+        assert_eq!("item", parser.expression().unwrap().to_string());
+
+        assert!(parser.is_keyword(Keyword::In));
+        assert_eq!("in", parser.consume_keyword(Keyword::In).unwrap());
+        assert_eq!("(1..5)", parser.expression().unwrap().to_string());
+        assert_eq!("reversed", parser.consume_keyword(Keyword::Reversed).unwrap());
+        assert!(!parser.is_keyword(Keyword::Offset));
+        assert_eq!("limit", parser.consume_keyword(Keyword::Limit).unwrap());
+    }
+
+    #[test]
+    fn keywords_can_still_be_used_as_variables() {
+        let mut parser = Parser::new("limit offset.with").unwrap();
+        assert_eq!("limit", parser.expression().unwrap().to_string());
+        assert_eq!("offset.with", parser.expression().unwrap().to_string());
+    }
+
+    #[test]
+    fn span_returns_the_position_of_the_current_token() {
+        let mut parser = Parser::new("a |\n  b").unwrap();
+        assert_eq!(Some(Span { start: 0, end: 1, line: 1, col: 1 }), parser.span());
+
+        parser.jump(2).unwrap();
+        assert_eq!(Some(Span { start: 6, end: 7, line: 2, col: 3 }), parser.span());
+
+        parser.jump(1).unwrap();
+        assert_eq!(None, parser.span());
+    }
+
+    #[test]
+    fn new_returns_lexing_errors_in_the_first_token() {
+        let error = Parser::new("% a").err().unwrap();
+        assert_eq!(ParseError::Lex(LexError::UnexpectedCharacter('%', span(0, 1))), error);
+    }
+
+    #[test]
+    fn lexing_errors_are_returned_once_the_parser_reaches_them() {
+        let mut parser = Parser::new("a | %").unwrap();
+        assert_eq!(Err(ParseError::Lex(LexError::UnexpectedCharacter('%', span(4, 5)))), parser.filter_chain());
+
+        let mut parser = Parser::new("a %").unwrap();
+        parser.expression().unwrap();
+        assert_eq!(Err(ParseError::Lex(LexError::UnexpectedCharacter('%', span(2, 3)))), parser.expect_end());
+    }
+
+    #[test]
+    fn jump_can_only_go_back_a_limited_distance() {
+        let mut parser = Parser::new("a.b.c.d.e.f.g.h.i | x").unwrap();
+
+        assert_eq!(variable("a.b.c.d.e.f.g.h.i", 0), parser.expression().unwrap());
+        assert_eq!(Ok(()), parser.jump(-(HISTORY as isize)));
+        assert_eq!(Err(ParseError::InvalidJump(-1)), parser.jump(-1));
+    }
+
+    #[test]
+    fn filter_chain_parses_filters_and_their_arguments() {
+        let mut parser = Parser::new("value | filter: arg1, arg2 | other | truncate: 20, ellipsis: '...'").unwrap();
+
+        let mut ellipsis = KeywordArguments::new();
+        ellipsis.insert("ellipsis".to_string(), literal("...", 61, 66));
+
+        assert_eq!(FilterChain {
+            expression: variable("value", 0),
+            filters: vec![
+                Filter { name: "filter".to_string(), arguments: vec![variable("arg1", 16), variable("arg2", 22)], keyword_arguments: KeywordArguments::new() },
+                Filter { name: "other".to_string(), arguments: vec![], keyword_arguments: KeywordArguments::new() },
+                Filter { name: "truncate".to_string(), arguments: vec![literal(20, 47, 49)], keyword_arguments: ellipsis }
+            ]
+        }, parser.filter_chain().unwrap());
+
+        assert_eq!(None, parser.span());
+    }
+
+    #[test]
+    fn filter_chain_without_filters() {
+        let mut parser = Parser::new("(1..5)").unwrap();
+        let range = Expression::Range(Box::new(literal(1, 1, 2)), Box::new(literal(5, 4, 5)), span(0, 6));
+        assert_eq!(FilterChain { expression: range, filters: vec![] }, parser.filter_chain().unwrap());
+    }
+
+    fn literal<V: Into<Variable>>(value: V, start: usize, end: usize) -> Expression {
+        Expression::Literal(Literal { value: value.into(), span: span(start, end) })
+    }
+
+    fn span(start: usize, end: usize) -> Span {
+        Span { start, end, line: 1, col: start + 1 }
+    }
+
+    fn variable(path: &str, start: usize) -> Expression {
+        Expression::variable(path, span(start, start + path.len()))
+    }
+
+    fn test(path: &str, start: usize) -> Box<Condition> {
+        Box::new(Condition::Test(variable(path, start)))
+    }
+
+    #[test]
+    fn condition_parses_comparisons() {
+        let mut parser = Parser::new("a == 1 tags contains 'x' user").unwrap();
+
+        assert_eq!(Ok(Condition::Comparison(variable("a", 0), CompareOp::Eq, literal(1, 5, 6))), parser.condition());
+        assert_eq!(Ok(Condition::Comparison(variable("tags", 7), CompareOp::Contains, literal("x", 21, 24))), parser.condition());
+        assert_eq!(Ok(Condition::Test(variable("user", 25))), parser.condition());
+        assert_eq!(Err(ParseError::UnexpectedEnd("expression".into())), parser.condition());
+    }
+
+    #[test]
+    fn condition_groups_and_or_from_the_right_by_default() {
+        let mut parser = Parser::new("a and b or c").unwrap();
+        assert_eq!(Ok(Condition::And(test("a", 0), Box::new(Condition::Or(test("b", 6), test("c", 11))))), parser.condition());
+
+        let mut parser = Parser::new("a or b and c").unwrap();
+        assert_eq!(Ok(Condition::Or(test("a", 0), Box::new(Condition::And(test("b", 5), test("c", 11))))), parser.condition());
+    }
+
+    #[test]
+    fn with_options_configures_the_lexer_and_precedence() {
+        let options = ParserOptions { precedence: Precedence::Standard, unicode_identifiers: false, ..ParserOptions::default() };
+        let parser  = Parser::with_options("a", Span::default(), &options).unwrap();
+        assert_eq!(Precedence::Standard, parser.precedence());
+
+        let mut parser = Parser::with_options("café", Span::default(), &options).unwrap();
+        parser.expression().unwrap();
+        assert_eq!(Err(ParseError::Lex(LexError::UnexpectedCharacter('é', span(3, 5)))), parser.expect_end());
+    }
+
+    #[test]
+    fn condition_can_use_standard_precedence() {
+        let mut parser = Parser::new("a and b or c and d or e").unwrap();
+        parser.set_precedence(Precedence::Standard);
+
+        let left  = Condition::Or(Box::new(Condition::And(test("a", 0), test("b", 6))), Box::new(Condition::And(test("c", 11), test("d", 17))));
+        let whole = Condition::Or(Box::new(left), test("e", 22));
+        assert_eq!(Ok(whole), parser.condition());
+    }
+
+    #[test]
+    fn expression_reports_what_it_found_instead() {
+        let mut parser = Parser::new("items[0 | size").unwrap();
+        let error      = parser.expression().unwrap_err();
+
+        assert_eq!(ParseError::UnexpectedToken("']'".into(), Token::Pipe, Span { start: 8, end: 9, line: 1, col: 9 }), error);
+        assert_eq!("Expected ']' but found '|' at line 1, column 9", error.to_string());
+
+        let mut parser = Parser::new("(1..").unwrap();
+        assert_eq!("Expected expression but reached the end of the markup", parser.expression().unwrap_err().to_string());
+
+        let mut parser = Parser::new("| upcase").unwrap();
+        assert_eq!(Err(ParseError::UnexpectedToken("expression".into(), Token::Pipe, Span { start: 0, end: 1, line: 1, col: 1 })), parser.expression());
+    }
+
+    #[test]
+    fn filter_chain_requires_filter_names() {
+        let mut parser = Parser::new("a | 'b'").unwrap();
+        assert_eq!(Err(ParseError::UnexpectedToken("filter name".into(), Token::String, Span { start: 4, end: 7, line: 1, col: 5 })), parser.filter_chain());
+
+        let mut parser = Parser::new("a | b:").unwrap();
+        assert_eq!(Err(ParseError::UnexpectedEnd("expression".into())), parser.filter_chain());
+    }
+
+    #[test]
+    fn expect_end_fails_on_leftover_tokens() {
+        let mut parser = Parser::new("a b").unwrap();
+        parser.expression().unwrap();
+        assert_eq!(Err(ParseError::UnexpectedToken("end of markup".into(), Token::Identifier, Span { start: 2, end: 3, line: 1, col: 3 })), parser.expect_end());
+
+        parser.expression().unwrap();
+        assert_eq!(Ok(()), parser.expect_end());
+    }
+
+    #[test]
+    fn keyword_arguments_parses_name_value_pairs_in_order() {
+        let mut parser = Parser::new("'x', product: item, class: 'big' size: 2 | upcase").unwrap();
+        parser.expression().unwrap();
+        parser.expect(Token::Comma).unwrap();
+
+        let arguments = parser.keyword_arguments().unwrap();
+        assert_eq!(vec![("product", &variable("item", 14)), ("class", &literal("big", 27, 32)), ("size", &literal(2, 39, 40))], arguments.iter().collect::<Vec<_>>());
+        assert!(parser.is_current(Token::Pipe));
+    }
+
+    #[test]
+    fn keyword_arguments_leaves_a_trailing_comma_alone() {
+        let mut parser = Parser::new("a: 1, 'b'").unwrap();
+
+        assert_eq!(1, parser.keyword_arguments().unwrap().len());
+        assert!(parser.is_current(Token::Comma));
+        assert!(Parser::new("a: ").unwrap().keyword_arguments().is_err());
+    }
+
+    #[test]
+    fn filter_chain_separates_keyword_arguments() {
+        let mut parser = Parser::new("a | f: 1, x: 2, 3, y: 4").unwrap();
+        let filter     = parser.filter_chain().unwrap().filters.remove(0);
+
+        assert_eq!(vec![literal(1, 7, 8), literal(3, 16, 17)], filter.arguments);
+        assert_eq!(vec![("x", &literal(2, 13, 14)), ("y", &literal(4, 22, 23))], filter.keyword_arguments.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn expression_parses_ranges_with_expression_bounds() {
+        let mut parser = Parser::new("i in (start..items.size) (-1..(0..2))").unwrap();
+        parser.expression().unwrap();
+        parser.consume_keyword(Keyword::In).unwrap();
+
+        let bounds = Expression::Range(Box::new(variable("start", 6)), Box::new(variable("items.size", 13)), span(5, 24));
+        assert_eq!(bounds, parser.expression().unwrap());
+
+        let nested = Expression::Range(Box::new(literal(-1, 26, 28)), Box::new(Expression::Range(Box::new(literal(0, 31, 32)), Box::new(literal(2, 34, 35)), span(30, 36))), span(25, 37));
+        assert_eq!(nested, parser.expression().unwrap());
+
+        let mut parser = Parser::new("(1 2)").unwrap();
+        assert_eq!(Err(ParseError::UnexpectedToken("'..'".into(), Token::Number, Span { start: 3, end: 4, line: 1, col: 4 })), parser.expression());
+    }
+
+    #[test]
+    fn expression_unquotes_and_unescapes_strings() {
+        let mut parser = Parser::new(r#"'it\'s' "say \"hi\"\n" 'C:\dir\\' ''"#).unwrap();
+
+        assert_eq!(literal("it's", 0, 7), parser.expression().unwrap());
+        assert_eq!(literal("say \"hi\"\n", 8, 22), parser.expression().unwrap());
+        assert_eq!(literal("C:\\dir\\", 23, 33), parser.expression().unwrap());
+        assert_eq!(literal("", 34, 36), parser.expression().unwrap());
+    }
+
+    #[test]
+    fn expression_parses_numbers_into_values() {
+        let mut parser = Parser::new("-12 | 1e3 | .5 | 99999999999999999999").unwrap();
+
+        assert_eq!(literal(-12, 0, 3), parser.expression().unwrap());
+        parser.expect(Token::Pipe).unwrap();
+        assert_eq!(literal(1000.0, 6, 9), parser.expression().unwrap());
+        parser.expect(Token::Pipe).unwrap();
+        assert_eq!(literal(0.5, 12, 14), parser.expression().unwrap());
+        parser.expect(Token::Pipe).unwrap();
+        assert_eq!(literal(1e20, 17, 37), parser.expression().unwrap());
+    }
 }
@@ -0,0 +1,217 @@
+use std::collections::HashSet;
+use std::slice;
+
+use ast::{Condition,Expression,FilterChain,Lookup,Tag,TagArguments};
+use lexer::Span;
+use parser::{ParseError,ParseLimits};
+use sandbox::Sandbox;
+use template::{BLOCKS,Template};
+use visit::{self,Visitor};
+
+// Variables Liquid defines for the body of a loop.
+const LOOP_VARIABLES: &[&str] = &["forloop", "tablerowloop"];
+
+// What a template has to keep to, checked against one that's already parsed so a platform can turn
+// a bad upload away without rendering it. A template that parsed under looser options is held to
+// the same sandbox and limits it would have been parsed with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Policy {
+    pub sandbox: Option<Sandbox>,
+    // The top-level names a template may read, like `product` for `product.title`. Anything it
+    // assigns, captures or loops over itself is allowed as well. Unset allows every name.
+    pub variables: Option<HashSet<String>>,
+    // The size is measured up to the end of the last node, and expression depth from the parsed
+    // expressions.
+    pub limits: ParseLimits
+}
+
+impl Policy {
+    pub fn new() -> Policy {
+        Policy::default()
+    }
+
+    pub fn allow_variable(&mut self, name: &str) {
+        self.variables.get_or_insert_with(HashSet::new).insert(name.to_string());
+    }
+
+    pub fn allows_variable(&self, name: &str) -> bool {
+        self.variables.as_ref().is_none_or(|variables| variables.contains(name))
+    }
+
+    // Every way `template` breaks the policy, in the order they appear. Empty when it doesn't.
+    pub fn check(&self, template: &Template) -> Vec<ParseError> {
+        let mut validator = Validator { policy: self, errors: vec![], defined: HashSet::new(), depth: 0 };
+
+        let size = template.nodes.last().map_or(0, |node| node.span().end);
+        if let Some(max) = self.limits.max_source_bytes.filter(|&max| size > max) { validator.errors.push(ParseError::TooLarge(max)); }
+
+        for node in &template.nodes {
+            if let Some(Err(error)) = self.sandbox.as_ref().map(|sandbox| sandbox.check(node)) { validator.errors.push(error); }
+            visit::walk(slice::from_ref(node), &mut validator);
+        }
+
+        validator.errors
+    }
+}
+
+struct Validator<'p> {
+    policy: &'p Policy,
+    errors: Vec<ParseError>,
+    // Names the template has given values to so far.
+    defined: HashSet<String>,
+    // Blocks open at this point.
+    depth: usize
+}
+
+impl<'p> Validator<'p> {
+    fn check_depth(&mut self, depth: usize, span: Span) {
+        if let Some(max) = self.policy.limits.max_expression_depth.filter(|&max| depth > max) { self.errors.push(ParseError::TooComplex(max, span)); }
+    }
+
+    fn nest(&mut self, name: &str, span: Span) {
+        if name.strip_prefix("end").is_some_and(|block| BLOCKS.contains(&block)) {
+            self.depth = self.depth.saturating_sub(1);
+        } else if BLOCKS.contains(&name) {
+            self.depth += 1;
+            if let Some(max) = self.policy.limits.max_nesting.filter(|&max| self.depth > max) { self.errors.push(ParseError::TooDeep(max, span)); }
+        }
+    }
+
+    // Tags that give a name a value. Captures aren't parsed yet, and neither are loops when `for`
+    // isn't registered, so their name is the first word of the markup.
+    fn define(&mut self, tag: &Tag) {
+        let first_word = tag.markup.split_whitespace().next().map(str::to_string);
+
+        match (tag.name.as_str(), &tag.arguments) {
+            (_, TagArguments::Assign(name, _))                    => { self.defined.insert(name.clone()); },
+            (_, TagArguments::For(each))                          => {
+                self.defined.insert(each.variable.clone());
+                self.defined.extend(LOOP_VARIABLES.iter().map(|name| name.to_string()));
+            },
+            ("capture", _) | ("increment", _) | ("decrement", _) => self.defined.extend(first_word),
+            ("for", _) | ("tablerow", _)                         => {
+                self.defined.extend(first_word);
+                self.defined.extend(LOOP_VARIABLES.iter().map(|name| name.to_string()));
+            },
+            _                                                    => {}
+        }
+    }
+}
+
+impl<'p> Visitor for Validator<'p> {
+    fn visit_output(&mut self, output: &FilterChain, span: Span) {
+        self.check_depth(chain_depth(output), span);
+        visit::walk_output(self, output);
+    }
+
+    fn visit_tag(&mut self, tag: &Tag, span: Span) {
+        self.nest(&tag.name, span);
+
+        let depth = match tag.arguments {
+            TagArguments::Condition(ref condition)                                 => condition_depth(condition),
+            TagArguments::Assign(_, ref output) | TagArguments::Output(ref output) => chain_depth(output),
+            _                                                                      => 0
+        };
+        self.check_depth(depth, span);
+
+        visit::walk_tag(self, tag);
+        self.define(tag);
+    }
+
+    fn visit_expression(&mut self, expression: &Expression) {
+        // Variables used as keys inside brackets are visited on their own.
+        if let Expression::Variable(ref lookups, span) = *expression {
+            if let Some(Lookup::Key(name)) = lookups.first() {
+                if !self.defined.contains(name) && !self.policy.allows_variable(name) { self.errors.push(ParseError::UnknownVariable(name.to_string(), span)); }
+            }
+        }
+
+        visit::walk_expression(self, expression);
+    }
+}
+
+// Counted the way the parser counts when it applies `max_expression_depth`.
+fn expression_depth(expression: &Expression) -> usize {
+    match *expression {
+        Expression::Literal(_)                   => 1,
+        Expression::Variable(ref lookups, _)     => 1 + lookups.iter().map(lookup_depth).max().unwrap_or(0),
+        Expression::Range(ref start, ref end, _) => 1 + expression_depth(start).max(expression_depth(end))
+    }
+}
+
+fn lookup_depth(lookup: &Lookup) -> usize {
+    match *lookup {
+        Lookup::Key(_)           => 0,
+        Lookup::Index(ref index) => expression_depth(index)
+    }
+}
+
+fn chain_depth(chain: &FilterChain) -> usize {
+    let arguments = chain.filters.iter().flat_map(|filter| filter.arguments.iter().chain(filter.keyword_arguments.iter().map(|(_, argument)| argument)));
+    arguments.chain(Some(&chain.expression)).map(expression_depth).max().unwrap_or(0)
+}
+
+fn condition_depth(condition: &Condition) -> usize {
+    match *condition {
+        Condition::Test(ref expression)                                          => expression_depth(expression),
+        Condition::Comparison(ref left, _, ref right)                            => expression_depth(left).max(expression_depth(right)),
+        Condition::And(ref left, ref right) | Condition::Or(ref left, ref right) => 1 + condition_depth(left).max(condition_depth(right))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::Parser;
+
+    fn policy(variables: &[&str]) -> Policy {
+        let mut policy = Policy::new();
+        variables.iter().for_each(|name| policy.allow_variable(name));
+
+        policy
+    }
+
+    #[test]
+    fn expression_depth_counts_nested_lookups() {
+        let depth = |source: &str| expression_depth(&Parser::new(source).unwrap().expression().unwrap());
+
+        assert_eq!(1, depth("product.title"));
+        assert_eq!(3, depth("a[b[c]]"));
+        assert_eq!(2, depth("a['[['][1]"));
+    }
+
+    #[test]
+    fn validate_reports_every_variable_outside_the_allow_list() {
+        let template = Template::parse("{% assign total = cart.total | plus: fee %}{{ total }}{{ user[field]['a[b]'][c.d] }}{% if secret %}{% endif %}").unwrap();
+        let errors   = template.validate(&policy(&["cart", "user"]));
+
+        assert_eq!(vec!["fee", "field", "c", "secret"], errors.iter().map(|error| match *error {
+            ParseError::UnknownVariable(ref name, _) => name.as_str(),
+            ref error                                => panic!("unexpected {}", error)
+        }).collect::<Vec<_>>());
+        assert_eq!("Variable 'secret' is not allowed at line 1, column 91", errors[3].to_string());
+
+        let template = Template::parse("{% for item in items %}{{ forloop.index }}{{ item }}{% endfor %}{% capture x %}{% endcapture %}{{ x }}").unwrap();
+        assert_eq!(Vec::<ParseError>::new(), template.validate(&policy(&[])));
+        assert_eq!(Vec::<ParseError>::new(), template.validate(&Policy::new()));
+    }
+
+    #[test]
+    fn validate_applies_the_sandbox_and_limits_to_parsed_templates() {
+        let template = Template::parse("{% if a and b and c %}{% if d %}{% include 'x' %}{{ e | upcase | md5 }}{% endif %}{% endif %}").unwrap();
+        let policy   = Policy {
+            sandbox: Some(Sandbox::new(&["if"], &["upcase"])),
+            limits: ParseLimits { max_source_bytes: Some(64), max_nesting: Some(1), max_expression_depth: Some(2) },
+            ..Policy::new()
+        };
+
+        assert_eq!(vec![
+            "Template is larger than the limit of 64 bytes",
+            "Expression nests deeper than the limit of 2 at line 1, column 1",
+            "Blocks nest deeper than the limit of 1 at line 1, column 23",
+            "Tag 'include' is not allowed at line 1, column 33",
+            "Filter 'md5' is not allowed at line 1, column 50"
+        ], template.validate(&policy).iter().map(ToString::to_string).collect::<Vec<_>>());
+        assert!(template.validate(&Policy::new()).is_empty());
+    }
+}
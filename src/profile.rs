@@ -0,0 +1,132 @@
+use std::cmp::Reverse;
+use std::fmt;
+use std::sync::{Mutex,PoisonError};
+use std::time::Duration;
+
+use ast::Node;
+use lexer::Span;
+
+// One rendered node. Time spent in an include counts toward the include tag as well as toward
+// each node of the partial.
+#[derive(Debug)]
+pub struct Sample<'a> {
+    pub node: &'a Node,
+    // The template the node belongs to, when it was rendered by name or included.
+    pub template: Option<&'a str>,
+    pub duration: Duration,
+    pub bytes: usize
+}
+
+// Told about every node a render gets to. Nodes in branches that aren't taken are skipped, apart
+// from the tags that open and close them.
+pub trait Profiler {
+    fn record(&self, sample: &Sample);
+}
+
+// A Profiler that keeps every sample and reports the slowest nodes first.
+#[derive(Debug, Default)]
+pub struct Profile {
+    entries: Mutex<Vec<Entry>>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub template: Option<String>,
+    pub span: Span,
+    // The node in brief, like `{{ product.title }}` or `{% include 'header' %}`.
+    pub description: String,
+    pub duration: Duration,
+    pub bytes: usize
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    // In the order the nodes finished rendering.
+    pub fn entries(&self) -> Vec<Entry> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn slowest(&self, count: usize) -> Vec<Entry> {
+        let mut entries = self.entries();
+        entries.sort_by_key(|entry| Reverse(entry.duration));
+        entries.truncate(count);
+
+        entries
+    }
+}
+
+impl Profiler for Profile {
+    fn record(&self, sample: &Sample) {
+        let entry = Entry {
+            template: sample.template.map(str::to_string),
+            span: sample.node.span(),
+            description: describe(sample.node),
+            duration: sample.duration,
+            bytes: sample.bytes
+        };
+
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner).push(entry);
+    }
+}
+
+// The twenty slowest nodes, one per line.
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for entry in self.slowest(20) {
+            let location = match entry.template {
+                Some(ref name) => format!("{}:{}:{}", name, entry.span.line, entry.span.col),
+                None           => format!("{}:{}", entry.span.line, entry.span.col)
+            };
+
+            writeln!(f, "{:>10.3?} {:>8} B  {}  {}", entry.duration, entry.bytes, location, entry.description)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn describe(node: &Node) -> String {
+    match *node {
+        Node::Text(ref text, _)    => format!("text ({} bytes)", text.len()),
+        Node::Output(ref chain, _) => {
+            let filters = chain.filters.iter().map(|filter| format!(" | {}", filter.name)).collect::<String>();
+            format!("{{{{ {}{} }}}}", chain.expression, filters)
+        },
+        Node::Tag(ref tag, _) if tag.markup.trim().is_empty() => format!("{{% {} %}}", tag.name),
+        Node::Tag(ref tag, _)                                 => format!("{{% {} {} %}}", tag.name, tag.markup.trim())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use context::Context;
+    use environment::Environment;
+
+    #[test]
+    fn profile_records_each_rendered_node() {
+        let mut env = Environment::new();
+        env.add_template("header", "<h1>{{ title | upcase }}</h1>").unwrap();
+        env.add_template("page", "{% include 'header' %}{% if false %}{{ hidden }}{% endif %}").unwrap();
+
+        let profile = Profile::new();
+        let output  = env.render_profiled("page", &mut Context::new(), &profile).unwrap();
+        let entries = profile.entries();
+
+        assert_eq!("<h1></h1>", output);
+        assert_eq!(vec![
+            (Some("header"), "text (4 bytes)", 4),
+            (Some("header"), "{{ title | upcase }}", 0),
+            (Some("header"), "text (5 bytes)", 5),
+            (Some("page"), "{% include 'header' %}", 9),
+            (Some("page"), "{% if false %}", 0),
+            (Some("page"), "{% endif %}", 0)
+        ], entries.iter().map(|entry| (entry.template.as_deref(), entry.description.as_str(), entry.bytes)).collect::<Vec<_>>());
+
+        assert_eq!(6, profile.to_string().lines().count());
+        assert_eq!(2, profile.slowest(2).len());
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc,Mutex,PoisonError};
+
+use notify::{Event,RecommendedWatcher,RecursiveMode,Watcher};
+
+use loader::{FsLoader,LoadResult,Loader};
+
+// An FsLoader that watches its root and reports the templates whose files change, so an
+// Environment parses them again on their next render instead of serving the cached copy. Meant for
+// development, where edits should show up without a restart.
+pub struct ReloadingLoader {
+    loader: FsLoader,
+    changes: Arc<Mutex<HashSet<String>>>,
+    // Stops watching when dropped.
+    _watcher: RecommendedWatcher
+}
+
+impl fmt::Debug for ReloadingLoader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReloadingLoader").field("loader", &self.loader).finish()
+    }
+}
+
+impl ReloadingLoader {
+    pub fn new(loader: FsLoader) -> notify::Result<ReloadingLoader> {
+        let root      = loader.root.canonicalize()?;
+        let changes   = Arc::new(Mutex::new(HashSet::new()));
+        let extension = loader.extension.clone();
+        let recorded  = changes.clone();
+        let watched   = root.clone();
+
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let Ok(event) = event else { return };
+            let mut changes = recorded.lock().unwrap_or_else(PoisonError::into_inner);
+
+            for path in &event.paths {
+                changes.extend(names(&root, &extension, path));
+            }
+        })?;
+
+        // Watched by its canonical path so the paths in events start with it.
+        watcher.watch(&watched, RecursiveMode::Recursive)?;
+        Ok(ReloadingLoader { loader, changes, _watcher: watcher })
+    }
+
+    pub fn loader(&self) -> &FsLoader {
+        &self.loader
+    }
+}
+
+impl Loader for ReloadingLoader {
+    fn load(&self, name: &str) -> LoadResult<String> {
+        self.loader.load(name)
+    }
+
+    fn changes(&self) -> Vec<String> {
+        let mut changes = self.changes.lock().unwrap_or_else(PoisonError::into_inner);
+        mem::take(&mut *changes).into_iter().collect()
+    }
+}
+
+// The names a template at `path` could have been loaded under: with `/` separators, and also
+// without the extension when it's the loader's.
+fn names(root: &Path, extension: &str, path: &Path) -> Vec<String> {
+    let name = |path: &Path| path.iter().map(|part| part.to_string_lossy()).collect::<Vec<_>>().join("/");
+
+    let Ok(relative) = path.strip_prefix(root) else { return vec![] };
+    let mut names    = vec![name(relative)];
+    if relative.extension().is_some_and(|found| found == extension) { names.push(name(&relative.with_extension(""))); }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::thread;
+    use std::time::{Duration,Instant};
+
+    use context::Context;
+    use environment::Environment;
+
+    #[test]
+    fn names_cover_both_spellings() {
+        let root = PathBuf::from("/templates");
+
+        assert_eq!(vec!["partials/header.liquid", "partials/header"], names(&root, "liquid", &root.join("partials/header.liquid")));
+        assert_eq!(vec!["robots.txt"], names(&root, "liquid", &root.join("robots.txt")));
+        assert!(names(&root, "liquid", Path::new("/elsewhere/header.liquid")).is_empty());
+    }
+
+    #[test]
+    fn edits_show_up_on_the_next_render() {
+        let root = env::temp_dir().join(format!("riquid-reload-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("footer.liquid"), "v1").unwrap();
+
+        let mut env = Environment::new();
+        env.set_loader(ReloadingLoader::new(FsLoader::new(&root, "liquid")).unwrap());
+        assert_eq!("v1", env.render("footer", &mut Context::new()).unwrap());
+
+        fs::write(root.join("footer.liquid"), "v2").unwrap();
+
+        // Events arrive on the watcher's own thread, so give them a moment.
+        let started = Instant::now();
+        while env.render("footer", &mut Context::new()).unwrap() != "v2" {
+            assert!(started.elapsed() < Duration::from_secs(5), "the edit was never picked up");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
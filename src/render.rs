@@ -0,0 +1,951 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::error;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+use std::time::{Duration,Instant};
+
+use ast::{Condition,Expression,FilterChain,ForLoop,Include,IncludeValue,Lookup,Node,Tag,TagArguments};
+use context::{Context,ErrorMode};
+use error::{Error,Result};
+use environment::Environment;
+use filters::escape_html;
+use lexer::CompareOp;
+use profile::{Profiler,Sample};
+use template::BLOCKS;
+use variable::{Variable,VariableError,VariableResult};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RenderError {
+    UnknownFilter(String),
+    UnknownTemplate(String),
+    // A block tag the renderer can't render, which fails rather than render its body as if it
+    // weren't there.
+    UnsupportedTag(String),
+    // An `elsif`, `else` or `end` tag outside of the block it belongs to.
+    UnexpectedTag(String),
+    // The limit and the templates that led to it, outermost first.
+    IncludeTooDeep(usize, Vec<String>),
+    // The `fmt::Write` being rendered into failed.
+    Output,
+    LimitExceeded(Limit),
+    Variable(VariableError)
+}
+
+impl fmt::Display for RenderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RenderError::UnknownFilter(ref name)   => write!(f, "Unknown filter '{}'", name),
+            RenderError::UnknownTemplate(ref name) => write!(f, "Unknown template '{}'", name),
+            RenderError::UnsupportedTag(ref name)  => write!(f, "Tag '{}' can't be rendered", name),
+            RenderError::UnexpectedTag(ref name)   => write!(f, "Unexpected tag '{}' outside of a block", name),
+            RenderError::IncludeTooDeep(limit, ref chain) => {
+                write!(f, "Include depth limit of {} exceeded: {}", limit, chain.join(" > "))
+            },
+            RenderError::Output                    => write!(f, "Could not write the output"),
+            RenderError::LimitExceeded(limit)      => write!(f, "Exceeded the {}", limit),
+            RenderError::Variable(ref error)       => write!(f, "{}", error)
+        }
+    }
+}
+
+impl error::Error for RenderError {}
+
+impl From<fmt::Error> for RenderError {
+    fn from(_: fmt::Error) -> RenderError {
+        RenderError::Output
+    }
+}
+
+impl From<VariableError> for RenderError {
+    fn from(error: VariableError) -> RenderError {
+        RenderError::Variable(error)
+    }
+}
+
+// How long a range can be by default.
+pub const DEFAULT_MAX_RANGE: usize = 100_000;
+
+// Caps on a single render, for templates that can't be trusted not to loop or write forever. Only
+// `max_range` is set by default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderLimits {
+    // Loop iterations across the whole render, includes and all.
+    pub max_iterations: Option<usize>,
+    pub max_output_bytes: Option<usize>,
+    // Measured from when the Renderer is made. Needs a clock, which wasm32-unknown-unknown doesn't
+    // have.
+    pub timeout: Option<Duration>,
+    // Items in any one range, or that a loop takes from one. A range used as a value is built as an
+    // array, so one like `(1..100000000000)` would run out of memory before any other limit got a
+    // look at it, and a loop through it would keep the render busy for hours.
+    pub max_range: Option<usize>
+}
+
+impl Default for RenderLimits {
+    fn default() -> RenderLimits {
+        RenderLimits { max_iterations: None, max_output_bytes: None, timeout: None, max_range: Some(DEFAULT_MAX_RANGE) }
+    }
+}
+
+// Which of the RenderLimits a render ran into.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Limit {
+    Iterations(usize),
+    OutputBytes(usize),
+    Time(Duration),
+    Range(usize)
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Limit::Iterations(max)  => write!(f, "iteration limit of {}", max),
+            Limit::OutputBytes(max) => write!(f, "output limit of {} bytes", max),
+            Limit::Time(max)        => write!(f, "time limit of {:?}", max),
+            Limit::Range(max)       => write!(f, "range limit of {} items", max)
+        }
+    }
+}
+
+// What a render has used of its limits, shared with the renderers for its includes.
+struct Budget {
+    limits: RenderLimits,
+    deadline: Option<Instant>,
+    iterations: Cell<usize>,
+    bytes: Cell<usize>
+}
+
+impl Budget {
+    fn new(limits: RenderLimits) -> Budget {
+        let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+        Budget { limits, deadline, iterations: Cell::new(0), bytes: Cell::new(0) }
+    }
+
+    fn iterate(&self) -> Result<()> {
+        self.iterations.set(self.iterations.get() + 1);
+
+        match self.limits.max_iterations {
+            Some(max) if self.iterations.get() > max => Err(RenderError::LimitExceeded(Limit::Iterations(max)).into()),
+            _                                        => Ok(())
+        }
+    }
+
+    // A range too long to ever loop over, or longer than ranges are allowed to be, fails before
+    // it's built or looped through.
+    fn range(&self, length: usize) -> Result<()> {
+        match (self.limits.max_iterations, self.limits.max_range) {
+            (Some(max), _) if length > max => Err(RenderError::LimitExceeded(Limit::Iterations(max)).into()),
+            (_, Some(max)) if length > max => Err(RenderError::LimitExceeded(Limit::Range(max)).into()),
+            _                              => Ok(())
+        }
+    }
+
+    // The output is checked after each node, and the deadline once a node has rendered, so a
+    // single slow filter can still run over.
+    fn check(&self) -> Result<()> {
+        if let Some(max) = self.limits.max_output_bytes {
+            if self.bytes.get() > max { return Err(RenderError::LimitExceeded(Limit::OutputBytes(max)).into()); }
+        }
+
+        match (self.deadline, self.limits.timeout) {
+            (Some(deadline), Some(timeout)) if Instant::now() > deadline => Err(RenderError::LimitExceeded(Limit::Time(timeout)).into()),
+            _                                                            => Ok(())
+        }
+    }
+}
+
+// Block tags aren't parsed into a tree, so `if`/`unless` and their branches are followed with a
+// stack while walking the flat list of nodes.
+#[derive(Clone, Copy)]
+struct Branch {
+    // Whether the block containing this one is being rendered.
+    outer: bool,
+    active: bool,
+    taken: bool
+}
+
+// A `break` or `continue` on its way out to the loop it's in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Interrupt {
+    Break,
+    Continue
+}
+
+// Renders nodes with an environment's filters and partials. Comments are skipped, and the other
+// blocks besides `if`, `unless` and `for` fail the render. Other tags besides `assign`, `echo`,
+// `include`, `break` and `continue` render nothing.
+pub struct Renderer<'a> {
+    environment: &'a Environment,
+    branches: Vec<Branch>,
+    interrupt: Option<Interrupt>,
+    // The templates being rendered, outermost first, for when includes nest too deeply.
+    chain: Vec<String>,
+    depth: usize,
+    profiler: Option<&'a dyn Profiler>,
+    budget: Rc<Budget>
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(environment: &'a Environment) -> Renderer<'a> {
+        let budget = Rc::new(Budget::new(environment.render_limits()));
+        Renderer { environment, branches: vec![], interrupt: None, chain: vec![], depth: 0, profiler: None, budget }
+    }
+
+    // For a template registered or loaded under `name`.
+    pub fn with_name(environment: &'a Environment, name: &str) -> Renderer<'a> {
+        Renderer { chain: vec![name.to_string()], ..Renderer::new(environment) }
+    }
+
+    // Reports each node rendered from here on, including the nodes of included templates.
+    pub fn with_profiler(self, profiler: &'a dyn Profiler) -> Renderer<'a> {
+        Renderer { profiler: Some(profiler), ..self }
+    }
+
+    pub fn render(&mut self, nodes: &[Node], context: &mut Context, output: &mut dyn fmt::Write) -> Result<()> {
+        // Includes write through this, so it only needs wrapping once.
+        if self.depth == 0 && self.budget.limits.max_output_bytes.is_some() {
+            let budget = self.budget.clone();
+            return self.render_nodes(nodes, context, &mut Limited { output, budget: &budget });
+        }
+
+        self.render_nodes(nodes, context, output)
+    }
+
+    // Stops early when a `break` or `continue` is on its way out.
+    fn render_nodes(&mut self, nodes: &[Node], context: &mut Context, output: &mut dyn fmt::Write) -> Result<()> {
+        let mut index = 0;
+
+        while index < nodes.len() && self.interrupt.is_none() {
+            let node = &nodes[index];
+            let Some(profiler) = self.profiler else {
+                index = self.render_node(nodes, index, context, output)?;
+                self.budget.check()?;
+                continue;
+            };
+
+            if !self.active() && !matches!(*node, Node::Tag(..)) {
+                index += 1;
+                continue;
+            }
+
+            let started    = Instant::now();
+            let mut output = Counting { output, bytes: 0 };
+            index          = self.render_node(nodes, index, context, &mut output)?;
+
+            profiler.record(&Sample { node, template: self.chain.last().map(String::as_str), duration: started.elapsed(), bytes: output.bytes });
+            self.budget.check()?;
+        }
+
+        Ok(())
+    }
+
+    // Renders the node at `index`, and the rest of its block if it's one rendered as a whole, like
+    // a loop. Returns the index of the node to render next.
+    fn render_node(&mut self, nodes: &[Node], index: usize, context: &mut Context, output: &mut dyn fmt::Write) -> Result<usize> {
+        match nodes[index] {
+            Node::Text(ref text, _) if self.active()    => output.write_str(text).map_err(RenderError::from)?,
+            Node::Output(ref chain, _) if self.active() => self.write_value(&*self.evaluate(chain, context)?, output)?,
+            Node::Tag(ref tag, _)                       => return self.render_tag(tag, nodes, index, context, output),
+            _                                           => {}
+        }
+
+        Ok(index + 1)
+    }
+
+    // The body of a block rendered on its own, so nothing in it can reach the branches around it.
+    fn render_block(&mut self, nodes: &[Node], context: &mut Context, output: &mut dyn fmt::Write) -> Result<()> {
+        let branches = mem::take(&mut self.branches);
+        let rendered = self.render_nodes(nodes, context, output);
+        self.branches = branches;
+
+        rendered
+    }
+
+    // The value of an expression with its filters applied. An unknown filter, or one that can't
+    // take its arguments, fails the render in strict mode and is skipped otherwise. Values are
+    // borrowed from the context until a filter makes a new one.
+    pub fn evaluate<'c>(&self, chain: &'c FilterChain, context: &'c Context) -> Result<Cow<'c, Variable>> {
+        let mut value = self.expression(&chain.expression, context)?;
+
+        for filter in &chain.filters {
+            let arguments = filter.arguments.iter()
+                .map(|argument| self.expression(argument, context).map(Cow::into_owned))
+                .collect::<Result<Vec<_>>>()?;
+
+            if let Some(apply) = self.environment.filters().get_contextual(&filter.name) {
+                value = Cow::Owned(apply(&value, &arguments, self, context)?);
+                continue;
+            }
+
+            value = match self.environment.filters().get(&filter.name).map(|apply| apply(&value, &arguments)) {
+                Some(Ok(filtered)) => Cow::Owned(filtered),
+                Some(Err(error))   => self.skip_filter(error.into(), value, context)?,
+                None               => self.skip_filter(RenderError::UnknownFilter(filter.name.clone()), value, context)?
+            };
+        }
+
+        Ok(value)
+    }
+
+    fn skip_filter<'c>(&self, error: RenderError, value: Cow<'c, Variable>, context: &Context) -> Result<Cow<'c, Variable>> {
+        match context.error_mode() {
+            ErrorMode::Strict                                                 => Err(error.into()),
+            // An unknown filter is most likely a typo, so it's recorded even in lax mode.
+            ErrorMode::Lax if !matches!(error, RenderError::UnknownFilter(_)) => Ok(value),
+            _                                                                 => { context.warn(error.to_string()); Ok(value) }
+        }
+    }
+
+    pub fn expression<'c>(&self, expression: &'c Expression, context: &'c Context) -> Result<Cow<'c, Variable>> {
+        match *expression {
+            Expression::Literal(ref literal) => Ok(Cow::Borrowed(&literal.value)),
+            Expression::Variable(ref lookups, _) => {
+                let keys = lookups.iter().map(|lookup| match *lookup {
+                    Lookup::Key(ref key)     => Ok(Cow::Borrowed(key.as_str())),
+                    Lookup::Index(ref index) => Ok(Cow::Owned(self.expression(index, context)?.render_to_string()))
+                }).collect::<Result<Vec<_>>>()?;
+
+                Ok(context.fetch_keys(&keys, expression)?)
+            },
+            Expression::Range(ref start, ref end, _) => {
+                let (start, end) = (integer(&*self.expression(start, context)?), integer(&*self.expression(end, context)?));
+                self.budget.range(range_length(start, end))?;
+
+                Ok(Cow::Owned(Variable::Array((start..=end).map(Variable::Int).collect())))
+            }
+        }
+    }
+
+    pub fn test(&self, condition: &Condition, context: &Context) -> Result<bool> {
+        match *condition {
+            Condition::Test(ref expression) => Ok(self.expression(expression, context)?.is_truthy()),
+            Condition::Comparison(ref left, op, ref right) => {
+                Ok(compare(&*self.expression(left, context)?, op, &*self.expression(right, context)?)?)
+            },
+            Condition::And(ref left, ref right) => Ok(self.test(left, context)? && self.test(right, context)?),
+            Condition::Or(ref left, ref right)  => Ok(self.test(left, context)? || self.test(right, context)?)
+        }
+    }
+
+    // When the environment auto-escapes, everything but Safe strings is written out HTML-escaped.
+    fn write_value(&self, value: &Variable, output: &mut dyn fmt::Write) -> Result<()> {
+        if !self.environment.auto_escape() { return Ok(write!(output, "{}", value).map_err(RenderError::from)?); }
+
+        match *value {
+            Variable::Safe(ref value)  => output.write_str(value).map_err(RenderError::from)?,
+            Variable::Array(ref items) => items.iter().try_for_each(|item| self.write_value(item, output))?,
+            ref value                  => output.write_str(&escape_html(&value.to_string())).map_err(RenderError::from)?
+        }
+
+        Ok(())
+    }
+
+    fn active(&self) -> bool {
+        self.branches.last().is_none_or(|branch| branch.active)
+    }
+
+    fn render_tag(&mut self, tag: &Tag, nodes: &[Node], index: usize, context: &mut Context, output: &mut dyn fmt::Write) -> Result<usize> {
+        let active = self.active();
+
+        match (tag.name.as_str(), &tag.arguments) {
+            (name @ "if", TagArguments::Condition(condition)) | (name @ "unless", TagArguments::Condition(condition)) => {
+                let holds = active && self.test(condition, context)? == (name == "if");
+                self.branches.push(Branch { outer: active, active: holds, taken: holds });
+            },
+            ("elsif", _) | ("else", _) | ("endif", _) | ("endunless", _) if self.branches.is_empty() => {
+                return Err(RenderError::UnexpectedTag(tag.name.clone()).into());
+            },
+            ("elsif", TagArguments::Condition(condition)) => {
+                if let Some(branch) = self.branches.pop() {
+                    let holds = branch.outer && !branch.taken && self.test(condition, context)?;
+                    self.branches.push(Branch { active: holds, taken: branch.taken || holds, ..branch });
+                }
+            },
+            ("else", _) => {
+                if let Some(branch) = self.branches.last_mut() {
+                    branch.active = branch.outer && !branch.taken;
+                    branch.taken  = true;
+                }
+            },
+            ("endif", _) | ("endunless", _) => { self.branches.pop(); },
+            ("for", TagArguments::For(each)) if active => return self.for_loop(each, nodes, index, context, output),
+            // Their bodies were kept as text, which a raw block renders and a comment skips.
+            ("raw", _) | ("endraw", _) => {},
+            ("comment", _) | ("doc", _) => return Ok(block_end(nodes, index).1 + 1),
+            (name, _) if BLOCKS.contains(&name) => match active {
+                true  => return Err(RenderError::UnsupportedTag(tag.name.clone()).into()),
+                false => return Ok(block_end(nodes, index).1 + 1)
+            },
+            _ if !active => {},
+            ("assign", TagArguments::Assign(name, chain)) => {
+                let value = self.evaluate(chain, context)?.into_owned();
+                context.add(name, value)?;
+            },
+            ("echo", TagArguments::Output(chain)) => self.write_value(&*self.evaluate(chain, context)?, output)?,
+            ("include", TagArguments::Include(include)) => self.include(include, context, output)?,
+            ("break", _) => self.interrupt = Some(Interrupt::Break),
+            ("continue", _) => self.interrupt = Some(Interrupt::Continue),
+            _ => {}
+        }
+
+        Ok(index + 1)
+    }
+
+    // Renders the body of the loop opened at `start` once for each item, or its `else` when there
+    // aren't any. Like an include, the loop's variables only last for the iteration and anything
+    // assigned is kept. Returns the index of the node after `endfor`.
+    fn for_loop(&mut self, each: &ForLoop, nodes: &[Node], start: usize, context: &mut Context, output: &mut dyn fmt::Write) -> Result<usize> {
+        let (otherwise, end) = block_end(nodes, start);
+        let items            = self.items(each, context)?;
+        let length           = items.len();
+
+        if length == 0 {
+            if let Some(otherwise) = otherwise { self.render_block(&nodes[otherwise + 1..end], context, output)?; }
+            return Ok(end + 1);
+        }
+
+        let body = &nodes[start + 1..otherwise.unwrap_or(end)];
+        for index in 0..length {
+            self.budget.iterate()?;
+            let item = items.get(if each.reversed { length - 1 - index } else { index });
+
+            context.push()?;
+            let rendered = context.extend(vec![(each.variable.clone(), item), ("forloop".to_string(), forloop(index, length))])
+                .map_err(Error::from)
+                .and_then(|_| self.render_block(body, context, output));
+            let scope = context.pop()?;
+
+            rendered?;
+            context.extend(scope.into_iter().filter(|(key, _)| **key != *each.variable && &**key != "forloop"))?;
+            if self.interrupt.take() == Some(Interrupt::Break) { break; }
+        }
+
+        Ok(end + 1)
+    }
+
+    // What a loop goes through, after its offset and limit.
+    fn items(&self, each: &ForLoop, context: &Context) -> Result<Items> {
+        let count = |argument: &Option<Expression>, default: usize| -> Result<usize> {
+            match *argument {
+                Some(ref argument) => Ok(integer(&*self.expression(argument, context)?).max(0) as usize),
+                None               => Ok(default)
+            }
+        };
+        let (offset, limit) = (count(&each.offset, 0)?, count(&each.limit, usize::MAX)?);
+
+        if let Expression::Range(ref start, ref end, _) = each.collection {
+            let (start, end) = (integer(&*self.expression(start, context)?), integer(&*self.expression(end, context)?));
+            let length       = range_length(start, end);
+            let offset       = offset.min(length);
+            let length       = (length - offset).min(limit);
+
+            self.budget.range(length)?;
+            return Ok(Items::Range(start as i128 + offset as i128, length));
+        }
+
+        let items = match *self.expression(&each.collection, context)? {
+            Variable::Array(ref items) => items.iter().skip(offset).take(limit).cloned().collect(),
+            Variable::Object(ref object) => {
+                let mut keys = object.keys().collect::<Vec<_>>();
+                keys.sort();
+                keys.into_iter().skip(offset).take(limit).map(|key| Variable::Array(vec![Variable::Str(key.as_str().into()), object[key].clone()])).collect()
+            },
+            ref value @ Variable::Str(_) | ref value @ Variable::Safe(_) => vec![value.clone()].into_iter().skip(offset).take(limit).collect(),
+            _ => vec![]
+        };
+
+        Ok(Items::List(items))
+    }
+
+    // As in Liquid, the partial sees the including template's variables, and its keyword arguments
+    // and `with`/`for` value only last while it renders. Anything it assigns is kept.
+    fn include(&self, include: &Include, context: &mut Context, output: &mut dyn fmt::Write) -> Result<()> {
+        let name      = self.expression(&include.template, context)?.render_to_string();
+        let mut chain = self.chain.clone();
+        chain.push(name.clone());
+
+        let limit = self.environment.max_include_depth();
+        if self.depth >= limit { return Err(RenderError::IncludeTooDeep(limit, chain).into()); }
+
+        let template = self.environment.load(&name)?;
+        let variable = include.alias.clone().unwrap_or_else(|| name.rsplit('/').next().unwrap_or_default().to_string());
+
+        let mut locals = vec![];
+        for (key, value) in include.arguments.iter() {
+            locals.push((key.to_string(), self.expression(value, context)?.into_owned()));
+        }
+
+        let values = match include.value {
+            Some(IncludeValue::With(ref value)) => vec![Some(self.expression(value, context)?.into_owned())],
+            Some(IncludeValue::For(ref values)) => match self.expression(values, context)?.into_owned() {
+                Variable::Array(items) => items.into_iter().map(Some).collect(),
+                value                  => vec![Some(value)]
+            },
+            None => vec![None]
+        };
+
+        for value in values {
+            if let Some(IncludeValue::For(_)) = include.value { self.budget.iterate()?; }
+
+            let mut variables = locals.clone();
+            variables.extend(value.map(|value| (variable.clone(), value)));
+            let names = variables.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>();
+
+            context.push()?;
+            let mut renderer = Renderer { environment: self.environment, branches: vec![], interrupt: None, chain: chain.clone(), depth: self.depth + 1, profiler: self.profiler, budget: self.budget.clone() };
+            let rendered     = context.extend(variables)
+                .map_err(Error::from)
+                .and_then(|_| renderer.render(&template.nodes, context, output));
+            let scope = context.pop()?;
+
+            // The chain already says where it happened.
+            match rendered {
+                Err(error @ Error::Render(RenderError::IncludeTooDeep(..))) => return Err(error),
+                rendered                                                    => rendered.map_err(|error| error.in_template(name.as_str()))?
+            }
+            context.extend(scope.into_iter().filter(|(key, _)| !names.iter().any(|name| **name == **key)))?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) fn compare(left: &Variable, op: CompareOp, right: &Variable) -> VariableResult<bool> {
+    let ordering = |expected: &[Ordering]| -> VariableResult<bool> {
+        Ok(left.compare(right)?.is_some_and(|ordering| expected.contains(&ordering)))
+    };
+
+    match op {
+        CompareOp::Eq       => Ok(left == right),
+        CompareOp::Ne       => Ok(left != right),
+        CompareOp::Lt       => ordering(&[Ordering::Less]),
+        CompareOp::Le       => ordering(&[Ordering::Less, Ordering::Equal]),
+        CompareOp::Gt       => ordering(&[Ordering::Greater]),
+        CompareOp::Ge       => ordering(&[Ordering::Greater, Ordering::Equal]),
+        CompareOp::Contains => Ok(left.contains(right))
+    }
+}
+
+// How many items there are from `start` to `end`, which is more than a usize can hold only on
+// targets where it's smaller than 64 bits.
+fn range_length(start: i64, end: i64) -> usize {
+    (end as i128 - start as i128 + 1).clamp(0, usize::MAX as i128) as usize
+}
+
+// Range bounds are read as numbers, with floats truncated.
+fn integer(value: &Variable) -> i64 {
+    match value.to_number() {
+        Variable::Int(value)   => value,
+        Variable::Float(value) => value as i64,
+        _                      => 0
+    }
+}
+
+// Where the block opened at `start` ends, and where its own `else` is if it has one. End tags that
+// don't close the innermost open block are passed over, and a block left open runs to the end of
+// the nodes.
+fn block_end(nodes: &[Node], start: usize) -> (Option<usize>, usize) {
+    let mut open      = vec![];
+    let mut otherwise = None;
+
+    for (index, node) in nodes.iter().enumerate().skip(start) {
+        let Node::Tag(ref tag, _) = *node else { continue };
+        let name                  = tag.name.as_str();
+
+        if BLOCKS.contains(&name) {
+            open.push(name);
+        } else if name.strip_prefix("end").is_some_and(|block| open.last() == Some(&block)) {
+            open.pop();
+            if open.is_empty() { return (otherwise, index); }
+        } else if name == "else" && open.len() == 1 {
+            otherwise = otherwise.or(Some(index));
+        }
+    }
+
+    (otherwise, nodes.len())
+}
+
+// What a `for` loop goes through. A range is counted through rather than built, so it's only its
+// first item and how many there are.
+enum Items {
+    Range(i128, usize),
+    List(Vec<Variable>)
+}
+
+impl Items {
+    fn len(&self) -> usize {
+        match *self {
+            Items::Range(_, length) => length,
+            Items::List(ref items)  => items.len()
+        }
+    }
+
+    fn get(&self, index: usize) -> Variable {
+        match *self {
+            Items::Range(start, _) => Variable::Int((start + index as i128) as i64),
+            Items::List(ref items) => items[index].clone()
+        }
+    }
+}
+
+// The `forloop` object for the body of a loop.
+fn forloop(index: usize, length: usize) -> Variable {
+    let values = vec![
+        ("index", Variable::Int(index as i64 + 1)),
+        ("index0", Variable::Int(index as i64)),
+        ("rindex", Variable::Int((length - index) as i64)),
+        ("rindex0", Variable::Int((length - index - 1) as i64)),
+        ("first", Variable::Bool(index == 0)),
+        ("last", Variable::Bool(index + 1 == length)),
+        ("length", Variable::Int(length as i64))
+    ];
+
+    Variable::Object(values.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+// Drops whatever would take the output past its limit, so it never grows beyond it. The render
+// fails once the node doing the writing is done.
+struct Limited<'a, 'b> {
+    output: &'a mut dyn fmt::Write,
+    budget: &'b Budget
+}
+
+impl<'a, 'b> fmt::Write for Limited<'a, 'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = self.budget.bytes.get() + s.len();
+        self.budget.bytes.set(bytes);
+
+        match self.budget.limits.max_output_bytes {
+            Some(max) if bytes > max => Ok(()),
+            _                        => self.output.write_str(s)
+        }
+    }
+}
+
+// Counts what a node writes, for the profiler.
+struct Counting<'a> {
+    output: &'a mut dyn fmt::Write,
+    bytes: usize
+}
+
+impl<'a> fmt::Write for Counting<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.bytes += s.len();
+        self.output.write_str(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use template::Template;
+
+    fn render_in(environment: &Environment, source: &str, context: &mut Context) -> String {
+        environment.render_template(&Template::parse(source).unwrap(), context).unwrap()
+    }
+
+    fn render_with(environment: &Environment, source: &str) -> Result<String> {
+        environment.render_template(&Template::parse(source)?, &mut Context::new())
+    }
+
+    fn render(source: &str, context: &mut Context) -> Result<String> {
+        let environment = Environment::new();
+        let mut output  = String::new();
+        Renderer::new(&environment).render(&Template::parse(source)?.nodes, context, &mut output)?;
+
+        Ok(output)
+    }
+
+    #[test]
+    fn evaluate_borrows_values_until_a_filter_runs() {
+        let environment = Environment::new();
+        let renderer    = Renderer::new(&environment);
+        let template    = Template::parse("{{ items }}{{ items.first | escape }}").unwrap();
+        let mut context = Context::new();
+        context.add("items", variable!(["a", "b"])).unwrap();
+
+        let chains = template.nodes.iter().filter_map(|node| match *node { Node::Output(ref chain, _) => Some(chain), _ => None }).collect::<Vec<_>>();
+        assert!(matches!(renderer.evaluate(chains[0], &context), Ok(Cow::Borrowed(&Variable::Array(_)))));
+        assert!(matches!(renderer.evaluate(chains[1], &context), Ok(Cow::Owned(Variable::Safe(_)))));
+    }
+
+    #[test]
+    fn renders_text_outputs_and_assignments() {
+        let mut context = Context::new();
+        context.add("name", "World".into()).unwrap();
+
+        assert_eq!("Hello World! 3", render("Hello {{ name }}! {% assign n = 1 | plus: 2 %}{{ n }}", &mut context).unwrap());
+        assert_eq!(Variable::Int(3), context.get("n").unwrap());
+        assert_eq!("123", render("{% echo (1..3) %}", &mut context).unwrap());
+    }
+
+    #[test]
+    fn bracketed_lookups_read_variables_at_render_time() {
+        let mut context = Context::new();
+        context.add("items", variable!(["a", "b", "c"])).unwrap();
+        context.add("user", variable!({"name": "Ada", "tags": ["x", "y"], "last": 2})).unwrap();
+        context.add("key", "name".into()).unwrap();
+
+        assert_eq!("b c Ada y", render("{% assign i = 1 %}{{ items[i] }} {{ items[user.last] }} {{ user[key] }} {{ user['tags'][i] }}", &mut context).unwrap());
+
+        context.set_error_mode(ErrorMode::Strict);
+        assert_eq!("Undefined variable 'items[n]'", render("{% assign n = 5 %}{{ items[n] }}", &mut context).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn renders_conditional_branches() {
+        let source = "{% if n > 2 %}big{% elsif n == 2 %}two{% else %}small{% endif %}{% unless n == 2 %}!{% endunless %}";
+
+        for &(n, expected) in &[(3, "big!"), (2, "two"), (1, "small!")] {
+            let mut context = Context::new();
+            context.add("n", n.into()).unwrap();
+            assert_eq!(expected, render(source, &mut context).unwrap());
+        }
+    }
+
+    #[test]
+    fn for_loops_count_through_ranges() {
+        let mut context = Context::new();
+        context.add("items", variable!(["a", "b", "c"])).unwrap();
+        context.add("n", 3.into()).unwrap();
+
+        assert_eq!("1 2 3 ", render("{% for i in (1..n) %}{{ i }} {% endfor %}", &mut context).unwrap());
+        assert_eq!("0:a 1:b 2:c", render("{% for i in (0..n) limit: n %}{{ i }}:{{ items[i] }}{% unless forloop.last %} {% endunless %}{% endfor %}", &mut context).unwrap());
+        assert_eq!("5432", render("{% for i in (1..6) reversed offset: 1 limit: 4 %}{% if i > 1 %}{{ i }}{% else %}{{ i }}{% endif %}{% endfor %}", &mut context).unwrap());
+        assert_eq!("none", render("{% for i in (3..1) %}{{ i }}{% else %}none{% endfor %}", &mut context).unwrap());
+        assert_eq!("-9223372036854775806-9223372036854775805", render("{% for i in (-9223372036854775806..9223372036854775807) limit: 2 %}{{ i }}{% endfor %}", &mut context).unwrap());
+    }
+
+    #[test]
+    fn for_loops_go_through_arrays_and_objects() {
+        let mut context = Context::new();
+        context.add("items", variable!(["a", "b", "c"])).unwrap();
+        context.add("user", variable!({"name": "Ada", "age": 36})).unwrap();
+
+        assert_eq!("1/3a 2/3b 3/3c ", render("{% for item in items %}{{ forloop.index }}/{{ forloop.length }}{{ item }} {% endfor %}", &mut context).unwrap());
+        assert_eq!("age=36 name=Ada ", render("{% for pair in user %}{{ pair[0] }}={{ pair[1] }} {% endfor %}", &mut context).unwrap());
+        assert_eq!("ac", render("{% for item in items %}{% if item == 'b' %}{% continue %}{% endif %}{{ item }}{% endfor %}", &mut context).unwrap());
+        assert_eq!("a", render("{% for item in items %}{% if item == 'b' %}{% break %}{% endif %}{{ item }}{% endfor %}", &mut context).unwrap());
+        assert_eq!("", render("{% if false %}{% for item in items %}{{ item }}{% endfor %}{% endif %}", &mut context).unwrap());
+        assert_eq!("c!", render("{% for item in items %}{% assign last = item %}{% endfor %}{{ last }}{% if item %}?{% endif %}!", &mut context).unwrap());
+    }
+
+    #[test]
+    fn comments_are_skipped_and_raw_blocks_written_as_they_are() {
+        let mut context = Context::new();
+
+        assert_eq!("ab", render("a{% comment %}secret {{ 'x' }}{% if %}{% endcomment %}b", &mut context).unwrap());
+        assert_eq!("ac", render("a{% comment %}{% comment %}b{% endcomment %}{% endcomment %}c", &mut context).unwrap());
+        assert_eq!("{{ x }}{% if %}", render("{% raw %}{{ x }}{% if %}{% endraw %}", &mut context).unwrap());
+        assert_eq!("{% comment %}", render("{% raw %}{% comment %}{% endraw %}", &mut context).unwrap());
+    }
+
+    #[test]
+    fn blocks_that_cant_be_rendered_fail() {
+        let error = |source| render(source, &mut Context::new()).unwrap_err().to_string();
+
+        assert_eq!("Tag 'case' can't be rendered", error("{% case 1 %}{% when 1 %}one{% when 2 %}two{% endcase %}"));
+        assert_eq!("Tag 'capture' can't be rendered", error("{% capture x %}hi{% endcapture %}[{{ x }}]"));
+        assert_eq!("Tag 'tablerow' can't be rendered", error("{% tablerow i in (1..2) %}{{ i }}{% endtablerow %}"));
+        assert_eq!("", render("{% if false %}{% case 1 %}{% when 1 %}one{% endcase %}{% endif %}", &mut Context::new()).unwrap());
+    }
+
+    #[test]
+    fn branch_tags_outside_their_block_fail() {
+        let error = |source| render(source, &mut Context::new()).unwrap_err().to_string();
+
+        assert_eq!("Unexpected tag 'elsif' outside of a block", error("{% elsif true %}x{% endif %}"));
+        assert_eq!("Unexpected tag 'else' outside of a block", error("x{% else %}y"));
+        assert_eq!("Unexpected tag 'endif' outside of a block", error("{% for i in (1..2) %}{% endif %}{% endfor %}"));
+    }
+
+    #[test]
+    fn nested_branches_follow_their_parent() {
+        let mut context = Context::new();
+        let source      = "{% if false %}{% if true %}a{% else %}b{% endif %}{% else %}c{% endif %}";
+
+        assert_eq!("c", render(source, &mut context).unwrap());
+    }
+
+    #[test]
+    fn unknown_filters_are_skipped_and_recorded_in_lax_mode() {
+        let mut context = Context::new();
+        assert_eq!("2 abc", render("{{ 1 | shout | plus: 1 }} {{ 'abc' | size }}", &mut context).unwrap());
+        assert_eq!(vec!["Unknown filter 'shout'", "Unknown filter 'size'"], context.warnings());
+    }
+
+    #[test]
+    fn unknown_filters_are_recorded_in_warn_mode() {
+        let mut context = Context::new();
+        context.set_error_mode(ErrorMode::Warn);
+
+        assert_eq!("2", render("{{ 1 | shout | plus: 1 }}", &mut context).unwrap());
+        assert_eq!(vec!["Unknown filter 'shout'"], context.take_warnings());
+    }
+
+    #[test]
+    fn unknown_filters_fail_in_strict_mode() {
+        let mut context = Context::new();
+        context.set_error_mode(ErrorMode::Strict);
+
+        let error = render("{{ 1 | shout }}", &mut context).unwrap_err();
+        assert_eq!("Unknown filter 'shout'", error.to_string());
+    }
+
+    #[test]
+    fn bad_filter_arguments_are_skipped_in_lax_mode() {
+        let mut context = Context::new();
+        assert_eq!("7", render("{{ 6 | divided_by: 0 | plus: 1 }}", &mut context).unwrap());
+        assert!(context.warnings().is_empty());
+    }
+
+    #[test]
+    fn bad_filter_arguments_are_recorded_in_warn_mode() {
+        let mut context = Context::new();
+        context.set_error_mode(ErrorMode::Warn);
+
+        assert_eq!("7", render("{{ 6 | divided_by: 0 | plus: 1 }}", &mut context).unwrap());
+        assert_eq!(vec!["Divided by 0"], context.take_warnings());
+    }
+
+    #[test]
+    fn bad_filter_arguments_fail_in_strict_mode() {
+        let mut context = Context::new();
+        context.set_error_mode(ErrorMode::Strict);
+
+        assert_eq!("Divided by 0", render("{{ 6 | divided_by: 0 }}", &mut context).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn includes_share_variables() {
+        let mut environment = Environment::new();
+        let mut context     = Context::new();
+        environment.add_template("greeting", "{% assign greeted = true %}Hi {{ name }}").unwrap();
+        environment.add_template("page", "{% assign name = 'Ada' %}{% include 'greeting' %}{% if greeted %}!{% endif %}").unwrap();
+
+        assert_eq!("Hi Ada!", environment.render("page", &mut context).unwrap());
+        assert_eq!("Unknown template 'missing'", render("{% include 'missing' %}", &mut context).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn includes_take_values_and_keyword_arguments() {
+        let mut environment = Environment::new();
+        let mut context     = Context::new();
+        context.add("items", variable!(["a", "b"])).unwrap();
+        environment.add_template("snippets/product", "<{{ product }}{{ item }}{% if class %} {{ class }}{% endif %}>").unwrap();
+
+        let render = |source: &str, context: &mut Context| environment.render_template(&Template::parse(source).unwrap(), context).unwrap();
+
+        assert_eq!("<a>", render("{% include 'snippets/product' with items.first %}", &mut context));
+        assert_eq!("<a big><b big>", render("{% include 'snippets/product' for items, class: 'big' %}", &mut context));
+        assert_eq!("<a>", render("{% include 'snippets/product' with 'a' as item %}", &mut context));
+        assert!(!context.has_key("product") && !context.has_key("class"));
+    }
+
+    #[test]
+    fn include_depth_is_limited() {
+        let mut environment = Environment::new();
+        let mut context     = Context::new();
+        context.set_error_mode(ErrorMode::Strict);
+        environment.set_max_include_depth(3);
+        environment.add_template("a", "a{% include 'b' %}").unwrap();
+        environment.add_template("b", "b{% include 'a' %}").unwrap();
+        environment.add_template("c", "{% include 'd' %}").unwrap();
+        environment.add_template("d", "{{ 1 | divided_by: 0 }}").unwrap();
+
+        let error = environment.render("a", &mut context).unwrap_err();
+        assert_eq!("a: Include depth limit of 3 exceeded: a > b > a > b > a", error.to_string());
+        assert_eq!("c: d: Divided by 0", environment.render("c", &mut context).unwrap_err().to_string());
+
+        environment.set_max_include_depth(0);
+        assert_eq!("c: Include depth limit of 0 exceeded: c > d", environment.render("c", &mut context).unwrap_err().to_string());
+    }
+
+    #[test]
+    fn render_limits_stop_runaway_templates() {
+        let mut environment = Environment::new();
+        let mut context     = Context::new();
+        environment.add_template("item", "{{ item }}").unwrap();
+        environment.add_template("list", "{% include 'item' for (1..3) %}{% include 'item' for (4..5) %}").unwrap();
+
+        environment.set_render_limits(RenderLimits { max_iterations: Some(4), ..RenderLimits::default() });
+        assert_eq!("list: Exceeded the iteration limit of 4", environment.render("list", &mut context).unwrap_err().to_string());
+        assert_eq!("Exceeded the iteration limit of 4", render_with(&environment, "{{ (1..100000000) | size }}").unwrap_err().to_string());
+        assert_eq!("1234", render_with(&environment, "{% include 'item' for (1..4) %}").unwrap());
+
+        environment.set_render_limits(RenderLimits { max_output_bytes: Some(3), ..RenderLimits::default() });
+        let mut output = String::new();
+        let error      = environment.render_into("list", &mut context, &mut output).unwrap_err();
+        assert_eq!("list: item: Exceeded the output limit of 3 bytes", error.to_string());
+        assert_eq!("123", output);
+
+        environment.set_render_limits(RenderLimits { timeout: Some(Duration::ZERO), ..RenderLimits::default() });
+        assert_eq!(Error::Render(RenderError::LimitExceeded(Limit::Time(Duration::ZERO))), render_with(&environment, "a").unwrap_err());
+    }
+
+    #[test]
+    fn every_loop_iteration_counts_against_the_limit() {
+        let mut environment = Environment::new();
+        environment.set_render_limits(RenderLimits { max_iterations: Some(5), ..RenderLimits::default() });
+
+        assert_eq!("12345", render_with(&environment, "{% for i in (1..3) %}{{ i }}{% endfor %}{% for i in (4..5) %}{{ i }}{% endfor %}").unwrap());
+        assert_eq!("Exceeded the iteration limit of 5", render_with(&environment, "{% for i in (1..3) %}{% for j in (1..2) %}{% endfor %}{% endfor %}").unwrap_err().to_string());
+        assert_eq!("Exceeded the iteration limit of 5", render_with(&environment, "{% for i in (1..100000000000) %}{% endfor %}").unwrap_err().to_string());
+        assert_eq!("123", render_with(&environment, "{% for i in (1..100000000000) limit: 3 %}{{ i }}{% endfor %}").unwrap());
+    }
+
+    #[test]
+    fn ranges_are_capped_by_default() {
+        let mut environment = Environment::new();
+        let error           = render_with(&environment, "{{ (1..100000000000) | plus: 0 }}").unwrap_err();
+
+        assert_eq!(Error::Render(RenderError::LimitExceeded(Limit::Range(DEFAULT_MAX_RANGE))), error);
+        assert_eq!("Exceeded the range limit of 100000 items", error.to_string());
+        assert!(render_with(&environment, "{{ (-9223372036854775807..9223372036854775807) }}").is_err());
+        assert_eq!("", render_with(&environment, "{{ (5..1) }}").unwrap());
+        assert_eq!(error, render_with(&environment, "{% for i in (1..100000000000) %}{% endfor %}").unwrap_err());
+        assert_eq!("99999", render_with(&environment, "{% for i in (1..100000000000) offset: 99998 limit: 1 %}{{ i }}{% endfor %}").unwrap());
+
+        environment.set_render_limits(RenderLimits { max_range: Some(3), ..RenderLimits::default() });
+        assert_eq!("123", render_with(&environment, "{{ (1..3) }}").unwrap());
+        assert!(render_with(&environment, "{{ (1..4) }}").is_err());
+    }
+
+    #[test]
+    fn auto_escape_leaves_safe_strings_alone() {
+        let mut environment = Environment::new();
+        let mut context     = Context::new();
+        context.add("comment", "<script>alert('hi')</script>".into()).unwrap();
+        context.add("signature", Variable::Safe("<em>Ada</em>".into())).unwrap();
+        context.add("tags", variable!(["<b>", Variable::Safe("<i>".into())])).unwrap();
+
+        let source = "{{ comment }} {{ signature }} {{ comment | raw }} {% echo tags %} {{ '&' | escape }}";
+        assert_eq!("<script>alert('hi')</script> <em>Ada</em> <script>alert('hi')</script> <b><i> &amp;", render_in(&environment, source, &mut context));
+
+        environment.set_auto_escape(true);
+        assert_eq!(
+            "&lt;script&gt;alert(&#39;hi&#39;)&lt;/script&gt; <em>Ada</em> <script>alert('hi')</script> &lt;b&gt;<i> &amp;",
+            render_in(&environment, source, &mut context)
+        );
+    }
+
+    #[test]
+    fn write_failures_stop_the_render() {
+        struct Full;
+
+        impl fmt::Write for Full {
+            fn write_str(&mut self, _: &str) -> fmt::Result {
+                Err(fmt::Error)
+            }
+        }
+
+        let environment = Environment::new();
+        let template    = Template::parse("a").unwrap();
+        let error       = environment.render_template_into(&template, &mut Context::new(), &mut Full).unwrap_err();
+
+        assert_eq!(Error::Render(RenderError::Output), error);
+    }
+}
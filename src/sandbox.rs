@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+
+use ast::{FilterChain,Node,TagArguments};
+use lexer::Span;
+use parser::{ParseError,ParseResult};
+
+// The tags that only make sense inside another, allowed along with any tag they can belong to.
+const COMPANIONS: &[(&str, &[&str])] = &[
+    ("elsif", &["if"]),
+    ("else", &["if", "unless", "case", "for"]),
+    ("when", &["case"]),
+    ("break", &["for"]),
+    ("continue", &["for"])
+];
+
+// The only tags and filters a template may use, say for templates written by users. Anything else
+// fails the parse, naming what wasn't allowed. A block's closing tag is allowed with the block, and
+// so are `else` and friends.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sandbox {
+    tags: HashSet<String>,
+    filters: HashSet<String>
+}
+
+impl Sandbox {
+    pub fn new(tags: &[&str], filters: &[&str]) -> Sandbox {
+        Sandbox {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            filters: filters.iter().map(|filter| filter.to_string()).collect()
+        }
+    }
+
+    pub fn allow_tag(&mut self, name: &str) {
+        self.tags.insert(name.to_string());
+    }
+
+    pub fn allow_filter(&mut self, name: &str) {
+        self.filters.insert(name.to_string());
+    }
+
+    pub fn allows_tag(&self, name: &str) -> bool {
+        if self.tags.contains(name) { return true; }
+        if name.strip_prefix("end").is_some_and(|block| self.tags.contains(block)) { return true; }
+
+        COMPANIONS.iter().any(|&(companion, blocks)| companion == name && blocks.iter().any(|block| self.tags.contains(*block)))
+    }
+
+    pub fn allows_filter(&self, name: &str) -> bool {
+        self.filters.contains(name)
+    }
+
+    // Errors at the first tag or filter in `node` that isn't allowed. Filters are reported at the
+    // span of the node they're in.
+    pub fn check(&self, node: &Node) -> ParseResult<()> {
+        match *node {
+            Node::Text(..)                => Ok(()),
+            Node::Output(ref chain, span) => self.check_filters(chain, span),
+            Node::Tag(ref tag, span) => {
+                if !self.allows_tag(&tag.name) { return Err(ParseError::ForbiddenTag(tag.name.clone(), span)); }
+
+                match tag.arguments {
+                    TagArguments::Assign(_, ref chain) | TagArguments::Output(ref chain) => self.check_filters(chain, span),
+                    _                                                                    => Ok(())
+                }
+            }
+        }
+    }
+
+    fn check_filters(&self, chain: &FilterChain, span: Span) -> ParseResult<()> {
+        match chain.filters.iter().find(|filter| !self.allows_filter(&filter.name)) {
+            Some(filter) => Err(ParseError::ForbiddenFilter(filter.name.clone(), span)),
+            None         => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use environment::Environment;
+    use parser::ParserOptions;
+    use template::Template;
+
+    #[test]
+    fn sandbox_allows_blocks_with_their_companions() {
+        let sandbox = Sandbox::new(&["if", "for"], &["upcase"]);
+
+        for name in &["if", "elsif", "else", "endif", "for", "break", "endfor"] {
+            assert!(sandbox.allows_tag(name), "{} should be allowed", name);
+        }
+
+        for name in &["unless", "endunless", "when", "include", "end"] {
+            assert!(!sandbox.allows_tag(name), "{} should be forbidden", name);
+        }
+    }
+
+    #[test]
+    fn forbidden_tags_and_filters_fail_the_parse() {
+        let options = ParserOptions { sandbox: Some(Sandbox::new(&["if", "assign"], &["upcase"])), ..ParserOptions::default() };
+
+        assert!(Template::parse_with("{% if a %}{{ a | upcase }}{% else %}{% assign b = a | upcase %}{% endif %}", &options).is_ok());
+        assert_eq!("Tag 'include' is not allowed at line 2, column 1", Template::parse_with("\n{% include 'secrets' %}", &options).unwrap_err().to_string());
+        assert_eq!(
+            ParseError::ForbiddenFilter("append".to_string(), Span { start: 3, end: 35, line: 1, col: 4 }),
+            Template::parse_with("ok {% assign b = a | append: 'x' %}", &options).unwrap_err()
+        );
+
+        let mut env = Environment::new();
+        env.set_sandbox(Sandbox::new(&[], &["plus"]));
+        assert_eq!("email: Filter 'minus' is not allowed at line 1, column 1", env.add_template("email", "{{ 1 | plus: 2 | minus: 1 }}").unwrap_err().to_string());
+        assert_eq!("3", env.render_template(&env.parse("{{ 1 | plus: 2 }}").unwrap(), &mut Default::default()).unwrap());
+    }
+}
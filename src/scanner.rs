@@ -1,25 +1,136 @@
 use std::cell::Cell;
 use std::cmp;
+use std::str;
 
-use regex::{Captures,Regex};
+use regex::{bytes,Captures,Regex};
 
-pub struct Scanner<'t> {
-    source: &'t str,
-    index: Cell<usize>,
-    length: usize
+// What a scanner can walk over. Text is matched with `Regex`; bytes with `regex::bytes::Regex`,
+// and only ASCII whitespace counts as whitespace in them.
+pub trait Input {
+    type Pattern;
+
+    fn as_bytes(&self) -> &[u8];
+    fn range(&self, from: usize, to: usize) -> &Self;
+    fn find(&self, pattern: &Self::Pattern) -> Option<(usize, usize)>;
+    // How many bytes the first character takes, or None when there's nothing left.
+    fn char_len(&self) -> Option<usize>;
+    fn leading_whitespace(&self) -> usize;
+}
+
+impl Input for str {
+    type Pattern = Regex;
+
+    fn as_bytes(&self) -> &[u8] {
+        str::as_bytes(self)
+    }
+
+    fn range(&self, from: usize, to: usize) -> &str {
+        &self[from..to]
+    }
+
+    fn find(&self, pattern: &Regex) -> Option<(usize, usize)> {
+        pattern.find(self).map(|found| (found.start(), found.end()))
+    }
+
+    fn char_len(&self) -> Option<usize> {
+        self.chars().next().map(char::len_utf8)
+    }
+
+    fn leading_whitespace(&self) -> usize {
+        self.len() - self.trim_start_matches(char::is_whitespace).len()
+    }
+}
+
+// A byte that doesn't start valid UTF-8 is a character of its own.
+impl Input for [u8] {
+    type Pattern = bytes::Regex;
+
+    fn as_bytes(&self) -> &[u8] {
+        self
+    }
+
+    fn range(&self, from: usize, to: usize) -> &[u8] {
+        &self[from..to]
+    }
+
+    fn find(&self, pattern: &bytes::Regex) -> Option<(usize, usize)> {
+        pattern.find(self).map(|found| (found.start(), found.end()))
+    }
+
+    fn char_len(&self) -> Option<usize> {
+        let first = *self.first()?;
+        let width = match first { 0xC0..=0xDF => 2, 0xE0..=0xEF => 3, 0xF0..=0xF7 => 4, _ => 1 };
+
+        match self.get(..width).map(str::from_utf8) {
+            Some(Ok(_)) => Some(width),
+            _           => Some(1)
+        }
+    }
+
+    fn leading_whitespace(&self) -> usize {
+        self.iter().take_while(|byte| byte.is_ascii_whitespace()).count()
+    }
+}
+
+pub struct Scanner<'t, T: ?Sized + Input + 't = str> {
+    source: &'t T,
+    pos: Cell<Pos>,
+    length: usize,
+    last: Cell<Option<Match>>
+}
+
+// A place to `rewind` the scanner to. Lines and columns (in characters) are 1-based.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pos {
+    index: usize,
+    line: usize,
+    col: usize
+}
+
+impl Default for Pos {
+    fn default() -> Pos {
+        Pos { index: 0, line: 1, col: 1 }
+    }
+}
+
+// The last successful scan: where it started, for `unscan`, and the byte range it matched.
+#[derive(Clone, Copy)]
+struct Match {
+    start: Pos,
+    from: usize,
+    to: usize
 }
 
-impl<'t> Scanner<'t> {
-    pub fn new<'a>(source: &'a str) -> Scanner<'a> {
+impl<'t, T: ?Sized + Input> Scanner<'t, T> {
+    pub fn new(source: &'t T) -> Scanner<'t, T> {
+        Scanner::resume(source, Pos::default())
+    }
+
+    // Picks up at `pos` from a scanner over the start of `source`, for buffers that are filled a
+    // piece at a time and so have to be scanned again once they've grown.
+    pub fn resume(source: &'t T, pos: Pos) -> Scanner<'t, T> {
         Scanner {
-            source: source,
-            index: Cell::new(0),
-            length: source.len()
+            source,
+            pos: Cell::new(pos),
+            length: source.as_bytes().len(),
+            last: Cell::new(None)
         }
     }
 
+    pub fn source(&self) -> &'t T {
+        self.source
+    }
+
     pub fn position(&self) -> usize {
-        cmp::min(self.index.get(), self.length)
+        cmp::min(self.pos.get().index, self.length)
+    }
+
+    pub fn line(&self) -> usize {
+        self.pos.get().line
+    }
+
+    pub fn col(&self) -> usize {
+        self.pos.get().col
     }
 
     pub fn is_eos(&self) -> bool {
@@ -27,61 +138,176 @@ impl<'t> Scanner<'t> {
     }
 
     pub fn skip(&self, n: usize) {
-        let pos = cmp::min(self.position() + n, self.length);
-        self.index.set(pos);
+        self.advance_to(cmp::min(self.position() + n, self.length));
+    }
+
+    pub fn checkpoint(&self) -> Pos {
+        self.pos.get()
+    }
+
+    pub fn rewind(&self, pos: Pos) {
+        self.pos.set(pos);
+        self.last.set(None);
+    }
+
+    pub fn reset(&self) {
+        self.rewind(Pos::default());
     }
 
-    pub fn rest(&self) -> Option<&str> {
+    pub fn terminate(&self) {
+        self.last.set(None);
+        self.advance_to(self.length);
+    }
+
+    // Undoes the last scan of any kind, whitespace included. False when the last one failed or
+    // there's nothing to undo.
+    pub fn unscan(&self) -> bool {
+        match self.last.take() {
+            Some(last) => { self.pos.set(last.start); true },
+            None       => false
+        }
+    }
+
+    // The source before the last successful scan's match, without the whitespace `scan` skips.
+    pub fn pre_match(&self) -> Option<&'t T> {
+        self.last.get().map(|last| self.source.range(0, last.from))
+    }
+
+    pub fn post_match(&self) -> Option<&'t T> {
+        self.last.get().map(|last| self.source.range(last.to, self.length))
+    }
+
+    pub fn rest(&self) -> Option<&T> {
         if self.is_eos() { return None; }
         Some(self.raw())
     }
 
-    pub fn get_char(&self) -> Option<&str> {
-        if self.is_eos() { return None; }
+    // A whole character, however many bytes it takes. Combining marks are characters of their own.
+    pub fn get_char(&self) -> Option<&T> {
+        let start = self.checkpoint();
+        let rest  = self.raw();
+        self.last.set(None);
 
-        let rest = self.raw();
-        let chr  = &rest[0..1];
-        self.skip(chr.len());
+        let chr = rest.range(0, rest.char_len()?);
+        self.skip(chr.as_bytes().len());
+        self.last.set(Some(Match { start, from: start.index, to: self.position() }));
 
         Some(chr)
     }
 
-    pub fn scan(&self, pattern: &Regex) -> Option<&str> {
+    pub fn scan(&self, pattern: &T::Pattern) -> Option<&T> {
+        let start = self.checkpoint();
         self.skip_whitespace();
-        let rest = self.raw();
+        self.last.set(None);
+
+        let rest     = self.raw();
+        let (_, end) = rest.find(pattern)?;
+        self.skip_match(start, rest, end);
+
+        Some(rest.range(0, end))
+    }
 
-        pattern.captures(rest).and_then(|captures| self.get_match(rest, &captures))
+    // `scan` for a fixed string, with a prefix compare instead of a regex.
+    pub fn scan_str(&self, literal: &T) -> Option<&T> {
+        let start = self.checkpoint();
+        self.skip_whitespace();
+        self.last.set(None);
+
+        let rest   = self.raw();
+        let length = literal.as_bytes().len();
+        if !rest.as_bytes().starts_with(literal.as_bytes()) { return None; }
+        self.skip_match(start, rest, length);
+
+        Some(rest.range(0, length))
     }
 
-    pub fn check(&self, pattern: &Regex) -> bool {
+    pub fn check_str(&self, literal: &T) -> bool {
         self.skip_whitespace();
+        self.raw().as_bytes().starts_with(literal.as_bytes())
+    }
+
+    // Everything up to and including the next match of `pattern`, wherever it is. Unlike `scan`,
+    // whitespace is left alone.
+    pub fn scan_until(&self, pattern: &T::Pattern) -> Option<&T> {
+        let start = self.checkpoint();
+        let rest  = self.raw();
+        self.last.set(None);
+
+        let (from, to) = rest.find(pattern)?;
+        self.skip(to);
+        self.last.set(Some(Match { start, from: start.index + from, to: start.index + to }));
+
+        Some(rest.range(0, to))
+    }
+
+    // Like `scan_until`, returning the new position instead.
+    pub fn skip_until(&self, pattern: &T::Pattern) -> Option<usize> {
+        self.scan_until(pattern).map(|_| self.position())
+    }
+
+    pub fn check(&self, pattern: &T::Pattern) -> bool {
+        self.skip_whitespace();
+        self.raw().find(pattern).is_some()
+    }
+
+    // What `scan` would return, without moving past it or the whitespace before it.
+    pub fn peek(&self, pattern: &T::Pattern) -> Option<&T> {
         let rest = self.raw();
+        let rest = rest.range(rest.leading_whitespace(), rest.as_bytes().len());
 
-        pattern.captures(rest).is_some()
+        rest.find(pattern).map(|(_, end)| rest.range(0, end))
     }
 
-    fn skip_whitespace(&self) {
-        self.skip(self.leading_chars(self.raw()));
+    pub fn match_len(&self, pattern: &T::Pattern) -> Option<usize> {
+        self.peek(pattern).map(|matched| matched.as_bytes().len())
     }
 
-    fn get_match<'a>(&'a self, source: &'a str, captures: &Captures) -> Option<&str> {
-        captures
-            .pos(0)
-            .and_then(|(_, count)| {
-                let matched   = &source[0..count];
-                let remaining = &source[count..];
+    pub fn skip_whitespace(&self) {
+        self.skip(self.raw().leading_whitespace());
+    }
+
+    // Moves past the `length` bytes matched at the start of `rest` and the whitespace after them.
+    fn skip_match(&self, start: Pos, rest: &T, length: usize) {
+        let from = self.position();
+        self.skip(length + rest.range(length, rest.as_bytes().len()).leading_whitespace());
+        self.last.set(Some(Match { start, from, to: from + length }));
+    }
+
+    // Works on bytes so `skip` can't panic partway through a character; continuation bytes don't
+    // start a new column.
+    fn advance_to(&self, index: usize) {
+        let Pos { index: from, mut line, mut col } = self.pos.get();
+
+        for &byte in &self.source.as_bytes()[from..index] {
+            if byte == b'\n' {
+                line += 1;
+                col   = 1;
+            } else if byte & 0xC0 != 0x80 {
+                col += 1;
+            }
+        }
 
-                self.skip(count + self.leading_chars(remaining));
-                Some(matched)
-            })
+        self.pos.set(Pos { index, line, col });
     }
 
-    fn leading_chars(&self, string: &str) -> usize {
-        string.len() - string.trim_left_matches(char::is_whitespace).len()
+    fn raw(&self) -> &T {
+        self.source.range(self.position(), self.length)
     }
+}
+
+impl<'t> Scanner<'t, str> {
+    // Like `scan`, but keeps every group so callers can tell which alternative matched.
+    pub fn scan_captures(&self, pattern: &Regex) -> Option<Captures<'_>> {
+        let start = self.checkpoint();
+        self.skip_whitespace();
+        self.last.set(None);
 
-    fn raw(&self) -> &str {
-        &self.source[self.position()..]
+        let rest     = self.raw();
+        let captures = pattern.captures(rest)?;
+        let end      = captures.get(0)?.end();
+        self.skip_match(start, rest, end);
+
+        Some(captures)
     }
 }
 
@@ -109,10 +335,10 @@ mod tests {
     #[test]
     fn is_eos_when_not_at_the_end_of_a_string() {
         let scanner = Scanner::new("test string");
-        assert_eq!(false, scanner.is_eos());
+        assert!(!scanner.is_eos());
 
         scanner.skip(4);
-        assert_eq!(false, scanner.is_eos());
+        assert!(!scanner.is_eos());
     }
 
     #[test]
@@ -120,7 +346,7 @@ mod tests {
         let scanner = Scanner::new("test");
         scanner.skip(4);
 
-        assert_eq!(true, scanner.is_eos());
+        assert!(scanner.is_eos());
     }
 
     #[test]
@@ -144,6 +370,19 @@ mod tests {
         assert_eq!("t", scanner.get_char().unwrap());
     }
 
+    #[test]
+    fn get_char_returns_multibyte_characters_whole() {
+        let scanner = Scanner::new("é %👍e\u{301}");
+        assert_eq!("é", scanner.get_char().unwrap());
+        assert_eq!(2, scanner.position());
+        assert_eq!(" ", scanner.get_char().unwrap());
+        assert_eq!("%", scanner.get_char().unwrap());
+        assert_eq!("👍", scanner.get_char().unwrap());
+        assert_eq!("e", scanner.get_char().unwrap());
+        assert_eq!("\u{301}", scanner.get_char().unwrap());
+        assert_eq!(None, scanner.get_char());
+    }
+
     #[test]
     fn get_char_when_eos_returns_none() {
         let scanner = Scanner::new("test");
@@ -183,4 +422,183 @@ mod tests {
         assert_eq!(None, scanner.scan(&pattern));
         assert!(scanner.is_eos())
     }
+
+    #[test]
+    fn scan_until_returns_everything_through_the_next_match() {
+        let pattern = Regex::new(r"\{%-?\s*endraw\s*-?%\}").unwrap();
+        let scanner = Scanner::new(" {{ a }} {% endraw %} b");
+        assert_eq!(" {{ a }} {% endraw %}", scanner.scan_until(&pattern).unwrap());
+        assert_eq!(" b", scanner.rest().unwrap());
+        assert_eq!(None, scanner.scan_until(&pattern));
+        assert_eq!(21, scanner.position());
+    }
+
+    #[test]
+    fn skip_until_returns_the_new_position() {
+        let pattern = Regex::new(r"%\}").unwrap();
+        let scanner = Scanner::new("comment %} text %}");
+        assert_eq!(Some(10), scanner.skip_until(&pattern));
+        assert_eq!(Some(18), scanner.skip_until(&pattern));
+        assert_eq!(None, scanner.skip_until(&pattern));
+        assert!(scanner.is_eos());
+    }
+
+    #[test]
+    fn peek_returns_the_next_match_without_advancing() {
+        let pattern = Regex::new(r"^\w+").unwrap();
+        let scanner = Scanner::new("  test string");
+        assert_eq!(Some("test"), scanner.peek(&pattern));
+        assert_eq!(Some(4), scanner.match_len(&pattern));
+        assert_eq!(0, scanner.position());
+
+        assert_eq!(Some("test"), scanner.scan(&pattern));
+        assert_eq!(Some("string"), scanner.peek(&pattern));
+        assert_eq!(None, scanner.match_len(&Regex::new(r"^\d+").unwrap()));
+    }
+
+    #[test]
+    fn rewind_returns_to_a_checkpoint() {
+        let pattern = Regex::new(r"^\w+").unwrap();
+        let scanner = Scanner::new("(1..n) x");
+        let start   = scanner.checkpoint();
+
+        scanner.get_char();
+        scanner.scan(&pattern);
+        assert_eq!(2, scanner.position());
+
+        scanner.rewind(start);
+        assert_eq!(0, scanner.position());
+        assert_eq!(Some("(1..n) x"), scanner.rest());
+        assert!(!scanner.unscan());
+    }
+
+    #[test]
+    fn unscan_undoes_the_last_match() {
+        let pattern = Regex::new(r"^\w+").unwrap();
+        let scanner = Scanner::new("a  b c");
+        scanner.scan(&pattern);
+        scanner.scan(&pattern);
+        assert_eq!(5, scanner.position());
+
+        assert!(scanner.unscan());
+        assert_eq!(3, scanner.position());
+        assert!(!scanner.unscan());
+
+        assert_eq!(None, scanner.scan(&Regex::new(r"^\d").unwrap()));
+        assert!(!scanner.unscan());
+    }
+
+    #[test]
+    fn line_and_col_follow_the_position() {
+        let pattern = Regex::new(r"^\w+").unwrap();
+        let scanner = Scanner::new("é a\n\n  b\nc");
+        assert_eq!((1, 1), (scanner.line(), scanner.col()));
+
+        scanner.get_char();
+        assert_eq!((1, 2), (scanner.line(), scanner.col()));
+
+        scanner.scan(&pattern);
+        assert_eq!((3, 3), (scanner.line(), scanner.col()));
+
+        let start = scanner.checkpoint();
+        scanner.skip(10);
+        assert_eq!((4, 2), (scanner.line(), scanner.col()));
+
+        scanner.rewind(start);
+        assert_eq!((3, 3), (scanner.line(), scanner.col()));
+    }
+
+    #[test]
+    fn scan_captures_returns_every_group_and_advances() {
+        let pattern = Regex::new(r"^(?:(\d+)|(\w+))").unwrap();
+        let scanner = Scanner::new(" 42 name");
+
+        let captures = scanner.scan_captures(&pattern).unwrap();
+        assert_eq!((Some("42"), None), (captures.get(1).map(|group| group.as_str()), captures.get(2)));
+
+        let captures = scanner.scan_captures(&pattern).unwrap();
+        assert_eq!((None, Some("name")), (captures.get(1), captures.get(2).map(|group| group.as_str())));
+
+        assert!(scanner.is_eos());
+        assert!(scanner.scan_captures(&pattern).is_none());
+    }
+
+    #[test]
+    fn scan_str_matches_fixed_strings() {
+        let scanner = Scanner::new(" == é | x");
+        assert!(!scanner.check_str("!="));
+        assert!(scanner.check_str("=="));
+        assert_eq!(1, scanner.position());
+
+        assert_eq!(Some("=="), scanner.scan_str("=="));
+        assert_eq!(None, scanner.scan_str("e"));
+        assert_eq!(Some("é"), scanner.scan_str("é"));
+        assert_eq!(Some("|"), scanner.scan_str("|"));
+        assert_eq!(None, scanner.scan_str("xy"));
+        assert_eq!("x", scanner.rest().unwrap());
+    }
+
+    #[test]
+    fn pre_match_and_post_match_surround_the_last_match() {
+        let pattern = Regex::new(r"^\w+").unwrap();
+        let scanner = Scanner::new("a  bc  d");
+        assert_eq!(None, scanner.pre_match());
+
+        scanner.scan(&pattern);
+        scanner.scan(&pattern);
+        assert_eq!(Some("a  "), scanner.pre_match());
+        assert_eq!(Some("  d"), scanner.post_match());
+
+        scanner.scan_until(&Regex::new("d").unwrap());
+        assert_eq!(Some("a  bc  "), scanner.pre_match());
+        assert_eq!(Some(""), scanner.post_match());
+
+        scanner.scan(&pattern);
+        assert_eq!(None, scanner.pre_match());
+    }
+
+    #[test]
+    fn scanners_work_over_bytes() {
+        let pattern = bytes::Regex::new(r"^\w+").unwrap();
+        let scanner = Scanner::new(&b" name \xff\xc3\xa9|"[..]);
+
+        assert_eq!(Some(&b"name"[..]), scanner.scan(&pattern));
+        assert_eq!(Some(&b"\xff"[..]), scanner.get_char());
+        assert_eq!(Some(&b"\xc3\xa9"[..]), scanner.get_char());
+        assert_eq!(Some(&b"|"[..]), scanner.scan_str(&b"|"[..]));
+        assert!(scanner.is_eos());
+        assert_eq!((1, 10), (scanner.line(), scanner.col()));
+    }
+
+    #[test]
+    fn resume_continues_over_a_grown_buffer() {
+        let pattern = Regex::new(r"^\w+").unwrap();
+        let mut buffer = String::from("one\ntw");
+
+        let pos = {
+            let scanner = Scanner::new(buffer.as_str());
+            scanner.scan(&pattern);
+            scanner.checkpoint()
+        };
+
+        buffer.push_str("o three");
+        let scanner = Scanner::resume(buffer.as_str(), pos);
+        assert_eq!(Some("two"), scanner.scan(&pattern));
+        assert_eq!((2, 5), (scanner.line(), scanner.col()));
+    }
+
+    #[test]
+    fn reset_and_terminate_jump_to_either_end() {
+        let pattern = Regex::new(r"^\w+").unwrap();
+        let scanner = Scanner::new("a\nb");
+
+        scanner.terminate();
+        assert!(scanner.is_eos());
+        assert_eq!((2, 2), (scanner.line(), scanner.col()));
+        assert!(!scanner.unscan());
+
+        scanner.reset();
+        assert_eq!((0, 1, 1), (scanner.position(), scanner.line(), scanner.col()));
+        assert_eq!(Some("a"), scanner.scan(&pattern));
+    }
 }
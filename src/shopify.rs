@@ -0,0 +1,159 @@
+use context::Context;
+use environment::Environment;
+use error::Result;
+use render::Renderer;
+use variable::{Variable,VariableResult};
+
+static NIL: Variable = Variable::Nil;
+
+// Where the URL builders put files when the render has no `cdn_url` variable.
+const SHOPIFY_CDN: &str = "//cdn.shopify.com/s/files";
+
+// The storefront filters themes use most, so their snippets render unchanged. `img_url`,
+// `asset_url` and `file_url` build URLs under the `cdn_url` variable, which a shop would usually
+// set in its Globals, or under Shopify's CDN without one.
+pub fn register(environment: &mut Environment) {
+    environment.register_contextual_filter("img_url", img_url);
+    environment.register_contextual_filter("asset_url", |input, _, _, context| Ok(Variable::Str(format!("{}/assets/{}", base(context), input).into())));
+    environment.register_contextual_filter("file_url", |input, _, _, context| Ok(Variable::Str(format!("{}/files/{}", base(context), input).into())));
+    environment.register_filter("handleize", |input, _| Ok(Variable::Str(handleize(&input.to_string()).into())));
+    environment.register_filter("camelize", |input, _| Ok(Variable::Str(camelize(&input.to_string()).into())));
+    environment.register_filter("url_param_escape", |input, _| Ok(Variable::Str(url_param_escape(&input.to_string()).into())));
+    environment.register_filter("weight_with_unit", weight_with_unit);
+}
+
+// `image | img_url: '300x300'` adds the size to the file name, before its extension. The image can
+// be a URL or path, or an object with a `src`. Without one it's Shopify's placeholder. The size
+// defaults to "small", and "master" or "original" leave the name as it is.
+fn img_url(input: &Variable, arguments: &[Variable], _renderer: &Renderer, context: &Context) -> Result<Variable> {
+    let size = match *argument(arguments, 0) {
+        Variable::Nil  => "small".to_string(),
+        ref size       => size.to_string()
+    };
+
+    let src = match *input {
+        Variable::Object(ref image) => image.get("src").map(ToString::to_string).unwrap_or_default(),
+        ref input                   => input.to_string()
+    };
+    if src.is_empty() { return Ok(Variable::Str(format!("//cdn.shopify.com/s/images/admin/no-image-{}.gif", size).into())); }
+
+    let src = if size == "master" || size == "original" { src } else { sized(&src, &size) };
+    match src.starts_with("//") || src.contains("://") {
+        true  => Ok(Variable::Str(src.into())),
+        false => Ok(Variable::Str(format!("{}/{}", base(context), src.trim_start_matches('/')).into()))
+    }
+}
+
+// `products/shirt.jpg?v=1` at `300x` is `products/shirt_300x.jpg?v=1`.
+fn sized(src: &str, size: &str) -> String {
+    let (path, query) = src.split_at(src.find('?').unwrap_or(src.len()));
+    let name_start    = path.rfind('/').map_or(0, |slash| slash + 1);
+
+    match path[name_start..].rfind('.') {
+        Some(dot) => format!("{}_{}{}{}", &path[..name_start + dot], size, &path[name_start + dot..], query),
+        None      => format!("{}_{}{}", path, size, query)
+    }
+}
+
+fn base(context: &Context) -> String {
+    match context.get("cdn_url") {
+        Ok(Variable::Nil) | Err(_) => SHOPIFY_CDN.to_string(),
+        Ok(url)                    => url.to_string().trim_end_matches('/').to_string()
+    }
+}
+
+// Lowercase letters and digits, with a dash for every run of anything else: "100% M&Ms!!!" is
+// "100-m-ms".
+fn handleize(input: &str) -> String {
+    input.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// "coming-soon" is "ComingSoon".
+fn camelize(input: &str) -> String {
+    input.split(|c: char| !c.is_alphanumeric()).flat_map(|word| {
+        let mut characters = word.chars();
+        characters.next().into_iter().flat_map(char::to_uppercase).chain(characters)
+    }).collect()
+}
+
+// Like `url_escape`, which leaves the characters URLs are made of alone, but for a query
+// parameter's value, so `&` is escaped too.
+fn url_param_escape(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+
+    for byte in input.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => escaped.push(byte as char),
+            _ if b"-._~!*'();:@=+$,/?#[]".contains(&byte) => escaped.push(byte as char),
+            _                                        => escaped.push_str(&format!("%{:02X}", byte))
+        }
+    }
+
+    escaped
+}
+
+// `variant.weight | weight_with_unit: 'lb'` turns a weight in grams into the unit, "kg" unless
+// one's given, rounded to two places: "1.5 kg".
+fn weight_with_unit(input: &Variable, arguments: &[Variable]) -> VariableResult<Variable> {
+    let unit = match *argument(arguments, 0) {
+        Variable::Nil  => "kg".to_string(),
+        ref unit       => unit.to_string()
+    };
+
+    let grams = match input.to_number() {
+        Variable::Int(grams)   => grams as f64,
+        Variable::Float(grams) => grams,
+        _                      => 0.0
+    };
+
+    let weight = match unit.as_str() {
+        "kg" => grams / 1000.0,
+        "lb" => grams / 453.592_37,
+        "oz" => grams / 28.349_523_125,
+        _    => grams
+    };
+
+    Ok(Variable::Str(format!("{} {}", Variable::Float((weight * 100.0).round() / 100.0), unit).into()))
+}
+
+fn argument(arguments: &[Variable], index: usize) -> &Variable {
+    arguments.get(index).unwrap_or(&NIL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str, cdn_url: Option<&str>) -> String {
+        let mut environment = Environment::new();
+        register(&mut environment);
+
+        let mut context = Context::new();
+        context.add("image", Variable::Object(vec![("src".to_string(), "products/shirt.jpg?v=1".into())].into_iter().collect())).unwrap();
+        if let Some(url) = cdn_url { context.add("cdn_url", url.into()).unwrap(); }
+
+        environment.render_template(&environment.parse(source).unwrap(), &mut context).unwrap()
+    }
+
+    #[test]
+    fn url_builders_use_the_cdn_url() {
+        assert_eq!("//cdn.shopify.com/s/files/products/shirt_300x300.jpg?v=1", render("{{ image | img_url: '300x300' }}", None));
+        assert_eq!("https://img.example.com/products/shirt_small.jpg?v=1", render("{{ image | img_url }}", Some("https://img.example.com/")));
+        assert_eq!("https://x.com/a.b/logo_100x https://x.com/logo.png", render("{{ 'https://x.com/a.b/logo' | img_url: '100x' }} {{ 'https://x.com/logo.png' | img_url: 'master' }}", None));
+        assert_eq!("//cdn.shopify.com/s/images/admin/no-image-large.gif", render("{{ nothing | img_url: 'large' }}", None));
+        assert_eq!("/cdn/assets/theme.css /cdn/files/terms.pdf", render("{{ 'theme.css' | asset_url }} {{ 'terms.pdf' | file_url }}", Some("/cdn")));
+    }
+
+    #[test]
+    fn text_and_weight_filters_match_shopify() {
+        assert_eq!("100-m-ms", handleize("100% M&Ms!!!"));
+        assert_eq!("ComingSoon", camelize("coming-soon"));
+        assert_eq!("%3Chello%3E%20%26%20%3Cshopify%3E", url_param_escape("<hello> & <shopify>"));
+        assert_eq!("https://x.com/?q=a%26b", url_param_escape("https://x.com/?q=a&b"));
+        assert_eq!("1.5 kg 3.31 lb 1500.0 g", render("{{ 1500 | weight_with_unit }} {{ 1500 | weight_with_unit: 'lb' }} {{ 1500 | weight_with_unit: 'g' }}", None));
+    }
+}
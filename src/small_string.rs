@@ -0,0 +1,235 @@
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash,Hasher};
+use std::ops::Deref;
+use std::str;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize,Deserializer,Serialize,Serializer};
+
+// The longest string kept inline, which with its length fits in the space a String takes.
+pub const INLINE_BYTES: usize = 22;
+
+// A string that keeps up to INLINE_BYTES bytes inline instead of on the heap, so the short keys and
+// values templates are full of don't allocate when they're made or cloned. Longer ones are kept as
+// the String they came in as, or a copy of the str.
+#[derive(Clone)]
+pub struct SmallString(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Inline(u8, [u8; INLINE_BYTES]),
+    Heap(String)
+}
+
+impl SmallString {
+    pub fn new() -> SmallString {
+        SmallString::default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self.0 {
+            // Only ever filled from a str, so the bytes up to the length are UTF-8.
+            Repr::Inline(length, ref bytes) => str::from_utf8(&bytes[..length as usize]).unwrap_or_default(),
+            Repr::Heap(ref value)           => value
+        }
+    }
+
+    pub fn is_inline(&self) -> bool {
+        matches!(self.0, Repr::Inline(..))
+    }
+
+    fn inline(value: &str) -> Option<SmallString> {
+        if value.len() > INLINE_BYTES { return None; }
+
+        let mut bytes = [0; INLINE_BYTES];
+        bytes[..value.len()].copy_from_slice(value.as_bytes());
+
+        Some(SmallString(Repr::Inline(value.len() as u8, bytes)))
+    }
+}
+
+impl Default for SmallString {
+    fn default() -> SmallString {
+        SmallString(Repr::Inline(0, [0; INLINE_BYTES]))
+    }
+}
+
+impl Deref for SmallString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for SmallString {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for SmallString {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<'a> From<&'a str> for SmallString {
+    fn from(value: &'a str) -> SmallString {
+        SmallString::inline(value).unwrap_or_else(|| SmallString(Repr::Heap(value.to_string())))
+    }
+}
+
+// A short String is copied inline and dropped, so clones of it don't allocate.
+impl From<String> for SmallString {
+    fn from(value: String) -> SmallString {
+        SmallString::inline(&value).unwrap_or(SmallString(Repr::Heap(value)))
+    }
+}
+
+impl From<SmallString> for String {
+    fn from(value: SmallString) -> String {
+        match value.0 {
+            Repr::Inline(..)  => value.as_str().to_string(),
+            Repr::Heap(value) => value
+        }
+    }
+}
+
+impl PartialEq for SmallString {
+    fn eq(&self, other: &SmallString) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for SmallString {}
+
+impl PartialEq<str> for SmallString {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for SmallString {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialOrd for SmallString {
+    fn partial_cmp(&self, other: &SmallString) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SmallString {
+    fn cmp(&self, other: &SmallString) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+// The same as the str's, so it can be looked up by one.
+impl Hash for SmallString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl fmt::Debug for SmallString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for SmallString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for SmallString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for SmallString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SmallString, D::Error> {
+        String::deserialize(deserializer).map(SmallString::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{GlobalAlloc,Layout,System};
+    use std::cell::Cell;
+    use variable::Variable;
+
+    // Counts the allocations made on each thread, so tests running alongside don't add to them.
+    struct Counting;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|allocations| allocations.set(allocations.get() + 1));
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(pointer, layout) }
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+
+    fn allocations<T, F: FnOnce() -> T>(f: F) -> (usize, T) {
+        let before = ALLOCATIONS.with(Cell::get);
+        let value  = f();
+
+        (ALLOCATIONS.with(Cell::get) - before, value)
+    }
+
+    #[test]
+    fn short_strings_are_kept_inline_without_allocating() {
+        let title = "Product title".to_string();
+
+        let (count, value) = allocations(|| {
+            let value = Variable::from("product.title");
+            let title = Variable::from(title);
+
+            assert_eq!(Variable::Str("Product title".into()), title.clone());
+            value
+        });
+        assert_eq!(0, count);
+        assert!(matches!(value, Variable::Str(ref value) if value.is_inline() && value == "product.title"));
+
+        let long = "a".repeat(INLINE_BYTES + 1);
+        let (count, value) = allocations(|| SmallString::from(long.as_str()));
+        assert_eq!((1, false), (count, value.is_inline()));
+        assert_eq!((true, false), (SmallString::from("a".repeat(INLINE_BYTES)).is_inline(), SmallString::from(long.clone()).is_inline()));
+    }
+
+    #[test]
+    fn small_strings_compare_and_hash_as_their_str() {
+        use std::collections::HashSet;
+
+        let short = SmallString::from("abc");
+        let long  = SmallString::from("b".repeat(30));
+
+        assert_eq!(short, SmallString::from("abc".to_string()));
+        assert!(short < long && short == "abc");
+        assert_eq!("\"abc\" abc", format!("{:?} {}", short, short));
+        assert_eq!("b".repeat(30), String::from(long));
+        assert!(vec![short].into_iter().collect::<HashSet<_>>().contains("abc"));
+        assert_eq!("", SmallString::new().as_str());
+    }
+}
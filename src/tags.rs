@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+
+use ast::{ForLoop,Include,IncludeValue,TagArguments};
+use lexer::{Keyword,Token};
+use parser::{ParseResult,Parser};
+
+pub type TagParser = fn(&mut Parser) -> ParseResult<TagArguments>;
+
+// Every tag Liquid itself defines, whether or not it has a parser here yet.
+const STANDARD_TAGS: &[&str] = &[
+    "assign", "break", "capture", "case", "comment", "continue", "cycle", "decrement", "doc", "echo",
+    "else", "elsif", "endcapture", "endcase", "endcomment", "enddoc", "endfor", "endif", "endraw",
+    "endtablerow", "endunless", "for", "if", "include", "increment", "liquid", "raw", "render",
+    "tablerow", "unless", "when"
+];
+
+// Maps tag names to the functions that parse their markup. The parser is positioned at the start
+// of the markup (after the tag name) and has to consume all of it. Tags without a parser are kept
+// with their markup unparsed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagRegistry {
+    parsers: HashMap<String, TagParser>
+}
+
+impl Default for TagRegistry {
+    fn default() -> TagRegistry {
+        let mut registry = TagRegistry::empty();
+        registry.register("if", condition);
+        registry.register("elsif", condition);
+        registry.register("unless", condition);
+        registry.register("assign", assign);
+        registry.register("echo", echo);
+        registry.register("include", include);
+        registry.register("for", for_loop);
+
+        registry
+    }
+}
+
+impl TagRegistry {
+    pub fn new() -> TagRegistry {
+        TagRegistry::default()
+    }
+
+    // Without any of the built-in tags.
+    pub fn empty() -> TagRegistry {
+        TagRegistry { parsers: HashMap::new() }
+    }
+
+    // Replaces any parser already registered under `name`, built-in or not.
+    pub fn register(&mut self, name: &str, parser: TagParser) {
+        self.parsers.insert(name.to_string(), parser);
+    }
+
+    pub fn get(&self, name: &str) -> Option<TagParser> {
+        self.parsers.get(name).copied()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.parsers.contains_key(name)
+    }
+
+    // Registered, or one of Liquid's own tags.
+    pub fn is_known(&self, name: &str) -> bool {
+        self.contains(name) || STANDARD_TAGS.contains(&name)
+    }
+}
+
+fn condition(parser: &mut Parser) -> ParseResult<TagArguments> {
+    parser.condition().map(TagArguments::Condition)
+}
+
+// `assign name = expression | filter ...`
+fn assign(parser: &mut Parser) -> ParseResult<TagArguments> {
+    let name = parser.expect(Token::Identifier)?.to_string();
+    parser.expect(Token::Assign)?;
+
+    parser.filter_chain().map(|value| TagArguments::Assign(name, value))
+}
+
+fn echo(parser: &mut Parser) -> ParseResult<TagArguments> {
+    parser.filter_chain().map(TagArguments::Output)
+}
+
+// `include 'name' [with value | for values] [as alias][,] [key: value, ...]`
+fn include(parser: &mut Parser) -> ParseResult<TagArguments> {
+    let template = parser.expression()?;
+    let value = if parser.consume_keyword(Keyword::With).is_some() {
+        Some(IncludeValue::With(parser.expression()?))
+    } else if parser.consume_keyword(Keyword::For).is_some() {
+        Some(IncludeValue::For(parser.expression()?))
+    } else {
+        None
+    };
+
+    let alias = match parser.consume_keyword(Keyword::As) {
+        Some(_) => Some(parser.expect(Token::Identifier)?.to_string()),
+        None    => None
+    };
+
+    parser.consume(Token::Comma);
+    let arguments = parser.keyword_arguments()?;
+
+    Ok(TagArguments::Include(Include { template, value, alias, arguments }))
+}
+
+// `for item in collection [reversed] [limit: n] [offset: n]`
+fn for_loop(parser: &mut Parser) -> ParseResult<TagArguments> {
+    let variable = parser.expect(Token::Identifier)?.to_string();
+    parser.expect_keyword(Keyword::In)?;
+
+    let collection = parser.expression()?;
+    let reversed   = parser.consume_keyword(Keyword::Reversed).is_some();
+    let mut each   = ForLoop { variable, collection, reversed, limit: None, offset: None };
+
+    loop {
+        let argument = if parser.consume_keyword(Keyword::Limit).is_some() {
+            &mut each.limit
+        } else if parser.consume_keyword(Keyword::Offset).is_some() {
+            &mut each.offset
+        } else {
+            break;
+        };
+
+        parser.expect(Token::Colon)?;
+        *argument = Some(parser.expression()?);
+        parser.consume(Token::Comma);
+    }
+
+    Ok(TagArguments::For(Box::new(each)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::{Condition,Expression,FilterChain};
+    use lexer::Span;
+
+    fn parse(registry: &TagRegistry, name: &str, markup: &str) -> ParseResult<TagArguments> {
+        let mut parser = Parser::new(markup)?;
+        registry.get(name).unwrap()(&mut parser)
+    }
+
+    #[test]
+    fn default_registry_parses_the_built_in_tags() {
+        let registry = TagRegistry::new();
+        let x        = Expression::variable("x", Span { start: 0, end: 1, line: 1, col: 1 });
+
+        assert_eq!(Ok(TagArguments::Condition(Condition::Test(x.clone()))), parse(&registry, "unless", "x"));
+        assert_eq!(Ok(TagArguments::Output(FilterChain { expression: x, filters: vec![] })), parse(&registry, "echo", "x"));
+        assert!(matches!(parse(&registry, "assign", "a = x | upcase"), Ok(TagArguments::Assign(ref name, _)) if name == "a"));
+        assert!(parse(&registry, "assign", "a x").is_err());
+        assert!(matches!(parse(&registry, "include", "'header'"), Ok(TagArguments::Include(_))));
+        assert!(!registry.contains("case"));
+    }
+
+    #[test]
+    fn for_takes_a_range_and_its_options() {
+        let registry = TagRegistry::new();
+        let each     = |markup| match parse(&registry, "for", markup) {
+            Ok(TagArguments::For(each)) => each,
+            result                      => panic!("{:?}", result)
+        };
+
+        let numbers = each("i in (1..n) reversed limit: 2, offset: start");
+        assert_eq!(("i", "(1..n)", true), (numbers.variable.as_str(), numbers.collection.to_string().as_str(), numbers.reversed));
+        assert!(matches!(numbers.collection, Expression::Range(..)));
+        assert_eq!((Some("2".to_string()), Some("start".to_string())), (numbers.limit.map(|limit| limit.to_string()), numbers.offset.map(|offset| offset.to_string())));
+
+        let items = each("item in (start..items.size)");
+        assert!(!items.reversed && items.limit.is_none() && items.offset.is_none());
+        assert!(parse(&registry, "for", "i (1..3)").is_err());
+    }
+
+    #[test]
+    fn include_takes_a_value_an_alias_and_keyword_arguments() {
+        let registry = TagRegistry::new();
+        let include  = |markup| match parse(&registry, "include", markup) {
+            Ok(TagArguments::Include(include)) => include,
+            result                             => panic!("{:?}", result)
+        };
+
+        let product = include("'product' for items as item, class: 'big' size: 2");
+        assert!(matches!(product.value, Some(IncludeValue::For(ref items)) if items.to_string() == "items"));
+        assert_eq!(Some("item".to_string()), product.alias);
+        assert_eq!(vec!["class", "size"], product.arguments.iter().map(|(name, _)| name).collect::<Vec<_>>());
+
+        let product = include("'product', class: 'big'");
+        assert_eq!((None, None, 1), (product.value, product.alias, product.arguments.len()));
+        assert!(matches!(include("'product' with item").value, Some(IncludeValue::With(_))));
+        assert!(parse(&registry, "include", "'product' with").is_err());
+    }
+
+    #[test]
+    fn register_adds_and_replaces_tags() {
+        fn raw(_parser: &mut Parser) -> ParseResult<TagArguments> {
+            Ok(TagArguments::Unparsed)
+        }
+
+        let mut registry = TagRegistry::empty();
+        assert!(!registry.contains("if"));
+
+        registry.register("if", raw);
+        registry.register("render", raw);
+        assert_eq!(Ok(TagArguments::Unparsed), parse(&registry, "if", "x"));
+        assert!(registry.contains("render"));
+    }
+}
@@ -0,0 +1,656 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+
+use ast::{FilterChain,Node,Tag,TagArguments,Text};
+use lexer::{LexedToken,Span};
+use error::Error;
+use filters::FilterRegistry;
+use fold;
+use parser::{ParseError,ParseResult,Parser,ParserOptions};
+use policy::Policy;
+use tokenizer::{self,FusedToken,TemplateToken,Tokenizer};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize,Serialize};
+
+// Bytes an output is guessed to render to.
+const OUTPUT_SIZE_HINT: usize = 16;
+
+// Liquid's tags that take an `end` tag, for `ParseLimits::max_nesting` and `Policy`.
+pub(crate) const BLOCKS: &[&str] = &["capture", "case", "comment", "doc", "for", "if", "raw", "tablerow", "unless"];
+
+// Blocks whose bodies are kept as text rather than parsed, so nothing in them can fail to parse or
+// render. Only comments nest.
+const VERBATIM: &[&str] = &["comment", "doc", "raw"];
+
+// Owns everything it holds and has no interior mutability, so a parsed template is Send + Sync and
+// one `Arc<Template>` can serve any number of concurrent renders.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Template {
+    pub nodes: Vec<Node>,
+    // The raw `---` block at the top of the template, when `ParserOptions::front_matter` is set.
+    pub front_matter: Option<String>,
+    // Anything odd that didn't stop the template from parsing. Tooling can report these; rendering
+    // ignores them.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub warnings: Vec<Warning>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Warning {
+    // Neither registered nor one of Liquid's own, so its markup is kept unparsed.
+    UnknownTag(String, Span)
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::UnknownTag(ref name, span) => write!(f, "Unknown tag '{}' at {}", name, span)
+        }
+    }
+}
+
+impl Template {
+    pub fn parse(source: &str) -> ParseResult<Template> {
+        Template::parse_with(source, &ParserOptions::default())
+    }
+
+    pub fn parse_with(source: &str, options: &ParserOptions) -> ParseResult<Template> {
+        check_size(source, options)?;
+
+        let shared                 = Arc::<str>::from(normalize(source, options));
+        let source                 = &*shared;
+        let mut nodes              = Nodes::new(shared.clone());
+        let (front_matter, tokens) = tokens(source, options);
+
+        for (token, span, lexed) in tokens {
+            match nodes.push(source, token, span, lexed, options) {
+                Err(ref error) if options.inline_errors => nodes.push_error(error, span),
+                result                                  => result?
+            }
+        }
+
+        Ok(Template::new(nodes, front_matter, options))
+    }
+
+    // Rather than stopping at the first error, skips to the next tag or output and keeps going so
+    // editors and linters can report every problem along with whatever did parse.
+    pub fn parse_recovering(source: &str) -> (Template, Vec<ParseError>) {
+        Template::parse_recovering_with(source, &ParserOptions::default())
+    }
+
+    pub fn parse_recovering_with(source: &str, options: &ParserOptions) -> (Template, Vec<ParseError>) {
+        if let Err(error) = check_size(source, options) {
+            return (Template { nodes: vec![], front_matter: None, warnings: vec![] }, vec![error]);
+        }
+
+        let shared                 = Arc::<str>::from(normalize(source, options));
+        let source                 = &*shared;
+        let mut nodes              = Nodes::new(shared.clone());
+        let mut errors             = vec![];
+        let (front_matter, tokens) = tokens(source, options);
+
+        for (token, span, lexed) in tokens {
+            if let Err(error) = nodes.push(source, token, span, lexed, options) {
+                if options.inline_errors { nodes.push_error(&error, span); }
+                errors.push(error);
+            }
+        }
+
+        (Template::new(nodes, front_matter, options), errors)
+    }
+
+    // A guess at how many bytes the template renders to: all of its text, plus a little for each
+    // output. Loops and includes make any guess a rough one.
+    pub fn size_hint(&self) -> usize {
+        self.nodes.iter().map(|node| match *node {
+            Node::Text(ref text, _) => text.len(),
+            Node::Output(..)        => OUTPUT_SIZE_HINT,
+            Node::Tag(..)           => 0
+        }).sum()
+    }
+
+    // Checks the template against a policy without rendering it, for templates from untrusted
+    // sources. See `Policy::check`.
+    pub fn validate(&self, policy: &Policy) -> Vec<ParseError> {
+        policy.check(self)
+    }
+
+    // Optional because it throws away the output nodes a linter or formatter would want to see.
+    // See `fold::fold`.
+    pub fn fold(&mut self, filters: &FilterRegistry) {
+        self.nodes = fold::fold(mem::take(&mut self.nodes), filters);
+    }
+
+    // Like Shopify's strip mode: drops text that's only whitespace when it sits between two tags, so
+    // loops and conditionals don't leave blank lines behind. Text next to an output is kept.
+    pub fn trim_blank_text(&mut self) {
+        let is_tag = |node: Option<&Node>| matches!(node, Some(Node::Tag(..)));
+        let blank  = (0..self.nodes.len()).map(|index| match self.nodes[index] {
+            Node::Text(ref text, _) if text.trim().is_empty() => index > 0 && is_tag(self.nodes.get(index - 1)) && is_tag(self.nodes.get(index + 1)),
+            _                                                 => false
+        }).collect::<Vec<_>>();
+
+        let mut blank = blank.into_iter();
+        self.nodes.retain(|_| !blank.next().unwrap_or(false));
+    }
+
+    fn new(nodes: Nodes, front_matter: Option<&str>, options: &ParserOptions) -> Template {
+        let mut template = Template { nodes: nodes.nodes, front_matter: front_matter.map(str::to_string), warnings: nodes.warnings };
+        if options.trim_blank_text { template.trim_blank_text(); }
+        // Which filters the template renders with isn't known until it's in an Environment.
+        if options.fold { template.fold(&FilterRegistry::empty()); }
+
+        template
+    }
+}
+
+fn check_size(source: &str, options: &ParserOptions) -> ParseResult<()> {
+    match options.limits.max_source_bytes {
+        Some(max) if source.len() > max => Err(ParseError::TooLarge(max)),
+        _                               => Ok(())
+    }
+}
+
+// Spans point into the normalized source, so with `normalize_newlines` set they're off by a byte
+// for every `\r` dropped before them.
+fn normalize<'a>(source: &'a str, options: &ParserOptions) -> Cow<'a, str> {
+    let source = if options.strip_bom { source.strip_prefix('\u{feff}').unwrap_or(source) } else { source };
+
+    match options.normalize_newlines && source.contains("\r\n") {
+        true  => Cow::Owned(source.replace("\r\n", "\n")),
+        false => Cow::Borrowed(source)
+    }
+}
+
+// Any front matter is split off first; spans still point into the whole source.
+fn tokens<'a>(source: &'a str, options: &'a ParserOptions) -> (Option<&'a str>, impl Iterator<Item = FusedToken<'a>>) {
+    let (front_matter, body) = match tokenizer::front_matter(source) {
+        Some((front_matter, body)) if options.front_matter => (Some(front_matter), body),
+        _                                                  => (None, source)
+    };
+
+    let origin = Span::default().after(&source[..source.len() - body.len()]);
+    let tokens = Tokenizer::new(body).fused(options)
+        .map(move |(token, span, lexed)| (token, span.offset(origin), lexed))
+        .filter(|(_, span, _)| span.start < span.end);
+
+    (front_matter, tokens)
+}
+
+// Collects nodes as the chunks are parsed, applying whitespace control: `{{-` and `{%-` strip all
+// whitespace from the end of the text before them, and `-}}` and `-%}` from the start of the text
+// after them. Text nodes are slices of `source`, which every chunk was cut from.
+struct Nodes {
+    source: Arc<str>,
+    nodes: Vec<Node>,
+    warnings: Vec<Warning>,
+    trim_next: bool,
+    // Blocks open at this point.
+    depth: usize,
+    // The VERBATIM block being read, and how many comments are open inside it.
+    verbatim: Option<(&'static str, usize)>
+}
+
+impl Nodes {
+    fn new(source: Arc<str>) -> Nodes {
+        Nodes { source, nodes: vec![], warnings: vec![], trim_next: false, depth: 0, verbatim: None }
+    }
+
+    // Trimming happens even when the markup fails to parse.
+    fn push(&mut self, source: &str, token: TemplateToken, span: Span, lexed: Option<Vec<LexedToken>>, options: &ParserOptions) -> ParseResult<()> {
+        if self.push_verbatim(source, &token, span) { return Ok(()); }
+
+        let (trim, node) = match token {
+            TemplateToken::Text(text) => {
+                self.push_text(text, span);
+                return Ok(());
+            },
+            TemplateToken::Output { markup, trim } => {
+                (trim, output(markup, origin(source, markup, span), lexed, options).map(|output| Node::Output(output, span)))
+            },
+            TemplateToken::Tag { name, markup, trim } => {
+                if !name.is_empty() && !options.tags.is_known(name) { self.warnings.push(Warning::UnknownTag(name.to_string(), span)); }
+                (trim, tag(name, markup, origin(source, markup, span), lexed, options).map(|tag| Node::Tag(tag, span)))
+            },
+            TemplateToken::Unclosed(delimiter) => {
+                self.trim_next = false;
+                return Err(ParseError::Unclosed(delimiter.to_string(), span));
+            }
+        };
+
+        if trim.before { self.trim_previous(); }
+        self.trim_next = trim.after;
+
+        let node = node?;
+        if let Some(ref sandbox) = options.sandbox { sandbox.check(&node)?; }
+        if let Node::Tag(ref tag, span) = node {
+            self.nest(&tag.name, span, options)?;
+            self.verbatim = VERBATIM.iter().find(|&&block| block == tag.name).map(|&block| (block, 0));
+        }
+
+        self.nodes.push(node);
+        Ok(())
+    }
+
+    // Inside a VERBATIM block, everything up to its end tag is pushed as text the way it's written.
+    // Returns whether the token was.
+    fn push_verbatim(&mut self, source: &str, token: &TemplateToken, span: Span) -> bool {
+        let Some((block, ref mut nested)) = self.verbatim else { return false };
+
+        match *token {
+            TemplateToken::Unclosed(_) => return false,
+            TemplateToken::Tag { name, .. } if block == "comment" && name == "comment" => *nested += 1,
+            TemplateToken::Tag { name, .. } if name.strip_prefix("end") == Some(block) => match *nested {
+                0 => {
+                    self.verbatim = None;
+                    return false;
+                },
+                _ => *nested -= 1
+            },
+            _ => {}
+        }
+
+        self.push_text(&source[span.start..span.end], span);
+        true
+    }
+
+    // With `inline_errors` set, a node that fails to parse is replaced with text saying why, the
+    // way Shopify renders errors into the page and carries on.
+    fn push_error(&mut self, error: &ParseError, span: Span) {
+        self.nodes.push(Node::Text(Error::from(error.clone()).inline_message().into(), span));
+    }
+
+    fn push_text(&mut self, text: &str, span: Span) {
+        let trimmed    = if self.trim_next { text.trim_start() } else { text };
+        self.trim_next = false;
+        if trimmed.is_empty() { return; }
+
+        let start = trimmed.as_ptr() as usize - self.source.as_ptr() as usize;
+        let span  = Span { end: span.end, ..span.after(&text[..text.len() - trimmed.len()]) };
+        self.nodes.push(Node::Text(Text::new(self.source.clone(), start..start + trimmed.len()), span));
+    }
+
+    fn nest(&mut self, name: &str, span: Span, options: &ParserOptions) -> ParseResult<()> {
+        if name.strip_prefix("end").is_some_and(|block| BLOCKS.contains(&block)) {
+            self.depth = self.depth.saturating_sub(1);
+        } else if BLOCKS.contains(&name) {
+            self.depth += 1;
+            if let Some(max) = options.limits.max_nesting.filter(|&max| self.depth > max) { return Err(ParseError::TooDeep(max, span)); }
+        }
+
+        Ok(())
+    }
+
+    fn trim_previous(&mut self) {
+        if let Some(Node::Text(text, span)) = self.nodes.last_mut() {
+            let length = text.trim_end().len();
+            span.end  -= text.len() - length;
+            text.truncate(length);
+
+            if text.is_empty() { self.nodes.pop(); }
+        }
+    }
+}
+
+fn output(markup: &str, origin: Span, lexed: Option<Vec<LexedToken>>, options: &ParserOptions) -> ParseResult<FilterChain> {
+    let mut parser = parser(markup, origin, lexed, options)?;
+    let chain      = parser.filter_chain()?;
+    parser.expect_end()?;
+
+    Ok(chain)
+}
+
+// `origin` is where `markup` starts, so spans in the parsed arguments still point into the template.
+fn tag(name: &str, markup: &str, origin: Span, lexed: Option<Vec<LexedToken>>, options: &ParserOptions) -> ParseResult<Tag> {
+    if name.is_empty() { return Err(ParseError::InvalidTag(markup.to_string())); }
+
+    let arguments = match options.tags.get(name) {
+        Some(parse) => {
+            let mut parser = parser(markup, origin, lexed, options)?;
+            let arguments  = parse(&mut parser)?;
+            parser.expect_end()?;
+
+            arguments
+        },
+        None => TagArguments::Unparsed
+    };
+
+    Ok(Tag { name: name.to_string(), markup: markup.to_string(), arguments })
+}
+
+// Picks up the markup's tokens where the tokenizer already lexed them.
+fn parser<'a>(markup: &'a str, origin: Span, lexed: Option<Vec<LexedToken>>, options: &ParserOptions) -> ParseResult<Parser<'a>> {
+    match lexed {
+        Some(tokens) => Parser::lexed(markup, tokens, origin, options),
+        None         => Parser::with_options(markup, origin, options)
+    }
+}
+
+// Where `markup`, which the tokenizer sliced out of `source`, starts in it. `span` covers the
+// token the markup came from.
+fn origin(source: &str, markup: &str, span: Span) -> Span {
+    let offset = markup.as_ptr() as usize - source.as_ptr() as usize;
+    span.after(&source[span.start..offset])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::{Condition,Expression,Filter,FilterChain,KeywordArguments};
+    use tokenizer::Delimiters;
+    use lexer::{Span,Token};
+    use parser::ParseLimits;
+
+    fn span(start: usize, end: usize) -> Span {
+        Span { start, end, line: 1, col: start + 1 }
+    }
+
+    fn text(value: &str, start: usize) -> Node {
+        Node::Text(value.into(), span(start, start + value.len()))
+    }
+
+    // `path` is assumed to start three bytes in, after `{{ `.
+    fn output(path: &str, node: Span, filters: Vec<Filter>) -> Node {
+        let expression = Expression::variable(path, span(node.start + 3, node.start + 3 + path.len()));
+        Node::Output(FilterChain { expression, filters }, node)
+    }
+
+    fn tag(name: &str, markup: &str, arguments: TagArguments, node: Span) -> Node {
+        Node::Tag(Tag { name: name.to_string(), markup: markup.to_string(), arguments }, node)
+    }
+
+    #[test]
+    fn parse_splits_a_template_into_nodes() {
+        let template = Template::parse("Hi {{ user.name | upcase }}!{% if user %} {%endif%}").unwrap();
+
+        assert_eq!(vec![
+            text("Hi ", 0),
+            output("user.name", span(3, 27), vec![Filter { name: "upcase".to_string(), arguments: vec![], keyword_arguments: KeywordArguments::new() }]),
+            text("!", 27),
+            tag("if", "user", TagArguments::Condition(Condition::Test(Expression::variable("user", span(34, 38)))), span(28, 41)),
+            text(" ", 41),
+            tag("endif", "", TagArguments::Unparsed, span(42, 51))
+        ], template.nodes);
+    }
+
+    #[test]
+    fn parse_an_empty_template() {
+        assert_eq!(Template { nodes: vec![], front_matter: None, warnings: vec![] }, Template::parse("").unwrap());
+    }
+
+    #[test]
+    fn parse_stops_at_the_first_error() {
+        let error = Template::parse("{{ a | }} {% 5 %}").unwrap_err();
+        assert_eq!(ParseError::UnexpectedEnd("filter name".to_string()), error);
+    }
+
+    #[test]
+    fn parse_recovering_collects_every_error() {
+        let (template, errors) = Template::parse_recovering("{{ a | }} ok {% %}{{ b c }}{% 5x %}{{ d }}");
+
+        assert_eq!(vec![text(" ok ", 9), output("d", span(35, 42), vec![])], template.nodes);
+        assert_eq!(vec![
+            ParseError::UnexpectedEnd("filter name".to_string()),
+            ParseError::InvalidTag("".to_string()),
+            ParseError::UnexpectedToken("end of markup".to_string(), Token::Identifier, span(23, 24)),
+            ParseError::InvalidTag("5x".to_string())
+        ], errors);
+    }
+
+    #[test]
+    fn parse_gives_spans_in_the_whole_template() {
+        let template = Template::parse("<p>\n  {{ a | f: b }}\n{% if c %}").unwrap();
+        let spans    = template.nodes.iter().map(Node::span).collect::<Vec<_>>();
+
+        assert_eq!(vec![
+            Span { start: 0, end: 6, line: 1, col: 1 },
+            Span { start: 6, end: 20, line: 2, col: 3 },
+            Span { start: 20, end: 21, line: 2, col: 17 },
+            Span { start: 21, end: 31, line: 3, col: 1 }
+        ], spans);
+
+        let Node::Output(ref chain, _) = template.nodes[1] else { panic!("expected an output") };
+        assert_eq!(Span { start: 9, end: 10, line: 2, col: 6 }, chain.expression.span());
+        assert_eq!(Span { start: 16, end: 17, line: 2, col: 13 }, chain.filters[0].arguments[0].span());
+
+        let error = Template::parse("x\n{{ a | § }}").unwrap_err();
+        assert_eq!("Unexpected character '§' at line 2, column 8", error.to_string());
+    }
+
+    #[test]
+    fn parse_with_applies_the_options() {
+        let options  = ParserOptions { fold: true, ..ParserOptions::default() };
+        let template = Template::parse_with("a{{ 1 }}b", &options).unwrap();
+        assert_eq!(vec![Node::Text("a1b".into(), span(0, 9))], template.nodes);
+
+        let options     = ParserOptions { unicode_identifiers: false, ..ParserOptions::default() };
+        let (_, errors) = Template::parse_recovering_with("{{ café }}", &options);
+        assert_eq!("Unexpected character 'é' at line 1, column 7", errors[0].to_string());
+    }
+
+    #[test]
+    fn parse_looks_tags_up_in_the_registry() {
+        let error = Template::parse("{% if %}").unwrap_err();
+        assert_eq!(ParseError::UnexpectedEnd("expression".to_string()), error);
+
+        let error = Template::parse("\n{%  assign  x = 1 2 %}").unwrap_err();
+        assert_eq!("Expected end of markup but found number literal at line 2, column 19", error.to_string());
+
+        let mut options = ParserOptions::default();
+        options.tags.register("render", |parser| parser.filter_chain().map(TagArguments::Output));
+
+        let template = Template::parse_with("{% render 'card' %}{% mystery 1 2 %}", &options).unwrap();
+        let Node::Tag(ref render, _)  = template.nodes[0] else { panic!("expected a tag") };
+        let Node::Tag(ref mystery, _) = template.nodes[1] else { panic!("expected a tag") };
+
+        assert!(matches!(render.arguments, TagArguments::Output(_)));
+        assert_eq!(TagArguments::Unparsed, mystery.arguments);
+        assert_eq!("1 2", mystery.markup);
+    }
+
+    #[test]
+    fn parse_applies_whitespace_control() {
+        let template = Template::parse("<ul>\n  {%- for x in xs -%}\n  <li>{{- x }} </li>\n  {%- endfor %}\n</ul>").unwrap();
+        let texts    = template.nodes.iter().filter_map(|node| match *node {
+            Node::Text(ref text, span) => Some((text.as_str(), span.start, span.end)),
+            _                          => None
+        });
+
+        assert_eq!(vec![("<ul>", 0, 4), ("<li>", 29, 33), (" </li>", 41, 47), ("\n</ul>", 63, 69)], texts.collect::<Vec<_>>());
+
+        let Node::Output(ref chain, _) = template.nodes[3] else { panic!("expected an output") };
+        assert_eq!(Span { start: 37, end: 38, line: 3, col: 11 }, chain.expression.span());
+
+        let template = Template::parse("a \n{{- -1 -}}\n b {%- if x -%}  {%- endif -%} c").unwrap();
+        assert_eq!(vec!["a", "-1", "b", "if", "endif", "c"], template.nodes.iter().map(|node| match *node {
+            Node::Text(ref text, _)     => text.to_string(),
+            Node::Output(ref chain, _)  => chain.expression.to_string(),
+            Node::Tag(ref tag, _)       => tag.name.clone()
+        }).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn parse_keeps_raw_and_comment_bodies_as_text() {
+        let template = Template::parse("{% raw -%} {{ x | }}{%- endraw %}{% comment %}{% if %}{% comment %}{% endcomment %}{% endcomment %}{% doc %}{{ | }}{% enddoc %}").unwrap();
+        let nodes    = template.nodes.iter().map(|node| match *node {
+            Node::Text(ref text, _) => text.as_str(),
+            Node::Output(..)        => "output",
+            Node::Tag(ref tag, _)   => tag.name.as_str()
+        }).collect::<Vec<_>>();
+
+        assert_eq!(vec!["raw", "{{ x | }}", "endraw", "comment", "{% if %}", "{% comment %}", "{% endcomment %}", "endcomment", "doc", "{{ | }}", "enddoc"], nodes);
+        assert_eq!(Node::Text("{{ x | }}".into(), span(11, 20)), template.nodes[1]);
+        assert!(template.warnings.is_empty());
+    }
+
+    #[test]
+    fn parse_reports_unclosed_delimiters() {
+        let error = Template::parse("<p>\n\n  {{ name </p>").unwrap_err();
+        assert_eq!(ParseError::Unclosed("{{".to_string(), Span { start: 7, end: 9, line: 3, col: 3 }), error);
+        assert_eq!("'{{' opened at line 3, column 3 was never closed", error.to_string());
+
+        let (template, errors) = Template::parse_recovering("a {% if\n  x %}b{%");
+        assert_eq!(3, template.nodes.len());
+        assert_eq!(vec![ParseError::Unclosed("{%".to_string(), Span { start: 15, end: 17, line: 2, col: 8 })], errors);
+    }
+
+    #[test]
+    fn trim_blank_text_between_tags() {
+        let options  = ParserOptions { trim_blank_text: true, ..ParserOptions::default() };
+        let template = Template::parse_with("{% if a %}\n  {% if b %}\n{{ c }}\n{% endif %} x {% endif %}\n", &options).unwrap();
+
+        let nodes = template.nodes.iter().map(|node| match *node {
+            Node::Text(ref text, _) => text.as_str(),
+            Node::Output(..)        => "output",
+            Node::Tag(ref tag, _)   => tag.name.as_str()
+        }).collect::<Vec<_>>();
+
+        assert_eq!(vec!["if", "if", "\n", "output", "\n", "endif", " x ", "endif", "\n"], nodes);
+        assert_eq!(10, Template::parse("{% if a %}\n  {% if b %}\n{{ c }}\n{% endif %} x {% endif %}\n").unwrap().nodes.len());
+    }
+
+    #[test]
+    fn parse_strips_a_byte_order_mark() {
+        assert_eq!(vec![text("a", 0)], Template::parse("\u{feff}a").unwrap().nodes);
+
+        let options = ParserOptions { strip_bom: false, ..ParserOptions::default() };
+        assert_eq!(vec![text("\u{feff}a", 0)], Template::parse_with("\u{feff}a", &options).unwrap().nodes);
+    }
+
+    #[test]
+    fn parse_with_normalized_newlines() {
+        let options  = ParserOptions { normalize_newlines: true, ..ParserOptions::default() };
+        let template = Template::parse_with("a\r\n{%- if b -%}\r\nc\r\n", &options).unwrap();
+
+        assert_eq!(Node::Text("a".into(), span(0, 1)), template.nodes[0]);
+        assert_eq!(Node::Text("c\n".into(), Span { start: 15, end: 17, line: 3, col: 1 }), template.nodes[2]);
+        assert_eq!(text("a\r\n", 0), Template::parse("a\r\n").unwrap().nodes[0]);
+    }
+
+    #[test]
+    fn parse_with_inline_errors() {
+        let options  = ParserOptions { inline_errors: true, ..ParserOptions::default() };
+        let template = Template::parse_with("a {{ b | }} c", &options).unwrap();
+
+        assert_eq!(vec![
+            text("a ", 0),
+            Node::Text("Liquid syntax error: Expected filter name but reached the end of the markup".into(), span(2, 11)),
+            text(" c", 11)
+        ], template.nodes);
+
+        let (template, errors) = Template::parse_recovering_with("{{ # }}", &options);
+        assert_eq!(1, errors.len());
+        assert_eq!(Node::Text(format!("Liquid syntax error: {}", errors[0]).into(), span(0, 7)), template.nodes[0]);
+    }
+
+    #[test]
+    fn parse_warns_about_unknown_tags() {
+        let template = Template::parse("{% if a %}{% section 'header' %}{% endif %}").unwrap();
+        assert_eq!(vec![Warning::UnknownTag("section".to_string(), span(10, 32))], template.warnings);
+        assert_eq!("Unknown tag 'section' at line 1, column 11", template.warnings[0].to_string());
+
+        let mut options = ParserOptions::default();
+        options.tags.register("section", |parser| parser.filter_chain().map(TagArguments::Output));
+        assert!(Template::parse_with("{% section 'header' %}", &options).unwrap().warnings.is_empty());
+    }
+
+    #[test]
+    fn parse_with_front_matter() {
+        let options  = ParserOptions { front_matter: true, ..ParserOptions::default() };
+        let template = Template::parse_with("---\ntitle: Home\n---\n{{ title }}", &options).unwrap();
+
+        assert_eq!(Some("title: Home\n".to_string()), template.front_matter);
+        assert_eq!(vec![
+            Node::Output(
+                FilterChain { expression: Expression::variable("title", Span { start: 23, end: 28, line: 4, col: 4 }), filters: vec![] },
+                Span { start: 20, end: 31, line: 4, col: 1 }
+            )
+        ], template.nodes);
+    }
+
+    #[test]
+    fn front_matter_is_text_unless_enabled() {
+        let template = Template::parse("---\na: 1\n---\n").unwrap();
+
+        assert_eq!(None, template.front_matter);
+        assert_eq!(vec![text("---\na: 1\n---\n", 0)], template.nodes);
+    }
+
+    #[test]
+    fn parse_fused_gives_the_same_templates() {
+        let options = ParserOptions { fused: true, ..ParserOptions::default() };
+        let sources = [
+            "Hi {{ user.name | upcase }}!{% if user %} {%endif%}",
+            "<p>\n  {{ a | f: b, c: (1..n) }}\n{%- if c > 1 and d contains 'x' -%}\n{% assign e = '%}' | g %}",
+            "{{ a } {{ b -}} {% include 'card' with x as y, z: 1 %} {% section §%}",
+            "{{ a | }} ok {% %}{{ b c }}{% 5x %}{{ d }}{% if %}{{ café }}{% if"
+        ];
+
+        for source in &sources {
+            assert_eq!(Template::parse_recovering(source), Template::parse_recovering_with(source, &options), "parsing {:?}", source);
+        }
+    }
+
+    #[test]
+    fn parse_limits_cap_size_and_nesting() {
+        let limits  = ParseLimits { max_source_bytes: Some(64), max_nesting: Some(2), ..ParseLimits::default() };
+        let options = ParserOptions { limits, ..ParserOptions::default() };
+
+        assert!(Template::parse_with("{% if a %}{% if b %}{% endif %}{% endif %}", &ParserOptions { limits: ParseLimits::default(), ..options.clone() }).is_ok());
+        assert_eq!(ParseError::TooLarge(64), Template::parse_with(&"x".repeat(65), &options).unwrap_err());
+        assert_eq!(vec![ParseError::TooLarge(64)], Template::parse_recovering_with(&"x".repeat(65), &options).1);
+
+        assert!(Template::parse_with("{% if a %}{% if b %}{% endif %}{% if c %}", &options).is_ok());
+        assert_eq!(
+            "Blocks nest deeper than the limit of 2 at line 1, column 21",
+            Template::parse_with("{% if a %}{% if b %}{% unless c %}", &options).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn parse_with_custom_delimiters() {
+        let options  = ParserOptions { delimiters: Delimiters::new(("[[", "]]"), ("[%", "%]")).unwrap(), ..ParserOptions::default() };
+        let template = Template::parse_with("{{ x }}[% if a %][[ b ]]", &options).unwrap();
+
+        assert_eq!(text("{{ x }}", 0), template.nodes[0]);
+        assert!(matches!(template.nodes[1], Node::Tag(ref tag, _) if tag.name == "if"));
+        assert_eq!(output("b", span(17, 24), vec![]), template.nodes[2]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn templates_round_trip_through_bincode() {
+        let template = Template::parse("Hi {{ name | default: 'you', size: (1..3) }}{% if a == nil %}{{ 2.5 }}").unwrap();
+        let bytes    = bincode::serialize(&template).unwrap();
+
+        assert_eq!(template, bincode::deserialize::<Template>(&bytes).unwrap());
+    }
+
+    #[test]
+    fn text_nodes_share_the_source() {
+        let template = Template::parse("ab{{ x }}cd").unwrap();
+        let texts    = template.nodes.iter().filter_map(|node| match *node {
+            Node::Text(ref text, _) => Some(text.as_ptr() as usize),
+            _                       => None
+        }).collect::<Vec<_>>();
+
+        assert_eq!(texts[0] + 9, texts[1]);
+    }
+
+    #[test]
+    fn size_hint_counts_text_and_outputs() {
+        assert_eq!(4 + OUTPUT_SIZE_HINT, Template::parse("ab{{ x }}{% if y %}cd{% endif %}").unwrap().size_hint());
+    }
+
+    #[test]
+    fn templates_are_send_and_sync() {
+        fn shareable<T: Send + Sync>() {}
+
+        shareable::<Template>();
+        shareable::<Warning>();
+    }
+}
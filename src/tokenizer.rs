@@ -1,113 +1,753 @@
-use std::fmt;
+use std::io::{self,Read};
+use std::str;
 
-use regex::Regex;
+use memchr::{memchr,memchr2};
 
-type SliceVec = Vec<(usize, usize)>;
+use lexer::{LexedToken,Lexer,Span,Token};
+use parser::ParserOptions;
 
-#[derive(Debug)]
-pub enum Pattern {
-    Template
+// The strings that open and close outputs and tags.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delimiters {
+    output: (String, String),
+    tag: (String, String),
+    // Only the defaults close an output on a single brace, as Ruby Liquid's tokenizer does.
+    loose_output: bool
 }
 
-impl fmt::Display for Pattern {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let result = match *self {
-            Pattern::Template => r"(\{%.*?%\}|\{\{.*?\}\}?|\{\{|\{%)"
-        };
+impl Default for Delimiters {
+    fn default() -> Delimiters {
+        Delimiters { output: ("{{".to_string(), "}}".to_string()), tag: ("{%".to_string(), "%}".to_string()), loose_output: true }
+    }
+}
+
+impl Delimiters {
+    // None if a delimiter is empty or outputs and tags open the same way.
+    pub fn new(output: (&str, &str), tag: (&str, &str)) -> Option<Delimiters> {
+        if [output.0, output.1, tag.0, tag.1].iter().any(|delimiter| delimiter.is_empty()) || output.0 == tag.0 {
+            return None;
+        }
+
+        if output == ("{{", "}}") && tag == ("{%", "%}") { return Some(Delimiters::default()); }
+
+        Some(Delimiters {
+            output: (output.0.to_string(), output.1.to_string()),
+            tag: (tag.0.to_string(), tag.1.to_string()),
+            loose_output: false
+        })
+    }
+
+    pub fn output(&self) -> (&str, &str) {
+        (&self.output.0, &self.output.1)
+    }
+
+    pub fn tag(&self) -> (&str, &str) {
+        (&self.tag.0, &self.tag.1)
+    }
+
+    // The byte range of the first tag or output in `haystack`. At the first opener, a closed token
+    // beats an unclosed one and the longer opener is tried first in case the other is a prefix of
+    // it. Most templates are mostly text, so this skips ahead with `memchr` rather than a regex.
+    pub fn find(&self, haystack: &str) -> Option<(usize, usize)> {
+        let (first, second) = if self.output.0.len() > self.tag.0.len() { (self.output(), self.tag()) } else { (self.tag(), self.output()) };
+        let bytes           = haystack.as_bytes();
+        let mut from        = 0;
+
+        while let Some(offset) = memchr2(first.0.as_bytes()[0], second.0.as_bytes()[0], &bytes[from..]) {
+            let start = from + offset;
+            let rest  = &haystack[start..];
+
+            let length = self.closed(rest, first)
+                .or_else(|| self.closed(rest, second))
+                .or_else(|| if rest.starts_with(first.0) { Some(first.0.len()) } else { None })
+                .or_else(|| if rest.starts_with(second.0) { Some(second.0.len()) } else { None });
+
+            if let Some(length) = length { return Some((start, start + length)); }
+            from = start + 1;
+        }
+
+        None
+    }
+
+    // Whether `text` holds anything that could close an output or tag.
+    fn closes(&self, text: &str) -> bool {
+        text.contains(&*self.output.1) || text.contains(&*self.tag.1) || (self.loose_output && text.contains('}'))
+    }
+
+    // How many bytes of a delimiter can be cut off at the end of a buffer.
+    fn overlap(&self) -> usize {
+        [&self.output.0, &self.output.1, &self.tag.0, &self.tag.1].iter().map(|delimiter| delimiter.len()).max().unwrap_or(1) - 1
+    }
+
+    // How long the token at the start of `rest` is if it opens with `open` and is closed.
+    fn closed(&self, rest: &str, (open, close): (&str, &str)) -> Option<usize> {
+        let inner = rest.strip_prefix(open)?;
+
+        if self.loose_output && open == self.output.0 {
+            let end = inner.find('}')? + 1;
+            return Some(open.len() + end + usize::from(inner[end..].starts_with('}')));
+        }
 
-        write!(f, "{}", result)
+        Some(open.len() + inner.find(close)? + close.len())
     }
 }
 
-impl Pattern {
-    pub fn to_regex(&self) -> Regex {
-        let pattern = format!("{}", self);
-        Regex::new(&pattern).unwrap()
+// Whether a tag or output strips the whitespace before it (`{{-`, `{%-`) or after it (`-}}`, `-%}`).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Trim {
+    pub before: bool,
+    pub after: bool
+}
+
+// A chunk of the template with its delimiters and whitespace control markers stripped. `name` is
+// empty when a tag doesn't start with one, and `markup` is then everything inside the tag.
+// `Unclosed` is a `{{` or `{%` with no closing delimiter anywhere after it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TemplateToken<'t> {
+    Text(&'t str),
+    Output { markup: &'t str, trim: Trim },
+    Tag { name: &'t str, markup: &'t str, trim: Trim },
+    Unclosed(&'t str)
+}
+
+impl<'t> TemplateToken<'t> {
+    // Classifies one of the slices the tokenizer cuts a template into.
+    pub fn new(chunk: &'t str) -> TemplateToken<'t> {
+        TemplateToken::with_delimiters(chunk, &Delimiters::default())
+    }
+
+    pub fn with_delimiters(chunk: &'t str, delimiters: &Delimiters) -> TemplateToken<'t> {
+        let (output, tag) = (delimiters.output(), delimiters.tag());
+
+        // The longer opener goes first in case the other is a prefix of it.
+        let candidates = if tag.0.len() > output.0.len() { [(tag, false), (output, true)] } else { [(output, true), (tag, false)] };
+        let matched    = candidates.iter().find(|((open, _), _)| chunk.starts_with(open));
+
+        let Some(&((open, close), is_output)) = matched else { return TemplateToken::Text(chunk) };
+        if chunk.len() == open.len() { return TemplateToken::Unclosed(chunk); }
+
+        let inner  = &chunk[open.len()..];
+        let inner  = inner.strip_suffix(close).unwrap_or(inner);
+        let before = inner.starts_with('-');
+        let markup = if before { &inner[1..] } else { inner };
+        let after  = markup.ends_with('-');
+        let markup = if after { &markup[..markup.len() - 1] } else { markup };
+        let trim   = Trim { before, after };
+
+        if is_output { return TemplateToken::Output { markup, trim }; }
+
+        let markup = markup.trim();
+        let length = tag_name(markup);
+
+        TemplateToken::Tag { name: &markup[..length], markup: markup[length..].trim_start(), trim }
     }
 }
 
+// How long the name at the start of a tag's markup is, or zero when it doesn't start with one.
+fn tag_name(markup: &str) -> usize {
+    if !markup.starts_with(|c: char| c == '_' || c.is_alphabetic()) { return 0; }
+    markup.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(markup.len())
+}
+
 pub struct Tokenizer<'t> {
     source: &'t str
 }
 
 impl<'t> Tokenizer<'t> {
     pub fn new<'a>(source: &'a str) -> Tokenizer<'a> {
-        Tokenizer { source: source }
+        Tokenizer { source }
     }
 
-    pub fn tokenize<'a>(&'a self, pattern: &'a Regex) -> Vec<&'a str> {
-        let slices = self.matched_slices(pattern);
-        slices.iter().map(|&(start, end)| &self.source[start..end]).collect()
+    pub fn tokenize<'r>(&self, delimiters: &'r Delimiters) -> impl Iterator<Item = TemplateToken<'t>> + 'r where 't: 'r {
+        let source = self.source;
+        self.slices(delimiters).map(move |(start, end)| TemplateToken::with_delimiters(&source[start..end], delimiters))
     }
 
-    fn matched_slices(&self, pattern: &Regex) -> SliceVec {
-        let mut slices = pattern.find_iter(self.source).collect::<Vec<_>>();
-        let missing = self.find_missing_slices(&slices);
+    // Like `tokenize`, along with where each token is in the source.
+    pub fn tokenize_with_spans<'r>(&self, delimiters: &'r Delimiters) -> impl Iterator<Item = (TemplateToken<'t>, Span)> + 'r where 't: 'r {
+        let source     = self.source;
+        let mut cursor = Span::default();
+
+        self.slices(delimiters).map(move |(start, end)| {
+            let chunk = &source[start..end];
+            let span  = Span { end, ..cursor };
+            cursor    = cursor.after(chunk);
 
-        slices.extend(&missing);
-        slices.sort();
-        slices
+            (TemplateToken::with_delimiters(chunk, delimiters), span)
+        })
     }
 
-    fn find_missing_slices(&self, slices: &SliceVec) -> SliceVec {
-        if slices.is_empty() { return vec![(0, self.source.len())]; }
+    // Like `tokenize_with_spans` with `options.delimiters`, but with `options.fused` set each output
+    // and registered tag is lexed while looking for its end, and comes with its markup's tokens.
+    pub fn fused<'r>(&self, options: &'r ParserOptions) -> Fused<'r, 't> {
+        let fuse = options.fused && options.delimiters == Delimiters::default();
+        Fused { source: self.source, options, fuse, pending: None, position: 0, cursor: Span::default(), done: false }
+    }
 
-        let mut missing = self.missing_middle_slices(slices);
+    // The byte ranges `tokenize` cuts the source into: every tag and output along with the text
+    // between them, in order.
+    pub fn slices<'d>(&self, delimiters: &'d Delimiters) -> Slices<'d, 't> {
+        Slices { source: self.source, delimiters, next_match: None, position: 0, done: false }
+    }
+}
 
-        if let Some(first) = self.missing_first_slice(slices) {
-            missing.push(first);
+// A Jekyll-style block fenced by `---` lines at the very start of a template. Returns the raw text
+// between the fences and the source after the closing one.
+pub fn front_matter(source: &str) -> Option<(&str, &str)> {
+    let rest         = source.strip_prefix("---\n").or_else(|| source.strip_prefix("---\r\n"))?;
+    let mut position = 0;
+
+    for line in rest.split_inclusive('\n') {
+        if line.trim_end_matches(['\r', '\n']) == "---" {
+            return Some((&rest[..position], &rest[position + line.len()..]));
         }
 
-        if let Some(last) = self.missing_last_slice(slices) {
-            missing.push(last);
+        position += line.len();
+    }
+
+    None
+}
+
+// Walks the source once, yielding the text before each match and then the match itself. An empty
+// source is a single empty slice.
+pub struct Slices<'d, 't> {
+    source: &'t str,
+    delimiters: &'d Delimiters,
+    next_match: Option<(usize, usize)>,
+    position: usize,
+    done: bool
+}
+
+impl<'d, 't> Iterator for Slices<'d, 't> {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        if self.done { return None; }
+
+        let position = self.position;
+        let matched  = self.next_match.take().or_else(|| {
+            self.delimiters.find(&self.source[position..]).map(|(start, end)| (position + start, position + end))
+        });
+
+        let (start, end) = match matched {
+            Some((start, end)) if start > self.position => {
+                self.next_match = Some((start, end));
+                (self.position, start)
+            },
+            Some(matched) => matched,
+            None => {
+                self.done = true;
+                if self.position == self.source.len() && self.position > 0 { return None; }
+
+                (self.position, self.source.len())
+            }
+        };
+
+        self.position = end;
+        Some((start, end))
+    }
+}
+
+// A chunk cut by `Tokenizer::fused`, and its markup's tokens if they were lexed along the way.
+pub type FusedToken<'t> = (TemplateToken<'t>, Span, Option<Vec<LexedToken>>);
+
+// Cuts the source into the same chunks as `Slices`. The usual cut finds a closing delimiter and
+// then the markup before it is lexed; here the markup is lexed until a closing delimiter turns up
+// where the next token would start. Anything that might not end up cut the same way, like a string
+// holding a closing delimiter or a character the lexer stops at, is cut the usual way instead.
+pub struct Fused<'r, 't> {
+    source: &'t str,
+    options: &'r ParserOptions,
+    fuse: bool,
+    pending: Option<(usize, usize, Option<Vec<LexedToken>>)>,
+    position: usize,
+    cursor: Span,
+    done: bool
+}
+
+impl<'r, 't> Fused<'r, 't> {
+    // The next tag or output at or after `from`.
+    fn next_match(&self, from: usize) -> Option<(usize, usize, Option<Vec<LexedToken>>)> {
+        let rest = &self.source[from..];
+        if !self.fuse { return self.options.delimiters.find(rest).map(|(start, end)| (from + start, from + end, None)); }
+
+        let mut at = 0;
+        while let Some(offset) = memchr(b'{', &rest.as_bytes()[at..]) {
+            let start = at + offset;
+            let chunk = &rest[start..];
+
+            if chunk.starts_with("{{") || chunk.starts_with("{%") {
+                return match self.lex(chunk) {
+                    Some((length, tokens)) => Some((from + start, from + start + length, Some(tokens))),
+                    // The usual cut always matches at an opener.
+                    None => self.options.delimiters.find(chunk).map(|(open, end)| (from + start + open, from + start + end, None))
+                };
+            }
+
+            at = start + 1;
         }
 
-        missing
+        None
     }
 
-    fn missing_first_slice(&self, slices: &SliceVec) -> Option<(usize, usize)> {
-        let pos = slices[0].0;
-        if pos == 0 { return None; }
+    // The length of the output or tag opening `chunk` and its markup's tokens. The tokens' spans
+    // are measured from where `TemplateToken` will say the markup starts.
+    fn lex(&self, chunk: &str) -> Option<(usize, Vec<LexedToken>)> {
+        let output = chunk.starts_with("{{");
+        let inner  = &chunk[2..];
+        let inner  = inner.strip_prefix('-').unwrap_or(inner);
+
+        let (markup, close) = if output {
+            (inner, "}}")
+        } else {
+            let inner = inner.trim_start();
+            let name  = tag_name(inner);
+            if name == 0 || self.options.tags.get(&inner[..name]).is_none() { return None; }
+
+            (inner[name..].trim_start(), "%}")
+        };
+
+        let mut lexer = Lexer::new(markup);
+        lexer.set_unicode_identifiers(self.options.unicode_identifiers);
 
-        Some((0, pos))
+        let closed         = |rest: &str| rest.starts_with(close) || rest.strip_prefix('-').is_some_and(|rest| rest.starts_with(close));
+        let (tokens, stop) = lexer.tokens().until(closed)?;
+
+        // The usual cut ends at the first closer even inside a string, and outputs at a single brace.
+        let closer = if output { "}" } else { close };
+        if tokens.iter().any(|(token, value, _)| *token == Token::String && value.contains(closer)) { return None; }
+
+        let end = chunk.len() - markup.len() + stop + usize::from(markup[stop..].starts_with('-')) + close.len();
+        Some((end, tokens))
     }
+}
+
+impl<'r, 't> Iterator for Fused<'r, 't> {
+    type Item = FusedToken<'t>;
+
+    fn next(&mut self) -> Option<FusedToken<'t>> {
+        if self.done { return None; }
 
-    fn missing_last_slice(&self, slices: &SliceVec) -> Option<(usize, usize)> {
-        let last = slices.last().unwrap();
-        let len  = self.source.len();
-        if last.1 == len { return None; }
+        let position = self.position;
+        let matched  = self.pending.take().or_else(|| self.next_match(position));
 
-        Some((last.1, len))
+        let (start, end, tokens) = match matched {
+            Some((start, end, tokens)) if start > position => {
+                self.pending = Some((start, end, tokens));
+                (position, start, None)
+            },
+            Some(matched) => matched,
+            None => {
+                self.done = true;
+                if position == self.source.len() && position > 0 { return None; }
+
+                (position, self.source.len(), None)
+            }
+        };
+
+        let chunk     = &self.source[start..end];
+        let span      = Span { end, ..self.cursor };
+        self.cursor   = self.cursor.after(chunk);
+        self.position = end;
+
+        Some((TemplateToken::with_delimiters(chunk, &self.options.delimiters), span, tokens))
+    }
+}
+
+const READ_SIZE: usize = 8 * 1024;
+
+// Cuts a template into the same chunks as `Tokenizer::slices` while reading it, so it never has to
+// be in memory all at once. Only the token being cut is kept, which for a tag or output that's
+// never closed means the rest of the input. Text longer than a read comes out in pieces rather than
+// being kept until the next tag, so it's the one place the chunks differ. Each read is searched
+// once, along with the few bytes before it a delimiter could have started in. Use
+// `TemplateToken::with_delimiters` to classify the chunks.
+pub struct StreamTokenizer<'d, R> {
+    reader: R,
+    delimiters: &'d Delimiters,
+    buffer: String,
+    position: usize,
+    // How much of the buffer after `position` has been searched without settling on a chunk.
+    searched: usize,
+    // The tag or output at `position` that more input could still change, from the last search.
+    unsettled: Option<usize>,
+    // Bytes of a character split across reads.
+    partial: Vec<u8>,
+    eof: bool
+}
+
+impl<'d, R: Read> StreamTokenizer<'d, R> {
+    pub fn new(reader: R, delimiters: &'d Delimiters) -> StreamTokenizer<'d, R> {
+        StreamTokenizer { reader, delimiters, buffer: String::new(), position: 0, searched: 0, unsettled: None, partial: vec![], eof: false }
+    }
+
+    // Whether more input could still change the match: the default `}}?` might gain a brace, a bare
+    // opener might be closed later, and so might the longer opener when only the shorter opener's
+    // closer has been seen so far.
+    fn settled(&self, chunk: &str, end: usize) -> bool {
+        if self.eof { return true; }
+        if end == self.buffer.len() { return false; }
+
+        let (output, tag) = (self.delimiters.output(), self.delimiters.tag());
+        let longer        = if output.0.len() > tag.0.len() { output } else { tag };
+
+        chunk != output.0 && chunk != tag.0 &&
+            (!chunk.starts_with(longer.0) || (chunk.len() >= longer.0.len() + longer.1.len() && chunk.ends_with(longer.1)))
     }
 
-    fn missing_middle_slices(&self, slices: &SliceVec) -> SliceVec {
-        let mut missing: SliceVec = Vec::new();
-        let last_index            = slices.len() - 1;
+    // The next match in the rest of the buffer. A tag or output that wasn't settled is only looked
+    // for again once something that could close it has been read, and text that had no match is
+    // only searched from where the last search stopped.
+    fn next_match(&self) -> Option<(usize, usize)> {
+        let rest = &self.buffer[self.position..];
+        let from = floor_char_boundary(rest, self.searched.saturating_sub(self.delimiters.overlap()));
+
+        match self.unsettled {
+            Some(end) if !self.delimiters.closes(&rest[from..]) => Some((0, end)),
+            Some(_)                                             => self.delimiters.find(rest),
+            None                                                => self.delimiters.find(&rest[from..]).map(|(start, end)| (from + start, from + end))
+        }
+    }
 
-        for (index, &(_, right)) in slices.iter().enumerate() {
-            if index == last_index { continue; }
+    // Hands out the next `length` bytes of the buffer.
+    fn take(&mut self, length: usize) -> String {
+        let chunk      = self.buffer[self.position..self.position + length].to_string();
+        self.position += length;
+        self.searched  = 0;
+        self.unsettled = None;
 
-            let edge = slices[index + 1].0;
-            if right != edge {
-                missing.push((right, edge));
+        chunk
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        self.buffer.drain(..self.position);
+        self.position = 0;
+
+        let mut bytes = [0; READ_SIZE];
+        let read      = loop {
+            match self.reader.read(&mut bytes) {
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                result                                                       => break result?
             }
+        };
+
+        if read == 0 {
+            self.eof = true;
+            if !self.partial.is_empty() { return Err(io::Error::new(io::ErrorKind::InvalidData, "stream ended mid-character")); }
+
+            return Ok(());
         }
 
-        missing
+        self.partial.extend_from_slice(&bytes[..read]);
+        let valid = match str::from_utf8(&self.partial) {
+            Ok(text)                                 => text.len(),
+            Err(error) if error.error_len().is_none() => error.valid_up_to(),
+            Err(error)                               => return Err(io::Error::new(io::ErrorKind::InvalidData, error))
+        };
+
+        self.buffer.push_str(str::from_utf8(&self.partial[..valid]).expect("checked above"));
+        self.partial.drain(..valid);
+
+        Ok(())
+    }
+}
+
+impl<'d, R: Read> Iterator for StreamTokenizer<'d, R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        loop {
+            let length = self.buffer.len() - self.position;
+
+            // Text before a match is settled even when the match isn't, since nothing read later
+            // can start a match before it.
+            match self.next_match() {
+                Some((0, end)) => {
+                    if self.settled(&self.buffer[self.position..self.position + end], self.position + end) { return Some(Ok(self.take(end))); }
+                    self.unsettled = Some(end);
+                },
+                Some((start, _))                => return Some(Ok(self.take(start))),
+                None if self.eof && length == 0 => return None,
+                None if self.eof                => return Some(Ok(self.take(length))),
+                None => {
+                    let text = floor_char_boundary(&self.buffer[self.position..], length.saturating_sub(self.delimiters.overlap()));
+                    if text >= READ_SIZE { return Some(Ok(self.take(text))); }
+                }
+            }
+
+            self.searched = length;
+            if let Err(error) = self.fill() {
+                self.eof = true;
+                self.buffer.clear();
+                self.position  = 0;
+                self.searched  = 0;
+                self.unsettled = None;
+
+                return Some(Err(error));
+            }
+        }
     }
 }
 
+// The closest character boundary in `text` at or before `index`.
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while !text.is_char_boundary(index) { index -= 1; }
+    index
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use regex::Regex;
 
     fn assert_tokens(tokenizer: &Tokenizer, expected: Vec<&str>) {
-        let re     = Pattern::Template.to_regex();
-        let actual = tokenizer.tokenize(&re);
+        let actual   = tokenizer.tokenize(&Delimiters::default()).collect::<Vec<_>>();
+        let expected = expected.into_iter().map(TemplateToken::new).collect::<Vec<_>>();
 
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn template_tokens_strip_delimiters() {
+        let none = Trim::default();
+
+        assert_eq!(TemplateToken::Text("a {b} %}"), TemplateToken::new("a {b} %}"));
+        assert_eq!(TemplateToken::Output { markup: " user.name ", trim: none }, TemplateToken::new("{{ user.name }}"));
+        assert_eq!(TemplateToken::Tag { name: "if", markup: "a == b", trim: none }, TemplateToken::new("{%  if a == b %}"));
+        assert_eq!(TemplateToken::Tag { name: "endif", markup: "", trim: none }, TemplateToken::new("{%endif%}"));
+        assert_eq!(TemplateToken::Tag { name: "", markup: "5x y", trim: none }, TemplateToken::new("{% 5x y %}"));
+    }
+
+    #[test]
+    fn template_tokens_spot_unclosed_delimiters() {
+        let tokenizer = Tokenizer::new("{{ a }} {% c\n%} {{ b {%");
+
+        assert_eq!(vec![
+            TemplateToken::Output { markup: " a ", trim: Trim::default() },
+            TemplateToken::Text(" "),
+            TemplateToken::Tag { name: "c", markup: "", trim: Trim::default() },
+            TemplateToken::Text(" "),
+            TemplateToken::Unclosed("{{"),
+            TemplateToken::Text(" b "),
+            TemplateToken::Unclosed("{%")
+        ], tokenizer.tokenize(&Delimiters::default()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn fused_cuts_the_same_chunks() {
+        let options = ParserOptions { fused: true, ..ParserOptions::default() };
+        let sources = [
+            "Hi {{ user.name | upcase }}!{%- if user -%} {%endif%}",
+            "{{ '}' }} {% assign a = '%}' %} {% mystery § %} {{ a } b }} {{ 5 -}}",
+            "{ {{ a -1}}{% if a--%}{{- b",
+            "{{ # }} {% if § %} {{"
+        ];
+
+        for source in &sources {
+            let tokenizer = Tokenizer::new(source);
+            let fused     = tokenizer.fused(&options).map(|(token, span, _)| (token, span)).collect::<Vec<_>>();
+            assert_eq!(tokenizer.tokenize_with_spans(&options.delimiters).collect::<Vec<_>>(), fused, "cutting {:?}", source);
+        }
+
+        let lexed = Tokenizer::new("{{ a }}{% if b %}{% mystery %}{{ '}' }}").fused(&options).map(|(_, _, tokens)| tokens.map(|tokens| tokens.len())).collect::<Vec<_>>();
+        assert_eq!(vec![Some(1), Some(1), None, None, None], lexed);
+    }
+
+    #[test]
+    fn delimiters_can_be_changed() {
+        let delimiters = Delimiters::new(("[[", "]]"), ("[%", "%]")).unwrap();
+        let tokenizer  = Tokenizer::new("{{ a }} [[- b ]] [% if c %] [%");
+
+        assert_eq!(vec![
+            TemplateToken::Text("{{ a }} "),
+            TemplateToken::Output { markup: " b ", trim: Trim { before: true, after: false } },
+            TemplateToken::Text(" "),
+            TemplateToken::Tag { name: "if", markup: "c", trim: Trim::default() },
+            TemplateToken::Text(" "),
+            TemplateToken::Unclosed("[%")
+        ], tokenizer.tokenize(&delimiters).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn delimiters_check_longer_openers_first() {
+        let delimiters = Delimiters::new(("<", ">"), ("<%", "%>")).unwrap();
+        let tokenizer  = Tokenizer::new("<a> <% b %>");
+
+        assert_eq!(vec![
+            TemplateToken::Output { markup: "a", trim: Trim::default() },
+            TemplateToken::Text(" "),
+            TemplateToken::Tag { name: "b", markup: "", trim: Trim::default() }
+        ], tokenizer.tokenize(&delimiters).collect::<Vec<_>>());
+
+        let delimiters = Delimiters::new(("[[", "]]"), ("[", "]")).unwrap();
+        let tokenizer  = Tokenizer::new("[[ a ]] [b]");
+
+        assert_eq!(vec![
+            TemplateToken::Output { markup: " a ", trim: Trim::default() },
+            TemplateToken::Text(" "),
+            TemplateToken::Tag { name: "b", markup: "", trim: Trim::default() }
+        ], tokenizer.tokenize(&delimiters).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn delimiters_find_what_the_template_pattern_matches() {
+        // The regex the tokenizer used before `Delimiters::find`, and its equivalent for `custom`.
+        let template = Regex::new(r"(?s)(\{%.*?%\}|\{\{.*?\}\}?|\{\{|\{%)").unwrap();
+        let custom   = Delimiters::new(("<", ">"), ("<%", "%>")).unwrap();
+        let pattern  = Regex::new(r"(?s)(<%.*?%>|<.*?>|<%|<)").unwrap();
+
+        let sources = vec![
+            "", "text", "{", "}", "{{", "{%", "{%}", "{{}", "{{ a }", "{{ a }}}", "{{ a %}", "{% a }}",
+            "a {{ b {% c\n%}", "{%- if a -%}\n{{ b }}", "é { ü {{ ö }} {% x", "{{ {{ }} }}",
+            "<", "<%", "<% a >", "<% a %>", "a < b <% c > %> d"
+        ];
+
+        let bounds = |found: regex::Match| (found.start(), found.end());
+
+        for source in sources {
+            assert_eq!(template.find(source).map(bounds), Delimiters::default().find(source), "{:?}", source);
+            assert_eq!(pattern.find(source).map(bounds), custom.find(source), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn delimiters_must_be_distinct_and_not_empty() {
+        assert_eq!(None, Delimiters::new(("", "}}"), ("{%", "%}")));
+        assert_eq!(None, Delimiters::new(("{{", "}}"), ("{{", "%}")));
+        assert_eq!(Some(Delimiters::default()), Delimiters::new(("{{", "}}"), ("{%", "%}")));
+    }
+
+    // Hands out at most `size` bytes per read.
+    struct Trickle<'a> {
+        bytes: &'a [u8],
+        size: usize
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            let size = self.size.min(buffer.len()).min(self.bytes.len());
+            buffer[..size].copy_from_slice(&self.bytes[..size]);
+            self.bytes = &self.bytes[size..];
+
+            Ok(size)
+        }
+    }
+
+    fn stream(source: &str, size: usize, delimiters: &Delimiters) -> Vec<String> {
+        StreamTokenizer::new(Trickle { bytes: source.as_bytes(), size }, delimiters).collect::<io::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn stream_tokenizer_cuts_the_same_chunks_as_slices() {
+        let default = Delimiters::default();
+        let custom  = Delimiters::new(("<", ">"), ("<%", "%>")).unwrap();
+
+        let cases = vec![
+            ("", &default),
+            ("plain text", &default),
+            ("é {{ a }}\n{%- if b -%}ü{{ c }", &default),
+            ("{{ a }}} {{ b }}} {{", &default),
+            ("{% a {{ b }} %} {{ c", &default),
+            ("<a> <% b > c %> <% d > <", &custom)
+        ];
+
+        for (source, delimiters) in cases {
+            let expected = Tokenizer::new(source).slices(delimiters)
+                .filter(|(start, end)| start < end)
+                .map(|(start, end)| source[start..end].to_string())
+                .collect::<Vec<_>>();
+
+            for size in 1..source.len() + 2 {
+                assert_eq!(expected, stream(source, size, delimiters), "{:?} read {} bytes at a time", source, size);
+            }
+        }
+    }
+
+    #[test]
+    fn stream_tokenizer_keeps_a_bounded_buffer_for_long_text() {
+        let delimiters = Delimiters::default();
+        let source     = format!("{}{{{{ a }}}}", "é long line of text without any tags in it {\n".repeat(100_000));
+        let mut tokens = StreamTokenizer::new(Trickle { bytes: source.as_bytes(), size: 100 }, &delimiters);
+        let mut chunks = vec![];
+
+        while let Some(chunk) = tokens.next() {
+            chunks.push(chunk.unwrap());
+            assert!(tokens.buffer.len() < 3 * READ_SIZE, "buffered {} bytes", tokens.buffer.len());
+        }
+
+        assert!(source.len() > 4_000_000 && chunks.len() > 2);
+        assert_eq!("{{ a }}", chunks.pop().unwrap());
+        assert_eq!(source.trim_end_matches("{{ a }}"), chunks.concat());
+    }
+
+    #[test]
+    fn stream_tokenizer_searches_an_unclosed_tag_once() {
+        let delimiters = Delimiters::default();
+        let source     = format!("a {{% {} %}} b", "x".repeat(2_000_000));
+
+        assert_eq!(vec!["a ".to_string(), source[2..source.len() - 2].to_string(), " b".to_string()], stream(&source, 64, &delimiters));
+        assert_eq!(vec!["{%".to_string(), source[4..source.len() - 5].to_string()], stream(&source[2..source.len() - 5], 64, &delimiters));
+    }
+
+    #[test]
+    fn stream_tokenizer_rejects_invalid_utf8() {
+        let delimiters = Delimiters::default();
+
+        let mut tokens = StreamTokenizer::new(&b"ab \xff {{ c }}"[..], &delimiters);
+        assert_eq!(io::ErrorKind::InvalidData, tokens.next().unwrap().unwrap_err().kind());
+        assert!(tokens.next().is_none());
+
+        let mut tokens = StreamTokenizer::new(&b"ab \xc3"[..], &delimiters);
+        assert_eq!(io::ErrorKind::InvalidData, tokens.next().unwrap().unwrap_err().kind());
+    }
+
+    #[test]
+    fn front_matter_is_split_from_the_template() {
+        assert_eq!(Some(("title: Home\ntags: [a]\n", "{{ title }}")), front_matter("---\ntitle: Home\ntags: [a]\n---\n{{ title }}"));
+        assert_eq!(Some(("a: 1\r\n", "b")), front_matter("---\r\na: 1\r\n---\r\nb"));
+        assert_eq!(Some(("", "")), front_matter("---\n---"));
+        assert_eq!(Some(("a: ---\n", "")), front_matter("---\na: ---\n---\n"));
+    }
+
+    #[test]
+    fn front_matter_needs_both_fences_at_the_start() {
+        assert_eq!(None, front_matter("---\na: 1\n"));
+        assert_eq!(None, front_matter(" ---\na: 1\n---\n"));
+        assert_eq!(None, front_matter("----\na: 1\n---\n"));
+        assert_eq!(None, front_matter("---"));
+    }
+
+    #[test]
+    fn template_tokens_record_whitespace_control() {
+        assert_eq!(TemplateToken::Output { markup: " x ", trim: Trim { before: true, after: true } }, TemplateToken::new("{{- x -}}"));
+        assert_eq!(TemplateToken::Output { markup: " -1 ", trim: Trim { before: false, after: false } }, TemplateToken::new("{{ -1 }}"));
+        assert_eq!(TemplateToken::Tag { name: "for", markup: "x in xs", trim: Trim { before: false, after: true } }, TemplateToken::new("{% for x in xs -%}"));
+    }
+
+    #[test]
+    fn slices_walk_the_source_in_order() {
+        let tokenizer  = Tokenizer::new("{{a}}{{b}} x {%c%}");
+        let delimiters = Delimiters::default();
+        let slices     = tokenizer.slices(&delimiters);
+
+        assert_eq!(vec![(0, 5), (5, 10), (10, 13), (13, 18)], slices.collect::<Vec<_>>());
+        assert_eq!(Some((0, 2)), Tokenizer::new("ab").slices(&Delimiters::default()).next());
+    }
+
+    #[test]
+    fn tokenize_with_spans_records_where_tokens_start() {
+        let tokenizer  = Tokenizer::new("é {{ a }}\n\n  {% b %}");
+        let delimiters = Delimiters::default();
+        let spans      = tokenizer.tokenize_with_spans(&delimiters).map(|(_, span)| span);
+
+        assert_eq!(vec![
+            Span { start: 0, end: 3, line: 1, col: 1 },
+            Span { start: 3, end: 10, line: 1, col: 3 },
+            Span { start: 10, end: 14, line: 1, col: 10 },
+            Span { start: 14, end: 21, line: 3, col: 3 }
+        ], spans.collect::<Vec<_>>());
+    }
+
     #[test]
     fn tokenize_blank_string() {
         let tokenizer = Tokenizer::new("");
@@ -175,6 +815,12 @@ mod tests {
         ]);
     }
 
+    #[test]
+    fn tokenize_whitespace_control_markers() {
+        let tokenizer = Tokenizer::new("a {%- if x -%} {{- x -}} {{-x}}");
+        assert_tokens(&tokenizer, vec!["a ", "{%- if x -%}", " ", "{{- x -}}", " ", "{{-x}}"]);
+    }
+
     #[test]
     fn tokenize_multiline_string() {
         let tokenizer = Tokenizer::new("{%comment%}\nMy Comment\n{%endcomment%}\n");
@@ -205,7 +851,7 @@ mod tests {
 </html>
         "#;
 
-        let tokenizer = Tokenizer::new(&content);
+        let tokenizer = Tokenizer::new(content);
         assert_tokens(&tokenizer, vec![
             "\n<html>\n  <head>\n    <title>",
             "{{ title }}",
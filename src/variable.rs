@@ -0,0 +1,827 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+
+use context::Context;
+use small_string::SmallString;
+
+#[cfg(feature = "chrono")]
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+#[cfg(feature = "serde")]
+use serde::{Deserialize,Deserializer,Serialize,Serializer};
+#[cfg(feature = "serde")]
+use serde::de::{MapAccess,SeqAccess,Visitor};
+#[cfg(feature = "serde_json")]
+use serde_json::Value;
+
+pub type VariableResult<T> = Result<T, VariableError>;
+
+pub type LambdaFn = dyn Fn(&Context) -> Variable + Send + Sync;
+
+#[derive(Clone, Debug)]
+pub enum Variable {
+    Nil,
+    Empty,
+    Blank,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(SmallString),
+    // A string that's already markup, like the output of `escape`, so auto-escaping leaves it be.
+    // Otherwise it's the same as a Str.
+    Safe(SmallString),
+    Array(Vec<Variable>),
+    Object(HashMap<String, Variable>),
+    Lambda(Lambda),
+    #[cfg(feature = "chrono")]
+    Date(DateTime<FixedOffset>)
+}
+
+#[derive(Clone)]
+pub struct Lambda(Arc<LambdaFn>);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VariableError {
+    InvalidComparison(&'static str, &'static str),
+    DivisionByZero,
+    Overflow(&'static str)
+}
+
+impl fmt::Display for VariableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VariableError::InvalidComparison(left, right) => {
+                write!(f, "Comparison of {} with {} failed", left, right)
+            },
+            VariableError::DivisionByZero      => write!(f, "Divided by 0"),
+            VariableError::Overflow(operation) => write!(f, "Integer overflow in {}", operation)
+        }
+    }
+}
+
+impl error::Error for VariableError {}
+
+impl Lambda {
+    pub fn new<F>(function: F) -> Lambda where F: Fn(&Context) -> Variable + Send + Sync + 'static {
+        Lambda(Arc::new(function))
+    }
+
+    pub fn call(&self, context: &Context) -> Variable {
+        (self.0)(context)
+    }
+}
+
+impl fmt::Debug for Lambda {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Lambda")
+    }
+}
+
+impl PartialEq for Lambda {
+    fn eq(&self, other: &Lambda) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl From<bool> for Variable {
+    fn from(value: bool) -> Variable {
+        Variable::Bool(value)
+    }
+}
+
+impl From<i32> for Variable {
+    fn from(value: i32) -> Variable {
+        Variable::Int(value as i64)
+    }
+}
+
+impl From<i64> for Variable {
+    fn from(value: i64) -> Variable {
+        Variable::Int(value)
+    }
+}
+
+impl From<f64> for Variable {
+    fn from(value: f64) -> Variable {
+        Variable::Float(value)
+    }
+}
+
+impl<'a> From<&'a str> for Variable {
+    fn from(value: &'a str) -> Variable {
+        Variable::Str(value.into())
+    }
+}
+
+impl From<String> for Variable {
+    fn from(value: String) -> Variable {
+        Variable::Str(value.into())
+    }
+}
+
+impl<T: Into<Variable>> From<Vec<T>> for Variable {
+    fn from(values: Vec<T>) -> Variable {
+        Variable::Array(values.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Variable>> From<Option<T>> for Variable {
+    fn from(value: Option<T>) -> Variable {
+        value.map_or(Variable::Nil, Into::into)
+    }
+}
+
+// As plain data, so Safe strings are written as strings, dates as RFC 3339 strings and empty,
+// blank and lambdas as nil, and that's what they read back as. Hashes are written in the order of
+// their keys, so the output is the same every time.
+#[cfg(feature = "serde")]
+impl Serialize for Variable {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Variable::Nil | Variable::Empty | Variable::Blank | Variable::Lambda(_) => serializer.serialize_unit(),
+            Variable::Bool(value)                                => serializer.serialize_bool(value),
+            Variable::Int(value)                                 => serializer.serialize_i64(value),
+            Variable::Float(value)                               => serializer.serialize_f64(value),
+            Variable::Str(ref value) | Variable::Safe(ref value) => serializer.serialize_str(value),
+            Variable::Array(ref items)                           => serializer.collect_seq(items),
+            Variable::Object(ref map)                            => {
+                let mut entries = map.iter().collect::<Vec<_>>();
+                entries.sort_by_key(|&(key, _)| key);
+
+                serializer.collect_map(entries)
+            },
+            #[cfg(feature = "chrono")]
+            Variable::Date(ref value)                            => serializer.serialize_str(&value.to_rfc3339())
+        }
+    }
+}
+
+// From any self-describing format, like JSON or YAML. Numbers that fit an i64 become Ints and the
+// rest Floats.
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Variable {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Variable, D::Error> {
+        deserializer.deserialize_any(VariableVisitor)
+    }
+}
+
+#[cfg(feature = "serde")]
+struct VariableVisitor;
+
+#[cfg(feature = "serde")]
+impl<'de> Visitor<'de> for VariableVisitor {
+    type Value = Variable;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "nil, a boolean, number, string, array or hash")
+    }
+
+    fn visit_unit<E>(self) -> Result<Variable, E> {
+        Ok(Variable::Nil)
+    }
+
+    fn visit_none<E>(self) -> Result<Variable, E> {
+        Ok(Variable::Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Variable, D::Error> {
+        Variable::deserialize(deserializer)
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<Variable, E> {
+        Ok(Variable::Bool(value))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<Variable, E> {
+        Ok(Variable::Int(value))
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<Variable, E> {
+        match value <= i64::MAX as u64 {
+            true  => Ok(Variable::Int(value as i64)),
+            false => Ok(Variable::Float(value as f64))
+        }
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<Variable, E> {
+        Ok(Variable::Float(value))
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Variable, E> {
+        Ok(Variable::Str(value.into()))
+    }
+
+    fn visit_string<E>(self, value: String) -> Result<Variable, E> {
+        Ok(Variable::Str(value.into()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Variable, A::Error> {
+        let mut items = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(item) = seq.next_element()? { items.push(item); }
+
+        Ok(Variable::Array(items))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Variable, A::Error> {
+        let mut object = HashMap::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some((key, value)) = map.next_entry()? { object.insert(key, value); }
+
+        Ok(Variable::Object(object))
+    }
+}
+
+// Numbers that fit an i64 become Ints and the rest Floats.
+#[cfg(feature = "serde_json")]
+impl From<Value> for Variable {
+    fn from(value: Value) -> Variable {
+        match value {
+            Value::Null                              => Variable::Nil,
+            Value::Bool(value)                       => Variable::Bool(value),
+            Value::Number(number) if number.is_i64() => Variable::Int(number.as_i64().unwrap_or_default()),
+            Value::Number(number)                    => Variable::Float(number.as_f64().unwrap_or_default()),
+            Value::String(value)                     => Variable::Str(value.into()),
+            Value::Array(values)                     => Variable::Array(values.into_iter().map(Variable::from).collect()),
+            Value::Object(map)                       => Variable::Object(map.into_iter().map(|(key, value)| (key, Variable::from(value))).collect())
+        }
+    }
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Variable::Nil | Variable::Empty | Variable::Blank | Variable::Lambda(_) => Ok(()),
+            Variable::Bool(value)      => write!(f, "{}", value),
+            Variable::Int(value)       => write!(f, "{}", value),
+            Variable::Float(value)     => write!(f, "{}", format_float(value)),
+            Variable::Str(ref value)   => write!(f, "{}", value),
+            Variable::Safe(ref value)  => write!(f, "{}", value),
+            Variable::Array(ref items) => items.iter().try_for_each(|item| write!(f, "{}", item)),
+            Variable::Object(_)        => write!(f, "{}", self.inspect()),
+            #[cfg(feature = "chrono")]
+            Variable::Date(ref value)  => write!(f, "{}", value.format("%Y-%m-%d %H:%M:%S %z"))
+        }
+    }
+}
+
+impl PartialEq for Variable {
+    fn eq(&self, other: &Variable) -> bool {
+        match (self, other) {
+            (Variable::Empty, Variable::Empty) => true,
+            (Variable::Blank, Variable::Blank) => true,
+            (Variable::Empty, value) | (value, Variable::Empty) => value.is_empty(),
+            (Variable::Blank, value) | (value, Variable::Blank) => value.is_blank(),
+            (Variable::Nil, Variable::Nil) => true,
+            (Variable::Bool(a), Variable::Bool(b)) => a == b,
+            (Variable::Int(a), Variable::Int(b)) => a == b,
+            (Variable::Float(a), Variable::Float(b)) => a == b,
+            (&Variable::Int(a), &Variable::Float(b)) => a as f64 == b,
+            (&Variable::Float(a), &Variable::Int(b)) => a == b as f64,
+            (a, b) if a.as_str().is_some() && b.as_str().is_some() => a.as_str() == b.as_str(),
+            (Variable::Array(a), Variable::Array(b)) => a == b,
+            (Variable::Object(a), Variable::Object(b)) => a == b,
+            (Variable::Lambda(a), Variable::Lambda(b)) => a == b,
+            #[cfg(feature = "chrono")]
+            (Variable::Date(a), Variable::Date(b)) => a == b,
+            _ => false
+        }
+    }
+}
+
+impl PartialOrd for Variable {
+    fn partial_cmp(&self, other: &Variable) -> Option<Ordering> {
+        match (self, other) {
+            (Variable::Int(a), Variable::Int(b)) => a.partial_cmp(b),
+            (Variable::Float(a), Variable::Float(b)) => a.partial_cmp(b),
+            (&Variable::Int(a), &Variable::Float(b)) => (a as f64).partial_cmp(&b),
+            (&Variable::Float(a), &Variable::Int(b)) => a.partial_cmp(&(b as f64)),
+            (a, b) if a.as_str().is_some() && b.as_str().is_some() => a.as_str().partial_cmp(&b.as_str()),
+            #[cfg(feature = "chrono")]
+            (Variable::Date(a), Variable::Date(b)) => a.partial_cmp(b),
+            _ if self == other => Some(Ordering::Equal),
+            _ => None
+        }
+    }
+}
+
+impl Variable {
+    pub fn render_to_string(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Variable::Nil       => "nil",
+            Variable::Empty     => "empty",
+            Variable::Blank     => "blank",
+            Variable::Bool(_)   => "boolean",
+            Variable::Int(_)    => "integer",
+            Variable::Float(_)  => "float",
+            Variable::Str(_)    => "string",
+            Variable::Safe(_)   => "string",
+            Variable::Array(_)  => "array",
+            Variable::Object(_) => "object",
+            Variable::Lambda(_) => "lambda",
+            #[cfg(feature = "chrono")]
+            Variable::Date(_)   => "date"
+        }
+    }
+
+    // Either kind of string.
+    pub fn as_str(&self) -> Option<&str> {
+        match *self {
+            Variable::Str(ref value) | Variable::Safe(ref value) => Some(value),
+            _                                                    => None
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match *self {
+            Variable::Str(ref value)   => value.is_empty(),
+            Variable::Safe(ref value)  => value.is_empty(),
+            Variable::Array(ref items) => items.is_empty(),
+            Variable::Object(ref map)  => map.is_empty(),
+            Variable::Empty            => true,
+            _                          => false
+        }
+    }
+
+    pub fn is_blank(&self) -> bool {
+        match *self {
+            Variable::Nil | Variable::Bool(false) => true,
+            Variable::Str(ref value)              => value.trim().is_empty(),
+            Variable::Safe(ref value)             => value.trim().is_empty(),
+            Variable::Blank                       => true,
+            _                                     => self.is_empty()
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        !matches!(*self, Variable::Nil | Variable::Bool(false))
+    }
+
+    // Numbers and strings can be ordered against their own kind, any other pair is simply not
+    // comparable (a false condition). Mixing a number with a string is an error, as in Liquid.
+    pub fn compare(&self, other: &Variable) -> VariableResult<Option<Ordering>> {
+        if self.is_orderable() && other.is_orderable() {
+            return self.partial_cmp(other)
+                .map(Some)
+                .ok_or_else(|| VariableError::InvalidComparison(self.type_name(), other.type_name()));
+        }
+
+        Ok(None)
+    }
+
+    pub fn contains(&self, other: &Variable) -> bool {
+        match (self, other.as_str()) {
+            (Variable::Array(items), _) => items.contains(other),
+            (Variable::Object(map), Some(key)) => map.contains_key(key),
+            (haystack, Some(needle)) => haystack.as_str().is_some_and(|haystack| haystack.contains(needle)),
+            _ => false
+        }
+    }
+
+    // Mirrors Liquid's Utils.to_number: decimal strings become floats, other strings are read
+    // like Ruby's String#to_i and anything non-numeric (nil included) counts as 0.
+    pub fn to_number(&self) -> Variable {
+        match *self {
+            Variable::Int(value)                                 => Variable::Int(value),
+            Variable::Float(value)                               => Variable::Float(value),
+            Variable::Str(ref value) | Variable::Safe(ref value) => parse_number(value.trim()),
+            _                                                    => Variable::Int(0)
+        }
+    }
+
+    // Dates pass through untouched, integers are read as unix timestamps and strings may be
+    // "now"/"today" or any of the common RFC 3339, RFC 2822 and `%Y-%m-%d` layouts.
+    #[cfg(feature = "chrono")]
+    pub fn to_date(&self) -> Option<DateTime<FixedOffset>> {
+        let utc = |date: DateTime<Utc>| date.with_timezone(&FixedOffset::east_opt(0).unwrap());
+
+        match *self {
+            Variable::Date(value)    => Some(value),
+            Variable::Int(value)     => Utc.timestamp_opt(value, 0).single().map(utc),
+            Variable::Str(ref value) | Variable::Safe(ref value) => parse_date(value.trim()).or_else(|| match value.trim() {
+                "now" | "today" => Some(utc(Utc::now())),
+                _               => None
+            }),
+            _ => None
+        }
+    }
+
+    pub fn plus(&self, other: &Variable) -> VariableResult<Variable> {
+        self.arithmetic(other, "plus", i64::checked_add, |a, b| a + b)
+    }
+
+    pub fn minus(&self, other: &Variable) -> VariableResult<Variable> {
+        self.arithmetic(other, "minus", i64::checked_sub, |a, b| a - b)
+    }
+
+    pub fn times(&self, other: &Variable) -> VariableResult<Variable> {
+        self.arithmetic(other, "times", i64::checked_mul, |a, b| a * b)
+    }
+
+    pub fn divided_by(&self, other: &Variable) -> VariableResult<Variable> {
+        if other.to_number().is_zero() { return Err(VariableError::DivisionByZero); }
+
+        self.arithmetic(other, "divided_by", floor_div, |a, b| a / b)
+    }
+
+    pub fn modulo(&self, other: &Variable) -> VariableResult<Variable> {
+        if other.to_number().is_zero() { return Err(VariableError::DivisionByZero); }
+
+        self.arithmetic(other, "modulo", floor_mod, |a, b| {
+            let remainder = a % b;
+            if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) { remainder + b } else { remainder }
+        })
+    }
+
+    pub(crate) fn child(&self, key: &str) -> Option<&Variable> {
+        match *self {
+            Variable::Object(ref map) => map.get(key),
+            Variable::Array(ref items) => match key {
+                "first" => items.first(),
+                "last"  => items.last(),
+                _       => key.parse::<isize>().ok().and_then(|index| {
+                    let index = if index < 0 { items.len() as isize + index } else { index };
+                    if index < 0 { return None; }
+
+                    items.get(index as usize)
+                })
+            },
+            _ => None
+        }
+    }
+
+    // Ruby's #inspect, which is how hashes (and anything nested inside them) are written out.
+    fn inspect(&self) -> String {
+        match *self {
+            Variable::Nil | Variable::Empty | Variable::Blank => "nil".into(),
+            Variable::Str(ref value)   => format!("{:?}", value),
+            Variable::Safe(ref value)  => format!("{:?}", value),
+            Variable::Array(ref items) => {
+                let items: Vec<String> = items.iter().map(Variable::inspect).collect();
+                format!("[{}]", items.join(", "))
+            },
+            Variable::Object(ref map)  => {
+                let mut keys: Vec<&String> = map.keys().collect();
+                keys.sort();
+
+                let pairs: Vec<String> = keys.iter().map(|key| format!("{:?}=>{}", key, map[*key].inspect())).collect();
+                format!("{{{}}}", pairs.join(", "))
+            },
+            _ => self.to_string()
+        }
+    }
+
+    fn arithmetic<I, F>(&self, other: &Variable, name: &'static str, int_op: I, float_op: F) -> VariableResult<Variable>
+        where I: Fn(i64, i64) -> Option<i64>, F: Fn(f64, f64) -> f64 {
+        match (self.to_number(), other.to_number()) {
+            (Variable::Int(a), Variable::Int(b)) => int_op(a, b).map(Variable::Int).ok_or(VariableError::Overflow(name)),
+            (left, right) => Ok(Variable::Float(float_op(left.as_float(), right.as_float())))
+        }
+    }
+
+    fn as_float(&self) -> f64 {
+        match *self {
+            Variable::Int(value)   => value as f64,
+            Variable::Float(value) => value,
+            _                      => 0.0
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        self.as_float() == 0.0
+    }
+
+    fn is_orderable(&self) -> bool {
+        match *self {
+            Variable::Int(_) | Variable::Float(_) | Variable::Str(_) | Variable::Safe(_) => true,
+            #[cfg(feature = "chrono")]
+            Variable::Date(_) => true,
+            _ => false
+        }
+    }
+}
+
+fn parse_number(value: &str) -> Variable {
+    let unsigned = value.strip_prefix('-').unwrap_or(value);
+    let mut parts = unsigned.splitn(2, '.');
+    let is_digits = |part: Option<&str>| part.is_some_and(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()));
+
+    if is_digits(parts.next()) && is_digits(parts.next()) {
+        return value.parse().map(Variable::Float).unwrap_or(Variable::Int(0));
+    }
+
+    let sign   = if value.starts_with('-') || value.starts_with('+') { 1 } else { 0 };
+    let digits = value[sign..].bytes().take_while(|b| b.is_ascii_digit()).count();
+    Variable::Int(value[..sign + digits].parse().unwrap_or(0))
+}
+
+#[cfg(feature = "chrono")]
+fn parse_date(value: &str) -> Option<DateTime<FixedOffset>> {
+    use chrono::NaiveDate;
+
+    DateTime::parse_from_rfc3339(value).ok()
+        .or_else(|| DateTime::parse_from_rfc2822(value).ok())
+        .or_else(|| DateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S %z").ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d").ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|date| FixedOffset::east_opt(0).unwrap().from_utc_datetime(&date))
+        })
+}
+
+fn format_float(value: f64) -> String {
+    if value.is_nan() { return "NaN".into(); }
+    if value.is_infinite() { return if value > 0.0 { "Infinity".into() } else { "-Infinity".into() }; }
+
+    let magnitude = value.abs();
+    if magnitude >= 1e16 || (magnitude != 0.0 && magnitude < 1e-4) {
+        let formatted       = format!("{:e}", value);
+        let (mantissa, exp) = formatted.split_at(formatted.find('e').unwrap());
+        let exp: i32        = exp[1..].parse().unwrap();
+        let mantissa        = if mantissa.contains('.') { mantissa.to_string() } else { format!("{}.0", mantissa) };
+
+        return format!("{}e{}{:02}", mantissa, if exp < 0 { '-' } else { '+' }, exp.abs());
+    }
+
+    let formatted = format!("{}", value);
+    if formatted.contains('.') { formatted } else { format!("{}.0", formatted) }
+}
+
+fn floor_div(a: i64, b: i64) -> Option<i64> {
+    let quotient = a.checked_div(b)?;
+    if a % b != 0 && (a < 0) != (b < 0) { quotient.checked_sub(1) } else { Some(quotient) }
+}
+
+fn floor_mod(a: i64, b: i64) -> Option<i64> {
+    let remainder = a.checked_rem(b)?;
+    if remainder != 0 && (remainder < 0) != (b < 0) { Some(remainder + b) } else { Some(remainder) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+    use std::collections::HashMap;
+
+    fn string(value: &str) -> Variable {
+        Variable::Str(value.into())
+    }
+
+    #[test]
+    fn empty_equals_empty_strings_arrays_and_objects() {
+        assert_eq!(Variable::Empty, string(""));
+        assert_eq!(Variable::Array(vec![]), Variable::Empty);
+        assert_eq!(Variable::Empty, Variable::Object(HashMap::new()));
+        assert_eq!(Variable::Empty, Variable::Empty);
+
+        assert!(Variable::Empty != string(" "));
+        assert!(Variable::Empty != Variable::Nil);
+        assert!(Variable::Empty != Variable::Array(vec![Variable::Nil]));
+        assert!(Variable::Empty != Variable::Blank);
+    }
+
+    #[test]
+    fn blank_equals_nil_false_whitespace_and_empty_values() {
+        assert_eq!(Variable::Blank, Variable::Nil);
+        assert_eq!(Variable::Blank, Variable::Bool(false));
+        assert_eq!(string(" \t\n"), Variable::Blank);
+        assert_eq!(Variable::Blank, Variable::Array(vec![]));
+        assert_eq!(Variable::Blank, Variable::Object(HashMap::new()));
+
+        assert!(Variable::Blank != Variable::Bool(true));
+        assert!(Variable::Blank != string("a"));
+        assert!(Variable::Blank != Variable::Int(0));
+    }
+
+    #[test]
+    fn equality_compares_integers_and_floats_numerically() {
+        assert_eq!(Variable::Int(1), Variable::Float(1.0));
+        assert_eq!(Variable::Float(2.0), Variable::Int(2));
+        assert!(Variable::Int(1) != Variable::Float(1.5));
+        assert!(Variable::Int(1) != string("1"));
+        assert!(Variable::Nil != Variable::Bool(false));
+    }
+
+    #[test]
+    fn partial_cmp_orders_numbers_and_strings() {
+        assert!(Variable::Int(1) < Variable::Int(2));
+        assert!(Variable::Int(2) >= Variable::Float(1.5));
+        assert!(Variable::Float(0.5) < Variable::Int(1));
+        assert!(string("apple") < string("banana"));
+
+        assert_eq!(None, Variable::Int(1).partial_cmp(&string("1")));
+        assert_eq!(None, Variable::Bool(true).partial_cmp(&Variable::Bool(false)));
+        assert_eq!(Some(Ordering::Equal), Variable::Nil.partial_cmp(&Variable::Nil));
+    }
+
+    #[test]
+    fn compare_returns_the_ordering_of_comparable_values() {
+        assert_eq!(Ok(Some(Ordering::Less)), Variable::Int(1).compare(&Variable::Float(1.5)));
+        assert_eq!(Ok(Some(Ordering::Equal)), string("a").compare(&string("a")));
+        assert_eq!(Ok(Some(Ordering::Greater)), Variable::Float(3.0).compare(&Variable::Int(2)));
+    }
+
+    #[test]
+    fn compare_returns_none_when_either_side_cant_be_ordered() {
+        assert_eq!(Ok(None), Variable::Nil.compare(&Variable::Int(1)));
+        assert_eq!(Ok(None), Variable::Bool(true).compare(&Variable::Bool(false)));
+        assert_eq!(Ok(None), Variable::Array(vec![]).compare(&Variable::Int(1)));
+    }
+
+    #[test]
+    fn compare_returns_an_error_for_incompatible_types() {
+        assert_eq!(
+            Err(VariableError::InvalidComparison("integer", "string")),
+            Variable::Int(1).compare(&string("a"))
+        );
+
+        assert_eq!(
+            Err(VariableError::InvalidComparison("float", "float")),
+            Variable::Float(f64::NAN).compare(&Variable::Float(1.0))
+        );
+    }
+
+    #[test]
+    fn contains_checks_substrings_elements_and_keys() {
+        let mut map = HashMap::new();
+        map.insert("name".to_string(), Variable::Nil);
+
+        assert!(string("hello world").contains(&string("lo w")));
+        assert!(!string("hello").contains(&string("z")));
+        assert!(Variable::Array(vec![Variable::Int(1), string("b")]).contains(&Variable::Float(1.0)));
+        assert!(!Variable::Array(vec![string("b")]).contains(&string("c")));
+        assert!(Variable::Object(map).contains(&string("name")));
+        assert!(!Variable::Nil.contains(&Variable::Nil));
+    }
+
+    #[test]
+    fn is_truthy_only_rejects_nil_and_false() {
+        assert!(!Variable::Nil.is_truthy());
+        assert!(!Variable::Bool(false).is_truthy());
+        assert!(Variable::Int(0).is_truthy());
+        assert!(string("").is_truthy());
+    }
+
+    #[test]
+    fn to_number_coerces_like_liquid() {
+        assert_eq!(Variable::Int(5), Variable::Int(5).to_number());
+        assert_eq!(Variable::Float(1.5), string(" 1.5 ").to_number());
+        assert_eq!(Variable::Float(-2.25), string("-2.25").to_number());
+        assert_eq!(Variable::Int(12), string("12abc").to_number());
+        assert_eq!(Variable::Int(-3), string("-3").to_number());
+        assert_eq!(Variable::Int(1), string("1.").to_number());
+        assert_eq!(Variable::Int(0), string("abc").to_number());
+        assert_eq!(Variable::Int(0), string("-").to_number());
+        assert_eq!(Variable::Int(0), Variable::Nil.to_number());
+        assert_eq!(Variable::Int(0), Variable::Bool(true).to_number());
+    }
+
+    #[test]
+    fn plus_minus_and_times_keep_integers_when_possible() {
+        assert_eq!(Ok(Variable::Int(5)), Variable::Int(2).plus(&Variable::Int(3)));
+        assert_eq!(Ok(Variable::Int(3)), Variable::Nil.plus(&string("3")));
+        assert_eq!(Ok(Variable::Float(3.5)), Variable::Int(2).plus(&string("1.5")));
+        assert_eq!(Ok(Variable::Int(-1)), string("2").minus(&Variable::Int(3)));
+        assert_eq!(Ok(Variable::Float(1.5)), Variable::Float(2.0).minus(&Variable::Float(0.5)));
+        assert_eq!(Ok(Variable::Int(12)), Variable::Int(4).times(&string("3")));
+        assert_eq!(Ok(Variable::Float(5.0)), Variable::Int(2).times(&Variable::Float(2.5)));
+    }
+
+    #[test]
+    fn divided_by_floors_integer_division() {
+        assert_eq!(Ok(Variable::Int(3)), Variable::Int(7).divided_by(&Variable::Int(2)));
+        assert_eq!(Ok(Variable::Int(-4)), Variable::Int(-7).divided_by(&Variable::Int(2)));
+        assert_eq!(Ok(Variable::Float(3.5)), Variable::Int(7).divided_by(&Variable::Float(2.0)));
+        assert_eq!(Err(VariableError::DivisionByZero), Variable::Int(7).divided_by(&Variable::Int(0)));
+        assert_eq!(Err(VariableError::DivisionByZero), Variable::Int(7).divided_by(&string("0.0")));
+    }
+
+    #[test]
+    fn modulo_follows_the_sign_of_the_divisor() {
+        assert_eq!(Ok(Variable::Int(1)), Variable::Int(7).modulo(&Variable::Int(3)));
+        assert_eq!(Ok(Variable::Int(2)), Variable::Int(-7).modulo(&Variable::Int(3)));
+        assert_eq!(Ok(Variable::Int(-2)), Variable::Int(7).modulo(&Variable::Int(-3)));
+        assert_eq!(Ok(Variable::Float(1.5)), Variable::Float(7.5).modulo(&Variable::Int(3)));
+        assert_eq!(Err(VariableError::DivisionByZero), Variable::Int(7).modulo(&Variable::Nil));
+    }
+
+    #[test]
+    fn arithmetic_reports_integer_overflow() {
+        let max = Variable::Int(i64::MAX);
+
+        assert_eq!(Err(VariableError::Overflow("plus")), max.plus(&Variable::Int(1)));
+        assert_eq!(Err(VariableError::Overflow("times")), max.times(&Variable::Int(2)));
+        assert_eq!(Err(VariableError::Overflow("divided_by")), Variable::Int(i64::MIN).divided_by(&Variable::Int(-1)));
+    }
+
+    #[test]
+    fn render_to_string_writes_scalars_like_ruby() {
+        assert_eq!("", Variable::Nil.render_to_string());
+        assert_eq!("", Variable::Empty.render_to_string());
+        assert_eq!("true", Variable::Bool(true).render_to_string());
+        assert_eq!("false", Variable::Bool(false).render_to_string());
+        assert_eq!("42", Variable::Int(42).render_to_string());
+        assert_eq!("-7", Variable::Int(-7).render_to_string());
+        assert_eq!("hello", string("hello").render_to_string());
+    }
+
+    #[test]
+    fn render_to_string_writes_floats_like_ruby() {
+        assert_eq!("1.5", Variable::Float(1.5).render_to_string());
+        assert_eq!("2.0", Variable::Float(2.0).render_to_string());
+        assert_eq!("-0.25", Variable::Float(-0.25).render_to_string());
+        assert_eq!("0.30000000000000004", Variable::Float(0.1 + 0.2).render_to_string());
+        assert_eq!("1.0e+20", Variable::Float(1e20).render_to_string());
+        assert_eq!("1.5e-05", Variable::Float(0.000015).render_to_string());
+        assert_eq!("Infinity", Variable::Float(f64::INFINITY).render_to_string());
+        assert_eq!("NaN", Variable::Float(f64::NAN).render_to_string());
+    }
+
+    #[test]
+    fn render_to_string_concatenates_arrays() {
+        let nested = Variable::Array(vec![Variable::Int(3), Variable::Nil, string("d")]);
+        let array  = Variable::Array(vec![string("a"), Variable::Float(1.0), Variable::Bool(true), nested]);
+
+        assert_eq!("a1.0true3d", array.render_to_string());
+        assert_eq!("", Variable::Array(vec![]).render_to_string());
+    }
+
+    #[test]
+    fn render_to_string_inspects_objects() {
+        let mut map = HashMap::new();
+        map.insert("name".to_string(), string("Peter \"P\""));
+        map.insert("age".to_string(), Variable::Int(30));
+        map.insert("tags".to_string(), Variable::Array(vec![string("a"), Variable::Nil]));
+
+        assert_eq!(r#"{"age"=>30, "name"=>"Peter \"P\"", "tags"=>["a", nil]}"#, Variable::Object(map).render_to_string());
+    }
+
+    #[cfg(feature = "chrono")]
+    mod dates {
+        use super::super::*;
+        use chrono::DateTime;
+
+        fn date(value: &str) -> Variable {
+            Variable::Date(DateTime::parse_from_rfc3339(value).unwrap())
+        }
+
+        #[test]
+        fn render_to_string_writes_dates_like_ruby_times() {
+            assert_eq!("2015-10-21 07:28:00 -0700", date("2015-10-21T07:28:00-07:00").render_to_string());
+        }
+
+        #[test]
+        fn dates_compare_chronologically() {
+            assert_eq!(date("2015-10-21T14:28:00Z"), date("2015-10-21T07:28:00-07:00"));
+            assert!(date("2015-10-21T00:00:00Z") < date("2015-10-22T00:00:00Z"));
+            assert_eq!(
+                Err(VariableError::InvalidComparison("date", "integer")),
+                date("2015-10-21T00:00:00Z").compare(&Variable::Int(1))
+            );
+        }
+
+        #[test]
+        fn to_date_converts_strings_and_timestamps() {
+            let expected = DateTime::parse_from_rfc3339("2015-10-21T00:00:00Z").ok();
+
+            assert_eq!(expected, Variable::Str("2015-10-21".into()).to_date());
+            assert_eq!(expected, Variable::Str("2015-10-21T00:00:00Z".into()).to_date());
+            assert_eq!(expected, Variable::Str("Wed, 21 Oct 2015 00:00:00 +0000".into()).to_date());
+            assert_eq!(expected, Variable::Int(1445385600).to_date());
+            assert_eq!(expected, date("2015-10-21T00:00:00Z").to_date());
+            assert!(Variable::Str("now".into()).to_date().is_some());
+            assert_eq!(None, Variable::Str("not a date".into()).to_date());
+            assert_eq!(None, Variable::Nil.to_date());
+        }
+    }
+
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn json_values_become_variables() {
+        let value: Value = serde_json::from_str(r#"{"n": null, "i": 1, "f": 1.5, "s": "x", "a": [true]}"#).unwrap();
+        let Variable::Object(object) = Variable::from(value) else { panic!("expected an object") };
+
+        assert_eq!(Some(&Variable::Nil), object.get("n"));
+        assert_eq!(Some(&Variable::Int(1)), object.get("i"));
+        assert_eq!(Some(&Variable::Float(1.5)), object.get("f"));
+        assert_eq!(Some(&string("x")), object.get("s"));
+        assert_eq!(Some(&Variable::Array(vec![Variable::Bool(true)])), object.get("a"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn variables_round_trip_through_serde() {
+        let source   = r#"{"c":18446744073709551615,"a":[true,null,1,1.5,"x"],"b":{"d":"e"}}"#;
+        let variable = serde_json::from_str::<Variable>(source).unwrap();
+        let Variable::Object(ref object) = variable else { panic!("expected an object") };
+
+        assert_eq!(Some(&Variable::Float(18446744073709551615.0)), object.get("c"));
+        assert_eq!(r#"{"a":[true,null,1,1.5,"x"],"b":{"d":"e"},"c":1.8446744073709552e+19}"#, serde_json::to_string(&variable).unwrap());
+        assert_eq!(r#"["<b>",null,null]"#, serde_json::to_string(&Variable::Array(vec![Variable::Safe("<b>".into()), Variable::Empty, Variable::Lambda(Lambda::new(|_| Variable::Nil))])).unwrap());
+        assert_eq!(Variable::from(serde_json::from_str::<Value>(source).unwrap()), serde_json::from_str::<Variable>(source).unwrap());
+    }
+}
@@ -0,0 +1,164 @@
+use ast::{Condition,Expression,FilterChain,ForLoop,Include,IncludeValue,Lookup,Node,Tag,TagArguments};
+use lexer::Span;
+
+// Every method has a default, so a visitor only overrides what it cares about. Overriding
+// `visit_output` or `visit_expression` stops the walk from going any deeper unless the override
+// calls `walk_output` or `walk_expression` itself.
+pub trait Visitor {
+    fn visit_text(&mut self, _text: &str, _span: Span) {}
+
+    fn visit_output(&mut self, output: &FilterChain, _span: Span) {
+        walk_output(self, output);
+    }
+
+    fn visit_tag(&mut self, tag: &Tag, _span: Span) {
+        walk_tag(self, tag);
+    }
+
+    fn visit_expression(&mut self, expression: &Expression) {
+        walk_expression(self, expression);
+    }
+}
+
+pub fn walk<V: Visitor + ?Sized>(nodes: &[Node], visitor: &mut V) {
+    for node in nodes {
+        match *node {
+            Node::Text(ref text, span)     => visitor.visit_text(text, span),
+            Node::Output(ref output, span) => visitor.visit_output(output, span),
+            Node::Tag(ref tag, span)       => visitor.visit_tag(tag, span)
+        }
+    }
+}
+
+pub fn walk_output<V: Visitor + ?Sized>(visitor: &mut V, output: &FilterChain) {
+    visitor.visit_expression(&output.expression);
+
+    for filter in &output.filters {
+        filter.arguments.iter().for_each(|argument| visitor.visit_expression(argument));
+        filter.keyword_arguments.iter().for_each(|(_, argument)| visitor.visit_expression(argument));
+    }
+}
+
+pub fn walk_tag<V: Visitor + ?Sized>(visitor: &mut V, tag: &Tag) {
+    match tag.arguments {
+        TagArguments::Condition(ref condition)                                 => walk_condition(visitor, condition),
+        TagArguments::Assign(_, ref output) | TagArguments::Output(ref output) => walk_output(visitor, output),
+        TagArguments::Include(ref include)                                     => walk_include(visitor, include),
+        TagArguments::For(ref each)                                            => walk_for_loop(visitor, each),
+        TagArguments::Unparsed                                                 => {}
+    }
+}
+
+pub fn walk_include<V: Visitor + ?Sized>(visitor: &mut V, include: &Include) {
+    visitor.visit_expression(&include.template);
+
+    match include.value {
+        Some(IncludeValue::With(ref value)) | Some(IncludeValue::For(ref value)) => visitor.visit_expression(value),
+        None                                                                     => {}
+    }
+
+    include.arguments.iter().for_each(|(_, argument)| visitor.visit_expression(argument));
+}
+
+pub fn walk_for_loop<V: Visitor + ?Sized>(visitor: &mut V, each: &ForLoop) {
+    visitor.visit_expression(&each.collection);
+    each.limit.iter().chain(each.offset.iter()).for_each(|argument| visitor.visit_expression(argument));
+}
+
+pub fn walk_condition<V: Visitor + ?Sized>(visitor: &mut V, condition: &Condition) {
+    match *condition {
+        Condition::Test(ref expression) => visitor.visit_expression(expression),
+        Condition::Comparison(ref left, _, ref right) => {
+            visitor.visit_expression(left);
+            visitor.visit_expression(right);
+        },
+        Condition::And(ref left, ref right) | Condition::Or(ref left, ref right) => {
+            walk_condition(visitor, left);
+            walk_condition(visitor, right);
+        }
+    }
+}
+
+pub fn walk_expression<V: Visitor + ?Sized>(visitor: &mut V, expression: &Expression) {
+    match *expression {
+        Expression::Variable(ref lookups, _) => {
+            for lookup in lookups {
+                if let Lookup::Index(ref index) = *lookup { visitor.visit_expression(index); }
+            }
+        },
+        Expression::Range(ref start, ref end, _) => {
+            visitor.visit_expression(start);
+            visitor.visit_expression(end);
+        },
+        Expression::Literal(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use template::Template;
+
+    #[derive(Default)]
+    struct Dependencies {
+        variables: Vec<String>,
+        tags: Vec<String>,
+        text: usize
+    }
+
+    impl Visitor for Dependencies {
+        fn visit_text(&mut self, text: &str, _span: Span) {
+            self.text += text.len();
+        }
+
+        fn visit_tag(&mut self, tag: &Tag, span: Span) {
+            self.tags.push(format!("{} at {}", tag.name, span));
+            walk_tag(self, tag);
+        }
+
+        fn visit_expression(&mut self, expression: &Expression) {
+            if let Expression::Variable(..) = *expression {
+                self.variables.push(expression.to_string());
+            }
+
+            walk_expression(self, expression);
+        }
+    }
+
+    #[test]
+    fn walk_visits_every_node_and_expression() {
+        let template         = Template::parse("Hi {{ user.name | default: guest, size: (1..max) }}{% if x and y > 1 %}!{% assign z = w | f %}").unwrap();
+        let mut dependencies = Dependencies::default();
+        walk(&template.nodes, &mut dependencies);
+
+        assert_eq!(vec!["user.name", "guest", "max", "x", "y", "w"], dependencies.variables);
+        assert_eq!(vec!["if at line 1, column 52", "assign at line 1, column 73"], dependencies.tags);
+        assert_eq!(4, dependencies.text);
+
+        let mut dependencies = Dependencies::default();
+        walk(&Template::parse("{{ a[b[c]]['d'] }}").unwrap().nodes, &mut dependencies);
+        assert_eq!(vec!["a[b[c]]['d']", "b[c]", "c"], dependencies.variables);
+    }
+
+    #[test]
+    fn overriding_a_visit_method_skips_its_children() {
+        struct Outputs(usize, usize);
+
+        impl Visitor for Outputs {
+            fn visit_output(&mut self, _output: &FilterChain, _span: Span) {
+                self.0 += 1;
+            }
+
+            fn visit_expression(&mut self, _expression: &Expression) {
+                self.1 += 1;
+            }
+        }
+
+        let template    = Template::parse("{{ a }} {{ b | f: c }}").unwrap();
+        let mut outputs = Outputs(0, 0);
+        walk(&template.nodes, &mut outputs);
+
+        assert_eq!(2, outputs.0);
+        assert_eq!(0, outputs.1);
+    }
+}
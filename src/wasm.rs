@@ -0,0 +1,110 @@
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use context::Context;
+use diagnostic::Diagnostic;
+use environment::Environment;
+use error::Error;
+use template::Template;
+use variable::Variable;
+
+// An Environment for JavaScript, say for previewing templates in the browser. There's no filesystem
+// to load includes from, so they have to be added first. Variables are passed as a JSON object.
+#[wasm_bindgen(js_name = Environment)]
+#[derive(Default)]
+pub struct WasmEnvironment {
+    environment: Environment
+}
+
+#[wasm_bindgen(js_class = Environment)]
+impl WasmEnvironment {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WasmEnvironment {
+        WasmEnvironment::default()
+    }
+
+    #[wasm_bindgen(js_name = addTemplate)]
+    pub fn add_template(&mut self, name: &str, source: &str) -> Result<(), JsError> {
+        self.environment.add_template(name, source).map_err(|error| JsError::new(&excerpt(&error, source)))
+    }
+
+    pub fn render(&self, name: &str, data: Option<String>) -> Result<String, JsError> {
+        let mut context = context(data.as_deref()).map_err(|message| JsError::new(&message))?;
+        self.environment.render(name, &mut context).map_err(|error| JsError::new(&Diagnostic::from(&error).message))
+    }
+
+    #[wasm_bindgen(js_name = renderSource)]
+    pub fn render_source(&self, source: &str, data: Option<String>) -> Result<String, JsError> {
+        render_with(&self.environment, source, data.as_deref()).map_err(|message| JsError::new(&message))
+    }
+}
+
+#[wasm_bindgen]
+pub fn render(source: &str, data: Option<String>) -> Result<String, JsError> {
+    render_with(&Environment::new(), source, data.as_deref()).map_err(|message| JsError::new(&message))
+}
+
+// Every syntax error in `source`, each with the line it's on.
+#[wasm_bindgen]
+pub fn check(source: &str) -> Vec<String> {
+    Template::parse_recovering(source).1.into_iter()
+        .map(|error| Diagnostic::from(&Error::from(error)).display(source).to_string())
+        .collect()
+}
+
+// Kept apart from the exported functions, which can only build a JsError when running as wasm.
+fn render_with(environment: &Environment, source: &str, data: Option<&str>) -> Result<String, String> {
+    let mut context = context(data)?;
+
+    environment.parse(source)
+        .and_then(|template| environment.render_template(&template, &mut context))
+        .map_err(|error| excerpt(&error, source))
+}
+
+fn context(data: Option<&str>) -> Result<Context, String> {
+    let mut context = Context::new();
+    let Some(data)  = data.filter(|data| !data.trim().is_empty()) else { return Ok(context) };
+
+    let variables = match serde_json::from_str::<Value>(data).map(Variable::from).map_err(|error| error.to_string())? {
+        Variable::Object(variables) => variables,
+        _                           => return Err("The data has to be a JSON object".to_string())
+    };
+
+    context.extend(variables).map_err(|error| error.to_string())?;
+    Ok(context)
+}
+
+// Errors from an include point into the include's source, which isn't at hand.
+fn excerpt(error: &Error, source: &str) -> String {
+    let diagnostic = Diagnostic::from(error);
+
+    match diagnostic.template {
+        Some(_) => diagnostic.message,
+        None    => diagnostic.display(source).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_with_takes_variables_as_json() {
+        let mut environment = Environment::new();
+        environment.add_template("name", "{{ user.name }}").unwrap();
+
+        assert_eq!(Ok("Hi ann!".to_string()), render_with(&environment, "Hi {% include 'name' %}!", Some(r#"{"user": {"name": "ann"}}"#)));
+        assert_eq!(Ok("Hi !".to_string()), render_with(&environment, "Hi {{ user }}!", None));
+        assert_eq!(Err("The data has to be a JSON object".to_string()), render_with(&environment, "", Some("[1]")));
+        assert_eq!(
+            "error: Unexpected character '#' at line 1, column 4\n --> <template>:1:4\n  |\n1 | {{ # }}\n  |    ^",
+            render_with(&environment, "{{ # }}", None).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn check_reports_every_error() {
+        assert_eq!(2, check("{{ # }} {% if %}").len());
+        assert!(check("{{ a }}").is_empty());
+    }
+}
@@ -0,0 +1,59 @@
+use actix_web::body::BoxBody;
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use actix_web::{HttpRequest,HttpResponse,Responder};
+
+use environment::Environment;
+use web::{Page,Render};
+
+// Renders with the app's `Data<Environment>`, so register one with `App::app_data`. Without it,
+// `Render` responds with a 500.
+impl<N: AsRef<str>> Responder for Render<N> {
+    type Body = BoxBody;
+
+    fn respond_to(self, request: &HttpRequest) -> HttpResponse {
+        let Some(environment) = request.app_data::<Data<Environment>>() else {
+            return HttpResponse::InternalServerError().body("Render needs a Data<Environment> in the app data");
+        };
+
+        let Render(name, mut context) = self;
+        Page::render(environment, name.as_ref(), &mut context).respond_to(request)
+    }
+}
+
+impl Responder for Page {
+    type Body = BoxBody;
+
+    fn respond_to(self, _request: &HttpRequest) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        HttpResponse::build(status).content_type(self.content_type).body(self.body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+    use actix_web::http::header;
+    use actix_web::test::TestRequest;
+
+    use context::Context;
+
+    #[test]
+    fn render_uses_the_environment_in_the_app_data() {
+        let mut environment = Environment::new();
+        environment.add_template("hello", "Hi {{ name }}").unwrap();
+
+        let mut context = Context::new();
+        context.add("name", "Ann".into()).unwrap();
+
+        let request  = TestRequest::default().app_data(Data::new(environment)).to_http_request();
+        let response = Render("hello", context).respond_to(&request);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!(Some("text/html; charset=utf-8"), response.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()));
+        assert_eq!(&b"Hi Ann"[..], &response.into_body().try_into_bytes().unwrap()[..]);
+
+        let response = Render("hello", Context::new()).respond_to(&TestRequest::default().to_http_request());
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+    }
+}
@@ -0,0 +1,83 @@
+use std::future::{self,Ready};
+use std::sync::{Arc,Mutex,PoisonError};
+
+use axum::extract::State;
+use axum::http::{header,HeaderValue,StatusCode};
+use axum::response::{IntoResponse,Response};
+
+use context::Context;
+use environment::Environment;
+use web::{Page,Render};
+
+// A response can't reach the app's state, so `Render` leaves the template to a layer that can:
+//
+//     Router::new()
+//         .route("/profile", get(profile))
+//         .layer(middleware::map_response_with_state(environment, riquid::web::axum::render))
+//
+// where `environment` is an `Arc<Environment>`. Without the layer, `Render` responds with a 500.
+pub fn render(State(environment): State<Arc<Environment>>, response: Response) -> Ready<Response> {
+    future::ready(finish(&environment, response))
+}
+
+impl<N: AsRef<str>> IntoResponse for Render<N> {
+    fn into_response(self) -> Response {
+        let Render(name, context) = self;
+        let pending               = Pending(Arc::new(Mutex::new(Some((name.as_ref().to_string(), context)))));
+
+        let mut response = (StatusCode::INTERNAL_SERVER_ERROR, "Render needs riquid::web::axum::render as a layer").into_response();
+        response.extensions_mut().insert(pending);
+        response
+    }
+}
+
+impl IntoResponse for Page {
+    fn into_response(self) -> Response {
+        let status       = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut response = (status, self.body).into_response();
+        response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(self.content_type));
+
+        response
+    }
+}
+
+// Extensions have to be Clone, which a Context isn't, so it's shared until the layer takes it.
+#[derive(Clone)]
+struct Pending(Arc<Mutex<Option<(String, Context)>>>);
+
+fn finish(environment: &Environment, mut response: Response) -> Response {
+    let Some(Pending(pending)) = response.extensions_mut().remove::<Pending>() else { return response };
+    let Some((name, mut context)) = pending.lock().unwrap_or_else(PoisonError::into_inner).take() else { return response };
+
+    Page::render(environment, &name, &mut context).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use axum::{Router,middleware};
+
+    #[test]
+    fn the_layer_renders_what_handlers_return() {
+        let mut environment = Environment::new();
+        environment.add_template("feed.json", "[]").unwrap();
+
+        let response = Render("feed.json", Context::new()).into_response();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, response.status());
+
+        let response = finish(&environment, response);
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("application/json", response.headers()[header::CONTENT_TYPE]);
+
+        let response = finish(&environment, Render("missing".to_string(), Context::new()).into_response());
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        assert_eq!(StatusCode::IM_A_TEAPOT, finish(&environment, StatusCode::IM_A_TEAPOT.into_response()).status());
+    }
+
+    #[test]
+    fn render_fits_map_response_with_state() {
+        let handler       = || future::ready(Render("profile", Context::new()));
+        let _: Router<()> = Router::new().route("/", get(handler)).layer(middleware::map_response_with_state(Arc::new(Environment::new()), render));
+    }
+}
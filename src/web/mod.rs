@@ -0,0 +1,87 @@
+use context::Context;
+use environment::Environment;
+use error::Error;
+use loader::LoadError;
+use render::RenderError;
+
+#[cfg(feature = "actix")]
+pub mod actix;
+#[cfg(feature = "axum")]
+pub mod axum;
+
+// What a handler returns to have the named template rendered as its response, like
+// `Render("profile", context)`. The content type goes by the name's extension, HTML when there
+// isn't one. How it finds the Environment depends on the framework; see `axum` and `actix`.
+pub struct Render<N = &'static str>(pub N, pub Context);
+
+// A rendered template, or what to send in its place, before it's made into a framework's response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Page {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String
+}
+
+impl Page {
+    // A template that doesn't exist is a 404, and any other error a 500. Only debug builds say
+    // what went wrong, so a production page doesn't give away its templates.
+    pub fn render(environment: &Environment, name: &str, context: &mut Context) -> Page {
+        match environment.render(name, context) {
+            Ok(body)                               => Page { status: 200, content_type: content_type(name), body },
+            Err(ref error) if missing(error, name) => Page::error(404, "Not Found", error),
+            Err(ref error)                         => Page::error(500, "Internal Server Error", error)
+        }
+    }
+
+    pub fn error(status: u16, reason: &str, error: &Error) -> Page {
+        let body = if cfg!(debug_assertions) { format!("{}: {}", reason, error) } else { reason.to_string() };
+        Page { status, content_type: "text/plain; charset=utf-8", body }
+    }
+}
+
+fn content_type(name: &str) -> &'static str {
+    match name.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("json") => "application/json",
+        Some("xml")  => "application/xml",
+        Some("txt")  => "text/plain; charset=utf-8",
+        Some("css")  => "text/css; charset=utf-8",
+        Some("js")   => "text/javascript; charset=utf-8",
+        _            => "text/html; charset=utf-8"
+    }
+}
+
+// Whether `error` is about `name` itself not existing, rather than an include it's missing.
+fn missing(error: &Error, name: &str) -> bool {
+    match *error {
+        Error::Render(RenderError::UnknownTemplate(ref missing))     => missing == name,
+        Error::Template(ref template, ref error) if template == name => matches!(**error, Error::Load(LoadError::NotFound(_))),
+        _                                                            => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loader::FsLoader;
+
+    #[test]
+    fn pages_carry_a_content_type_and_status() {
+        let mut environment = Environment::new();
+        environment.add_template("profile", "<p>{{ name }}</p>{% include 'avatar' %}").unwrap();
+        environment.add_template("avatar", "").unwrap();
+        environment.add_template("feed.json", "{}").unwrap();
+        environment.add_template("broken", "{% include 'gone' %}").unwrap();
+
+        let mut context = Context::new();
+        context.add("name", "Ann".into()).unwrap();
+
+        assert_eq!(Page { status: 200, content_type: "text/html; charset=utf-8", body: "<p>Ann</p>".to_string() }, Page::render(&environment, "profile", &mut context));
+        assert_eq!("application/json", Page::render(&environment, "feed.json", &mut Context::new()).content_type);
+        assert_eq!(404, Page::render(&environment, "missing", &mut Context::new()).status);
+        assert_eq!(500, Page::render(&environment, "broken", &mut Context::new()).status);
+
+        environment.set_loader(FsLoader::new(".", "liquid"));
+        assert_eq!(404, Page::render(&environment, "missing", &mut Context::new()).status);
+        assert!(Page::render(&environment, "missing", &mut Context::new()).body.starts_with("Not Found: "));
+    }
+}